@@ -0,0 +1,197 @@
+use crate::dom::{self, URL_ATTRIBUTES};
+use crate::srcset;
+use ego_tree::NodeId;
+use lazy_static::lazy_static;
+use scraper::{Html, Node, Selector};
+use url::Url;
+
+lazy_static! {
+    static ref BASE_SELECTOR: Selector = Selector::parse("base[href]").unwrap();
+}
+
+/// Attributes whose values are a comma-separated list of image candidates
+const SRCSET_ATTRIBUTES: [&str; 2] = ["imagesrcset", "srcset"];
+
+/// Rewrites relative URLs in the document's attributes into absolute ones
+///
+/// URLs are resolved against the document's `<base href>` if it has one
+/// (itself resolved against `base_url`), and against `base_url` otherwise.
+/// Values that can't be resolved are left untouched.
+pub(crate) fn absolutize_urls(html: &mut Html, base_url: &Url) {
+    let base_url = document_base_url(html, Some(base_url)).unwrap_or_else(|| base_url.clone());
+
+    let element_ids: Vec<NodeId> = html
+        .tree
+        .root()
+        .descendants()
+        .filter(|node| node.value().is_element())
+        .map(|node| node.id())
+        .collect();
+
+    for element_id in element_ids {
+        let Some(mut node) = html.tree.get_mut(element_id) else {
+            continue;
+        };
+
+        if let Node::Element(element) = node.value() {
+            dom::map_attrs(element, |name, value| {
+                if URL_ATTRIBUTES.contains(&name) {
+                    absolutize(&base_url, value)
+                } else if SRCSET_ATTRIBUTES.contains(&name) {
+                    Some(absolutize_srcset(&base_url, value))
+                } else {
+                    None
+                }
+            });
+        }
+    }
+}
+
+/// Returns the URL that relative URLs in the document are resolved against
+/// ([HTML spec][1]): the first `<base href>` resolved against `document_url`,
+/// or `document_url` itself if there is no such element or it is invalid.
+///
+/// [1]: https://html.spec.whatwg.org/multipage/urls-and-fetching.html#document-base-url
+pub(crate) fn document_base_url(html: &Html, document_url: Option<&Url>) -> Option<Url> {
+    dom::select(html, &BASE_SELECTOR)
+        .next()
+        .and_then(|base| {
+            let href = base.attr("href")?.trim();
+
+            match document_url {
+                Some(document_url) => document_url.join(href),
+                None => Url::parse(href),
+            }
+            .ok()
+        })
+        .or_else(|| document_url.cloned())
+}
+
+fn absolutize(base_url: &Url, value: &str) -> Option<String> {
+    base_url.join(value.trim()).ok().map(String::from)
+}
+
+/// Resolves each URL of a `srcset` attribute, keeping its descriptors
+fn absolutize_srcset(base_url: &Url, srcset: &str) -> String {
+    srcset::parse_srcset(srcset)
+        .into_iter()
+        .map(|candidate| {
+            let url =
+                absolutize(base_url, candidate.url).unwrap_or_else(|| candidate.url.to_string());
+
+            std::iter::once(url.as_str())
+                .chain(candidate.descriptors)
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn absolutize_urls(input: &str, base_url: &str) -> String {
+        let mut html = Html::parse_fragment(input);
+        super::absolutize_urls(&mut html, &Url::parse(base_url).unwrap());
+        html.root_element().inner_html()
+    }
+
+    #[test]
+    fn test_absolutize_urls() {
+        assert_eq!(
+            r##"<a href="https://example.com/blog/post">Post</a><a href="https://example.com/about">About</a><a href="https://example.com/blog/feed#top">Top</a>"##,
+            absolutize_urls(
+                r##"<a href="post">Post</a><a href="/about">About</a><a href="#top">Top</a>"##,
+                "https://example.com/blog/feed"
+            ),
+            "relative URLs are resolved"
+        );
+
+        assert_eq!(
+            r#"<a href="mailto:me@example.com">Mail</a><img src="https://cdn.example.com/a.png"><a title="page">Title</a>"#,
+            absolutize_urls(
+                r#"<a href="mailto:me@example.com">Mail</a><img src="https://cdn.example.com/a.png"><a title="page">Title</a>"#,
+                "https://example.com/"
+            ),
+            "absolute URLs and other attributes are left alone"
+        );
+
+        assert_eq!(
+            r#"<form action="https://example.com/search"><button formaction="https://example.com/lucky"></button></form><video poster="https://example.com/p.jpg"></video>"#,
+            absolutize_urls(
+                r#"<form action=" search "><button formaction="lucky"></button></form><video poster="p.jpg"></video>"#,
+                "https://example.com/"
+            ),
+            "every URL attribute is handled"
+        );
+
+        assert_eq!(
+            r#"<base href="https://cdn.example.com/assets/"><img src="https://cdn.example.com/assets/a.png">"#,
+            absolutize_urls(
+                r#"<base href="/assets/"><img src="a.png">"#,
+                "https://cdn.example.com/blog/"
+            ),
+            "the document's base URL is used"
+        );
+    }
+
+    #[test]
+    fn test_document_base_url() {
+        let document_url = Url::parse("https://example.com/blog/post").unwrap();
+        let base_url = |input: &str, document_url: Option<&Url>| {
+            document_base_url(&Html::parse_document(input), document_url).map(String::from)
+        };
+
+        assert_eq!(
+            Some("https://example.com/blog/post".to_string()),
+            base_url("<p>No base</p>", Some(&document_url))
+        );
+        assert_eq!(None, base_url("<p>No base</p>", None));
+
+        assert_eq!(
+            Some("https://example.com/assets/".to_string()),
+            base_url(
+                r#"<base target="_blank"><base href="/assets/"><base href="/other/">"#,
+                Some(&document_url)
+            ),
+            "the first base element with an href is used"
+        );
+        assert_eq!(
+            None,
+            base_url(r#"<base href="/assets/">"#, None),
+            "relative base URLs need a document URL"
+        );
+        assert_eq!(
+            Some("https://cdn.example.com/".to_string()),
+            base_url(r#"<base href="https://cdn.example.com/">"#, None)
+        );
+        assert_eq!(
+            Some("https://example.com/blog/post".to_string()),
+            base_url(r#"<base href="https://[invalid">"#, Some(&document_url)),
+            "invalid base URLs are ignored"
+        );
+    }
+
+    #[test]
+    fn test_absolutize_srcset() {
+        let base_url = Url::parse("https://example.com/images/").unwrap();
+
+        assert_eq!(
+            "https://example.com/images/a.png 1x, https://example.com/b.png 2x",
+            absolutize_srcset(&base_url, "a.png 1x,/b.png   2x")
+        );
+
+        assert_eq!(
+            "https://example.com/images/a.png, https://example.com/images/b.png 100w",
+            absolutize_srcset(&base_url, " a.png, b.png 100w ,")
+        );
+
+        assert_eq!(
+            "data:image/png;base64,AAAA 1x",
+            absolutize_srcset(&base_url, "data:image/png;base64,AAAA 1x"),
+            "commas inside URLs are kept"
+        );
+    }
+}
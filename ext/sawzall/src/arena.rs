@@ -0,0 +1,334 @@
+use crate::parse_error::{ErrorSink, Parsed, SourceLines};
+use crate::parse_limits::{LimitExceeded, ParseLimits};
+use ego_tree::Tree;
+use html5ever::driver::{self, Parser};
+use html5ever::{local_name, namespace_url, ns, tendril::TendrilSink, QualName};
+use scraper::node::{Element, Text};
+use scraper::{Html, Node};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Inputs up to this many bytes get a tree sized from their markup
+const SMALL_FRAGMENT_LEN: usize = 4096;
+
+/// Input is fed to the parser in chunks of this many bytes when the tree is
+/// limited, so that parsing stops soon after it outgrows the limits
+const LIMITED_CHUNK_LEN: usize = 16_384;
+
+/// Parses HTML into trees whose node storage is allocated up front
+///
+/// Parsed trees own their nodes so they can't be handed back for reuse, but
+/// remembering how many nodes the previous parse produced lets the next one
+/// allocate them all at once instead of repeatedly growing (and copying) the
+/// tree. This pays off when parsing many similarly sized inputs in a loop.
+#[derive(Debug, Default)]
+pub(crate) struct Arena {
+    capacity: AtomicUsize,
+}
+
+impl Arena {
+    /// The number of nodes allocated up front by the next parse
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn parse(&self, input: &str, fragment: bool) -> Parsed {
+        let parsed = parse_with_capacity(input, fragment, self.capacity());
+
+        self.capacity
+            .store(parsed.html.tree.nodes().count(), Ordering::Relaxed);
+
+        parsed
+    }
+}
+
+/// Parses an HTML fragment, taking shortcuts for small inputs
+///
+/// Inputs without any markup skip the parser entirely, and other small
+/// inputs get a tree sized from their number of tags. Both produce the same
+/// tree as [`Html::parse_fragment`], along with the parse errors.
+pub(crate) fn parse_fragment(input: &str) -> Parsed {
+    // Character references, carriage returns and NULs are all rewritten by
+    // the tokenizer so they need the full parser too
+    if !input
+        .bytes()
+        .any(|b| matches!(b, b'<' | b'&' | b'\r' | b'\0'))
+    {
+        return Parsed {
+            html: text_fragment(input),
+            errors: Vec::new(),
+            lines: SourceLines::default(),
+        };
+    }
+
+    if input.len() > SMALL_FRAGMENT_LEN {
+        return parse_with_capacity(input, true, 0);
+    }
+
+    // Each tag creates at most one node and is followed by at most one text
+    // node, on top of the root, context and `<html>` nodes
+    let tags = input.bytes().filter(|&b| b == b'<').count();
+
+    parse_with_capacity(input, true, 2 * tags + 4)
+}
+
+/// Parses an HTML document, along with the parse errors
+pub(crate) fn parse_document(input: &str) -> Parsed {
+    parse_with_capacity(input, false, 0)
+}
+
+/// Parses HTML, failing once the tree outgrows the depth and node limits
+///
+/// The input size limit is left to the caller, which can check it before
+/// converting the input to UTF-8.
+pub(crate) fn parse_within(
+    input: &str,
+    fragment: bool,
+    limits: ParseLimits,
+) -> Result<Parsed, LimitExceeded> {
+    if !limits.limits_tree() {
+        return Ok(if fragment {
+            parse_fragment(input)
+        } else {
+            parse_document(input)
+        });
+    }
+
+    let mut parser = StreamingParser::new(fragment, limits);
+    let mut rest = input;
+
+    while !rest.is_empty() {
+        let mut end = rest.len().min(LIMITED_CHUNK_LEN);
+        while !rest.is_char_boundary(end) {
+            end += 1;
+        }
+
+        let (chunk, tail) = rest.split_at(end);
+        parser.feed(chunk)?;
+        rest = tail;
+    }
+
+    parser.finish()
+}
+
+/// Parses HTML that is fed to it piece by piece, e.g. as it is read from an
+/// IO, checking the depth and node limits in between
+pub(crate) struct StreamingParser(Parser<ErrorSink>);
+
+// SAFETY: the parser's tendrils aren't `Send` because their reference counts
+// aren't atomic, but none of them are shared outside of it (the tree copies
+// them into its own), so moving the parser as a whole to another thread is
+// fine
+unsafe impl Send for StreamingParser {}
+
+impl StreamingParser {
+    pub(crate) fn new(fragment: bool, limits: ParseLimits) -> Self {
+        Self(parser(fragment, 0, limits))
+    }
+
+    pub(crate) fn feed(&mut self, input: &str) -> Result<(), LimitExceeded> {
+        self.0.process(input.into());
+
+        match self.0.tokenizer.sink.sink.exceeded() {
+            Some(exceeded) => Err(exceeded),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<Parsed, LimitExceeded> {
+        self.0.finish()
+    }
+}
+
+fn parse_with_capacity(input: &str, fragment: bool, capacity: usize) -> Parsed {
+    parser(fragment, capacity, ParseLimits::default())
+        .one(input)
+        .expect("parsing without limits can't exceed them")
+}
+
+fn parser(fragment: bool, capacity: usize, limits: ParseLimits) -> Parser<ErrorSink> {
+    if fragment {
+        let mut html = Html::new_fragment();
+        html.tree = Tree::with_capacity(Node::Fragment, capacity);
+
+        driver::parse_fragment(
+            ErrorSink::new(html, limits),
+            Default::default(),
+            QualName::new(None, ns!(html), local_name!("body")),
+            Vec::new(),
+        )
+    } else {
+        let mut html = Html::new_document();
+        html.tree = Tree::with_capacity(Node::Document, capacity);
+
+        driver::parse_document(ErrorSink::new(html, limits), Default::default())
+    }
+}
+
+/// Builds the tree the parser would produce for a fragment that is only text
+fn text_fragment(input: &str) -> Html {
+    let mut html = Html::new_fragment();
+    html.tree = Tree::with_capacity(Node::Fragment, 4);
+
+    // The parser leaves the `<body>` context element detached from the tree
+    html.tree.orphan(Node::Element(Element::new(
+        QualName::new(None, ns!(html), local_name!("body")),
+        Vec::new(),
+    )));
+
+    let mut root = html.tree.root_mut();
+    let mut html_element = root.append(Node::Element(Element::new(
+        QualName::new(None, ns!(html), local_name!("html")),
+        Vec::new(),
+    )));
+
+    if !input.is_empty() {
+        html_element.append(Node::Text(Text { text: input.into() }));
+    }
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_error::ParseError;
+
+    #[test]
+    fn test_parse() {
+        let arena = Arena::default();
+        let input = "<p>One <b>two</b></p><p>Three</p>";
+
+        assert_eq!(0, arena.capacity());
+        assert_eq!(
+            Html::parse_fragment(input).root_element().html(),
+            arena.parse(input, true).html.root_element().html()
+        );
+        assert_eq!(
+            Html::parse_fragment(input).tree.nodes().count(),
+            arena.capacity(),
+            "the capacity matches the previous tree"
+        );
+
+        assert_eq!(
+            Html::parse_document(input).html(),
+            arena.parse(input, false).html.html()
+        );
+        assert_eq!(
+            Html::parse_fragment(input).root_element().html(),
+            arena.parse(input, true).html.root_element().html(),
+            "preallocated trees parse the same"
+        );
+    }
+
+    #[test]
+    fn test_parse_fragment() {
+        for input in [
+            "",
+            "Plain text",
+            "  Text with\nwhitespace ",
+            "Text with &amp; references",
+            "Line\r\nbreaks",
+            "<p>Small <b>markup</b></p>",
+            "Unclosed <p",
+            &"<p>Large</p>".repeat(1000),
+        ] {
+            assert_eq!(
+                Html::parse_fragment(input),
+                parse_fragment(input).html,
+                "{input:?} is parsed the same"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Vec::<ParseError>::new(),
+            parse_fragment("<p>Valid</p>").errors
+        );
+        assert_eq!(
+            Html::parse_document("<p>One\n</b>").html(),
+            parse_document("<p>One\n</b>").html.html()
+        );
+
+        let lines = |parsed: Parsed| {
+            parsed
+                .errors
+                .iter()
+                .map(|error| error.line)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![2], lines(parse_fragment("<p>One\n</b>")));
+        assert_eq!(
+            vec![1, 3],
+            lines(parse_document("<p>One\n\n</b>")),
+            "documents without a doctype are reported too"
+        );
+    }
+
+    #[test]
+    fn test_source_lines() {
+        let parsed = parse_document("<title>One</title>\n<p\n class=two>Two\n<b>three</b></p>");
+        let lines = parsed
+            .html
+            .tree
+            .nodes()
+            .filter(|node| node.value().is_element())
+            .map(|node| parsed.lines.get(node.id()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![Some(1), Some(1), Some(1), Some(3), Some(3), Some(4)],
+            lines,
+            "elements get the line their start tag ends on"
+        );
+        assert_eq!(None, parsed.lines.get(parsed.html.tree.root().id()));
+    }
+
+    #[test]
+    fn test_parse_within() {
+        let input = "<div><p>One <b>two</b></p></div>";
+        let limits = |max_depth, max_nodes| ParseLimits {
+            max_input_bytes: None,
+            max_depth,
+            max_nodes,
+        };
+
+        assert_eq!(
+            parse_fragment(input),
+            parse_within(input, true, limits(Some(4), Some(8))).unwrap()
+        );
+        assert_eq!(
+            Err(LimitExceeded::Depth(3)),
+            parse_within(input, true, limits(Some(3), None))
+        );
+        assert_eq!(
+            Err(LimitExceeded::Nodes(7)),
+            parse_within(input, true, limits(None, Some(7)))
+        );
+        assert_eq!(
+            Err(LimitExceeded::Depth(100)),
+            parse_within(&"<div>".repeat(100_000), false, limits(Some(100), None)),
+            "parsing stops at the first chunk"
+        );
+
+        let large = "<p>é</p>".repeat(LIMITED_CHUNK_LEN);
+        assert_eq!(
+            parse_document(&large),
+            parse_within(&large, false, limits(None, Some(usize::MAX))).unwrap(),
+            "chunks end on character boundaries"
+        );
+    }
+
+    #[test]
+    fn test_streaming_parser() {
+        let input = "<!doctype html><title>One</title><p>Two\r\nthree";
+        let mut parser = StreamingParser::new(false, ParseLimits::default());
+
+        for chunk in input.as_bytes().chunks(3) {
+            parser.feed(std::str::from_utf8(chunk).unwrap()).unwrap();
+        }
+
+        assert_eq!(parse_document(input), parser.finish().unwrap());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::dom;
+use regex::{Regex, RegexBuilder};
+use scraper::node::Element;
+
+/// `Regexp::IGNORECASE`
+const IGNORECASE: u32 = 1;
+/// `Regexp::EXTENDED`
+const EXTENDED: u32 = 2;
+/// `Regexp::MULTILINE`, which makes `.` match newlines
+const MULTILINE: u32 = 4;
+
+/// Builds a regex that matches like a Ruby `Regexp` with the given source
+/// and options
+///
+/// The syntax the two support mostly overlaps, but features only Ruby has
+/// (e.g. lookarounds and backreferences) are rejected.
+pub(crate) fn from_ruby(source: &str, options: u32) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(source)
+        // `^` and `$` always match at line boundaries in Ruby
+        .multi_line(true)
+        .case_insensitive(options & IGNORECASE != 0)
+        .ignore_whitespace(options & EXTENDED != 0)
+        .dot_matches_new_line(options & MULTILINE != 0)
+        .build()
+}
+
+/// Attributes whose values must match a regex, checked against the elements
+/// matched by a selector
+#[derive(Debug, Default)]
+pub(crate) struct AttrMatches(Vec<(String, Regex)>);
+
+impl AttrMatches {
+    pub(crate) fn push(&mut self, name: String, regex: Regex) {
+        self.0.push((name, regex));
+    }
+
+    /// Checks whether the element has every attribute and each value
+    /// matches its regex
+    pub(crate) fn matches(&self, element: &Element) -> bool {
+        self.0.iter().all(|(name, regex)| {
+            dom::attr(element, name).is_some_and(|value| regex.is_match(value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::{Html, Selector};
+
+    #[test]
+    fn test_from_ruby() {
+        let regex = from_ruby(r"\d{4}\/\d{2}", 0).unwrap();
+        assert!(regex.is_match("/2024/05/post"));
+        assert!(!regex.is_match("/about"));
+
+        assert!(from_ruby("^b", 0).unwrap().is_match("a\nb"));
+        assert!(from_ruby("A.B", IGNORECASE | MULTILINE)
+            .unwrap()
+            .is_match("a\nb"));
+        assert!(!from_ruby("a.b", 0).unwrap().is_match("a\nb"));
+        assert!(from_ruby("a b # comment", EXTENDED).unwrap().is_match("ab"));
+        assert!(from_ruby("(?<=a)b", 0).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let html = Html::parse_fragment(
+            r#"<a href="/2024/05/post" rel="next">Post</a><a href="/about">About</a><a>None</a>"#,
+        );
+        let mut attr_matches = AttrMatches::default();
+        attr_matches.push("href".to_string(), from_ruby(r"\d{4}", 0).unwrap());
+
+        let matches = |attr_matches: &AttrMatches| {
+            html.select(&Selector::parse("a").unwrap())
+                .filter(|element| attr_matches.matches(element.value()))
+                .map(|element| element.inner_html())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["Post"], matches(&attr_matches));
+
+        attr_matches.push("rel".to_string(), from_ruby("^prev$", 0).unwrap());
+        assert_eq!(Vec::<String>::new(), matches(&attr_matches));
+        assert_eq!(3, matches(&AttrMatches::default()).len());
+    }
+}
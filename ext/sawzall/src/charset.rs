@@ -0,0 +1,763 @@
+/// Only this many bytes are scanned for a `<meta>` declaring the encoding,
+/// like browsers do
+const PRESCAN_LEN: usize = 1024;
+
+/// An encoding from the Encoding Standard, see
+/// https://encoding.spec.whatwg.org/#names-and-labels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Charset {
+    /// The name from the standard
+    pub(crate) name: &'static str,
+    /// The name of the equivalent Ruby encoding, which does the transcoding
+    pub(crate) ruby_name: &'static str,
+    labels: &'static [&'static str],
+}
+
+pub(crate) const UTF_8: Charset = Charset {
+    name: "UTF-8",
+    ruby_name: "UTF-8",
+    labels: &[
+        "unicode-1-1-utf-8",
+        "unicode11utf8",
+        "unicode20utf8",
+        "utf-8",
+        "utf8",
+        "x-unicode20utf8",
+    ],
+};
+
+const UTF_16BE: Charset = Charset {
+    name: "UTF-16BE",
+    ruby_name: "UTF-16BE",
+    labels: &["unicodefffe", "utf-16be"],
+};
+
+const UTF_16LE: Charset = Charset {
+    name: "UTF-16LE",
+    ruby_name: "UTF-16LE",
+    labels: &[
+        "csunicode",
+        "iso-10646-ucs-2",
+        "ucs-2",
+        "unicode",
+        "unicodefeff",
+        "utf-16",
+        "utf-16le",
+    ],
+};
+
+const WINDOWS_1252: Charset = Charset {
+    name: "windows-1252",
+    ruby_name: "Windows-1252",
+    labels: &[
+        "ansi_x3.4-1968",
+        "ascii",
+        "cp1252",
+        "cp819",
+        "csisolatin1",
+        "ibm819",
+        "iso-8859-1",
+        "iso-ir-100",
+        "iso8859-1",
+        "iso88591",
+        "iso_8859-1",
+        "iso_8859-1:1987",
+        "l1",
+        "latin1",
+        "us-ascii",
+        "windows-1252",
+        "x-cp1252",
+    ],
+};
+
+/// Every encoding of the standard but `replacement` and `x-user-defined`,
+/// which have no Ruby equivalent
+const CHARSETS: [Charset; 38] = [
+    UTF_8,
+    UTF_16BE,
+    UTF_16LE,
+    WINDOWS_1252,
+    Charset {
+        name: "IBM866",
+        ruby_name: "IBM866",
+        labels: &["866", "cp866", "csibm866", "ibm866"],
+    },
+    Charset {
+        name: "ISO-8859-2",
+        ruby_name: "ISO-8859-2",
+        labels: &[
+            "csisolatin2",
+            "iso-8859-2",
+            "iso-ir-101",
+            "iso8859-2",
+            "iso88592",
+            "iso_8859-2",
+            "iso_8859-2:1987",
+            "l2",
+            "latin2",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-3",
+        ruby_name: "ISO-8859-3",
+        labels: &[
+            "csisolatin3",
+            "iso-8859-3",
+            "iso-ir-109",
+            "iso8859-3",
+            "iso88593",
+            "iso_8859-3",
+            "iso_8859-3:1988",
+            "l3",
+            "latin3",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-4",
+        ruby_name: "ISO-8859-4",
+        labels: &[
+            "csisolatin4",
+            "iso-8859-4",
+            "iso-ir-110",
+            "iso8859-4",
+            "iso88594",
+            "iso_8859-4",
+            "iso_8859-4:1988",
+            "l4",
+            "latin4",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-5",
+        ruby_name: "ISO-8859-5",
+        labels: &[
+            "csisolatincyrillic",
+            "cyrillic",
+            "iso-8859-5",
+            "iso-ir-144",
+            "iso8859-5",
+            "iso88595",
+            "iso_8859-5",
+            "iso_8859-5:1988",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-6",
+        ruby_name: "ISO-8859-6",
+        labels: &[
+            "arabic",
+            "asmo-708",
+            "csiso88596e",
+            "csiso88596i",
+            "csisolatinarabic",
+            "ecma-114",
+            "iso-8859-6",
+            "iso-8859-6-e",
+            "iso-8859-6-i",
+            "iso-ir-127",
+            "iso8859-6",
+            "iso88596",
+            "iso_8859-6",
+            "iso_8859-6:1987",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-7",
+        ruby_name: "ISO-8859-7",
+        labels: &[
+            "csisolatingreek",
+            "ecma-118",
+            "elot_928",
+            "greek",
+            "greek8",
+            "iso-8859-7",
+            "iso-ir-126",
+            "iso8859-7",
+            "iso88597",
+            "iso_8859-7",
+            "iso_8859-7:1987",
+            "sun_eu_greek",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-8",
+        ruby_name: "ISO-8859-8",
+        labels: &[
+            "csiso88598e",
+            "csisolatinhebrew",
+            "hebrew",
+            "iso-8859-8",
+            "iso-8859-8-e",
+            "iso-ir-138",
+            "iso8859-8",
+            "iso88598",
+            "iso_8859-8",
+            "iso_8859-8:1988",
+            "visual",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-8-I",
+        ruby_name: "ISO-8859-8",
+        labels: &["csiso88598i", "iso-8859-8-i", "logical"],
+    },
+    Charset {
+        name: "ISO-8859-10",
+        ruby_name: "ISO-8859-10",
+        labels: &[
+            "csisolatin6",
+            "iso-8859-10",
+            "iso-ir-157",
+            "iso8859-10",
+            "iso885910",
+            "l6",
+            "latin6",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-13",
+        ruby_name: "ISO-8859-13",
+        labels: &["iso-8859-13", "iso8859-13", "iso885913"],
+    },
+    Charset {
+        name: "ISO-8859-14",
+        ruby_name: "ISO-8859-14",
+        labels: &["iso-8859-14", "iso8859-14", "iso885914"],
+    },
+    Charset {
+        name: "ISO-8859-15",
+        ruby_name: "ISO-8859-15",
+        labels: &[
+            "csisolatin9",
+            "iso-8859-15",
+            "iso8859-15",
+            "iso885915",
+            "iso_8859-15",
+            "l9",
+        ],
+    },
+    Charset {
+        name: "ISO-8859-16",
+        ruby_name: "ISO-8859-16",
+        labels: &["iso-8859-16"],
+    },
+    Charset {
+        name: "KOI8-R",
+        ruby_name: "KOI8-R",
+        labels: &["cskoi8r", "koi", "koi8", "koi8-r", "koi8_r"],
+    },
+    Charset {
+        name: "KOI8-U",
+        ruby_name: "KOI8-U",
+        labels: &["koi8-ru", "koi8-u"],
+    },
+    Charset {
+        name: "macintosh",
+        ruby_name: "macRoman",
+        labels: &["csmacintosh", "mac", "macintosh", "x-mac-roman"],
+    },
+    Charset {
+        name: "windows-874",
+        ruby_name: "Windows-874",
+        labels: &[
+            "dos-874",
+            "iso-8859-11",
+            "iso8859-11",
+            "iso885911",
+            "tis-620",
+            "windows-874",
+        ],
+    },
+    Charset {
+        name: "windows-1250",
+        ruby_name: "Windows-1250",
+        labels: &["cp1250", "windows-1250", "x-cp1250"],
+    },
+    Charset {
+        name: "windows-1251",
+        ruby_name: "Windows-1251",
+        labels: &["cp1251", "windows-1251", "x-cp1251"],
+    },
+    Charset {
+        name: "windows-1253",
+        ruby_name: "Windows-1253",
+        labels: &["cp1253", "windows-1253", "x-cp1253"],
+    },
+    Charset {
+        name: "windows-1254",
+        ruby_name: "Windows-1254",
+        labels: &[
+            "cp1254",
+            "csisolatin5",
+            "iso-8859-9",
+            "iso-ir-148",
+            "iso8859-9",
+            "iso88599",
+            "iso_8859-9",
+            "iso_8859-9:1989",
+            "l5",
+            "latin5",
+            "windows-1254",
+            "x-cp1254",
+        ],
+    },
+    Charset {
+        name: "windows-1255",
+        ruby_name: "Windows-1255",
+        labels: &["cp1255", "windows-1255", "x-cp1255"],
+    },
+    Charset {
+        name: "windows-1256",
+        ruby_name: "Windows-1256",
+        labels: &["cp1256", "windows-1256", "x-cp1256"],
+    },
+    Charset {
+        name: "windows-1257",
+        ruby_name: "Windows-1257",
+        labels: &["cp1257", "windows-1257", "x-cp1257"],
+    },
+    Charset {
+        name: "windows-1258",
+        ruby_name: "Windows-1258",
+        labels: &["cp1258", "windows-1258", "x-cp1258"],
+    },
+    Charset {
+        name: "x-mac-cyrillic",
+        ruby_name: "macCyrillic",
+        labels: &["x-mac-cyrillic", "x-mac-ukrainian"],
+    },
+    Charset {
+        name: "GBK",
+        ruby_name: "GBK",
+        labels: &[
+            "chinese",
+            "csgb2312",
+            "csiso58gb231280",
+            "gb2312",
+            "gb_2312",
+            "gb_2312-80",
+            "gbk",
+            "iso-ir-58",
+            "x-gbk",
+        ],
+    },
+    Charset {
+        name: "gb18030",
+        ruby_name: "GB18030",
+        labels: &["gb18030"],
+    },
+    Charset {
+        name: "Big5",
+        ruby_name: "Big5-HKSCS",
+        labels: &["big5", "big5-hkscs", "cn-big5", "csbig5", "x-x-big5"],
+    },
+    Charset {
+        name: "EUC-JP",
+        ruby_name: "EUC-JP",
+        labels: &["cseucpkdfmtjapanese", "euc-jp", "x-euc-jp"],
+    },
+    Charset {
+        name: "ISO-2022-JP",
+        ruby_name: "ISO-2022-JP",
+        labels: &["csiso2022jp", "iso-2022-jp"],
+    },
+    Charset {
+        name: "Shift_JIS",
+        ruby_name: "Windows-31J",
+        labels: &[
+            "csshiftjis",
+            "ms932",
+            "ms_kanji",
+            "shift-jis",
+            "shift_jis",
+            "sjis",
+            "windows-31j",
+            "x-sjis",
+        ],
+    },
+    Charset {
+        name: "EUC-KR",
+        ruby_name: "CP949",
+        labels: &[
+            "cseuckr",
+            "csksc56011987",
+            "euc-kr",
+            "iso-ir-149",
+            "korean",
+            "ks_c_5601-1987",
+            "ks_c_5601-1989",
+            "ksc5601",
+            "ksc_5601",
+            "windows-949",
+        ],
+    },
+];
+
+/// The encoding of a document, and how many bytes of byte order mark to skip
+#[derive(Debug, PartialEq)]
+pub(crate) struct Sniffed {
+    pub(crate) charset: Charset,
+    pub(crate) bom_len: usize,
+}
+
+/// Looks up an encoding by one of its labels, like `"latin1"` or `"sjis"`
+pub(crate) fn charset_for_label(label: &str) -> Option<Charset> {
+    let label = label
+        .trim_matches(|c: char| c.is_ascii_whitespace())
+        .to_ascii_lowercase();
+
+    CHARSETS
+        .into_iter()
+        .find(|charset| charset.labels.contains(&label.as_str()))
+}
+
+/// Determines the encoding of a document following the HTML encoding
+/// sniffing algorithm, see
+/// https://html.spec.whatwg.org/multipage/parsing.html#encoding-sniffing-algorithm
+///
+/// A byte order mark wins over the charset of the `Content-Type` header the
+/// document was served with, which wins over a `<meta>` in its first 1024
+/// bytes. Without any of them, the document is UTF-8 if it's valid UTF-8 and
+/// windows-1252 otherwise, as that's what legacy pages most likely use.
+pub(crate) fn sniff(bytes: &[u8], content_type: Option<&str>) -> Sniffed {
+    for (bom, charset) in [
+        (&b"\xEF\xBB\xBF"[..], UTF_8),
+        (b"\xFE\xFF", UTF_16BE),
+        (b"\xFF\xFE", UTF_16LE),
+    ] {
+        if bytes.starts_with(bom) {
+            return Sniffed {
+                charset,
+                bom_len: bom.len(),
+            };
+        }
+    }
+
+    let charset = content_type
+        .and_then(|content_type| charset_from_content(content_type.as_bytes()))
+        .or_else(|| {
+            // The `<meta>` was read as ASCII so the document can't be UTF-16
+            prescan(&bytes[..bytes.len().min(PRESCAN_LEN)]).map(|charset| {
+                if charset == UTF_16BE || charset == UTF_16LE {
+                    UTF_8
+                } else {
+                    charset
+                }
+            })
+        })
+        .unwrap_or_else(|| {
+            if std::str::from_utf8(bytes).is_ok() {
+                UTF_8
+            } else {
+                WINDOWS_1252
+            }
+        });
+
+    Sniffed {
+        charset,
+        bom_len: 0,
+    }
+}
+
+/// Looks for a `<meta charset>` or `<meta http-equiv="Content-Type">`,
+/// skipping comments and other tags, see
+/// https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+fn prescan(bytes: &[u8]) -> Option<Charset> {
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let rest = &bytes[position..];
+
+        if rest.starts_with(b"<!--") {
+            position += find(&rest[2..], b"-->").map_or(rest.len(), |end| end + 5);
+        } else if starts_with_ignore_case(rest, b"<meta")
+            && rest.get(5).is_some_and(|&b| is_space(b) || b == b'/')
+        {
+            position += 5;
+            if let Some(charset) = meta_charset(bytes, &mut position) {
+                return Some(charset);
+            }
+        } else if rest.len() > 1
+            && (rest[1].is_ascii_alphabetic()
+                || (rest[1] == b'/' && rest.get(2).is_some_and(u8::is_ascii_alphabetic)))
+            && rest[0] == b'<'
+        {
+            // Skips the tag's name and then its attributes, so that their
+            // values can't be mistaken for markup
+            position += rest
+                .iter()
+                .position(|&b| is_space(b) || b == b'>')
+                .unwrap_or(rest.len());
+            while attribute(bytes, &mut position).is_some() {}
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"</") || rest.starts_with(b"<?") {
+            position += rest
+                .iter()
+                .position(|&b| b == b'>')
+                .map_or(rest.len(), |end| end + 1);
+        } else {
+            position += 1;
+        }
+    }
+
+    None
+}
+
+/// Reads the attributes of a `<meta>` and returns the encoding they declare
+fn meta_charset(bytes: &[u8], position: &mut usize) -> Option<Charset> {
+    let mut seen = Vec::new();
+    let mut got_pragma = false;
+    // Whether the encoding came from `content`, which needs `http-equiv`
+    let mut need_pragma = None;
+    let mut charset = None;
+
+    while let Some((name, value)) = attribute(bytes, position) {
+        if seen.contains(&name) {
+            continue;
+        }
+
+        match name.as_slice() {
+            b"http-equiv" => got_pragma |= value == b"content-type",
+            b"content" if charset.is_none() => {
+                if let Some(content_charset) = charset_from_content(&value) {
+                    charset = Some(content_charset);
+                    need_pragma = Some(true);
+                }
+            }
+            b"charset" => {
+                charset = std::str::from_utf8(&value).ok().and_then(charset_for_label);
+                need_pragma = Some(false);
+            }
+            _ => {}
+        }
+        seen.push(name);
+    }
+
+    match need_pragma {
+        Some(true) if !got_pragma => None,
+        Some(_) => charset,
+        None => None,
+    }
+}
+
+/// Reads an attribute of a tag, returning its lowercased name and value, or
+/// `None` at the end of the tag, see
+/// https://html.spec.whatwg.org/multipage/parsing.html#concept-get-attributes-when-sniffing
+fn attribute(bytes: &[u8], position: &mut usize) -> Option<(Vec<u8>, Vec<u8>)> {
+    let next = |position: &mut usize| {
+        let byte = bytes.get(*position).copied();
+        *position += 1;
+        byte
+    };
+    let skip_spaces = |position: &mut usize| {
+        while bytes
+            .get(*position)
+            .is_some_and(|&b| is_space(b) || b == b'/')
+        {
+            *position += 1;
+        }
+    };
+
+    skip_spaces(position);
+    if bytes.get(*position).map_or(true, |&b| b == b'>') {
+        return None;
+    }
+
+    let mut name = Vec::new();
+    let mut value = Vec::new();
+
+    loop {
+        match next(position)? {
+            b'=' if !name.is_empty() => break,
+            b'/' | b'>' => {
+                *position -= 1;
+                return Some((name, value));
+            }
+            b if is_space(b) => {
+                while bytes.get(*position).copied().is_some_and(is_space) {
+                    *position += 1;
+                }
+                if bytes.get(*position) != Some(&b'=') {
+                    return Some((name, value));
+                }
+                *position += 1;
+                break;
+            }
+            b => name.push(b.to_ascii_lowercase()),
+        }
+    }
+
+    while bytes.get(*position).copied().is_some_and(is_space) {
+        *position += 1;
+    }
+
+    match next(position)? {
+        quote @ (b'"' | b'\'') => loop {
+            match next(position)? {
+                b if b == quote => return Some((name, value)),
+                b => value.push(b.to_ascii_lowercase()),
+            }
+        },
+        b'>' => {
+            *position -= 1;
+            Some((name, value))
+        }
+        b => {
+            value.push(b.to_ascii_lowercase());
+            loop {
+                match bytes.get(*position) {
+                    None => return Some((name, value)),
+                    Some(&b) if is_space(b) || b == b'>' => return Some((name, value)),
+                    Some(&b) => value.push(b.to_ascii_lowercase()),
+                }
+                *position += 1;
+            }
+        }
+    }
+}
+
+/// Extracts the encoding from a `Content-Type` like
+/// `text/html; charset=utf-8`, see
+/// https://html.spec.whatwg.org/multipage/urls-and-fetching.html#algorithm-for-extracting-a-character-encoding-from-a-meta-element
+fn charset_from_content(content: &[u8]) -> Option<Charset> {
+    let mut position = 0;
+
+    loop {
+        position += find_ignore_case(&content[position..], b"charset")? + b"charset".len();
+
+        let rest = &content[position..];
+        let after_spaces = rest.iter().position(|&b| !is_space(b))?;
+        if rest[after_spaces] == b'=' {
+            position += after_spaces + 1;
+            break;
+        }
+    }
+
+    let rest = &content[position..];
+    let rest = &rest[rest.iter().position(|&b| !is_space(b))?..];
+
+    let value = match rest[0] {
+        quote @ (b'"' | b'\'') => {
+            let end = rest[1..].iter().position(|&b| b == quote)?;
+            &rest[1..=end]
+        }
+        _ => {
+            let end = rest
+                .iter()
+                .position(|&b| is_space(b) || b == b';')
+                .unwrap_or(rest.len());
+            &rest[..end]
+        }
+    };
+
+    charset_for_label(std::str::from_utf8(value).ok()?)
+}
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len() && bytes[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+fn find(bytes: &[u8], needle: &[u8]) -> Option<usize> {
+    bytes
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn find_ignore_case(bytes: &[u8], needle: &[u8]) -> Option<usize> {
+    bytes
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sniffed_name(bytes: &[u8], content_type: Option<&str>) -> &'static str {
+        sniff(bytes, content_type).charset.name
+    }
+
+    #[test]
+    fn test_charset_for_label() {
+        assert_eq!(Some(WINDOWS_1252), charset_for_label(" Latin1\n"));
+        assert_eq!(
+            Some("Shift_JIS"),
+            charset_for_label("sjis").map(|charset| charset.name)
+        );
+        assert_eq!(None, charset_for_label("utf-7"));
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(
+            Sniffed {
+                charset: UTF_16LE,
+                bom_len: 2
+            },
+            sniff(b"\xFF\xFE<\0p\0>\0", Some("text/html; charset=utf-8")),
+            "the byte order mark wins"
+        );
+        assert_eq!(
+            "windows-1251",
+            sniffed_name(
+                b"<meta charset=koi8-r>",
+                Some("text/html; Charset=\"windows-1251\"")
+            ),
+            "the Content-Type wins over the <meta>"
+        );
+        assert_eq!(
+            "KOI8-R",
+            sniffed_name(b"<meta charset=koi8-r>", Some("text/html"))
+        );
+        assert_eq!("UTF-8", sniffed_name("Caf\u{e9}".as_bytes(), None));
+        assert_eq!(
+            "windows-1252",
+            sniffed_name(b"Caf\xE9", None),
+            "invalid UTF-8 is most likely windows-1252"
+        );
+    }
+
+    #[test]
+    fn test_prescan() {
+        let prescanned = |html: &str| prescan(html.as_bytes()).map(|charset| charset.name);
+
+        assert_eq!(
+            Some("Shift_JIS"),
+            prescanned("<!DOCTYPE html><html lang=ja><head><META CHARSET='Shift_JIS'>")
+        );
+        assert_eq!(
+            Some("ISO-8859-2"),
+            prescanned(
+                r#"<meta http-equiv="Content-Type" content="text/html; charset=iso-8859-2">"#
+            )
+        );
+        assert_eq!(
+            None,
+            prescanned(r#"<meta content="text/html; charset=iso-8859-2">"#),
+            "content only counts with http-equiv"
+        );
+        assert_eq!(
+            Some("GBK"),
+            prescanned(r#"<!-- <meta charset="utf-8"> --><meta charset="gb2312">"#),
+            "comments are skipped"
+        );
+        assert_eq!(
+            Some("EUC-KR"),
+            prescanned(r#"<div title="<meta charset=utf-8>"><meta/charset=euc-kr>"#),
+            "attribute values are skipped"
+        );
+        assert_eq!(
+            Some("UTF-8"),
+            prescanned(r#"<meta charset="utf-8" charset="latin1">"#),
+            "the first duplicate attribute wins"
+        );
+        assert_eq!(
+            Some("windows-1252"),
+            prescanned("<meta charset=unknown><meta charset=latin1>"),
+            "unknown encodings are ignored"
+        );
+    }
+}
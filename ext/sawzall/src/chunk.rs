@@ -0,0 +1,320 @@
+use crate::dom;
+use crate::html_to_plain::{paragraphs, Paragraph};
+use scraper::{ElementRef, Html};
+
+/// Where chunks may start besides where the previous chunk filled up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Boundary {
+    /// Each heading starts a new chunk, so chunks don't mix sections
+    Headings,
+    /// Chunks are filled with as many paragraphs as fit
+    Paragraphs,
+}
+
+impl Boundary {
+    pub(crate) const ALL: [Boundary; 2] = [Boundary::Headings, Boundary::Paragraphs];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Boundary::Headings => "headings",
+            Boundary::Paragraphs => "paragraphs",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|boundary| boundary.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Options {
+    /// Must be positive
+    pub(crate) max_tokens: usize,
+    /// How many tokens from the end of a chunk to repeat at the start of the
+    /// next one in the same section. Must be less than `max_tokens`.
+    pub(crate) overlap: usize,
+    pub(crate) boundary: Boundary,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Chunk {
+    /// The text of the paragraphs in the chunk, separated by blank lines
+    pub(crate) text: String,
+    pub(crate) tokens: usize,
+    /// Selectors for the elements the text comes from, see [`dom::css_path`]
+    pub(crate) paths: Vec<String>,
+}
+
+/// A token along with the paragraph it's in and where it is in its text
+struct Token {
+    paragraph: usize,
+    start: usize,
+    end: usize,
+}
+
+/// Splits the text of a document's content into chunks of at most
+/// `max_tokens` tokens, keeping paragraphs whole when they fit
+///
+/// Tokens are approximated as runs of non-whitespace characters. Paragraphs
+/// too long for a single chunk are split between tokens.
+pub(crate) fn chunks(html: &Html, options: Options) -> Vec<Chunk> {
+    let paragraphs = paragraphs(dom::content_root(html));
+    let mut chunks = Vec::new();
+
+    for section in sections(&paragraphs, html, options.boundary) {
+        chunk_section(section, html, options, &mut chunks);
+    }
+
+    chunks
+}
+
+/// Splits paragraphs into runs that chunks may not span
+fn sections<'a>(
+    paragraphs: &'a [Paragraph],
+    html: &Html,
+    boundary: Boundary,
+) -> Vec<&'a [Paragraph]> {
+    if boundary == Boundary::Paragraphs {
+        return vec![paragraphs];
+    }
+
+    let mut sections = Vec::new();
+    let mut start = 0;
+
+    for (index, paragraph) in paragraphs.iter().enumerate() {
+        let is_heading = html
+            .tree
+            .get(paragraph.element)
+            .and_then(ElementRef::wrap)
+            .is_some_and(|element| {
+                matches!(
+                    element.value().name(),
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6"
+                )
+            });
+
+        if is_heading && index > start {
+            sections.push(&paragraphs[start..index]);
+            start = index;
+        }
+    }
+
+    sections.push(&paragraphs[start..]);
+    sections
+}
+
+fn chunk_section(section: &[Paragraph], html: &Html, options: Options, chunks: &mut Vec<Chunk>) {
+    let tokens: Vec<Token> = section
+        .iter()
+        .enumerate()
+        .flat_map(|(index, paragraph)| tokens(index, &paragraph.text))
+        .collect();
+    // Where the paragraph of each token ends, in tokens
+    let mut paragraph_ends = vec![0; section.len()];
+    for (index, token) in tokens.iter().enumerate() {
+        paragraph_ends[token.paragraph] = index + 1;
+    }
+
+    let mut start = 0;
+    let mut previous_end = 0;
+
+    while start < tokens.len() {
+        let mut end = start;
+
+        while end < tokens.len() {
+            let paragraph_end = paragraph_ends[tokens[end].paragraph];
+
+            if paragraph_end - start > options.max_tokens {
+                break;
+            }
+
+            end = paragraph_end;
+        }
+
+        // Split the paragraph when it doesn't fit, or when only the overlap
+        // with the previous chunk would
+        if end <= previous_end || end == start {
+            end = tokens.len().min(start + options.max_tokens);
+        }
+
+        chunks.push(chunk(section, &tokens[start..end], html));
+
+        if end == tokens.len() {
+            break;
+        }
+
+        start = end.saturating_sub(options.overlap).max(start + 1);
+        previous_end = end;
+    }
+}
+
+fn chunk(section: &[Paragraph], tokens: &[Token], html: &Html) -> Chunk {
+    let mut text = String::new();
+    let mut paths = Vec::new();
+
+    let mut remaining = tokens;
+
+    while let Some(first) = remaining.first() {
+        let count = remaining
+            .iter()
+            .take_while(|token| token.paragraph == first.paragraph)
+            .count();
+        let last = &remaining[count - 1];
+        let paragraph = &section[first.paragraph];
+        let path = html
+            .tree
+            .get(paragraph.element)
+            .and_then(ElementRef::wrap)
+            .map(dom::css_path)
+            .expect("paragraphs are in elements of the document");
+
+        if !text.is_empty() {
+            text.push_str("\n\n");
+        }
+        text.push_str(&paragraph.text[first.start..last.end]);
+
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+
+        remaining = &remaining[count..];
+    }
+
+    Chunk {
+        text,
+        tokens: tokens.len(),
+        paths,
+    }
+}
+
+fn tokens(paragraph: usize, text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+
+    for (index, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(index),
+            (Some(token_start), true) => {
+                tokens.push(Token {
+                    paragraph,
+                    start: token_start,
+                    end: index,
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(html: &str, max_tokens: usize, overlap: usize, boundary: Boundary) -> Vec<String> {
+        chunks(
+            &Html::parse_fragment(html),
+            Options {
+                max_tokens,
+                overlap,
+                boundary,
+            },
+        )
+        .into_iter()
+        .map(|chunk| chunk.text)
+        .collect()
+    }
+
+    #[test]
+    fn test_chunks() {
+        let html = "<h1>Title</h1><p>One two</p><p>Three\nfour</p><h2>Next</h2><p>Five six seven eight</p>";
+
+        assert_eq!(
+            vec![
+                "Title\n\nOne two\n\nThree four",
+                "Next\n\nFive six seven eight"
+            ],
+            texts(html, 10, 0, Boundary::Headings),
+            "headings start new chunks"
+        );
+        assert_eq!(
+            vec![
+                "Title\n\nOne two\n\nThree four",
+                "Next\n\nFive six seven eight"
+            ],
+            texts(html, 5, 0, Boundary::Headings),
+        );
+        assert_eq!(
+            vec![
+                "Title\n\nOne two",
+                "Three four\n\nNext",
+                "Five six seven eight"
+            ],
+            texts(html, 4, 0, Boundary::Paragraphs),
+            "paragraphs are kept whole when they fit"
+        );
+        assert_eq!(
+            vec![
+                "Title\n\nOne two",
+                "Three four",
+                "Next",
+                "Five six seven",
+                "eight"
+            ],
+            texts(html, 3, 0, Boundary::Headings),
+            "paragraphs that don't fit are split"
+        );
+        assert_eq!(
+            vec![
+                "Title\n\nOne two",
+                "two\n\nThree four",
+                "Next",
+                "Five six seven",
+                "seven eight"
+            ],
+            texts(html, 3, 1, Boundary::Headings),
+            "the overlap is repeated within sections"
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            texts("<p> </p>", 3, 1, Boundary::Headings)
+        );
+    }
+
+    #[test]
+    fn test_chunk_paths() {
+        let html =
+            Html::parse_document("<ul><li>One</li><li>Two</li></ul><p>Three <b>four</b></p>");
+        let chunks = chunks(
+            &html,
+            Options {
+                max_tokens: 2,
+                overlap: 0,
+                boundary: Boundary::Headings,
+            },
+        );
+
+        assert_eq!(
+            vec![
+                Chunk {
+                    text: "One\n\nTwo".to_string(),
+                    tokens: 2,
+                    paths: vec![
+                        "html > body > ul > li:nth-of-type(1)".to_string(),
+                        "html > body > ul > li:nth-of-type(2)".to_string()
+                    ],
+                },
+                Chunk {
+                    text: "Three four".to_string(),
+                    tokens: 2,
+                    paths: vec!["html > body > p".to_string()],
+                }
+            ],
+            chunks
+        );
+    }
+}
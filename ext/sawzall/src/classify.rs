@@ -0,0 +1,508 @@
+use scraper::{ElementRef, Html};
+use std::collections::HashMap;
+
+/// Paragraphs with at least this many characters count as article prose
+const LONG_PARAGRAPH_CHARS: usize = 150;
+
+/// Siblings sharing a name and class, each with a link, that make a listing
+const MIN_REPEATED_ITEMS: usize = 5;
+
+/// Phrases in a page's title or `<h1>` that mark it as an error page
+const ERROR_PHRASES: [&str; 7] = [
+    "404",
+    "not found",
+    "500",
+    "internal server error",
+    "access denied",
+    "forbidden",
+    "does not exist",
+];
+
+const LOGIN_PHRASES: [&str; 4] = ["log in", "login", "sign in", "signin"];
+
+const CART_PHRASES: [&str; 4] = ["add to cart", "add to bag", "add to basket", "buy now"];
+
+/// Kinds of pages told apart by [`classify`], from the most to the least
+/// specific, which is how ties are broken
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PageType {
+    Error,
+    Login,
+    Product,
+    Article,
+    Listing,
+}
+
+impl PageType {
+    pub(crate) const ALL: [PageType; 5] = [
+        PageType::Error,
+        PageType::Login,
+        PageType::Product,
+        PageType::Article,
+        PageType::Listing,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            PageType::Error => "error",
+            PageType::Login => "login",
+            PageType::Product => "product",
+            PageType::Article => "article",
+            PageType::Listing => "listing",
+        }
+    }
+}
+
+/// A structural signal pointing towards a type of page
+#[derive(Debug, PartialEq)]
+pub(crate) struct Evidence {
+    pub(crate) page_type: PageType,
+    pub(crate) weight: u32,
+    pub(crate) description: String,
+}
+
+/// The most likely type of a page along with what points towards each type
+#[derive(Debug, PartialEq)]
+pub(crate) struct Classification {
+    /// `None` when no type has enough evidence
+    pub(crate) page_type: Option<PageType>,
+    pub(crate) evidence: Vec<Evidence>,
+}
+
+impl Classification {
+    /// Returns the total weight of the evidence for a type of page
+    pub(crate) fn score(&self, page_type: PageType) -> u32 {
+        self.evidence
+            .iter()
+            .filter(|evidence| evidence.page_type == page_type)
+            .map(|evidence| evidence.weight)
+            .sum()
+    }
+}
+
+/// Signals gathered while walking the document
+#[derive(Default)]
+struct Features {
+    headings: Vec<String>,
+    og_type: Option<String>,
+    schema_types: Vec<String>,
+    article_elements: usize,
+    long_paragraphs: usize,
+    published_time: bool,
+    author: bool,
+    password_fields: usize,
+    login_phrase: Option<String>,
+    cart_phrase: Option<String>,
+    price: bool,
+    pagination: bool,
+    repeated_items: usize,
+}
+
+/// Guesses whether a page is an error page, a login form, a product, an
+/// article or a listing from structural signals gathered in one pass
+pub(crate) fn classify(html: &Html) -> Classification {
+    let features = features(html);
+    let mut evidence = Vec::new();
+    let mut add = |page_type, weight, description: String| {
+        evidence.push(Evidence {
+            page_type,
+            weight,
+            description,
+        })
+    };
+
+    for heading in &features.headings {
+        let lowercase = heading.to_lowercase();
+
+        if let Some(phrase) = ERROR_PHRASES
+            .iter()
+            .find(|phrase| lowercase.contains(*phrase))
+        {
+            add(
+                PageType::Error,
+                3,
+                format!("{heading:?} mentions {phrase:?}"),
+            );
+            break;
+        }
+    }
+
+    if features.password_fields > 0 {
+        add(PageType::Login, 3, "has a password field".to_string());
+    }
+
+    if let Some(phrase) = &features.login_phrase {
+        add(PageType::Login, 1, format!("mentions {phrase:?}"));
+    }
+
+    for schema_type in &features.schema_types {
+        match schema_type.as_str() {
+            "Product" => add(
+                PageType::Product,
+                3,
+                "has Product structured data".to_string(),
+            ),
+            "Article" | "NewsArticle" | "BlogPosting" | "Report" => add(
+                PageType::Article,
+                3,
+                format!("has {schema_type} structured data"),
+            ),
+            "ItemList" | "CollectionPage" | "SearchResultsPage" => add(
+                PageType::Listing,
+                3,
+                format!("has {schema_type} structured data"),
+            ),
+            _ => {}
+        }
+    }
+
+    match features.og_type.as_deref() {
+        Some("product") => add(PageType::Product, 3, "og:type is product".to_string()),
+        Some("article") => add(PageType::Article, 3, "og:type is article".to_string()),
+        _ => {}
+    }
+
+    if let Some(phrase) = &features.cart_phrase {
+        add(
+            PageType::Product,
+            2,
+            format!("has a button saying {phrase:?}"),
+        );
+    }
+
+    if features.price {
+        add(PageType::Product, 1, "has a price".to_string());
+    }
+
+    if features.article_elements == 1 {
+        add(PageType::Article, 2, "has an <article> element".to_string());
+    } else if features.article_elements >= 3 {
+        add(
+            PageType::Listing,
+            2,
+            format!("has {} <article> elements", features.article_elements),
+        );
+    }
+
+    if features.long_paragraphs >= 3 {
+        add(
+            PageType::Article,
+            2,
+            format!(
+                "has {} paragraphs of {LONG_PARAGRAPH_CHARS}+ characters",
+                features.long_paragraphs
+            ),
+        );
+    }
+
+    if features.published_time {
+        add(PageType::Article, 1, "has a publication date".to_string());
+    }
+
+    if features.author {
+        add(PageType::Article, 1, "has an author".to_string());
+    }
+
+    if features.repeated_items >= MIN_REPEATED_ITEMS {
+        add(
+            PageType::Listing,
+            3,
+            format!(
+                "has {} similar items with links in a row",
+                features.repeated_items
+            ),
+        );
+    }
+
+    if features.pagination {
+        add(PageType::Listing, 1, "has pagination".to_string());
+    }
+
+    let mut classification = Classification {
+        page_type: None,
+        evidence,
+    };
+    let mut best_score = 1;
+
+    for page_type in PageType::ALL {
+        let score = classification.score(page_type);
+
+        if score > best_score {
+            best_score = score;
+            classification.page_type = Some(page_type);
+        }
+    }
+
+    classification
+}
+
+fn features(html: &Html) -> Features {
+    let mut features = Features::default();
+
+    for element in html.tree.root().descendants().filter_map(ElementRef::wrap) {
+        let value = element.value();
+
+        match value.name() {
+            "title" | "h1" => features.headings.push(squished_text(element)),
+            "meta" => {
+                let key = value.attr("property").or_else(|| value.attr("name"));
+                let content = value.attr("content").unwrap_or_default();
+
+                match key.map(str::to_ascii_lowercase).as_deref() {
+                    Some("og:type") => features.og_type = Some(content.to_ascii_lowercase()),
+                    Some("article:published_time") => features.published_time = true,
+                    Some("author" | "article:author") => features.author = true,
+                    Some("product:price:amount") => features.price = true,
+                    _ => {}
+                }
+            }
+            "script" if value.attr("type") == Some("application/ld+json") => {
+                let json: String = element.text().collect();
+                features.schema_types.extend(json_ld_types(&json));
+            }
+            "article" => features.article_elements += 1,
+            "p" if squished_text(element).chars().count() >= LONG_PARAGRAPH_CHARS => {
+                features.long_paragraphs += 1;
+            }
+            "time" if value.attr("datetime").is_some() => features.published_time = true,
+            "input"
+                if value
+                    .attr("type")
+                    .is_some_and(|t| t.eq_ignore_ascii_case("password")) =>
+            {
+                features.password_fields += 1;
+            }
+            "a" | "link"
+                if value.attr("rel").is_some_and(|rel| {
+                    rel.split_ascii_whitespace()
+                        .any(|token| matches!(token, "next" | "prev"))
+                }) =>
+            {
+                features.pagination = true;
+            }
+            _ => {}
+        }
+
+        if let Some(item_type) = value.attr("itemtype") {
+            if let Some((_, name)) = item_type.trim().rsplit_once('/') {
+                features.schema_types.push(name.to_string());
+            }
+        }
+
+        match value.attr("itemprop") {
+            Some("price") => features.price = true,
+            Some("author") => features.author = true,
+            Some("datePublished") => features.published_time = true,
+            _ => {}
+        }
+
+        if value.attr("rel") == Some("author") {
+            features.author = true;
+        }
+
+        if value.classes().any(|class| class == "pagination") {
+            features.pagination = true;
+        }
+
+        if matches!(value.name(), "button" | "a" | "input" | "h1" | "title") {
+            let text = match value.name() {
+                "input" => value.attr("value").unwrap_or_default().to_lowercase(),
+                _ => squished_text(element).to_lowercase(),
+            };
+
+            if let Some(phrase) = CART_PHRASES.iter().find(|phrase| text.contains(*phrase)) {
+                features
+                    .cart_phrase
+                    .get_or_insert_with(|| phrase.to_string());
+            }
+
+            if let Some(phrase) = LOGIN_PHRASES.iter().find(|phrase| text.contains(*phrase)) {
+                features
+                    .login_phrase
+                    .get_or_insert_with(|| phrase.to_string());
+            }
+        }
+
+        if !is_in_navigation(element) {
+            features.repeated_items = features.repeated_items.max(repeated_items(element));
+        }
+    }
+
+    features
+}
+
+/// Returns the size of the largest group of child elements that share a name
+/// and class and each contain a link
+fn repeated_items(parent: ElementRef) -> usize {
+    let mut groups: HashMap<(&str, Option<&str>), usize> = HashMap::new();
+
+    for child in parent.child_elements() {
+        let has_link = child.value().name() == "a"
+            || child
+                .descendants()
+                .filter_map(ElementRef::wrap)
+                .any(|descendant| descendant.value().name() == "a");
+
+        if has_link {
+            *groups
+                .entry((child.value().name(), child.value().attr("class")))
+                .or_default() += 1;
+        }
+    }
+
+    groups.into_values().max().unwrap_or(0)
+}
+
+/// Checks whether an element is part of the site's navigation, whose menus
+/// look like listings
+fn is_in_navigation(element: ElementRef) -> bool {
+    element
+        .ancestors()
+        .chain([*element])
+        .filter_map(ElementRef::wrap)
+        .any(|ancestor| {
+            matches!(
+                ancestor.value().name(),
+                "nav" | "header" | "footer" | "aside"
+            )
+        })
+}
+
+/// Returns the values of the `@type` keys of JSON-LD, without parsing it
+fn json_ld_types(json: &str) -> Vec<String> {
+    let mut types = Vec::new();
+    let mut rest = json;
+
+    while let Some(index) = rest.find("\"@type\"") {
+        rest = rest[index + "\"@type\"".len()..].trim_start();
+
+        let Some(value) = rest.strip_prefix(':') else {
+            continue;
+        };
+        let value = value.trim_start();
+
+        // Either a single type or an array of them
+        let end = if value.starts_with('[') {
+            value.find(']')
+        } else {
+            value.find(['"', ',', '}']).and_then(|start| {
+                value[start + 1..]
+                    .find(['"', ',', '}'])
+                    .map(|end| start + end + 1)
+            })
+        };
+
+        let Some(end) = end else {
+            break;
+        };
+
+        types.extend(
+            value[..end]
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .filter(|name| !name.is_empty())
+                .map(ToString::to_string),
+        );
+        rest = &value[end..];
+    }
+
+    types
+}
+
+fn squished_text(element: ElementRef) -> String {
+    element
+        .text()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify_type(html: &str) -> Option<PageType> {
+        classify(&Html::parse_document(html)).page_type
+    }
+
+    #[test]
+    fn test_classify() {
+        let paragraph = format!("<p>{}</p>", "Lorem ipsum dolor sit amet. ".repeat(10));
+        let items = "<li class='result'><a href='/item'>Item</a></li>".repeat(8);
+        let menu = "<li><a href='/section'>Section</a></li>".repeat(8);
+
+        assert_eq!(
+            Some(PageType::Article),
+            classify_type(&format!(
+                "<nav><ul>{menu}</ul></nav><article><h1>Title</h1><time datetime='2024-01-01'></time>{}</article>",
+                paragraph.repeat(4)
+            ))
+        );
+        assert_eq!(
+            Some(PageType::Listing),
+            classify_type(&format!(
+                "<nav><ul>{menu}</ul></nav><ul>{items}</ul><a rel='next' href='?page=2'>Next</a>"
+            ))
+        );
+        assert_eq!(
+            Some(PageType::Product),
+            classify_type(
+                r#"<script type="application/ld+json">{"@context": "https://schema.org", "@type": "Product"}</script>
+                   <span itemprop="price">$5</span><button>Add to cart</button>"#
+            )
+        );
+        assert_eq!(
+            Some(PageType::Login),
+            classify_type(
+                "<h1>Sign in</h1><form><input name='user'><input type='password'></form>"
+            )
+        );
+        assert_eq!(
+            Some(PageType::Error),
+            classify_type("<title>404 Not Found</title><h1>Not Found</h1>")
+        );
+        assert_eq!(None, classify_type("<p>Hello</p>"));
+        assert_eq!(
+            None,
+            classify_type(&format!("<nav><ul>{menu}</ul></nav>")),
+            "navigation menus aren't listings"
+        );
+    }
+
+    #[test]
+    fn test_classify_evidence() {
+        let classification = classify(&Html::parse_document(
+            r#"<meta property="og:type" content="article"><h1>Sign in to comment</h1>"#,
+        ));
+
+        assert_eq!(Some(PageType::Article), classification.page_type);
+        assert_eq!(
+            vec![
+                Evidence {
+                    page_type: PageType::Login,
+                    weight: 1,
+                    description: "mentions \"sign in\"".to_string(),
+                },
+                Evidence {
+                    page_type: PageType::Article,
+                    weight: 3,
+                    description: "og:type is article".to_string(),
+                },
+            ],
+            classification.evidence
+        );
+        assert_eq!(3, classification.score(PageType::Article));
+    }
+
+    #[test]
+    fn test_json_ld_types() {
+        assert_eq!(
+            vec!["NewsArticle", "Person", "Organization", "WebPage"],
+            json_ld_types(
+                r#"{"@type": "NewsArticle", "author": {"@type":"Person"},
+                    "publisher": {"@type" : ["Organization", "WebPage"]}}"#
+            )
+        );
+        assert_eq!(Vec::<String>::new(), json_ld_types(r#"{"@type": "#));
+    }
+}
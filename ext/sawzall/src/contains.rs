@@ -0,0 +1,309 @@
+use scraper::{ElementRef, Selector};
+use std::fmt;
+
+/// A selector using the non-standard `:contains("text")` pseudo-class, which
+/// matches elements whose text content includes the given text, compared
+/// case-insensitively
+///
+/// The selectors crate doesn't support it, so it's removed from the selector
+/// and checked against the elements the rest of it matches instead. That
+/// limits it to the subject of each selector in the list, e.g.
+/// `li:contains(a)` but not `li:contains(a) > b`.
+#[derive(Debug)]
+pub(crate) struct ContainsSelector {
+    /// The selector list without `:contains`, which matches every element
+    /// the whole list matches
+    selector: Selector,
+    /// Each selector in the list along with the lowercased text its subject
+    /// must contain
+    alternatives: Vec<(Selector, Vec<String>)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ContainsError {
+    /// `:contains` applies to an element other than the subject
+    NotSubject,
+    Unterminated,
+    /// The selector is invalid once `:contains` is removed
+    Invalid(String),
+}
+
+impl ContainsSelector {
+    /// Parses a selector list that uses `:contains`, or returns `None` if
+    /// none of it does
+    pub(crate) fn parse(css_selector: &str) -> Result<Option<Self>, ContainsError> {
+        let mut alternatives = Vec::new();
+        let mut any_contains = false;
+
+        for alternative in split_list(css_selector) {
+            let (selector, needles) = strip_contains(alternative.trim())?;
+            any_contains |= !needles.is_empty();
+            alternatives.push((selector, needles));
+        }
+
+        if !any_contains {
+            return Ok(None);
+        }
+
+        let selector = alternatives
+            .iter()
+            .map(|(selector, _)| selector.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        Ok(Some(Self {
+            selector: parse(&selector)?,
+            alternatives: alternatives
+                .into_iter()
+                .map(|(selector, needles)| Ok((parse(&selector)?, needles)))
+                .collect::<Result<_, ContainsError>>()?,
+        }))
+    }
+
+    /// Returns the descendants of `scope` matching the selector, in order
+    pub(crate) fn select<'a, 'b>(
+        &'b self,
+        scope: ElementRef<'a>,
+    ) -> impl Iterator<Item = ElementRef<'a>> + 'b
+    where
+        'a: 'b,
+    {
+        scope
+            .select(&self.selector)
+            .filter(move |element| self.matches_text(element, scope))
+    }
+
+    fn matches_text(&self, element: &ElementRef, scope: ElementRef) -> bool {
+        let mut text = None;
+
+        self.alternatives.iter().any(|(selector, needles)| {
+            if !selector.matches_with_scope(element, Some(scope)) {
+                return false;
+            }
+
+            let text =
+                text.get_or_insert_with(|| element.text().collect::<String>().to_lowercase());
+            needles.iter().all(|needle| text.contains(needle.as_str()))
+        })
+    }
+}
+
+impl fmt::Display for ContainsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainsError::NotSubject => write!(
+                f,
+                ":contains() is only supported on the last compound selector"
+            ),
+            ContainsError::Unterminated => write!(f, "unterminated :contains()"),
+            ContainsError::Invalid(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+fn parse(css_selector: &str) -> Result<Selector, ContainsError> {
+    Selector::parse(css_selector).map_err(|e| ContainsError::Invalid(e.to_string()))
+}
+
+/// Walks a selector, keeping track of strings and nesting so that only the
+/// parts of it that are at the top level are looked at
+struct Scanner<'a> {
+    input: &'a str,
+    position: usize,
+    depth: usize,
+    quote: Option<char>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            position: 0,
+            depth: 0,
+            quote: None,
+        }
+    }
+
+    /// Returns the next character and its offset, if it's at the top level
+    /// and outside of any string
+    fn next(&mut self) -> Option<(usize, Option<char>)> {
+        let mut chars = self.input[self.position..].chars();
+        let c = chars.next()?;
+        let start = self.position;
+        self.position += c.len_utf8();
+
+        let top_level = self.depth == 0 && self.quote.is_none();
+
+        match (self.quote, c) {
+            (_, '\\') => {
+                self.position += chars.next().map_or(0, char::len_utf8);
+                return Some((start, None));
+            }
+            (Some(quote), c) if c == quote => self.quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => self.quote = Some(c),
+            (None, '(' | '[') => self.depth += 1,
+            (None, ')' | ']') => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+
+        Some((start, top_level.then_some(c)))
+    }
+}
+
+/// Splits a selector list at its top-level commas
+fn split_list(css_selector: &str) -> Vec<&str> {
+    let mut scanner = Scanner::new(css_selector);
+    let mut alternatives = Vec::new();
+    let mut start = 0;
+
+    while let Some((offset, c)) = scanner.next() {
+        if c == Some(',') {
+            alternatives.push(&css_selector[start..offset]);
+            start = offset + 1;
+        }
+    }
+    alternatives.push(&css_selector[start..]);
+
+    alternatives
+}
+
+/// Removes the `:contains` pseudo-classes from a complex selector, returning
+/// what's left and their lowercased arguments
+fn strip_contains(alternative: &str) -> Result<(String, Vec<String>), ContainsError> {
+    let mut scanner = Scanner::new(alternative);
+    let mut stripped = String::new();
+    let mut needles = Vec::new();
+    let mut copied_up_to = 0;
+    // Where the subject compound selector starts
+    let mut subject = 0;
+
+    while let Some((offset, c)) = scanner.next() {
+        match c {
+            Some(c) if c.is_ascii_whitespace() || matches!(c, '>' | '+' | '~') => {
+                if !needles.is_empty() {
+                    return Err(ContainsError::NotSubject);
+                }
+                subject = offset + 1;
+            }
+            Some(':') if starts_with_ignore_case(&alternative[offset..], ":contains(") => {
+                let argument_start = offset + ":contains(".len();
+                let argument_end = loop {
+                    match scanner.next() {
+                        Some((end, _)) if scanner.depth == 0 && scanner.quote.is_none() => {
+                            if end >= argument_start {
+                                break end;
+                            }
+                        }
+                        Some(_) => {}
+                        None => return Err(ContainsError::Unterminated),
+                    }
+                };
+
+                stripped.push_str(&alternative[copied_up_to..offset]);
+                copied_up_to = argument_end + 1;
+                needles
+                    .push(unquote(alternative[argument_start..argument_end].trim()).to_lowercase());
+            }
+            _ => {}
+        }
+    }
+    stripped.push_str(&alternative[copied_up_to..]);
+
+    // The subject may have been nothing but `:contains`
+    if !needles.is_empty() && stripped.len() <= subject {
+        stripped.push('*');
+    }
+
+    Ok((stripped, needles))
+}
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// Returns the text of a quoted string with its escapes resolved, or an
+/// unquoted argument as is
+fn unquote(argument: &str) -> String {
+    let mut chars = argument.chars();
+
+    match chars.next() {
+        Some(quote @ ('"' | '\'')) if argument.len() >= 2 && argument.ends_with(quote) => {
+            let mut text = String::new();
+            let mut chars = argument[1..argument.len() - 1].chars();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => text.extend(chars.next()),
+                    c => text.push(c),
+                }
+            }
+
+            text
+        }
+        _ => argument.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Html;
+
+    fn select(html: &str, css_selector: &str) -> Vec<String> {
+        let html = Html::parse_fragment(html);
+        let selector = ContainsSelector::parse(css_selector).unwrap().unwrap();
+
+        selector
+            .select(html.root_element())
+            .map(|element| element.html())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(ContainsSelector::parse("p, a[title=':contains(x)']")
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            ContainsError::NotSubject,
+            ContainsSelector::parse("p:contains(a) > b").unwrap_err()
+        );
+        assert_eq!(
+            ContainsError::Unterminated,
+            ContainsSelector::parse("p:contains('a)").unwrap_err()
+        );
+        assert!(matches!(
+            ContainsSelector::parse("p:contains(a)."),
+            Err(ContainsError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_select() {
+        let html =
+            r#"<ul><li>Apple</li><li class="b">Banana (ripe)</li><li>Cherry <b>pie</b></li></ul>"#;
+
+        assert_eq!(
+            vec![r#"<li class="b">Banana (ripe)</li>"#],
+            select(html, r#"li:CONTAINS("(RIPE)")"#)
+        );
+        assert_eq!(
+            vec!["<li>Cherry <b>pie</b></li>", "<b>pie</b>"],
+            select(html, "ul :contains( pie )")
+        );
+        assert_eq!(
+            vec![r#"<li class="b">Banana (ripe)</li>"#],
+            select(html, r#"li:contains('a'):contains("\(")"#)
+        );
+        assert_eq!(
+            vec!["<li>Apple</li>", "<li>Cherry <b>pie</b></li>"],
+            select(html, "li:contains(pp), li:contains(pie), li.x")
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            select(html, "li:contains(apple):not(li)")
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use html5ever::{driver, tendril::TendrilSink, QualName};
+use scraper::{Html, HtmlTreeSink};
+
+/// Elements that can't have content, which are serialized without a closing
+/// tag
+const VOID_ELEMENTS: [&str; 18] = [
+    "area", "base", "basefont", "bgsound", "br", "col", "embed", "frame", "hr", "img", "input",
+    "keygen", "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// Elements whose content the parser reads as text until their closing tag,
+/// and that the serializer writes back without escaping it
+///
+/// `<noscript>` is one as the parser runs with scripting enabled.
+const RAW_TEXT_ELEMENTS: [&str; 8] = [
+    "iframe",
+    "noembed",
+    "noframes",
+    "noscript",
+    "plaintext",
+    "script",
+    "style",
+    "xmp",
+];
+
+/// Elements whose content the parser reads as text until their closing tag,
+/// but where character references are decoded, so the serializer escapes it
+const ESCAPABLE_RAW_TEXT_ELEMENTS: [&str; 2] = ["textarea", "title"];
+
+pub(crate) fn is_void_element(name: &str) -> bool {
+    VOID_ELEMENTS
+        .iter()
+        .any(|void| void.eq_ignore_ascii_case(name))
+}
+
+/// Checks whether the element's content is text rather than markup, whether
+/// or not it's escaped when serialized
+pub(crate) fn is_raw_text_element(name: &str) -> bool {
+    RAW_TEXT_ELEMENTS
+        .iter()
+        .chain(&ESCAPABLE_RAW_TEXT_ELEMENTS)
+        .any(|raw_text| raw_text.eq_ignore_ascii_case(name))
+}
+
+/// Checks whether the text in the element is escaped when serialized
+pub(crate) fn escapes_text(name: &str) -> bool {
+    !RAW_TEXT_ELEMENTS.contains(&name)
+}
+
+/// Checks whether `text` can't be serialized as the content of the element
+/// as it would close it early, like `</script>` in a script. The text of
+/// other elements is always escaped.
+pub(crate) fn ends_raw_text(name: &str, text: &str) -> bool {
+    // Nothing closes <plaintext>
+    if name == "plaintext" || escapes_text(name) {
+        return false;
+    }
+
+    let end_tag = format!("</{name}");
+
+    text.as_bytes()
+        .windows(end_tag.len())
+        .any(|window| window.eq_ignore_ascii_case(end_tag.as_bytes()))
+}
+
+/// Parses HTML as the content of an element named `context`, like setting
+/// its `innerHTML` does, so that the content of e.g. a `<script>` is text
+/// and the content of a `<table>` can be rows
+pub(crate) fn parse_inner_html(input: &str, context: QualName) -> Html {
+    driver::parse_fragment(
+        HtmlTreeSink::new(Html::new_fragment()),
+        Default::default(),
+        context,
+        Vec::new(),
+    )
+    .one(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use html5ever::{local_name, namespace_url, ns};
+
+    #[test]
+    fn test_predicates() {
+        assert!(is_void_element("br"));
+        assert!(is_void_element("IMG"));
+        assert!(!is_void_element("p"));
+
+        assert!(is_raw_text_element("script"));
+        assert!(is_raw_text_element("textarea"));
+        assert!(!is_raw_text_element("pre"));
+    }
+
+    #[test]
+    fn test_ends_raw_text() {
+        assert!(ends_raw_text("script", "a = '</SCRIPT>'"));
+        assert!(ends_raw_text("style", "</style"));
+        assert!(!ends_raw_text("script", "a = '<\\/script>'"));
+        assert!(!ends_raw_text("script", "</style>"));
+        assert!(
+            !ends_raw_text("textarea", "</textarea>"),
+            "escapable raw text is escaped"
+        );
+        assert!(!ends_raw_text("plaintext", "</plaintext>"));
+    }
+
+    #[test]
+    fn test_parse_inner_html() {
+        let inner_html = |input: &str, context: QualName| {
+            parse_inner_html(input, context).root_element().inner_html()
+        };
+
+        assert_eq!(
+            "if (a &lt; b) {}",
+            inner_html(
+                "if (a < b) {}",
+                QualName::new(None, ns!(html), local_name!("title"))
+            )
+        );
+        assert_eq!(
+            "<tbody><tr><td>One</td></tr></tbody>",
+            inner_html(
+                "<tr><td>One</td></tr>",
+                QualName::new(None, ns!(html), local_name!("table"))
+            ),
+            "table rows aren't dropped"
+        );
+        assert_eq!(
+            "One",
+            inner_html(
+                "<tr><td>One</td></tr>",
+                QualName::new(None, ns!(html), local_name!("div"))
+            )
+        );
+
+        let script = parse_inner_html(
+            "if (a < b) { c('<b>') }",
+            QualName::new(None, ns!(html), local_name!("script")),
+        );
+        let text = script.root_element().first_child().unwrap();
+        assert_eq!(
+            Some("if (a < b) { c('<b>') }"),
+            text.value().as_text().map(|text| &**text)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::dom;
+use lazy_static::lazy_static;
+use scraper::{Html, Selector};
+
+lazy_static! {
+    static ref HTTP_EQUIV_META_SELECTOR: Selector =
+        Selector::parse("meta[http-equiv][content]").unwrap();
+}
+
+/// A parsed policy as a list of directive names and their values, in order
+pub(crate) type Policy = Vec<(String, Vec<String>)>;
+
+/// Parses a serialized Content Security Policy ([CSP spec][1])
+///
+/// Directive names are lowercased and only the first occurrence of a
+/// directive is kept, as browsers ignore duplicates.
+///
+/// [1]: https://w3c.github.io/webappsec-csp/#parse-serialized-policy
+pub(crate) fn parse_policy(serialized: &str) -> Policy {
+    let mut policy = Policy::new();
+
+    for token in serialized.split(';') {
+        let mut parts = token.split_ascii_whitespace();
+
+        let Some(name) = parts.next() else {
+            continue;
+        };
+        let name = name.to_ascii_lowercase();
+
+        if policy.iter().any(|(existing, _)| *existing == name) {
+            continue;
+        }
+
+        policy.push((name, parts.map(ToString::to_string).collect()));
+    }
+
+    policy
+}
+
+/// Returns the policies declared with `<meta http-equiv="Content-Security-Policy">`
+/// in document order
+pub(crate) fn meta_policies(html: &Html) -> Vec<Policy> {
+    dom::select(html, &HTTP_EQUIV_META_SELECTOR)
+        .filter(|meta| {
+            meta.attr("http-equiv")
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case("content-security-policy")
+        })
+        .map(|meta| parse_policy(meta.attr("content").unwrap_or_default()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(directives: &[(&str, &[&str])]) -> Policy {
+        directives
+            .iter()
+            .map(|(name, values)| {
+                (
+                    name.to_string(),
+                    values.iter().map(ToString::to_string).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(Policy::new(), parse_policy(""));
+
+        assert_eq!(
+            policy(&[
+                ("default-src", &["'self'"]),
+                ("img-src", &["*", "data:"]),
+                ("upgrade-insecure-requests", &[]),
+            ]),
+            parse_policy(" default-src 'self';IMG-SRC  *\tdata: ; ; upgrade-insecure-requests;"),
+            "directives are split on semicolons and values on whitespace"
+        );
+
+        assert_eq!(
+            policy(&[("script-src", &["'none'"])]),
+            parse_policy("script-src 'none'; script-src *"),
+            "duplicate directives are ignored"
+        );
+    }
+
+    #[test]
+    fn test_meta_policies() {
+        let html = Html::parse_document(
+            r#"<meta http-equiv="Content-Security-Policy" content="default-src 'self'">
+               <meta http-equiv="refresh" content="5">
+               <meta http-equiv="content-security-policy" content="img-src https:">"#,
+        );
+
+        assert_eq!(
+            vec![
+                policy(&[("default-src", &["'self'"])]),
+                policy(&[("img-src", &["https:"])]),
+            ],
+            meta_policies(&html),
+            "http-equiv is matched case-insensitively and other metas are ignored"
+        );
+    }
+}
@@ -0,0 +1,582 @@
+use crate::dom;
+use ego_tree::NodeId;
+use lazy_static::lazy_static;
+use scraper::{node::Text, Html, Node, Selector};
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref STYLE_SELECTOR: Selector = Selector::parse("style").unwrap();
+}
+
+/// Pseudo-classes and pseudo-elements that depend on user interaction or
+/// generate content, which inline styles can't express
+const NON_INLINABLE_PSEUDO_CLASSES: [&str; 10] = [
+    ":active",
+    ":after",
+    ":before",
+    ":first-letter",
+    ":first-line",
+    ":focus",
+    ":hover",
+    ":link",
+    ":target",
+    ":visited",
+];
+
+/// Elements which aren't rendered and therefore never get inline styles
+const NON_RENDERED_ELEMENTS: [&str; 8] = [
+    "base", "head", "link", "meta", "noscript", "script", "style", "title",
+];
+
+type Specificity = (u32, u32, u32);
+
+/// Orders declarations in the cascade: importance, then inline styles, then
+/// specificity, then order of appearance
+type Priority = (bool, bool, Specificity, usize);
+
+#[derive(Debug, PartialEq)]
+struct Declaration {
+    property: String,
+    value: String,
+    important: bool,
+}
+
+struct Rule {
+    selector: Selector,
+    specificity: Specificity,
+    declarations: Vec<Declaration>,
+}
+
+/// Moves the rules of the document's `<style>` elements into `style`
+/// attributes on the elements they match, similarly to [premailer][1].
+///
+/// Declarations are applied following the cascade (importance, inline
+/// styles, specificity and order of appearance). Rules that can't be inlined
+/// (at-rules like `@media` and selectors using dynamic pseudo-classes or
+/// pseudo-elements) are kept in their `<style>` element, and `<style>`
+/// elements left empty are removed. `<style>` elements targeting media other
+/// than `all` or `screen` are ignored.
+///
+/// [1]: https://github.com/premailer/premailer
+pub(crate) fn inline_css(html: &mut Html) {
+    let style_elements = dom::select(html, &STYLE_SELECTOR)
+        .filter(|style| applies_to_screen(style.attr("media")))
+        .map(|style| (style.id(), style.text().collect::<String>()))
+        .collect::<Vec<_>>();
+
+    let mut rules = Vec::new();
+    let mut remaining_css = Vec::new();
+
+    for (style_id, css) in style_elements {
+        let (style_rules, remaining) = parse_stylesheet(&css);
+        rules.extend(style_rules);
+        remaining_css.push((style_id, remaining));
+    }
+
+    let mut matches: HashMap<NodeId, Vec<usize>> = HashMap::new();
+    for (index, rule) in rules.iter().enumerate() {
+        for element in dom::select(html, &rule.selector) {
+            if !NON_RENDERED_ELEMENTS.contains(&element.value().name()) {
+                matches.entry(element.id()).or_default().push(index);
+            }
+        }
+    }
+
+    let styles = matches
+        .into_iter()
+        .map(|(element_id, rule_indices)| {
+            let inline_style = html
+                .tree
+                .get(element_id)
+                .and_then(|node| node.value().as_element())
+                .and_then(|element| element.attr("style"))
+                .map(parse_declarations)
+                .unwrap_or_default();
+
+            (element_id, cascade(&rules, &rule_indices, &inline_style))
+        })
+        .collect::<Vec<_>>();
+
+    for (element_id, style) in styles {
+        if let Some(mut node) = html.tree.get_mut(element_id) {
+            if let Node::Element(element) = node.value() {
+                dom::set_attr(element, "style", &style);
+            }
+        }
+    }
+
+    for (style_id, remaining) in remaining_css {
+        let Some(mut style) = html.tree.get_mut(style_id) else {
+            continue;
+        };
+
+        if remaining.trim().is_empty() {
+            style.detach();
+        } else {
+            while let Some(mut child) = style.first_child() {
+                child.detach();
+            }
+
+            style.append(Node::Text(Text {
+                text: remaining.as_str().into(),
+            }));
+        }
+    }
+}
+
+/// Computes the value of an element's `style` attribute from the rules it
+/// matched and its existing inline declarations
+fn cascade(rules: &[Rule], rule_indices: &[usize], inline_style: &[Declaration]) -> String {
+    let mut declarations: Vec<(Priority, &Declaration)> = Vec::new();
+
+    for &index in rule_indices {
+        let rule = &rules[index];
+
+        for declaration in &rule.declarations {
+            let order = declarations.len();
+            declarations.push((
+                (declaration.important, false, rule.specificity, order),
+                declaration,
+            ));
+        }
+    }
+
+    for declaration in inline_style {
+        let order = declarations.len();
+        declarations.push(((declaration.important, true, (0, 0, 0), order), declaration));
+    }
+
+    declarations.sort_by_key(|(priority, _)| *priority);
+
+    let mut properties: Vec<(&str, &str)> = Vec::new();
+    for (_, declaration) in declarations {
+        match properties
+            .iter_mut()
+            .find(|(property, _)| *property == declaration.property)
+        {
+            Some((_, value)) => *value = &declaration.value,
+            None => properties.push((&declaration.property, &declaration.value)),
+        }
+    }
+
+    properties
+        .into_iter()
+        .map(|(property, value)| format!("{property}: {value}"))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn applies_to_screen(media: Option<&str>) -> bool {
+    let Some(media) = media else {
+        return true;
+    };
+
+    media.split(',').any(|query| {
+        let query = query.trim();
+        query.is_empty()
+            || query.eq_ignore_ascii_case("all")
+            || query.eq_ignore_ascii_case("screen")
+    })
+}
+
+fn is_inlinable(selector: &str) -> bool {
+    let selector = selector.to_ascii_lowercase();
+
+    !selector.contains("::")
+        && !NON_INLINABLE_PSEUDO_CLASSES
+            .iter()
+            .any(|pseudo_class| selector.contains(pseudo_class))
+}
+
+/// Parses a stylesheet into the rules that can be inlined and the CSS that
+/// can't (e.g. at-rules)
+fn parse_stylesheet(css: &str) -> (Vec<Rule>, String) {
+    let css = strip_comments(css);
+    let mut rest = css.as_str();
+    let mut rules = Vec::new();
+    let mut remaining = String::new();
+
+    loop {
+        rest = rest.trim_start();
+
+        if rest.is_empty() {
+            break;
+        }
+
+        if rest.starts_with('@') {
+            let end = match find_top_level(rest, &[';', '{']) {
+                Some(index) if rest[index..].starts_with(';') => index + 1,
+                Some(index) => find_block_end(rest, index).map_or(rest.len(), |end| end + 1),
+                None => rest.len(),
+            };
+
+            remaining.push_str(rest[..end].trim());
+            remaining.push('\n');
+            rest = &rest[end..];
+            continue;
+        }
+
+        let Some(open) = find_top_level(rest, &['{']) else {
+            // Trailing garbage without a block is ignored, as it is by browsers
+            break;
+        };
+        let (body, end) = match find_block_end(rest, open) {
+            Some(close) => (&rest[open + 1..close], close + 1),
+            None => (&rest[open + 1..], rest.len()),
+        };
+        let prelude = &rest[..open];
+        rest = &rest[end..];
+
+        for selector_text in split_top_level(prelude, ',') {
+            let selector_text = selector_text.trim();
+
+            if selector_text.is_empty() {
+                continue;
+            }
+
+            if !is_inlinable(selector_text) {
+                remaining.push_str(&format!("{selector_text} {{ {} }}\n", body.trim()));
+            } else if let Ok(selector) = Selector::parse(selector_text) {
+                rules.push(Rule {
+                    selector,
+                    specificity: specificity(selector_text),
+                    declarations: parse_declarations(body),
+                });
+            }
+        }
+    }
+
+    (rules, remaining)
+}
+
+fn parse_declarations(block: &str) -> Vec<Declaration> {
+    split_top_level(block, ';')
+        .into_iter()
+        .filter_map(|declaration| {
+            let (property, value) = declaration.split_once(':')?;
+            let property = property.trim().to_ascii_lowercase();
+            let mut value = value.trim();
+            let mut important = false;
+
+            if let Some(bang) = value.rfind('!') {
+                if value[bang + 1..].trim().eq_ignore_ascii_case("important") {
+                    important = true;
+                    value = value[..bang].trim_end();
+                }
+            }
+
+            (!property.is_empty() && !value.is_empty()).then(|| Declaration {
+                property,
+                value: value.to_string(),
+                important,
+            })
+        })
+        .collect()
+}
+
+/// Approximates the [specificity][1] of a single complex selector
+///
+/// [1]: https://www.w3.org/TR/selectors-4/#specificity-rules
+fn specificity(selector: &str) -> Specificity {
+    let mut total = (0, 0, 0);
+    let mut chars = selector.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                total.0 += 1;
+                read_ident(&mut chars);
+            }
+            '.' => {
+                total.1 += 1;
+                read_ident(&mut chars);
+            }
+            '[' => {
+                total.1 += 1;
+                read_until_close(&mut chars, '[', ']');
+            }
+            ':' if chars.peek() == Some(&':') => {
+                chars.next();
+                total.2 += 1;
+                read_ident(&mut chars);
+            }
+            ':' => {
+                let name = read_ident(&mut chars).to_ascii_lowercase();
+                let argument = if chars.peek() == Some(&'(') {
+                    chars.next();
+                    read_until_close(&mut chars, '(', ')')
+                } else {
+                    String::new()
+                };
+
+                match name.as_str() {
+                    "where" => {}
+                    // These take the specificity of their most specific argument
+                    "is" | "not" | "has" | "matches" => {
+                        let most_specific = split_top_level(&argument, ',')
+                            .into_iter()
+                            .map(specificity)
+                            .max()
+                            .unwrap_or_default();
+
+                        total.0 += most_specific.0;
+                        total.1 += most_specific.1;
+                        total.2 += most_specific.2;
+                    }
+                    _ => total.1 += 1,
+                }
+            }
+            c if c.is_alphabetic() || c == '_' || c == '-' || c == '\\' || !c.is_ascii() => {
+                total.2 += 1;
+                read_ident(&mut chars);
+            }
+            _ => {}
+        }
+    }
+
+    total
+}
+
+fn read_ident(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut ident = String::new();
+
+    while let Some(&c) = chars.peek() {
+        if c == '\\' {
+            chars.next();
+            ident.extend(chars.next());
+        } else if c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii() {
+            chars.next();
+            ident.push(c);
+        } else {
+            break;
+        }
+    }
+
+    ident
+}
+
+/// Consumes characters up to the matching `close` character, returning what
+/// came before it
+fn read_until_close(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    open: char,
+    close: char,
+) -> String {
+    let mut depth = 0;
+    let mut contents = String::new();
+
+    for c in chars.by_ref() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            if depth == 0 {
+                break;
+            }
+            depth -= 1;
+        }
+
+        contents.push(c);
+    }
+
+    contents
+}
+
+/// Removes `/* ... */` comments outside of strings
+fn strip_comments(css: &str) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut chars = css.chars().peekable();
+    let mut quote = None;
+
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) => {
+                output.push(c);
+
+                if c == '\\' {
+                    output.extend(chars.next());
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '/' && chars.peek() == Some(&'*') => {
+                chars.next();
+
+                let mut previous = None;
+                for c in chars.by_ref() {
+                    if previous == Some('*') && c == '/' {
+                        break;
+                    }
+                    previous = Some(c);
+                }
+            }
+            None => {
+                if c == '"' || c == '\'' {
+                    quote = Some(c);
+                }
+
+                output.push(c);
+            }
+        }
+    }
+
+    output
+}
+
+/// Iterates over the characters of `css` that are outside of strings,
+/// parentheses and (if `skip_blocks` is set) blocks, along with their byte
+/// offset and nesting depth
+fn scan(css: &str) -> impl Iterator<Item = (usize, char, usize)> + '_ {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut depth = 0usize;
+
+    css.char_indices().filter_map(move |(index, c)| {
+        if escaped {
+            escaped = false;
+            return None;
+        }
+
+        if c == '\\' {
+            escaped = true;
+            return None;
+        }
+
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            return None;
+        }
+
+        match c {
+            '"' | '\'' => {
+                quote = Some(c);
+                None
+            }
+            '(' | '[' | '{' => {
+                depth += 1;
+                Some((index, c, depth - 1))
+            }
+            ')' | ']' | '}' => {
+                depth = depth.saturating_sub(1);
+                Some((index, c, depth))
+            }
+            _ => Some((index, c, depth)),
+        }
+    })
+}
+
+/// Finds the first of the given characters outside of any nesting
+fn find_top_level(css: &str, targets: &[char]) -> Option<usize> {
+    scan(css)
+        .find(|(_, c, depth)| *depth == 0 && targets.contains(c))
+        .map(|(index, _, _)| index)
+}
+
+/// Finds the `}` closing the block opened at `open`
+fn find_block_end(css: &str, open: usize) -> Option<usize> {
+    scan(&css[open..])
+        .skip(1)
+        .find(|(_, c, depth)| *depth == 0 && *c == '}')
+        .map(|(index, _, _)| open + index)
+}
+
+/// Splits on the given separator when it isn't nested in strings or brackets
+fn split_top_level(css: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+
+    for (index, c, depth) in scan(css) {
+        if depth == 0 && c == separator {
+            parts.push(&css[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+
+    parts.push(&css[start..]);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inline_css(input: &str) -> String {
+        let mut html = Html::parse_fragment(input);
+        super::inline_css(&mut html);
+        html.root_element().inner_html()
+    }
+
+    #[test]
+    fn test_inline_css() {
+        assert_eq!(
+            r#"<p style="color: red">Hello</p>"#,
+            inline_css("<style>p { color: red }</style><p>Hello</p>"),
+            "rules are inlined and empty style elements are removed"
+        );
+
+        assert_eq!(
+            r#"<p class="intro" id="first" style="color: blue; margin: 0; font-weight: bold">Hello</p>"#,
+            inline_css(
+                r#"<style>
+                  #first { color: blue }
+                  p.intro { color: green; margin: 0 }
+                  p { color: red; font-weight: normal !important }
+                </style><p id="first" class="intro" style="font-weight: bold !important">Hello</p>"#
+            ),
+            "declarations follow the cascade"
+        );
+
+        assert_eq!(
+            r#"<style>@media (max-width: 600px) { p { color: blue } }
+a:hover { color: red }
+</style><p style="color: green">Hi</p><a style="color: red; text-decoration: none">link</a>"#,
+            inline_css(
+                "<style>/* comment */ @media (max-width: 600px) { p { color: blue } } a, a:hover { color: red } a { text-decoration: none } p { color: green }</style><p>Hi</p><a>link</a>"
+            ),
+            "rules that can't be inlined are kept"
+        );
+
+        assert_eq!(
+            r#"<style media="print">p { color: red }</style><p>Hi</p>"#,
+            inline_css(r#"<style media="print">p { color: red }</style><p>Hi</p>"#),
+            "print stylesheets are ignored"
+        );
+
+        assert_eq!(
+            r#"<p style="background: url(&quot;a;b.png&quot;)">Hi</p>"#,
+            inline_css(r#"<style>p { background: url("a;b.png") }</style><p>Hi</p>"#),
+            "separators inside strings are ignored"
+        );
+    }
+
+    #[test]
+    fn test_specificity() {
+        assert_eq!((0, 0, 1), specificity("p"));
+        assert_eq!((0, 0, 0), specificity("*"));
+        assert_eq!((1, 1, 1), specificity("p#main.big"));
+        assert_eq!((0, 2, 2), specificity("ul > li[data-x='a.b']:first-child"));
+        assert_eq!((0, 1, 2), specificity("p::first-line.x"));
+        assert_eq!((1, 0, 1), specificity("a:not(#x, .y)"));
+        assert_eq!((0, 0, 1), specificity("a:where(#x)"));
+    }
+
+    #[test]
+    fn test_parse_declarations() {
+        assert_eq!(
+            vec![
+                Declaration {
+                    property: "color".to_string(),
+                    value: "red".to_string(),
+                    important: false,
+                },
+                Declaration {
+                    property: "margin".to_string(),
+                    value: "0 auto".to_string(),
+                    important: true,
+                },
+            ],
+            parse_declarations(" COLOR: red;; margin: 0 auto ! IMPORTANT ; broken ")
+        );
+    }
+}
@@ -0,0 +1,435 @@
+use crate::dom;
+use ego_tree::iter::Edge;
+use ego_tree::{NodeId, NodeRef};
+use html5ever::QualName;
+use scraper::node::Doctype;
+use scraper::{Html, Node};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::mem;
+
+/// Sibling lists are only aligned node by node when that takes at most this
+/// many comparisons, past which the nodes between their common start and
+/// end are replaced as a whole
+const MAX_ALIGNMENT_STEPS: usize = 4_000_000;
+
+/// A difference between two trees, with the ids of the nodes involved in the
+/// old and the new tree
+#[derive(Debug, PartialEq)]
+pub(crate) enum Change {
+    /// A node of the new tree, along with its descendants, isn't in the old
+    /// one
+    Insert(NodeId),
+    /// A node of the old tree, along with its descendants, isn't in the new
+    /// one
+    Delete(NodeId),
+    /// An attribute of matching elements was added, removed or changed
+    Attribute {
+        old: NodeId,
+        new: NodeId,
+        name: String,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    /// The text of matching text nodes or comments changed
+    Text {
+        old: NodeId,
+        new: NodeId,
+        from: String,
+        to: String,
+    },
+}
+
+/// What a node must share with another to be compared with it rather than
+/// replaced by it
+#[derive(PartialEq)]
+enum Key<'a> {
+    Element(&'a QualName),
+    Text,
+    Comment,
+    Doctype(&'a Doctype),
+}
+
+impl<'a> Key<'a> {
+    /// Returns the key of a node that takes part in the comparison
+    fn of(node: NodeRef<'a, Node>) -> Option<Self> {
+        match node.value() {
+            Node::Element(element) => Some(Key::Element(&element.name)),
+            // Whitespace between elements is formatting, which changes
+            // whenever the markup is reindented
+            Node::Text(text) if text.trim().is_empty() => None,
+            Node::Text(_) => Some(Key::Text),
+            Node::Comment(_) => Some(Key::Comment),
+            Node::Doctype(doctype) => Some(Key::Doctype(doctype)),
+            _ => None,
+        }
+    }
+}
+
+/// A child taking part in the comparison, along with a hash of its subtree
+/// that is the same for identical subtrees
+struct Child<'a> {
+    id: NodeId,
+    key: Key<'a>,
+    hash: u64,
+}
+
+enum Step {
+    Compare(NodeId, NodeId),
+    Delete(NodeId),
+    Insert(NodeId),
+}
+
+/// Compares two trees, returning what changed from `old` to `new` in
+/// document order
+///
+/// Children are aligned the way a line-based diff aligns lines: identical
+/// subtrees are matched up first, and the nodes between them are then
+/// matched up by type and name and compared in turn. Attributes are compared
+/// by name, regardless of their order, and text exactly, except for text
+/// made only of whitespace, which is ignored.
+pub(crate) fn diff(old: &Html, new: &Html) -> Vec<Change> {
+    let old_hashes = subtree_hashes(old);
+    let new_hashes = subtree_hashes(new);
+    let mut changes = Vec::new();
+    let mut steps = vec![Step::Compare(old.tree.root().id(), new.tree.root().id())];
+
+    while let Some(step) = steps.pop() {
+        let (old_node, new_node) = match step {
+            Step::Compare(old_id, new_id) => (
+                old.tree
+                    .get(old_id)
+                    .expect("compared nodes are in the tree"),
+                new.tree
+                    .get(new_id)
+                    .expect("compared nodes are in the tree"),
+            ),
+            Step::Delete(id) => {
+                changes.push(Change::Delete(id));
+                continue;
+            }
+            Step::Insert(id) => {
+                changes.push(Change::Insert(id));
+                continue;
+            }
+        };
+
+        compare_values(old_node, new_node, &mut changes);
+
+        // Steps are taken last in, first out
+        let start = steps.len();
+        steps.extend(align_children(
+            children(old_node, &old_hashes),
+            children(new_node, &new_hashes),
+        ));
+        steps[start..].reverse();
+    }
+
+    changes
+}
+
+/// Hashes the subtree of every node that takes part in the comparison
+fn subtree_hashes(html: &Html) -> HashMap<NodeId, u64> {
+    let mut hashes = HashMap::new();
+    // The hashes of the children of each open node
+    let mut open: Vec<Vec<u64>> = Vec::new();
+
+    for edge in html.tree.root().traverse() {
+        let node = match edge {
+            Edge::Open(_) => {
+                open.push(Vec::new());
+                continue;
+            }
+            Edge::Close(node) => node,
+        };
+
+        let children = open.pop().unwrap_or_default();
+        if Key::of(node).is_none() {
+            continue;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        hash_value(node.value(), &mut hasher);
+        children.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        hashes.insert(node.id(), hash);
+        if let Some(siblings) = open.last_mut() {
+            siblings.push(hash);
+        }
+    }
+
+    hashes
+}
+
+fn hash_value(node: &Node, hasher: &mut DefaultHasher) {
+    mem::discriminant(node).hash(hasher);
+
+    match node {
+        Node::Element(element) => {
+            element.name.hash(hasher);
+
+            let mut attrs: Vec<_> = element
+                .attrs
+                .iter()
+                .map(|(name, value)| (dom::attribute_name(name), &**value))
+                .collect();
+            attrs.sort_unstable();
+            attrs.hash(hasher);
+        }
+        Node::Text(text) => text.text.hash(hasher),
+        Node::Comment(comment) => comment.comment.hash(hasher),
+        Node::Doctype(doctype) => {
+            doctype.name.hash(hasher);
+            doctype.public_id.hash(hasher);
+            doctype.system_id.hash(hasher);
+        }
+        _ => {}
+    }
+}
+
+fn children<'a>(node: NodeRef<'a, Node>, hashes: &HashMap<NodeId, u64>) -> Vec<Child<'a>> {
+    node.children()
+        .filter_map(|child| {
+            Some(Child {
+                id: child.id(),
+                key: Key::of(child)?,
+                hash: *hashes.get(&child.id())?,
+            })
+        })
+        .collect()
+}
+
+/// Compares the attributes or text of two nodes with the same key
+fn compare_values(old: NodeRef<Node>, new: NodeRef<Node>, changes: &mut Vec<Change>) {
+    match (old.value(), new.value()) {
+        (Node::Element(old_element), Node::Element(new_element)) => {
+            for (name, from) in old_element.attrs.iter() {
+                let name = dom::attribute_name(name);
+                let to = dom::attr(new_element, &name);
+
+                if to != Some(&**from) {
+                    changes.push(Change::Attribute {
+                        old: old.id(),
+                        new: new.id(),
+                        from: Some(from.to_string()),
+                        to: to.map(str::to_string),
+                        name,
+                    });
+                }
+            }
+
+            for (name, to) in new_element.attrs.iter() {
+                let name = dom::attribute_name(name);
+
+                if dom::attr(old_element, &name).is_none() {
+                    changes.push(Change::Attribute {
+                        old: old.id(),
+                        new: new.id(),
+                        from: None,
+                        to: Some(to.to_string()),
+                        name,
+                    });
+                }
+            }
+        }
+        (Node::Text(from), Node::Text(to)) if from.text != to.text => {
+            changes.push(Change::Text {
+                old: old.id(),
+                new: new.id(),
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+        }
+        (Node::Comment(from), Node::Comment(to)) if from.comment != to.comment => {
+            changes.push(Change::Text {
+                old: old.id(),
+                new: new.id(),
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+        }
+        _ => {}
+    }
+}
+
+/// Matches up two lists of children, leaving out identical subtrees since
+/// there's nothing to compare in them
+fn align_children(old: Vec<Child>, new: Vec<Child>) -> Vec<Step> {
+    let mut steps = Vec::new();
+    let (mut old_start, mut new_start) = (0, 0);
+
+    let identical = align(&old, &new, |old, new| old.hash == new.hash);
+    for (old_end, new_end) in identical
+        .into_iter()
+        .chain(iter::once((old.len(), new.len())))
+    {
+        let old_gap = &old[old_start..old_end];
+        let new_gap = &new[new_start..new_end];
+        let (mut i, mut j) = (0, 0);
+
+        let similar = align(old_gap, new_gap, |old, new| old.key == new.key);
+        for (old_match, new_match) in similar
+            .into_iter()
+            .chain(iter::once((old_gap.len(), new_gap.len())))
+        {
+            steps.extend(old_gap[i..old_match].iter().map(|old| Step::Delete(old.id)));
+            steps.extend(new_gap[j..new_match].iter().map(|new| Step::Insert(new.id)));
+
+            if let (Some(old), Some(new)) = (old_gap.get(old_match), new_gap.get(new_match)) {
+                steps.push(Step::Compare(old.id, new.id));
+            }
+
+            (i, j) = (old_match + 1, new_match + 1);
+        }
+
+        (old_start, new_start) = (old_end + 1, new_end + 1);
+    }
+
+    steps
+}
+
+/// Returns the indices of the items to match up in each list, as a longest
+/// common subsequence of them
+fn align<T>(old: &[T], new: &[T], eq: impl Fn(&T, &T) -> bool) -> Vec<(usize, usize)> {
+    // Most changes leave the start and the end of the lists alone
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(old, new)| eq(old, new))
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| eq(old, new))
+        .count();
+
+    let mut pairs: Vec<_> = (0..prefix).map(|i| (i, i)).collect();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    if old_middle.len() * new_middle.len() <= MAX_ALIGNMENT_STEPS {
+        // lengths[i * width + j] is the length of the longest common
+        // subsequence of old_middle[i..] and new_middle[j..]
+        let width = new_middle.len() + 1;
+        let mut lengths = vec![0u32; (old_middle.len() + 1) * width];
+        for i in (0..old_middle.len()).rev() {
+            for j in (0..new_middle.len()).rev() {
+                lengths[i * width + j] = if eq(&old_middle[i], &new_middle[j]) {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < old_middle.len() && j < new_middle.len() {
+            if eq(&old_middle[i], &new_middle[j]) {
+                pairs.push((prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+
+    pairs.extend((0..suffix).map(|k| (old.len() - suffix + k, new.len() - suffix + k)));
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::ElementRef;
+
+    /// Describes the changes between two fragments, with nodes as HTML
+    fn changes(old: &str, new: &str) -> Vec<String> {
+        let old = Html::parse_fragment(old);
+        let new = Html::parse_fragment(new);
+        let html = |html: &Html, id| {
+            ElementRef::wrap(html.tree.get(id).unwrap()).map_or_else(String::new, |e| e.html())
+        };
+
+        diff(&old, &new)
+            .into_iter()
+            .map(|change| match change {
+                Change::Insert(id) => format!("+ {}", html(&new, id)),
+                Change::Delete(id) => format!("- {}", html(&old, id)),
+                Change::Attribute { name, from, to, .. } => format!("{name}: {from:?} -> {to:?}"),
+                Change::Text { from, to, .. } => format!("{from:?} -> {to:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            Vec::<String>::new(),
+            changes(
+                r#"<ul class="a" id="b"><li>One</li></ul>"#,
+                "<ul id=b class=a>\n  <li>One</li>\n</ul>"
+            )
+        );
+        assert_eq!(
+            vec![
+                "\"One\" -> \"2\"",
+                "- <li>Two</li>",
+                "+ <li>Four</li>",
+                "+ <p>Five</p>"
+            ],
+            changes(
+                "<ul><li>One</li><li>Two</li><li>Three</li></ul>",
+                "<ul><li>2</li><li>Three</li><li>Four</li></ul><p>Five</p>"
+            )
+        );
+        assert_eq!(
+            vec![
+                "href: Some(\"/a\") -> Some(\"/b\")",
+                "rel: Some(\"next\") -> None",
+                "title: None -> Some(\"B\")",
+                "\" a \" -> \" b \""
+            ],
+            changes(
+                r#"<a href="/a" rel="next"><!-- a --></a>"#,
+                r#"<a title="B" href="/b"><!-- b --></a>"#
+            )
+        );
+        assert_eq!(
+            vec!["+ <li>New</li>"],
+            changes(
+                "<ul><li>A</li><li>B</li></ul>",
+                "<ul><li>New</li><li>A</li><li>B</li></ul>"
+            ),
+            "identical subtrees are matched up first"
+        );
+        assert_eq!(
+            vec!["- <p>One</p>", "+ <div>One</div>"],
+            changes("<p>One</p>", "<div>One</div>"),
+            "elements with different names are replaced"
+        );
+    }
+
+    #[test]
+    fn test_diff_documents() {
+        let old = Html::parse_document("<!DOCTYPE html><title>Old</title><p>Text</p>");
+        let new = Html::parse_document("<title>New</title><p>Text</p>");
+
+        let changes = diff(&old, &new);
+
+        assert!(
+            matches!(changes[0], Change::Delete(id) if old.tree.get(id).unwrap().value().is_doctype())
+        );
+        assert!(matches!(
+            &changes[1],
+            Change::Text { from, to, .. } if from == "Old" && to == "New"
+        ));
+        assert_eq!(2, changes.len());
+    }
+}
@@ -0,0 +1,1208 @@
+use ego_tree::iter::{Children, Edge};
+use ego_tree::{NodeId, NodeRef};
+use html5ever::{
+    local_name, namespace_url, ns, tendril::StrTendril, Attribute, LocalName, QualName,
+};
+use scraper::node::{Comment, Doctype, Element, ProcessingInstruction, Text};
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::HashMap;
+
+/// Attributes whose values are a single URL (see also `srcset`)
+pub(crate) const URL_ATTRIBUTES: [&str; 10] = [
+    "action",
+    "background",
+    "cite",
+    "data",
+    "formaction",
+    "href",
+    "longdesc",
+    "poster",
+    "src",
+    "xlink:href",
+];
+
+/// Returns the elements of the document that match the selector, in order
+///
+/// Unlike [`Html::select`], this skips nodes that were detached from the tree
+/// (e.g. removed elements), which ego-tree keeps around in its storage.
+pub(crate) fn select<'a, 'b>(
+    html: &'a Html,
+    selector: &'b Selector,
+) -> impl Iterator<Item = ElementRef<'a>> + 'b
+where
+    'a: 'b,
+{
+    html.tree
+        .root()
+        .descendants()
+        .filter_map(ElementRef::wrap)
+        .filter(|element| selector.matches(element))
+}
+
+/// Returns the attribute's name including its prefix (e.g. `xlink:href`)
+pub(crate) fn attribute_name(name: &QualName) -> String {
+    match &name.prefix {
+        Some(prefix) => format!("{prefix}:{}", name.local),
+        None => name.local.to_string(),
+    }
+}
+
+/// Returns the value of an attribute by its name as written in the markup,
+/// including any prefix (e.g. `xlink:href` in inline SVG)
+pub(crate) fn attr<'a>(element: &'a Element, name: &str) -> Option<&'a str> {
+    element.attr(name).or_else(|| {
+        element
+            .attrs
+            .iter()
+            .find(|(key, _)| key.prefix.is_some() && attribute_name(key) == name)
+            .map(|(_, value)| &**value)
+    })
+}
+
+/// Returns the element's `data-*` attributes like the DOM's `dataset`, except
+/// that the names are underscored rather than camel-cased, e.g. `user_id`
+/// for `data-user-id`
+pub(crate) fn data_attrs(element: &Element) -> Vec<(String, &str)> {
+    element
+        .attrs
+        .iter()
+        .filter(|(key, _)| key.prefix.is_none())
+        .filter_map(|(key, value)| {
+            let name = key.local.strip_prefix("data-")?;
+
+            (!name.is_empty()).then(|| (name.replace('-', "_"), &**value))
+        })
+        .collect()
+}
+
+/// Checks whether an attribute name can be serialized ([HTML spec][1]),
+/// which is required for names that don't come from the parser
+///
+/// [1]: https://html.spec.whatwg.org/multipage/syntax.html#attributes-2
+pub(crate) fn is_valid_attribute_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.chars().any(|c| {
+            c.is_ascii_whitespace()
+                || c.is_control()
+                || matches!(c, '"' | '\'' | '>' | '/' | '=')
+                || ('\u{FDD0}'..='\u{FDEF}').contains(&c)
+                || (c as u32 & 0xFFFE) == 0xFFFE
+        })
+}
+
+/// Checks whether an element name can be serialized, which requires it to
+/// start with an ASCII letter and only contain characters that would be
+/// part of the name when parsing it back
+pub(crate) fn is_valid_element_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_whitespace() || matches!(c, '/' | '>' | '\0'))
+}
+
+/// Checks whether a class can be added to the `class` attribute without
+/// being split into several
+pub(crate) fn is_valid_class_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c.is_ascii_whitespace())
+}
+
+/// Adds classes that the element doesn't have yet to the end of its `class`
+/// attribute
+pub(crate) fn add_classes(element: &mut Element, names: &[String]) {
+    let mut classes = class_list(element);
+
+    for name in names {
+        if !classes.contains(name) {
+            classes.push(name.clone());
+        }
+    }
+
+    set_class_list(element, classes);
+}
+
+pub(crate) fn remove_classes(element: &mut Element, names: &[String]) {
+    let mut classes = class_list(element);
+
+    classes.retain(|class| !names.contains(class));
+    set_class_list(element, classes);
+}
+
+/// Removes the class if the element has it and adds it otherwise, unless
+/// `force` says whether it should be added
+pub(crate) fn toggle_class(element: &mut Element, name: &str, force: Option<bool>) {
+    let mut classes = class_list(element);
+    let present = classes.iter().any(|class| class == name);
+    let add = force.unwrap_or(!present);
+
+    if add && !present {
+        classes.push(name.to_string());
+    } else if !add {
+        classes.retain(|class| class != name);
+    }
+
+    set_class_list(element, classes);
+}
+
+/// Returns the element's classes without duplicates, like `classList`
+fn class_list(element: &Element) -> Vec<String> {
+    let mut classes: Vec<String> = Vec::new();
+
+    for class in element
+        .attr("class")
+        .unwrap_or_default()
+        .split_ascii_whitespace()
+    {
+        if !classes.iter().any(|existing| existing == class) {
+            classes.push(class.to_string());
+        }
+    }
+
+    classes
+}
+
+/// Rewrites the `class` attribute, removing it once there are no classes
+/// left instead of leaving it empty
+fn set_class_list(element: &mut Element, classes: Vec<String>) {
+    if classes.is_empty() {
+        remove_attr(element, "class");
+    } else {
+        set_attr(element, "class", &classes.join(" "));
+    }
+}
+
+/// Creates an element that isn't attached to the tree yet, returning its id
+pub(crate) fn create_element(html: &mut Html, name: &str, attrs: &[(String, String)]) -> NodeId {
+    let mut element = Element::new(
+        QualName::new(None, ns!(html), LocalName::from(name)),
+        Vec::new(),
+    );
+
+    for (name, value) in attrs {
+        set_attr(&mut element, name, value);
+    }
+
+    html.tree.orphan(Node::Element(element)).id()
+}
+
+/// Replaces the values of the attributes for which `f` returns a new one
+pub(crate) fn map_attrs<F>(element: &mut Element, mut f: F)
+where
+    F: FnMut(&str, &str) -> Option<String>,
+{
+    let mut changed = false;
+
+    let attributes = element
+        .attrs
+        .iter()
+        .map(|(key, value)| {
+            let value = match f(&attribute_name(key), value) {
+                Some(new_value) => {
+                    changed = true;
+                    StrTendril::from(new_value)
+                }
+                None => StrTendril::from(&**value),
+            };
+
+            Attribute {
+                name: key.clone(),
+                value,
+            }
+        })
+        .collect();
+
+    if changed {
+        rebuild(element, attributes);
+    }
+}
+
+/// Sets the value of an (unprefixed) attribute, adding it if needed
+pub(crate) fn set_attr(element: &mut Element, name: &str, value: &str) {
+    let qualname = QualName::new(None, ns!(), LocalName::from(name));
+    let mut found = false;
+
+    let mut attributes = element
+        .attrs
+        .iter()
+        .map(|(key, existing)| {
+            let value = if *key == qualname {
+                found = true;
+                value
+            } else {
+                existing
+            };
+
+            Attribute {
+                name: key.clone(),
+                value: StrTendril::from(value),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if !found {
+        attributes.push(Attribute {
+            name: qualname,
+            value: StrTendril::from(value),
+        });
+    }
+
+    rebuild(element, attributes);
+}
+
+/// Removes an (unprefixed) attribute, returning whether it was present
+pub(crate) fn remove_attr(element: &mut Element, name: &str) -> bool {
+    let qualname = QualName::new(None, ns!(), LocalName::from(name));
+
+    if !element.attrs.iter().any(|(key, _)| *key == qualname) {
+        return false;
+    }
+
+    let attributes = element
+        .attrs
+        .iter()
+        .filter(|(key, _)| *key != qualname)
+        .map(|(key, value)| Attribute {
+            name: key.clone(),
+            value: StrTendril::from(&**value),
+        })
+        .collect();
+
+    rebuild(element, attributes);
+
+    true
+}
+
+/// Copies an element and its descendants into a new fragment, returning it
+/// along with the id of the copied element. Like with [`Html::parse_fragment`],
+/// the copy is wrapped in an `<html>` root element.
+pub(crate) fn copy_subtree(element_ref: ElementRef) -> (Html, NodeId) {
+    let mut html = empty_fragment();
+    let root_id = html.root_element().id();
+
+    let copy_id = html
+        .tree
+        .get_mut(root_id)
+        .expect("root must be in the tree")
+        .append(copy_value((*element_ref).value()))
+        .id();
+
+    copy_children(&mut html, copy_id, *element_ref);
+
+    (html, copy_id)
+}
+
+/// Creates a fragment without any content, whose root is an `<html>` element
+/// like with [`Html::parse_fragment`]
+pub(crate) fn empty_fragment() -> Html {
+    let mut html = Html::new_fragment();
+
+    html.tree.root_mut().append(Node::Element(Element::new(
+        QualName::new(None, ns!(html), local_name!("html")),
+        Vec::new(),
+    )));
+
+    html
+}
+
+/// Returns the nodes making up a document's content, which are the children
+/// of its root element for fragments and of its `<body>` otherwise
+pub(crate) fn content(html: &Html) -> Children<'_, Node> {
+    content_root(html).children()
+}
+
+/// Returns the element whose children make up a document's content, see
+/// [`content`]
+pub(crate) fn content_root(html: &Html) -> ElementRef<'_> {
+    let root_element = html.root_element();
+
+    if html.tree.root().value().is_fragment() {
+        return root_element;
+    }
+
+    root_element
+        .child_elements()
+        .find(|child| child.value().name() == "body")
+        .unwrap_or(root_element)
+}
+
+/// Returns the elements inside `scope` in document order, each with the
+/// position in the list just past its descendants, so that walking the list
+/// can skip over an element's subtree
+pub(crate) fn descendant_elements(scope: NodeRef<Node>) -> Vec<(NodeId, usize)> {
+    let mut elements = Vec::new();
+    // The positions of the open elements
+    let mut open = Vec::new();
+
+    for edge in scope.traverse() {
+        match edge {
+            Edge::Open(node) if node != scope && node.value().is_element() => {
+                open.push(elements.len());
+                elements.push((node.id(), 0));
+            }
+            Edge::Close(node) if node != scope && node.value().is_element() => {
+                let position = open.pop().expect("elements are opened before being closed");
+                elements[position].1 = elements.len();
+            }
+            _ => {}
+        }
+    }
+
+    elements
+}
+
+/// Returns a selector matching only `element`, made of the names of its
+/// ancestors starting from the root element, e.g.
+/// `html > body > ul > li:nth-of-type(2)`
+///
+/// Positions are only added where siblings share a name, so the selector
+/// stops matching if such siblings are added before the element.
+pub(crate) fn css_path(element: ElementRef) -> String {
+    let mut parts: Vec<String> = [*element]
+        .into_iter()
+        .chain(element.ancestors())
+        .filter_map(ElementRef::wrap)
+        .map(|element| {
+            let name = element.value().name();
+            let same_name = |sibling: &ElementRef| sibling.value().name() == name;
+            let position = element
+                .prev_siblings()
+                .filter_map(ElementRef::wrap)
+                .filter(same_name)
+                .count();
+
+            if position == 0
+                && !element
+                    .next_siblings()
+                    .filter_map(ElementRef::wrap)
+                    .any(|sibling| same_name(&sibling))
+            {
+                name.to_string()
+            } else {
+                format!("{name}:nth-of-type({})", position + 1)
+            }
+        })
+        .collect();
+
+    parts.reverse();
+    parts.join(" > ")
+}
+
+/// Returns a selector matching only `element` in its document, starting
+/// from the closest of it and its ancestors with an id no other element
+/// has, e.g. `#main > ul > li:nth-child(2)`
+///
+/// Without such an id, the selector starts from the root element like
+/// [`css_path`]'s. Positions are only added where siblings share a name, and
+/// count every element sibling so that they don't depend on how names are
+/// compared.
+pub(crate) fn unique_css_path(element: ElementRef) -> String {
+    let root = element.ancestors().last().unwrap_or(*element);
+    let mut id_counts = None;
+    let mut parts = Vec::new();
+
+    for element in [*element]
+        .into_iter()
+        .chain(element.ancestors())
+        .filter_map(ElementRef::wrap)
+    {
+        if let Some(id) = element.value().id().filter(|id| !id.is_empty()) {
+            let id_counts = id_counts.get_or_insert_with(|| id_counts_in(root));
+
+            if id_counts.get(&id.to_ascii_lowercase()) == Some(&1) {
+                let mut part = "#".to_string();
+                cssparser::serialize_identifier(id, &mut part).expect("writing to a string");
+                parts.push(part);
+                break;
+            }
+        }
+
+        let name = element.value().name();
+        let mut part = String::new();
+        cssparser::serialize_identifier(name, &mut part).expect("writing to a string");
+
+        let same_name = element
+            .parent()
+            .into_iter()
+            .flat_map(|parent| parent.children())
+            .filter_map(ElementRef::wrap)
+            .filter(|sibling| sibling.value().name() == name)
+            .count();
+        if same_name > 1 {
+            let position = element
+                .prev_siblings()
+                .filter(|sibling| sibling.value().is_element())
+                .count();
+            part.push_str(&format!(":nth-child({})", position + 1));
+        }
+
+        parts.push(part);
+    }
+
+    parts.reverse();
+    parts.join(" > ")
+}
+
+/// Counts the elements with each id, compared ASCII case-insensitively as
+/// browsers do in quirks mode
+fn id_counts_in(root: NodeRef<Node>) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+
+    for element in root.descendants().filter_map(ElementRef::wrap) {
+        if let Some(id) = element.value().id() {
+            *counts.entry(id.to_ascii_lowercase()).or_default() += 1;
+        }
+    }
+
+    counts
+}
+
+/// Where nodes are inserted relative to an element, named after the
+/// positions of the DOM's `insertAdjacentHTML`
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Position {
+    /// Before the element, which must have a parent
+    BeforeBegin,
+    /// Before its first child
+    AfterBegin,
+    /// After its last child
+    BeforeEnd,
+    /// After the element, which must have a parent
+    AfterEnd,
+}
+
+/// Inserts copies of `nodes` and their descendants at `position` relative to
+/// `target`, keeping them in order
+pub(crate) fn insert<'a>(
+    html: &mut Html,
+    target: NodeId,
+    position: Position,
+    nodes: impl IntoIterator<Item = NodeRef<'a, Node>>,
+) {
+    let ids: Vec<NodeId> = nodes
+        .into_iter()
+        .map(|node| orphan_copy(html, node))
+        .collect();
+
+    place(html, target, position, &ids);
+}
+
+/// Moves nodes of the tree at `position` relative to `target`, keeping them
+/// in order. None of them can be `target` or one of its ancestors.
+pub(crate) fn place(html: &mut Html, target: NodeId, position: Position, ids: &[NodeId]) {
+    let mut previous = None;
+
+    for &id in ids {
+        let mut anchor = html
+            .tree
+            .get_mut(previous.unwrap_or(target))
+            .expect("target must be in the tree");
+
+        match (previous, position) {
+            (Some(_), _) | (None, Position::AfterEnd) => anchor.insert_id_after(id),
+            (None, Position::BeforeBegin) => anchor.insert_id_before(id),
+            (None, Position::AfterBegin) => anchor.prepend_id(id),
+            (None, Position::BeforeEnd) => anchor.append_id(id),
+        };
+
+        previous = Some(id);
+    }
+}
+
+/// Copies a node and its descendants into the tree without attaching them,
+/// returning the id of the copy
+pub(crate) fn orphan_copy(html: &mut Html, node: NodeRef<Node>) -> NodeId {
+    let id = html.tree.orphan(copy_value(node.value())).id();
+
+    copy_children(html, id, node);
+
+    id
+}
+
+/// Creates a text node that isn't attached to the tree yet, returning its id
+pub(crate) fn create_text(html: &mut Html, text: &str) -> NodeId {
+    html.tree
+        .orphan(Node::Text(Text { text: text.into() }))
+        .id()
+}
+
+/// Serializes a text node the same way as when serializing its parent,
+/// escaping it unless the parent is a raw text element like `<script>`
+pub(crate) fn text_html(node: NodeRef<Node>) -> String {
+    let Node::Text(text) = node.value() else {
+        return String::new();
+    };
+
+    let is_raw_text = node
+        .parent()
+        .and_then(ElementRef::wrap)
+        .is_some_and(|parent| {
+            matches!(
+                parent.value().name(),
+                "style" | "script" | "xmp" | "iframe" | "noembed" | "noframes" | "plaintext"
+            )
+        });
+
+    if is_raw_text {
+        return text.to_string();
+    }
+
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '\u{a0}' => escaped.push_str("&nbsp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Encloses `target` in a copy of `wrapper`, moving it after the existing
+/// children of the wrapper's innermost first element. `target` must have a
+/// parent.
+pub(crate) fn wrap(html: &mut Html, target: NodeId, wrapper: ElementRef) {
+    insert(html, target, Position::BeforeBegin, [*wrapper]);
+
+    let mut innermost = html
+        .tree
+        .get(target)
+        .and_then(|node| node.prev_sibling())
+        .and_then(ElementRef::wrap)
+        .expect("the wrapper must have been inserted before the target");
+
+    while let Some(child) = innermost.children().find_map(ElementRef::wrap) {
+        innermost = child;
+    }
+
+    let innermost_id = innermost.id();
+
+    html.tree
+        .get_mut(innermost_id)
+        .expect("the wrapper must be in the tree")
+        .append_id(target);
+}
+
+/// Detaches the given elements, which must be below the root element and in
+/// document order, skipping those that are already out of the document
+/// because an element before them contains them
+pub(crate) fn detach_all(html: &mut Html, ids: &[NodeId]) {
+    let root_id = html.root_element().id();
+
+    for &id in ids {
+        let in_document = html
+            .tree
+            .get(id)
+            .expect("node must be in the tree")
+            .ancestors()
+            .any(|ancestor| ancestor.id() == root_id);
+
+        if in_document {
+            html.tree
+                .get_mut(id)
+                .expect("node must be in the tree")
+                .detach();
+        }
+    }
+}
+
+/// Replaces `target` with its children. `target` must have a parent.
+pub(crate) fn unwrap(html: &mut Html, target: NodeId) {
+    let mut node = html
+        .tree
+        .get_mut(target)
+        .expect("target must be in the tree");
+
+    while let Some(child_id) = node.first_child().map(|child| child.id()) {
+        node.insert_id_before(child_id);
+    }
+
+    node.detach();
+}
+
+/// Appends copies of the descendants of `source` to `parent_id`
+fn copy_children(html: &mut Html, parent_id: NodeId, source: NodeRef<Node>) {
+    // Uses an explicit stack rather than recursion as documents can be deeply nested
+    let mut stack: Vec<_> = source
+        .children()
+        .rev()
+        .map(|child| (parent_id, child))
+        .collect();
+
+    while let Some((parent_id, source)) = stack.pop() {
+        let id = html
+            .tree
+            .get_mut(parent_id)
+            .expect("parent must have been copied")
+            .append(copy_value(source.value()))
+            .id();
+
+        stack.extend(source.children().rev().map(|child| (id, child)));
+    }
+}
+
+/// Copies a whole tree, keeping node ids so that whatever refers to nodes by
+/// id still applies to the copy
+pub(crate) fn copy_html(html: &Html) -> Html {
+    Html {
+        errors: html.errors.clone(),
+        quirks_mode: html.quirks_mode,
+        tree: html.tree.map_ref(copy_value),
+    }
+}
+
+/// Copies a node's value with new strings rather than cloning it
+///
+/// Cloning a string the tree owns marks its buffer as shared, which writes to
+/// the node. Copying instead only reads it, so it's safe while other threads
+/// are reading the tree too.
+pub(crate) fn copy_value(node: &Node) -> Node {
+    match node {
+        Node::Document => Node::Document,
+        Node::Fragment => Node::Fragment,
+        Node::Doctype(doctype) => Node::Doctype(Doctype {
+            name: (*doctype.name).into(),
+            public_id: (*doctype.public_id).into(),
+            system_id: (*doctype.system_id).into(),
+        }),
+        Node::Comment(comment) => Node::Comment(Comment {
+            comment: (**comment).into(),
+        }),
+        Node::Text(text) => Node::Text(Text {
+            text: (**text).into(),
+        }),
+        Node::Element(element) => {
+            let attributes = element
+                .attrs
+                .iter()
+                .map(|(key, value)| Attribute {
+                    name: key.clone(),
+                    value: StrTendril::from(&**value),
+                })
+                .collect();
+
+            Node::Element(Element::new(element.name.clone(), attributes))
+        }
+        Node::ProcessingInstruction(instruction) => {
+            Node::ProcessingInstruction(ProcessingInstruction {
+                target: (*instruction.target).into(),
+                data: (*instruction.data).into(),
+            })
+        }
+    }
+}
+
+/// Replaces the element with a new one so that scraper re-sorts the attributes
+/// (which it relies on for lookups) and drops its cached `id` and classes
+fn rebuild(element: &mut Element, attributes: Vec<Attribute>) {
+    *element = Element::new(element.name.clone(), attributes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::{CaseSensitivity, Selector};
+
+    fn with_element<F: FnOnce(&mut Element)>(input: &str, f: F) -> String {
+        let mut html = Html::parse_fragment(input);
+        let id = html.root_element().first_child().unwrap().id();
+
+        if let Node::Element(element) = html.tree.get_mut(id).unwrap().value() {
+            f(element);
+        }
+
+        html.root_element().inner_html()
+    }
+
+    #[test]
+    fn test_set_attr() {
+        assert_eq!(
+            r#"<p class="b" id="a">text</p>"#,
+            with_element(r#"<p id="a">text</p>"#, |element| {
+                set_attr(element, "class", "b");
+                assert_eq!(Some("b"), element.attr("class"), "lookups still work");
+            }),
+            "new attributes are added"
+        );
+
+        assert_eq!(
+            r#"<p class="c">text</p>"#,
+            with_element(r#"<p class="a b">text</p>"#, |element| {
+                assert!(element.has_class("a", CaseSensitivity::CaseSensitive));
+                set_attr(element, "class", "c");
+                assert!(
+                    !element.has_class("a", CaseSensitivity::CaseSensitive),
+                    "cached classes are reset"
+                );
+            }),
+            "existing attributes are replaced"
+        );
+    }
+
+    #[test]
+    fn test_attr() {
+        let html = Html::parse_fragment(
+            r##"<svg><use xlink:href="#icon" href="#other" xml:lang="en"/></svg>"##,
+        );
+        let element = html.root_element().descendants().nth(2).unwrap();
+        let element = element.value().as_element().unwrap();
+
+        assert_eq!(Some("#icon"), attr(element, "xlink:href"));
+        assert_eq!(Some("#other"), attr(element, "href"));
+        assert_eq!(Some("en"), attr(element, "xml:lang"));
+        assert_eq!(None, attr(element, "lang"));
+    }
+
+    #[test]
+    fn test_data_attrs() {
+        let html = Html::parse_fragment(
+            r#"<div data-id="1" data-user-name="Ada" data-="" id="x" DATA-Theme="dark"></div>"#,
+        );
+        let element = html.root_element().first_child().unwrap();
+        let mut data = data_attrs(element.value().as_element().unwrap());
+        data.sort();
+
+        assert_eq!(
+            vec![
+                ("id".to_string(), "1"),
+                ("theme".to_string(), "dark"),
+                ("user_name".to_string(), "Ada"),
+            ],
+            data
+        );
+    }
+
+    #[test]
+    fn test_is_valid_attribute_name() {
+        for name in [
+            "id",
+            "data-foo",
+            "aria-label",
+            "xml:lang",
+            "@click",
+            "émoji",
+        ] {
+            assert!(is_valid_attribute_name(name), "{name:?} is valid");
+        }
+
+        for name in [
+            "",
+            "a b",
+            "a=b",
+            "a\"",
+            "a'",
+            "a>",
+            "a/",
+            "a\0",
+            "a\u{FFFF}",
+        ] {
+            assert!(!is_valid_attribute_name(name), "{name:?} is invalid");
+        }
+    }
+
+    #[test]
+    fn test_is_valid_element_name() {
+        for name in ["p", "h1", "my-element", "x:y"] {
+            assert!(is_valid_element_name(name), "{name:?} is valid");
+        }
+
+        for name in ["", "1p", "-p", "a b", "a/", "a>", "a\0"] {
+            assert!(!is_valid_element_name(name), "{name:?} is invalid");
+        }
+    }
+
+    #[test]
+    fn test_classes() {
+        let names = |names: &[&str]| names.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(
+            r#"<p class="a b c">Text</p>"#,
+            with_element(r#"<p class=" a  b a ">Text</p>"#, |element| {
+                add_classes(element, &names(&["c", "b", "c"]))
+            }),
+            "duplicates are removed"
+        );
+        assert_eq!(
+            r#"<p class="b">Text</p>"#,
+            with_element(r#"<p class="a b a">Text</p>"#, |element| {
+                remove_classes(element, &names(&["a", "c"]))
+            })
+        );
+        assert_eq!(
+            "<p>Text</p>",
+            with_element(r#"<p class="a">Text</p>"#, |element| {
+                remove_classes(element, &names(&["a"]))
+            }),
+            "empty class attributes are removed"
+        );
+        assert_eq!(
+            r#"<p class="b a">Text</p>"#,
+            with_element(r#"<p class="a b">Text</p>"#, |element| {
+                toggle_class(element, "a", None);
+                toggle_class(element, "a", None);
+                toggle_class(element, "b", Some(true));
+            })
+        );
+        assert_eq!(
+            "<p>Text</p>",
+            with_element("<p>Text</p>", |element| {
+                toggle_class(element, "a", Some(false));
+            })
+        );
+        assert!(is_valid_class_name("a-b_c"));
+        assert!(!is_valid_class_name(""));
+        assert!(!is_valid_class_name("a b"));
+    }
+
+    #[test]
+    fn test_create_element() {
+        let mut html = Html::parse_fragment("<p></p>");
+        let attrs = [
+            ("href".to_string(), "/".to_string()),
+            ("class".to_string(), "a".to_string()),
+            ("class".to_string(), "b".to_string()),
+        ];
+        let id = create_element(&mut html, "a", &attrs);
+        let element = ElementRef::wrap(html.tree.get(id).unwrap()).unwrap();
+
+        assert_eq!(r#"<a class="b" href="/"></a>"#, element.html());
+        assert!(element.parent().is_none(), "the element is detached");
+        assert_eq!("<p></p>", html.root_element().inner_html());
+    }
+
+    #[test]
+    fn test_remove_attr() {
+        assert_eq!(
+            r#"<p class="a">text</p>"#,
+            with_element(r#"<p class="a" id="b">text</p>"#, |element| {
+                assert_eq!(Some("b"), element.id());
+                assert!(remove_attr(element, "id"));
+                assert_eq!(None, element.id(), "cached id is reset");
+                assert!(!remove_attr(element, "id"));
+            })
+        );
+    }
+
+    #[test]
+    fn test_copy_subtree() {
+        let html = Html::parse_fragment(
+            "<table><tr id=row><td>One<b>!</b></td><td>Two</td></tr></table><p>Other</p>",
+        );
+        let row = html.select(&Selector::parse("tr").unwrap()).next().unwrap();
+
+        let (copy, id) = copy_subtree(row);
+        let copied_row = ElementRef::wrap(copy.tree.get(id).unwrap()).unwrap();
+
+        assert_eq!(
+            r#"<tr id="row"><td>One<b>!</b></td><td>Two</td></tr>"#,
+            copied_row.html(),
+            "the element is copied along with its descendants"
+        );
+        assert_eq!(
+            copied_row.html(),
+            copy.root_element().inner_html(),
+            "nothing else is copied"
+        );
+        assert_eq!("html", copy.root_element().value().name());
+        assert_eq!(Some("row"), copied_row.value().id());
+    }
+
+    #[test]
+    fn test_copy_html() {
+        let mut html = Html::parse_document(
+            "<!DOCTYPE html><p class=a id=b>One <!-- x --><?pi data?></p><p>Two</p>",
+        );
+        let second = html
+            .select(&Selector::parse("p + p").unwrap())
+            .next()
+            .unwrap()
+            .id();
+        html.tree.get_mut(second).unwrap().detach();
+
+        let copy = copy_html(&html);
+
+        assert_eq!(html.html(), copy.html());
+        assert!(
+            copy.tree.get(second).unwrap().parent().is_none(),
+            "detached nodes are kept under the same ids"
+        );
+        assert_eq!(
+            copy.tree.nodes().map(|node| node.id()).collect::<Vec<_>>(),
+            html.tree.nodes().map(|node| node.id()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some("b"),
+            copy.select(&Selector::parse(".a").unwrap())
+                .next()
+                .and_then(|element| element.value().id())
+        );
+    }
+
+    #[test]
+    fn test_detach_all() {
+        let mut html = Html::parse_fragment(
+            "<div class=ad><p class=ad>One</p></div><p>Two</p><span class=ad>Three</span>",
+        );
+        let ids = |html: &Html, selector: &str| -> Vec<NodeId> {
+            html.select(&Selector::parse(selector).unwrap())
+                .map(|element| element.id())
+                .collect()
+        };
+        let ads = ids(&html, ".ad");
+
+        detach_all(&mut html, &ads);
+        assert_eq!("<p>Two</p>", html.root_element().inner_html());
+        assert_eq!(
+            r#"<div class="ad"><p class="ad">One</p></div>"#,
+            ElementRef::wrap(html.tree.get(ads[0]).unwrap())
+                .unwrap()
+                .html(),
+            "elements inside removed ones stay in them"
+        );
+    }
+
+    #[test]
+    fn test_place() {
+        let mut html = Html::parse_fragment("<p>One</p><div><b>Two</b></div>");
+        let select = |html: &Html, selector: &str| {
+            html.select(&Selector::parse(selector).unwrap())
+                .next()
+                .unwrap()
+                .id()
+        };
+        let paragraph = select(&html, "p");
+        let bold = select(&html, "b");
+        let text = create_text(&mut html, "<3 & ");
+
+        place(&mut html, bold, Position::BeforeBegin, &[text, paragraph]);
+
+        assert_eq!(
+            "<div>&lt;3 &amp; <p>One</p><b>Two</b></div>",
+            html.root_element().inner_html(),
+            "new and existing nodes are moved in order"
+        );
+    }
+
+    #[test]
+    fn test_text_html() {
+        let mut html = Html::parse_fragment("<p></p><script></script>");
+        let text = "a < b && c\u{a0}> d";
+
+        for (selector, expected) in [("p", "a &lt; b &amp;&amp; c&nbsp;&gt; d"), ("script", text)] {
+            let parent = html
+                .select(&Selector::parse(selector).unwrap())
+                .next()
+                .unwrap()
+                .id();
+            let id = create_text(&mut html, text);
+
+            html.tree.get_mut(parent).unwrap().append_id(id);
+
+            assert!(
+                html.tree
+                    .get(parent)
+                    .and_then(ElementRef::wrap)
+                    .unwrap()
+                    .html()
+                    .contains(&text_html(html.tree.get(id).unwrap())),
+                "matches the serialized parent"
+            );
+            assert_eq!(expected, text_html(html.tree.get(id).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_wrap() {
+        let wrapper = Html::parse_fragment("<div class=outer><p><b>Label</b> </p><i></i></div>");
+
+        for (input, expected) in [
+            (
+                "<section><img> after</section>",
+                r#"<section><div class="outer"><p><b>Label<img></b> </p><i></i></div> after</section>"#,
+            ),
+            (
+                "<section>before <img></section>",
+                r#"<section>before <div class="outer"><p><b>Label<img></b> </p><i></i></div></section>"#,
+            ),
+        ] {
+            let mut html = Html::parse_fragment(input);
+            let target = html
+                .select(&Selector::parse("img").unwrap())
+                .next()
+                .unwrap()
+                .id();
+
+            wrap(
+                &mut html,
+                target,
+                wrapper
+                    .root_element()
+                    .first_child()
+                    .and_then(ElementRef::wrap)
+                    .unwrap(),
+            );
+
+            assert_eq!(expected, html.root_element().inner_html());
+        }
+    }
+
+    #[test]
+    fn test_unwrap() {
+        let mut html = Html::parse_fragment("<p>A <font color=red>b <i>c</i> d</font>.</p>");
+        let target = html
+            .select(&Selector::parse("font").unwrap())
+            .next()
+            .unwrap()
+            .id();
+
+        unwrap(&mut html, target);
+
+        assert_eq!("<p>A b <i>c</i> d.</p>", html.root_element().inner_html());
+    }
+
+    #[test]
+    fn test_content() {
+        let serialized = |html: &Html| -> Vec<String> {
+            content(html)
+                .map(|node| ElementRef::wrap(node).map_or_else(String::new, |e| e.html()))
+                .collect()
+        };
+
+        assert_eq!(
+            vec!["<p>One</p>", "<b>Two</b>"],
+            serialized(&Html::parse_fragment("<p>One</p><b>Two</b>"))
+        );
+        assert_eq!(
+            vec!["<p>One</p>"],
+            serialized(&Html::parse_document("<title>Title</title><p>One</p>")),
+            "documents only include their body"
+        );
+        assert_eq!(0, content(&empty_fragment()).count());
+    }
+
+    #[test]
+    fn test_css_path() {
+        for html in [
+            Html::parse_fragment("<ul><li>One</li><li>Two <b>2</b></li></ul><p>Three</p>"),
+            Html::parse_document("<ul><li>One</li><li>Two <b>2</b></li></ul><p>Three</p>"),
+        ] {
+            for selector in ["b", "p", "li"] {
+                let element = html
+                    .select(&Selector::parse(selector).unwrap())
+                    .next_back()
+                    .unwrap();
+                let path = css_path(element);
+
+                assert_eq!(
+                    vec![element.id()],
+                    html.select(&Selector::parse(&path).unwrap())
+                        .map(|element| element.id())
+                        .collect::<Vec<_>>(),
+                    "{path} matches only the element"
+                );
+            }
+        }
+
+        let html = Html::parse_document("<ul><li>One</li><li>Two <b>2</b></li></ul>");
+        let b = html.select(&Selector::parse("b").unwrap()).next().unwrap();
+        assert_eq!("html > body > ul > li:nth-of-type(2) > b", css_path(b));
+    }
+
+    #[test]
+    fn test_unique_css_path() {
+        let html = Html::parse_document(
+            r#"<div id="Main"><p>One</p><ul id="1a"><li>Two</li><li id="x">Three <b>3</b></li></ul></div><p id="x"></p><p id="MAIN"></p>"#,
+        );
+
+        for (selector, expected) in [
+            ("div", "html > body > div"),
+            ("div > p", "html > body > div > p"),
+            ("li + li", "#\\31 a > li:nth-child(2)"),
+            ("b", "#\\31 a > li:nth-child(2) > b"),
+            ("body > p", "html > body > p:nth-child(2)"),
+        ] {
+            let element = html
+                .select(&Selector::parse(selector).unwrap())
+                .next()
+                .unwrap();
+            let path = unique_css_path(element);
+
+            assert_eq!(expected, path, "{selector}");
+            assert_eq!(
+                vec![element.id()],
+                html.select(&Selector::parse(&path).unwrap())
+                    .map(|element| element.id())
+                    .collect::<Vec<_>>(),
+                "{path} matches only the element"
+            );
+        }
+    }
+
+    #[test]
+    fn test_descendant_elements() {
+        let html = Html::parse_fragment("<nav><a>One</a><a>Two</a></nav><p>Text <b>three</b></p>");
+        let names: Vec<(&str, usize)> = descendant_elements(*html.root_element())
+            .into_iter()
+            .map(|(id, after)| {
+                let node = html.tree.get(id).unwrap();
+                (node.value().as_element().unwrap().name(), after)
+            })
+            .collect();
+
+        assert_eq!(
+            vec![("nav", 3), ("a", 2), ("a", 3), ("p", 5), ("b", 5)],
+            names
+        );
+        assert_eq!(
+            6,
+            descendant_elements(html.tree.root()).len(),
+            "includes the root element"
+        );
+    }
+
+    #[test]
+    fn test_insert() {
+        let source = Html::parse_fragment("<i>New <u>nodes</u></i> and text");
+
+        for (position, expected) in [
+            (
+                Position::BeforeBegin,
+                "<p>One <i>New <u>nodes</u></i> and text<b>two</b></p>",
+            ),
+            (
+                Position::AfterBegin,
+                "<p>One <b><i>New <u>nodes</u></i> and texttwo</b></p>",
+            ),
+            (
+                Position::BeforeEnd,
+                "<p>One <b>two<i>New <u>nodes</u></i> and text</b></p>",
+            ),
+            (
+                Position::AfterEnd,
+                "<p>One <b>two</b><i>New <u>nodes</u></i> and text</p>",
+            ),
+        ] {
+            let mut html = Html::parse_fragment("<p>One <b>two</b></p>");
+            let target = html
+                .select(&Selector::parse("b").unwrap())
+                .next()
+                .unwrap()
+                .id();
+
+            insert(
+                &mut html,
+                target,
+                position,
+                source.root_element().children(),
+            );
+
+            assert_eq!(
+                expected,
+                html.root_element().inner_html(),
+                "{position:?} inserts in order"
+            );
+        }
+    }
+}
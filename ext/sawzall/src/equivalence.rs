@@ -0,0 +1,163 @@
+use ego_tree::NodeRef;
+use scraper::Node;
+
+/// What [`equivalent`] disregards when comparing two trees
+///
+/// Attributes are always compared regardless of their order since elements
+/// keep them sorted by name.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Options {
+    /// Compares text with runs of whitespace collapsed and leading and
+    /// trailing whitespace removed, so that text made only of whitespace
+    /// doesn't count
+    pub(crate) ignore_whitespace: bool,
+    /// Leaves out comments, joining the text on either side of them
+    pub(crate) ignore_comments: bool,
+}
+
+/// A child that takes part in the comparison, with adjacent text joined
+enum Child<'a> {
+    Node(NodeRef<'a, Node>),
+    Text(String),
+}
+
+/// Checks whether the children of two nodes, and their descendants, are the
+/// same once what the options disregard is left out
+pub(crate) fn equivalent(a: NodeRef<Node>, b: NodeRef<Node>, options: Options) -> bool {
+    let mut pending = vec![(a, b)];
+
+    while let Some((a, b)) = pending.pop() {
+        let a_children = children(a, options);
+        let b_children = children(b, options);
+
+        if a_children.len() != b_children.len() {
+            return false;
+        }
+
+        for (a, b) in a_children.into_iter().zip(b_children) {
+            match (a, b) {
+                (Child::Text(a), Child::Text(b)) if a == b => {}
+                (Child::Node(a), Child::Node(b)) if same_value(a.value(), b.value(), options) => {
+                    pending.push((a, b));
+                }
+                _ => return false,
+            }
+        }
+    }
+
+    true
+}
+
+fn children(node: NodeRef<Node>, options: Options) -> Vec<Child> {
+    let mut children = Vec::new();
+
+    for child in node.children() {
+        match child.value() {
+            Node::Text(text) => match children.last_mut() {
+                Some(Child::Text(previous)) => previous.push_str(text),
+                _ => children.push(Child::Text(text.to_string())),
+            },
+            Node::Comment(_) if options.ignore_comments => {}
+            _ => children.push(Child::Node(child)),
+        }
+    }
+
+    if options.ignore_whitespace {
+        children.retain_mut(|child| match child {
+            Child::Text(text) => {
+                *text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                !text.is_empty()
+            }
+            Child::Node(_) => true,
+        });
+    }
+
+    children
+}
+
+fn same_value(a: &Node, b: &Node, options: Options) -> bool {
+    match (a, b) {
+        (Node::Element(a), Node::Element(b)) => a.name == b.name && a.attrs == b.attrs,
+        (Node::Comment(a), Node::Comment(b)) if options.ignore_whitespace => {
+            a.split_whitespace().eq(b.split_whitespace())
+        }
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Html;
+
+    const ALL: Options = Options {
+        ignore_whitespace: true,
+        ignore_comments: true,
+    };
+    const NONE: Options = Options {
+        ignore_whitespace: false,
+        ignore_comments: false,
+    };
+
+    fn equivalent_fragments(a: &str, b: &str, options: Options) -> bool {
+        let a = Html::parse_fragment(a);
+        let b = Html::parse_fragment(b);
+
+        equivalent(a.tree.root(), b.tree.root(), options)
+    }
+
+    #[test]
+    fn test_equivalent() {
+        let html = r#"<ul class="a" id="b"><li>One  two</li></ul>"#;
+
+        assert!(equivalent_fragments(html, html, NONE));
+        assert!(equivalent_fragments(
+            html,
+            "<ul id=b class=a><li>One  two</li></ul>",
+            NONE
+        ));
+        assert!(equivalent_fragments(
+            html,
+            "\n<ul id=b class=a>\n  <li>\n    One two <!-- x -->\n  </li>\n</ul>\n",
+            ALL
+        ));
+        assert!(equivalent_fragments(
+            "<p>One <!-- x -->two</p>",
+            "<p>One two</p>",
+            Options {
+                ignore_comments: true,
+                ..NONE
+            }
+        ));
+
+        for (other, option) in [
+            (
+                "<ul class=a id=b>\n  <li>One two</li>\n</ul>",
+                Options {
+                    ignore_whitespace: true,
+                    ..NONE
+                },
+            ),
+            (
+                "<ul class=a id=b><li>One  two</li><!-- x --></ul>",
+                Options {
+                    ignore_comments: true,
+                    ..NONE
+                },
+            ),
+        ] {
+            assert!(equivalent_fragments(html, other, option), "{other}");
+            assert!(!equivalent_fragments(html, other, NONE), "{other}");
+        }
+
+        for other in [
+            "<ul class=a id=b><li>One two three</li></ul>",
+            "<ul class=a id=c><li>One two</li></ul>",
+            "<ul class=a id=b hidden><li>One two</li></ul>",
+            "<ol class=a id=b><li>One two</li></ol>",
+            "<ul class=a id=b><li>One two</li><li></li></ul>",
+        ] {
+            assert!(!equivalent_fragments(html, other, ALL), "{other}");
+        }
+    }
+}
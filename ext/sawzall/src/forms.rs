@@ -0,0 +1,403 @@
+use crate::dom;
+use crate::images::parse_dimension;
+use ego_tree::NodeId;
+use lazy_static::lazy_static;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref FORM_SELECTOR: Selector = Selector::parse("form").unwrap();
+    static ref FIELD_SELECTOR: Selector =
+        Selector::parse("button, input, select, textarea").unwrap();
+}
+
+/// Every input type, starting with the default one
+const INPUT_TYPES: [&str; 22] = [
+    "text",
+    "hidden",
+    "search",
+    "tel",
+    "url",
+    "email",
+    "password",
+    "date",
+    "month",
+    "week",
+    "time",
+    "datetime-local",
+    "number",
+    "range",
+    "color",
+    "checkbox",
+    "radio",
+    "file",
+    "submit",
+    "image",
+    "reset",
+    "button",
+];
+
+/// A `<form>` element
+#[derive(Debug, PartialEq)]
+pub(crate) struct Form {
+    pub(crate) action: Option<String>,
+    /// `get`, `post` or `dialog`
+    pub(crate) method: String,
+    pub(crate) enctype: String,
+    pub(crate) fields: Vec<Field>,
+}
+
+/// A `<button>`, `<input>`, `<select>` or `<textarea>` belonging to a form
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Field {
+    /// The input's type, or the same value as the DOM's `type` property for
+    /// other elements (e.g. `select-one` or `textarea`)
+    pub(crate) field_type: String,
+    pub(crate) name: Option<String>,
+    /// The default value, which is `None` for selects as their value comes
+    /// from their options
+    pub(crate) value: Option<String>,
+    pub(crate) checked: bool,
+    pub(crate) disabled: bool,
+    pub(crate) options: Vec<FormOption>,
+}
+
+/// An `<option>` of a `<select>`
+#[derive(Debug, PartialEq)]
+pub(crate) struct FormOption {
+    pub(crate) value: String,
+    pub(crate) text: String,
+    pub(crate) selected: bool,
+    pub(crate) disabled: bool,
+}
+
+/// Returns every form of the document along with its fields, in order
+///
+/// Fields belong to the form referenced by their `form` attribute if they
+/// have one, and to the form they are in otherwise ([HTML spec][1]).
+///
+/// [1]: https://html.spec.whatwg.org/multipage/form-control-infrastructure.html#association-of-controls-and-forms
+pub(crate) fn forms(html: &Html) -> Vec<Form> {
+    let mut indices = HashMap::new();
+    let mut forms = Vec::new();
+
+    for form in dom::select(html, &FORM_SELECTOR) {
+        indices.insert(form.id(), forms.len());
+        forms.push(Form {
+            action: form.attr("action").map(ToString::to_string),
+            method: keyword(form.attr("method"), &["get", "post", "dialog"]),
+            enctype: keyword(
+                form.attr("enctype"),
+                &[
+                    "application/x-www-form-urlencoded",
+                    "multipart/form-data",
+                    "text/plain",
+                ],
+            ),
+            fields: Vec::new(),
+        });
+    }
+
+    for field in dom::select(html, &FIELD_SELECTOR) {
+        let Some(index) = form_owner(html, field).and_then(|id| indices.get(&id)) else {
+            continue;
+        };
+
+        forms[*index].fields.push(Field::new(field));
+    }
+
+    forms
+}
+
+impl Field {
+    fn new(element: ElementRef) -> Self {
+        let mut field = Field {
+            name: element.attr("name").map(ToString::to_string),
+            disabled: is_disabled(element),
+            ..Default::default()
+        };
+
+        match element.value().name() {
+            "input" => {
+                let input_type = keyword(element.attr("type"), &INPUT_TYPES);
+                let default_value = match input_type.as_str() {
+                    "checkbox" | "radio" => "on",
+                    _ => "",
+                };
+
+                field.value = Some(element.attr("value").unwrap_or(default_value).to_string());
+                field.checked = element.attr("checked").is_some();
+                field.field_type = input_type;
+            }
+            "button" => {
+                field.field_type = keyword(element.attr("type"), &["submit", "reset", "button"]);
+                field.value = Some(element.attr("value").unwrap_or_default().to_string());
+            }
+            "select" => {
+                let multiple = element.attr("multiple").is_some();
+                field.field_type = if multiple {
+                    "select-multiple"
+                } else {
+                    "select-one"
+                }
+                .to_string();
+                field.options = select_options(element, multiple);
+            }
+            _ => {
+                field.field_type = "textarea".to_string();
+                field.value = Some(element.text().collect());
+            }
+        }
+
+        field
+    }
+}
+
+/// Returns the id of the form a field belongs to
+fn form_owner(html: &Html, field: ElementRef) -> Option<NodeId> {
+    match field.attr("form") {
+        Some(form_id) => html
+            .tree
+            .root()
+            .descendants()
+            .filter_map(ElementRef::wrap)
+            .find(|element| element.value().id() == Some(form_id))
+            .filter(|element| element.value().name() == "form")
+            .map(|form| form.id()),
+        None => field
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .find(|ancestor| ancestor.value().name() == "form")
+            .map(|form| form.id()),
+    }
+}
+
+/// Checks whether a field is disabled, either directly or by a disabled
+/// `<fieldset>` (except for fields in its first `<legend>`)
+fn is_disabled(field: ElementRef) -> bool {
+    if field.attr("disabled").is_some() {
+        return true;
+    }
+
+    field
+        .ancestors()
+        .filter_map(ElementRef::wrap)
+        .filter(|ancestor| {
+            ancestor.value().name() == "fieldset" && ancestor.attr("disabled").is_some()
+        })
+        .any(|fieldset| {
+            let legend = fieldset
+                .children()
+                .filter_map(ElementRef::wrap)
+                .find(|child| child.value().name() == "legend");
+
+            !legend.is_some_and(|legend| field.ancestors().any(|ancestor| ancestor == *legend))
+        })
+}
+
+/// Returns the options of a `<select>` with their [selectedness][1] as shown
+/// to users: selects that only allow one option pick the last one marked as
+/// selected, or the first one that isn't disabled.
+///
+/// [1]: https://html.spec.whatwg.org/multipage/form-elements.html#selectedness-setting-algorithm
+fn select_options(select: ElementRef, multiple: bool) -> Vec<FormOption> {
+    let mut options: Vec<FormOption> = select
+        .children()
+        .filter_map(ElementRef::wrap)
+        .flat_map(|child| match child.value().name() {
+            "optgroup" => {
+                let group_disabled = child.attr("disabled").is_some();
+
+                child
+                    .children()
+                    .filter_map(ElementRef::wrap)
+                    .map(|option| (option, group_disabled))
+                    .collect()
+            }
+            _ => vec![(child, false)],
+        })
+        .filter(|(option, _)| option.value().name() == "option")
+        .map(|(option, group_disabled)| {
+            let text = option
+                .text()
+                .collect::<String>()
+                .split_ascii_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            FormOption {
+                value: option
+                    .attr("value")
+                    .map_or_else(|| text.clone(), ToString::to_string),
+                text,
+                selected: option.attr("selected").is_some(),
+                disabled: group_disabled || option.attr("disabled").is_some(),
+            }
+        })
+        .collect();
+
+    // A `size` above 1 shows a list box, which can have nothing selected
+    let size = select.attr("size").and_then(parse_dimension).unwrap_or(1);
+
+    if !multiple {
+        match options.iter().rposition(|option| option.selected) {
+            Some(last) => {
+                for option in &mut options[..last] {
+                    option.selected = false;
+                }
+            }
+            None if size <= 1 => {
+                if let Some(option) = options.iter_mut().find(|option| !option.disabled) {
+                    option.selected = true;
+                }
+            }
+            None => {}
+        }
+    }
+
+    options
+}
+
+/// Lowercases an [enumerated attribute][1], falling back to the first
+/// allowed value if it is missing or invalid
+///
+/// [1]: https://html.spec.whatwg.org/multipage/common-microsyntaxes.html#enumerated-attribute
+fn keyword(value: Option<&str>, allowed: &[&str]) -> String {
+    let value = value.unwrap_or_default().to_ascii_lowercase();
+
+    if allowed.contains(&value.as_str()) {
+        value
+    } else {
+        allowed[0].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(field_type: &str, name: &str, value: Option<&str>) -> Field {
+        Field {
+            field_type: field_type.to_string(),
+            name: Some(name.to_string()),
+            value: value.map(ToString::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn option(value: &str, text: &str, selected: bool) -> FormOption {
+        FormOption {
+            value: value.to_string(),
+            text: text.to_string(),
+            selected,
+            disabled: false,
+        }
+    }
+
+    #[test]
+    fn test_forms() {
+        let html = Html::parse_document(
+            r#"<form action="/search">
+                 <input name="q" value="rust">
+                 <input type="CHECKBOX" name="safe" checked>
+                 <input type="bogus" name="page">
+                 <textarea name="notes">Some notes</textarea>
+                 <button>Search</button>
+               </form>
+               <form id="login" method="POST" enctype="multipart/form-data">
+                 <fieldset disabled>
+                   <legend><input name="legend"></legend>
+                   <input name="user">
+                 </fieldset>
+               </form>
+               <input name="password" type="password" form="login">
+               <input name="orphan">"#,
+        );
+
+        assert_eq!(
+            vec![
+                Form {
+                    action: Some("/search".to_string()),
+                    method: "get".to_string(),
+                    enctype: "application/x-www-form-urlencoded".to_string(),
+                    fields: vec![
+                        field("text", "q", Some("rust")),
+                        Field {
+                            checked: true,
+                            ..field("checkbox", "safe", Some("on"))
+                        },
+                        field("text", "page", Some("")),
+                        field("textarea", "notes", Some("Some notes")),
+                        Field {
+                            name: None,
+                            ..field("submit", "", Some(""))
+                        },
+                    ],
+                },
+                Form {
+                    action: None,
+                    method: "post".to_string(),
+                    enctype: "multipart/form-data".to_string(),
+                    fields: vec![
+                        field("text", "legend", Some("")),
+                        Field {
+                            disabled: true,
+                            ..field("text", "user", Some(""))
+                        },
+                        field("password", "password", Some("")),
+                    ],
+                },
+            ],
+            forms(&html)
+        );
+    }
+
+    #[test]
+    fn test_select_options() {
+        let html = Html::parse_fragment(
+            r#"<form>
+                 <select name="one">
+                   <option disabled>Pick one</option>
+                   <optgroup label="Fruits">
+                     <option value="a">  Apple
+                       pie </option>
+                   </optgroup>
+                   <option>Banana</option>
+                 </select>
+                 <select name="many" multiple>
+                   <option selected>A</option>
+                   <option selected>B</option>
+                 </select>
+                 <select name="last">
+                   <option selected>A</option>
+                   <option selected>B</option>
+                 </select>
+                 <select name="list" size="2"><option>A</option></select>
+               </form>"#,
+        );
+
+        let options: Vec<Vec<FormOption>> = forms(&html)
+            .remove(0)
+            .fields
+            .into_iter()
+            .map(|field| field.options)
+            .collect();
+
+        assert_eq!(
+            vec![
+                vec![
+                    FormOption {
+                        disabled: true,
+                        ..option("Pick one", "Pick one", false)
+                    },
+                    option("a", "Apple pie", true),
+                    option("Banana", "Banana", false),
+                ],
+                vec![option("A", "A", true), option("B", "B", true)],
+                vec![option("A", "A", false), option("B", "B", true)],
+                vec![option("A", "A", false)],
+            ],
+            options
+        );
+    }
+}
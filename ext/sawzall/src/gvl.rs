@@ -0,0 +1,110 @@
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::thread;
+
+/// Inputs at least this many bytes long are parsed without holding the GVL.
+/// Below that, releasing and reacquiring it costs more than it frees up.
+const RELEASE_LEN: usize = 65_536;
+
+/// Runs `f`, which processes `len` bytes of input, without holding the GVL
+/// if the input is large enough for it to take a while
+pub(crate) fn without_gvl_for<F, T>(len: usize, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    if len >= RELEASE_LEN {
+        without_gvl(f)
+    } else {
+        f()
+    }
+}
+
+/// Runs `f` without holding the GVL, so that other Ruby threads keep running
+/// in the meantime
+///
+/// `f` mustn't use any Ruby objects or call into Ruby. It can't be
+/// interrupted either, so signals and `Thread#raise` are only handled once it
+/// returns.
+pub(crate) fn without_gvl<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    struct Call<F, T> {
+        f: Option<F>,
+        result: Option<thread::Result<T>>,
+    }
+
+    unsafe extern "C" fn run<F, T>(data: *mut c_void) -> *mut c_void
+    where
+        F: FnOnce() -> T,
+    {
+        let call = &mut *data.cast::<Call<F, T>>();
+        let f = call.f.take().expect("called more than once");
+
+        // Unwinding out of an `extern "C"` function aborts
+        call.result = Some(panic::catch_unwind(AssertUnwindSafe(f)));
+
+        ptr::null_mut()
+    }
+
+    let mut call = Call {
+        f: Some(f),
+        result: None,
+    };
+
+    // SAFETY: `call` outlives the call, which runs `run::<F, T>` on this
+    // thread before returning
+    unsafe {
+        rb_sys::rb_thread_call_without_gvl(
+            Some(run::<F, T>),
+            ptr::addr_of_mut!(call).cast(),
+            None,
+            ptr::null_mut(),
+        );
+    }
+
+    match call.result.expect("not called") {
+        Ok(result) => result,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+/// Locks `mutex`, waiting for it without holding the GVL if another thread
+/// has it
+///
+/// That thread may have released the GVL while holding the lock, e.g. to
+/// run a selection, and be waiting to reacquire it. Waiting for the lock
+/// while holding the GVL would deadlock.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    let guard = match mutex.try_lock() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::WouldBlock) => without_gvl(|| mutex.lock()),
+        Err(TryLockError::Poisoned(error)) => Err(error),
+    };
+
+    guard.expect("failed to lock mutex")
+}
+
+/// Locks `lock` for reading like [`lock`] locks a mutex
+pub(crate) fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    let guard = match lock.try_read() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::WouldBlock) => without_gvl(|| lock.read()),
+        Err(TryLockError::Poisoned(error)) => Err(error),
+    };
+
+    guard.expect("failed to lock rwlock")
+}
+
+/// Locks `lock` for writing like [`lock`] locks a mutex
+pub(crate) fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    let guard = match lock.try_write() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::WouldBlock) => without_gvl(|| lock.write()),
+        Err(TryLockError::Poisoned(error)) => Err(error),
+    };
+
+    guard.expect("failed to lock rwlock")
+}
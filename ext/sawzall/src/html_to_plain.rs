@@ -1,4 +1,5 @@
 use ego_tree::iter::Edge;
+use ego_tree::NodeId;
 use lazy_static::lazy_static;
 use scraper::{ElementRef, Node};
 use std::collections::HashSet;
@@ -42,20 +43,83 @@ const BLOCK_LEVEL_ELEMENTS: [&str; 33] = [
     "ul",
 ];
 
+/// Elements whose text is rendered with `white-space: pre` (or `pre-wrap`) by
+/// the [default stylesheet][1]
+///
+/// [1]: https://html.spec.whatwg.org/multipage/rendering.html#the-pre-element
+const PREFORMATTED_ELEMENTS: [&str; 5] = ["listing", "plaintext", "pre", "textarea", "xmp"];
+
 lazy_static! {
     static ref BLOCK_LEVEL_ELEMENTS_SET: HashSet<&'static str> =
         BLOCK_LEVEL_ELEMENTS.iter().copied().collect();
 }
 
-fn is_block_element(name: &str) -> bool {
+pub(crate) fn is_block_element(name: &str) -> bool {
     BLOCK_LEVEL_ELEMENTS_SET.contains(&name)
 }
 
+pub(crate) fn is_preformatted_element(name: &str) -> bool {
+    PREFORMATTED_ELEMENTS.contains(&name)
+}
+
 enum Item<'a> {
     Text(&'a str),
+    PreformattedText(&'a str),
     Newlines(usize),
 }
 
+/// Accumulates output while deferring whitespace until we know whether it
+/// separates two pieces of text
+#[derive(Default)]
+struct Writer {
+    output: String,
+    pending_newlines: usize,
+    pending_space: bool,
+}
+
+impl Writer {
+    fn push_str(&mut self, text: &str) {
+        // Don't insert whitespace if we're at the beginning
+        if !self.output.is_empty() {
+            if self.pending_newlines > 0 {
+                self.output.push_str(&"\n".repeat(self.pending_newlines));
+            } else if self.pending_space {
+                self.output.push(' ');
+            }
+        }
+
+        self.pending_newlines = 0;
+        self.pending_space = false;
+        self.output.push_str(text);
+    }
+
+    /// Collapses runs of whitespace into a single space as described in
+    /// [CSS Text][1]
+    ///
+    /// [1]: https://www.w3.org/TR/css-text-3/#white-space-phase-1
+    fn push_collapsible_str(&mut self, text: &str) {
+        for (index, segment) in text.split(|c: char| c.is_ascii_whitespace()).enumerate() {
+            if index > 0 {
+                self.pending_space = true;
+            }
+
+            if !segment.is_empty() {
+                self.push_str(segment);
+            }
+        }
+    }
+
+    fn push_newlines(&mut self, count: usize) {
+        // Combine all subsequent newlines into one, using the maximum value
+        self.pending_newlines = self.pending_newlines.max(count);
+    }
+
+    /// Returns the output, dropping any whitespace pending at the end
+    fn finish(self) -> String {
+        self.output
+    }
+}
+
 /// Converts HTML to plain text using a subset of the [`HTMLElement.innerText`][1]
 /// algorithm ([WHATWG spec][2], [Chromium source][3]).
 ///
@@ -68,55 +132,148 @@ enum Item<'a> {
 /// [2]: https://html.spec.whatwg.org/multipage/dom.html#the-innertext-idl-attribute
 /// [3]: https://source.chromium.org/chromium/chromium/src/+/main:third_party/blink/renderer/core/editing/element_inner_text.cc;l=262;drc=eca6a1b4c221dc66cf40d0d1ee8eff3f3028ce26?q=innerText&ss=chromium
 pub(crate) fn html_to_plain(element: ElementRef) -> String {
-    let mut item_iter = element
-        .traverse()
-        .filter_map(|edge| match edge {
-            Edge::Open(node) => match node.value() {
-                Node::Text(text) if !text.trim().is_empty() => Some(Item::Text(text)),
-                Node::Element(element) => match element.name() {
+    let mut preformatted_depth = 0;
+
+    let item_iter = element.traverse().filter_map(|edge| match edge {
+        Edge::Open(node) => match node.value() {
+            Node::Text(text) if preformatted_depth > 0 => Some(Item::PreformattedText(text)),
+            Node::Text(text) => Some(Item::Text(text)),
+            Node::Element(element) => {
+                if is_preformatted_element(element.name()) {
+                    preformatted_depth += 1;
+                }
+
+                match element.name() {
                     "br" => Some(Item::Newlines(1)),
                     "p" => Some(Item::Newlines(2)),
                     name if is_block_element(name) => Some(Item::Newlines(1)),
                     _ => None,
-                },
-                _ => None,
-            },
-            Edge::Close(node) => match node.value() {
-                Node::Element(element) => match element.name() {
+                }
+            }
+            _ => None,
+        },
+        Edge::Close(node) => match node.value() {
+            Node::Element(element) => {
+                if is_preformatted_element(element.name()) {
+                    preformatted_depth -= 1;
+                }
+
+                match element.name() {
                     "p" => Some(Item::Newlines(2)),
                     name if is_block_element(name) => Some(Item::Newlines(1)),
                     _ => None,
-                },
-                _ => None,
-            },
-        })
-        .peekable();
+                }
+            }
+            _ => None,
+        },
+    });
 
-    let mut output = String::new();
+    let mut writer = Writer::default();
 
-    while let Some(item) = item_iter.next() {
+    for item in item_iter {
         match item {
-            Item::Text(text) => {
-                output.push_str(text);
-            }
-            Item::Newlines(count) => {
-                let mut max = count;
+            Item::Text(text) => writer.push_collapsible_str(text),
+            Item::PreformattedText(text) => writer.push_str(text),
+            Item::Newlines(count) => writer.push_newlines(count),
+        }
+    }
+
+    writer.finish()
+}
 
-                // Combine all subsequent newlines into one, using the maximum value
-                while let Some(Item::Newlines(next_count)) = item_iter.peek() {
-                    max = max.max(*next_count);
-                    item_iter.next();
+/// A block of text along with what it's made of
+#[derive(Debug, PartialEq)]
+pub(crate) struct Paragraph {
+    pub(crate) text: String,
+    /// The innermost block element containing the text, or the element the
+    /// paragraphs were extracted from
+    pub(crate) element: NodeId,
+    pub(crate) word_count: usize,
+    /// The share of the text's non-whitespace characters that are in links
+    pub(crate) link_density: f64,
+}
+
+/// Splits the text of an element into paragraphs at the boundaries of block
+/// elements, using the same rules as [`html_to_plain`] within each one
+pub(crate) fn paragraphs(element: ElementRef) -> Vec<Paragraph> {
+    let mut paragraphs = Vec::new();
+    let mut containers = vec![element.id()];
+    let mut writer = Writer::default();
+    let mut link_chars = 0;
+    let mut preformatted_depth = 0;
+    let mut link_depth = 0;
+
+    let mut flush = |writer: &mut Writer, link_chars: &mut usize, container: NodeId| {
+        let text = std::mem::take(writer).finish();
+        let chars = text.chars().filter(|c| !c.is_whitespace()).count();
+
+        if chars > 0 {
+            paragraphs.push(Paragraph {
+                word_count: text.split_whitespace().count(),
+                link_density: *link_chars as f64 / chars as f64,
+                text,
+                element: container,
+            });
+        }
+
+        *link_chars = 0;
+    };
+
+    for edge in element.traverse() {
+        match edge {
+            Edge::Open(node) => match node.value() {
+                Node::Text(text) => {
+                    if link_depth > 0 {
+                        link_chars += text.chars().filter(|c| !c.is_whitespace()).count();
+                    }
+
+                    if preformatted_depth > 0 {
+                        writer.push_str(text);
+                    } else {
+                        writer.push_collapsible_str(text);
+                    }
                 }
+                Node::Element(child) => {
+                    if is_preformatted_element(child.name()) {
+                        preformatted_depth += 1;
+                    }
 
-                // Don't insert newlines if we're at the beginning or the end
-                if !(output.is_empty() || item_iter.peek().is_none()) {
-                    output.push_str(&"\n".repeat(max));
+                    match child.name() {
+                        "a" => link_depth += 1,
+                        "br" => writer.push_newlines(1),
+                        name if is_block_element(name) && node.id() != element.id() => {
+                            flush(&mut writer, &mut link_chars, *containers.last().unwrap());
+                            containers.push(node.id());
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            },
+            Edge::Close(node) => {
+                let Node::Element(child) = node.value() else {
+                    continue;
+                };
+
+                if is_preformatted_element(child.name()) {
+                    preformatted_depth -= 1;
+                }
+
+                match child.name() {
+                    "a" => link_depth -= 1,
+                    name if is_block_element(name) && node.id() != element.id() => {
+                        flush(&mut writer, &mut link_chars, *containers.last().unwrap());
+                        containers.pop();
+                    }
+                    _ => {}
                 }
             }
         }
     }
 
-    output
+    flush(&mut writer, &mut link_chars, element.id());
+
+    paragraphs
 }
 
 #[cfg(test)]
@@ -195,5 +352,76 @@ mod tests {
             html_to_plain("<h1>Hello, world</h1>\n<p>This is an HTML fragment</p>"),
             "empty lines are ignored"
         );
+
+        assert_eq!(
+            "a paragraph wrapped over several lines",
+            html_to_plain("<p>\n  a paragraph wrapped\n  over   several\tlines\n</p>"),
+            "whitespace within text is collapsed"
+        );
+
+        assert_eq!(
+            "one two",
+            html_to_plain("<b>one</b>\n<i>two</i>"),
+            "whitespace between inline elements becomes a single space"
+        );
+
+        assert_eq!(
+            "trailing\nleading",
+            html_to_plain("trailing <br> leading"),
+            "whitespace around line breaks is removed"
+        );
+
+        assert_eq!(
+            "  keep\n    this\n\nnot this",
+            html_to_plain("<pre>  keep\n    this</pre><p>not  this</p>"),
+            "whitespace in preformatted elements is preserved"
+        );
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let doc = scraper::Html::parse_fragment(
+            "<article>Go back <a href='/'>home</a><h1>Title</h1><p>One\n two<br>three</p><div><p><a href='/a'>Link</a></p>  </div><pre> a  b </pre></article>",
+        );
+        let article = doc
+            .select(&scraper::Selector::parse("article").unwrap())
+            .next()
+            .unwrap();
+        let paragraphs = super::paragraphs(article);
+        let names: Vec<&str> = paragraphs
+            .iter()
+            .map(|paragraph| {
+                doc.tree
+                    .get(paragraph.element)
+                    .unwrap()
+                    .value()
+                    .as_element()
+                    .unwrap()
+                    .name()
+            })
+            .collect();
+
+        assert_eq!(
+            vec!["Go back home", "Title", "One two\nthree", "Link", " a  b "],
+            paragraphs
+                .iter()
+                .map(|paragraph| paragraph.text.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["article", "h1", "p", "p", "pre"], names);
+        assert_eq!(
+            vec![3, 1, 3, 1, 2],
+            paragraphs
+                .iter()
+                .map(|paragraph| paragraph.word_count)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![0.4, 0.0, 0.0, 1.0, 0.0],
+            paragraphs
+                .iter()
+                .map(|paragraph| paragraph.link_density)
+                .collect::<Vec<_>>()
+        );
     }
 }
@@ -0,0 +1,277 @@
+use crate::dom;
+use crate::srcset::{self, Candidate};
+use lazy_static::lazy_static;
+use scraper::{ElementRef, Html, Selector};
+
+lazy_static! {
+    static ref IMG_SELECTOR: Selector = Selector::parse("img").unwrap();
+}
+
+/// An `<img>` element
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Image {
+    pub(crate) src: Option<String>,
+    pub(crate) alt: Option<String>,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) srcset: Vec<ImageCandidate>,
+    pub(crate) sizes: Option<String>,
+    /// The `<source>` elements preceding the image in its `<picture>`
+    pub(crate) sources: Vec<Source>,
+}
+
+/// A `<source>` element of a `<picture>`
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Source {
+    pub(crate) srcset: Vec<ImageCandidate>,
+    pub(crate) sizes: Option<String>,
+    pub(crate) media: Option<String>,
+    pub(crate) mime_type: Option<String>,
+}
+
+/// A `srcset` candidate with its descriptors parsed
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ImageCandidate {
+    pub(crate) url: String,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) density: Option<f64>,
+}
+
+impl ImageCandidate {
+    /// Parses a candidate's descriptors, returning `None` if they are invalid
+    /// as browsers then ignore the candidate
+    fn parse(candidate: Candidate) -> Option<Self> {
+        let mut image_candidate = ImageCandidate {
+            url: candidate.url.to_string(),
+            ..Default::default()
+        };
+
+        for descriptor in candidate.descriptors {
+            let kind_start = descriptor.char_indices().last().map_or(0, |(i, _)| i);
+            let (value, kind) = descriptor.split_at(kind_start);
+
+            match kind {
+                "w" if image_candidate.width.is_none() && image_candidate.density.is_none() => {
+                    image_candidate.width = Some(parse_integer(value).filter(|width| *width > 0)?);
+                }
+                "h" if image_candidate.height.is_none() && image_candidate.density.is_none() => {
+                    image_candidate.height =
+                        Some(parse_integer(value).filter(|height| *height > 0)?);
+                }
+                "x" if image_candidate.density.is_none()
+                    && image_candidate.width.is_none()
+                    && image_candidate.height.is_none() =>
+                {
+                    image_candidate.density = Some(parse_density(value)?);
+                }
+                _ => return None,
+            }
+        }
+
+        // A height is only allowed along with a width
+        if image_candidate.height.is_some() && image_candidate.width.is_none() {
+            return None;
+        }
+
+        Some(image_candidate)
+    }
+}
+
+/// Returns every `<img>` element of the document in order
+pub(crate) fn images(html: &Html) -> Vec<Image> {
+    dom::select(html, &IMG_SELECTOR)
+        .map(|img| Image {
+            src: img.attr("src").map(ToString::to_string),
+            alt: img.attr("alt").map(ToString::to_string),
+            width: img.attr("width").and_then(parse_dimension),
+            height: img.attr("height").and_then(parse_dimension),
+            srcset: parse_candidates(img.attr("srcset")),
+            sizes: img.attr("sizes").map(ToString::to_string),
+            sources: picture_sources(img),
+        })
+        .collect()
+}
+
+fn picture_sources(img: ElementRef) -> Vec<Source> {
+    let in_picture = img
+        .parent()
+        .and_then(ElementRef::wrap)
+        .is_some_and(|parent| parent.value().name() == "picture");
+
+    if !in_picture {
+        return Vec::new();
+    }
+
+    let mut sources: Vec<Source> = img
+        .prev_siblings()
+        .filter_map(ElementRef::wrap)
+        .filter(|sibling| sibling.value().name() == "source")
+        .map(|source| Source {
+            srcset: parse_candidates(source.attr("srcset")),
+            sizes: source.attr("sizes").map(ToString::to_string),
+            media: source.attr("media").map(ToString::to_string),
+            mime_type: source.attr("type").map(ToString::to_string),
+        })
+        .collect();
+
+    sources.reverse();
+    sources
+}
+
+fn parse_candidates(srcset: Option<&str>) -> Vec<ImageCandidate> {
+    srcset::parse_srcset(srcset.unwrap_or_default())
+        .into_iter()
+        .filter_map(ImageCandidate::parse)
+        .collect()
+}
+
+/// Parses a [valid non-negative integer][1]
+///
+/// [1]: https://html.spec.whatwg.org/multipage/common-microsyntaxes.html#valid-non-negative-integer
+fn parse_integer(value: &str) -> Option<u32> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    value.parse().ok()
+}
+
+/// Parses a [valid floating-point number][1] that isn't negative
+///
+/// [1]: https://html.spec.whatwg.org/multipage/common-microsyntaxes.html#valid-floating-point-number
+fn parse_density(value: &str) -> Option<f64> {
+    // Rust also accepts a leading `+` and words like `inf` or `NaN`
+    if !value.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|density| density.is_finite())
+}
+
+/// Parses `width` and `height` attributes following the lenient
+/// [rules for parsing non-negative integers][1], which ignore trailing
+/// garbage (e.g. `100px`)
+///
+/// [1]: https://html.spec.whatwg.org/multipage/common-microsyntaxes.html#rules-for-parsing-non-negative-integers
+pub(crate) fn parse_dimension(value: &str) -> Option<u32> {
+    let value = value.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let value = value.strip_prefix('+').unwrap_or(value);
+    let digits_end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+
+    parse_integer(&value[..digits_end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(url: &str) -> ImageCandidate {
+        ImageCandidate {
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_images() {
+        let html = Html::parse_fragment(
+            r#"<img src="a.png" alt="A" width="100px" height=" 50" srcset="a-2x.png 2x, a-400.png 400w 200h, bad.png 2x 100w">
+               <picture>
+                 <source srcset="b.avif" type="image/avif">
+                 <source srcset="b-wide.webp 1200w" sizes="100vw" media="(min-width: 800px)">
+                 <img src="b.png">
+               </picture>"#,
+        );
+
+        assert_eq!(
+            vec![
+                Image {
+                    src: Some("a.png".to_string()),
+                    alt: Some("A".to_string()),
+                    width: Some(100),
+                    height: Some(50),
+                    srcset: vec![
+                        ImageCandidate {
+                            density: Some(2.0),
+                            ..candidate("a-2x.png")
+                        },
+                        ImageCandidate {
+                            width: Some(400),
+                            height: Some(200),
+                            ..candidate("a-400.png")
+                        },
+                    ],
+                    ..Default::default()
+                },
+                Image {
+                    src: Some("b.png".to_string()),
+                    sources: vec![
+                        Source {
+                            srcset: vec![candidate("b.avif")],
+                            mime_type: Some("image/avif".to_string()),
+                            ..Default::default()
+                        },
+                        Source {
+                            srcset: vec![ImageCandidate {
+                                width: Some(1200),
+                                ..candidate("b-wide.webp")
+                            }],
+                            sizes: Some("100vw".to_string()),
+                            media: Some("(min-width: 800px)".to_string()),
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                },
+            ],
+            images(&html)
+        );
+    }
+
+    #[test]
+    fn test_image_candidate_parse() {
+        let parse = |url, descriptors: &[&'static str]| {
+            ImageCandidate::parse(Candidate {
+                url,
+                descriptors: descriptors.to_vec(),
+            })
+        };
+
+        assert_eq!(Some(candidate("a.png")), parse("a.png", &[]));
+        assert_eq!(
+            Some(ImageCandidate {
+                density: Some(1.5),
+                ..candidate("a.png")
+            }),
+            parse("a.png", &["1.5x"])
+        );
+        assert_eq!(None, parse("a.png", &["0w"]), "widths must be positive");
+        assert_eq!(None, parse("a.png", &["+2x"]));
+        assert_eq!(None, parse("a.png", &["infx"]));
+        assert_eq!(None, parse("a.png", &["100h"]), "heights need a width");
+        assert_eq!(
+            None,
+            parse("a.png", &["1x", "2x"]),
+            "duplicates are invalid"
+        );
+        assert_eq!(
+            None,
+            parse("a.png", &["big"]),
+            "unknown descriptors are invalid"
+        );
+    }
+
+    #[test]
+    fn test_parse_dimension() {
+        assert_eq!(Some(100), parse_dimension("100"));
+        assert_eq!(Some(100), parse_dimension(" +100.5px"));
+        assert_eq!(None, parse_dimension("-1"));
+        assert_eq!(None, parse_dimension("auto"));
+    }
+}
@@ -1,190 +1,4328 @@
+mod absolutize;
+mod arena;
+mod attr_regex;
+mod charset;
+mod chunk;
+mod classify;
+mod contains;
+mod content_model;
+mod csp;
+mod css_inliner;
+mod diff;
+mod dom;
+mod equivalence;
+mod forms;
+mod gvl;
 mod html_to_plain;
+mod images;
+mod limits;
+mod link_graph;
+mod memsize;
+mod mmap;
+mod namespaces;
+mod normalize_url;
+mod not_found;
+mod outline;
+mod parse_error;
+mod parse_limits;
+mod pool;
+mod rel;
+mod repair;
+mod robots;
+mod sanitizer;
+mod selector_index;
+mod serialize;
+mod shared_html;
+mod snapshot;
+mod srcset;
+mod stats;
+mod strip;
+mod table;
+mod text_metrics;
+mod text_search;
+mod tokens;
+mod utf8_stream;
+mod xml;
+mod xpath;
 
-use ego_tree::NodeId;
+use ego_tree::{iter::Edge, NodeId, NodeRef};
+use html5ever::{namespace_url, ns, tree_builder::QuirksMode, QualName};
 use magnus::{
-    function, method,
+    function, gc, kwargs, method,
     prelude::*,
-    scan_args::{get_kwargs, scan_args},
-    Error, RArray, RString, Ruby, Value,
+    r_hash::ForEach,
+    rb_sys::{AsRawValue, FromRawValue},
+    scan_args::{get_kwargs, scan_args, ScanArgsOpt},
+    typed_data::Obj,
+    value::{Lazy, Opaque},
+    DataTypeFunctions, Error, ExceptionClass, KwArgs, RArray, RClass, RHash, RRegexp, RString,
+    Ruby, Symbol, TypedData, Value,
 };
-use scraper::{CaseSensitivity, ElementRef, Html, Selector};
-use std::sync::{Arc, Mutex};
+use scraper::{CaseSensitivity, ElementRef, Html, Node, Selector};
+use selector_index::SimpleSelector;
+use shared_html::SharedHtml;
+use stats::CachedHtml;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex, RwLockWriteGuard, Weak};
+use std::thread::{self, JoinHandle};
+use url::Url;
+
+/// How many bytes [`parse_io`] reads at a time
+const IO_CHUNK_LEN: usize = 65_536;
+
+/// Documents with at least this many nodes are searched without holding the
+/// GVL, which is only reacquired to build the matching elements
+const SELECT_WITHOUT_GVL_NODES: usize = 10_000;
+
+/// Values of the `weak_elements:` and `strict_encoding:` options used when
+/// they aren't passed, set with `Sawzall.configure`
+static DEFAULT_WEAK_ELEMENTS: AtomicBool = AtomicBool::new(false);
+static DEFAULT_STRICT_ENCODING: AtomicBool = AtomicBool::new(false);
+
+static STALE_DOCUMENT_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| {
+    ruby.define_module("Sawzall")
+        .and_then(|module| {
+            module.define_error("StaleDocumentError", ruby.exception_standard_error())
+        })
+        .expect("failed to define Sawzall::StaleDocumentError")
+});
+
+static OUTPUT_TOO_LARGE_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| {
+    ruby.define_module("Sawzall")
+        .and_then(|module| {
+            module.define_error("OutputTooLargeError", ruby.exception_standard_error())
+        })
+        .expect("failed to define Sawzall::OutputTooLargeError")
+});
+
+static INVALID_ENCODING_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| {
+    ruby.define_module("Sawzall")
+        .and_then(|module| {
+            module.define_error("InvalidEncodingError", ruby.exception_encoding_error())
+        })
+        .expect("failed to define Sawzall::InvalidEncodingError")
+});
+
+static PARSE_LIMIT_ERROR: Lazy<ExceptionClass> = Lazy::new(|ruby| {
+    ruby.define_module("Sawzall")
+        .and_then(|module| module.define_error("ParseLimitError", ruby.exception_standard_error()))
+        .expect("failed to define Sawzall::ParseLimitError")
+});
+
+static NULL_ELEMENT: Lazy<RClass> = Lazy::new(|ruby| {
+    ruby.define_module("Sawzall")
+        .and_then(|module| module.const_get("NullElement"))
+        .expect("failed to get Sawzall::NullElement")
+});
+
+static NOT_FOUND: Lazy<ExceptionClass> = Lazy::new(|ruby| {
+    ruby.define_module("Sawzall")
+        .and_then(|module| module.const_get("NotFound"))
+        .expect("failed to get Sawzall::NotFound")
+});
 
 #[magnus::init]
 fn init(ruby: &Ruby) -> Result<(), Error> {
+    // Lets the parsing functions and the methods of documents and their
+    // nodes, defined below, be called from any Ractor. Documents guard their
+    // tree with a lock, and frozen ones can't be modified, so they can be
+    // shared between Ractors along with their nodes.
+    //
+    // SAFETY: called from the thread loading the extension
+    unsafe { rb_sys::rb_ext_ractor_safe(true) };
+
     let module = ruby.define_module("Sawzall")?;
-    module.define_singleton_method("parse_fragment", function!(parse_fragment, 1))?;
-    module.define_singleton_method("parse_document", function!(parse_document, 1))?;
+    module.define_singleton_method("parse_fragment", function!(parse_fragment, -1))?;
+    module.define_singleton_method("parse_document", function!(parse_document, -1))?;
+    module.define_singleton_method("parse_file", function!(parse_file, -1))?;
+    module.define_singleton_method("each_token", method!(each_token, 1))?;
+    module.define_singleton_method("parse_xml", function!(parse_xml, -1))?;
+    module.define_singleton_method("join", function!(join, -1))?;
+    module.define_singleton_method("void_element?", function!(is_void_element, 1))?;
+    module.define_singleton_method("raw_text_element?", function!(is_raw_text_element, 1))?;
+    module.define_singleton_method("valid_selector?", function!(is_valid_selector, 1))?;
+    module.define_singleton_method("diff", function!(diff_documents, 2))?;
+    Lazy::force(&STALE_DOCUMENT_ERROR, ruby);
+    Lazy::force(&OUTPUT_TOO_LARGE_ERROR, ruby);
+    Lazy::force(&INVALID_ENCODING_ERROR, ruby);
+    Lazy::force(&PARSE_LIMIT_ERROR, ruby);
 
     let document_class = module.define_class("Document", ruby.class_object())?;
-    document_class.define_method("select", method!(Document::select, 1))?;
+    document_class.define_method("select", method!(Document::select, -1))?;
     document_class.define_method("root_element", method!(Document::root_element, 0))?;
+    document_class.define_method("at", method!(Document::at, -1))?;
+    document_class.define_method("xpath", method!(Document::xpath, 1))?;
+    document_class.define_method("at!", method!(Document::at_bang, 1))?;
+    document_class.define_method("build_index!", method!(Document::build_index, 0))?;
+    document_class.define_method("element_for_id", method!(Document::element_for_id, -1))?;
+    document_class.define_method("element_by_id", method!(Document::element_by_id, -1))?;
+    document_class.define_method("find_by_text", method!(Document::find_by_text, -1))?;
+    document_class.define_method(
+        "content_security_policies",
+        method!(Document::content_security_policies, 0),
+    )?;
+    document_class.define_method("robots_directives", method!(Document::robots_directives, 0))?;
+    document_class.define_method("indexability", method!(Document::indexability, 1))?;
+    document_class.define_method("images", method!(Document::images, 0))?;
+    document_class.define_method("forms", method!(Document::forms, 0))?;
+    document_class.define_method("outline", method!(Document::outline, 0))?;
+    document_class.define_method("probable_type", method!(Document::probable_type, 0))?;
+    document_class.define_method("chunks", method!(Document::chunks, -1))?;
+    document_class.define_method("strip!", method!(Document::strip, -1))?;
+    document_class.define_method("remove!", method!(Document::remove, 1))?;
+    document_class.define_method("inline_css!", method!(Document::inline_css, 0))?;
+    document_class.define_method("create_element", method!(Document::create_element, -1))?;
+    document_class.define_method("create_text_node", method!(Document::create_text_node, 1))?;
+    document_class.define_method("doctype", method!(Document::doctype, 0))?;
+    document_class.define_method("encoding", method!(Document::encoding, 0))?;
+    document_class.define_method("quirks_mode", method!(Document::quirks_mode, 0))?;
+    document_class.define_method("quirks_mode?", method!(Document::is_quirks_mode, 0))?;
+    document_class.define_method("comments", method!(Document::comments, 0))?;
+    document_class.define_method("each_node", method!(Document::each_node, 0))?;
+    document_class.define_method("each_element", method!(Document::each_element, 0))?;
+    document_class.define_method("tag_counts", method!(Document::tag_counts, 0))?;
+    document_class.define_method("stats", method!(Document::stats, 0))?;
+    document_class.define_method("repairs", method!(Document::repairs, 0))?;
+    document_class.define_method("errors", method!(Document::errors, 0))?;
+    document_class.define_method("annotations", method!(Document::annotations, 0))?;
+    document_class.define_method("to_html", method!(Document::to_html, -1))?;
+    document_class.define_method("to_h", method!(Document::to_h, 0))?;
+    document_class.define_method("to_json", method!(Document::to_json, -1))?;
+    document_class.define_method("dup", method!(Document::deep_copy, 0))?;
+    document_class.define_method("clone", method!(Document::clone_document, -1))?;
+    document_class.define_method("freeze", method!(Document::freeze_document, 0))?;
+    document_class.define_method("_dump", method!(Document::dump, 1))?;
+    document_class.define_singleton_method("_load", function!(Document::load, 1))?;
+    document_class.define_method("+", method!(Document::concat, 1))?;
+    document_class.define_method("equivalent_to?", method!(Document::is_equivalent_to, -1))?;
+    document_class.define_method("base_uri", method!(Document::base_uri, -1))?;
+    document_class.define_method("absolutize_urls!", method!(Document::absolutize_urls, 1))?;
+    document_class.define_method("css", method!(Document::select, -1))?;
+    document_class.define_method("at_css", method!(Document::at, -1))?;
+    document_class.define_method("children", method!(Document::children, 0))?;
+    document_class.define_method("content", method!(Document::content, 0))?;
 
     let element_class = module.define_class("Element", ruby.class_object())?;
-    element_class.define_method("name", method!(Element::name, 0))?;
-    element_class.define_method("html", method!(Element::html, 0))?;
-    element_class.define_method("inner_html", method!(Element::inner_html, 0))?;
+    element_class.define_method("name", method!(Element::name, -1))?;
+    element_class.define_method("namespace", method!(Element::namespace, 0))?;
+    element_class.define_method("name_sym", method!(Element::name_sym, 0))?;
+    element_class.define_method("html", method!(Element::html, -1))?;
+    element_class.define_method("inner_html", method!(Element::inner_html, -1))?;
+    element_class.define_method("inner_html=", method!(Element::set_inner_html, 1))?;
     element_class.define_method("attr", method!(Element::attr, 1))?;
-    element_class.define_method("attrs", method!(Element::attrs, 0))?;
-    element_class.define_method("select", method!(Element::select, 1))?;
+    element_class.define_method("id", method!(Element::id, 0))?;
+    element_class.define_method("attr!", method!(Element::attr_bang, 1))?;
+    element_class.define_method("attr_ns", method!(Element::attr_ns, 2))?;
+    element_class.define_method("annotation", method!(Element::annotation, 0))?;
+    element_class.define_method("annotation=", method!(Element::set_annotation, 1))?;
+    element_class.define_method("attrs", method!(Element::attrs, -1))?;
+    element_class.define_method("attributes", method!(Element::attributes, -1))?;
+    element_class.define_method("data", method!(Element::data, -1))?;
+    element_class.define_method("each_attr", method!(Element::each_attr, 0))?;
+    element_class.define_method("attr_names", method!(Element::attr_names, -1))?;
+    element_class.define_method("set_attr", method!(Element::set_attr, 2))?;
+    element_class.define_method("remove_attr", method!(Element::remove_attr, 1))?;
+    element_class.define_method("add_class", method!(Element::add_class, -1))?;
+    element_class.define_method("remove_class", method!(Element::remove_class, -1))?;
+    element_class.define_method("toggle_class", method!(Element::toggle_class, -1))?;
+    element_class.define_method("remove", method!(Element::remove, 0))?;
+    element_class.define_method("replace_with", method!(Element::replace_with, 1))?;
+    element_class.define_method("add_child", method!(Element::add_child, 1))?;
+    element_class.define_method(
+        "add_previous_sibling",
+        method!(Element::add_previous_sibling, 1),
+    )?;
+    element_class.define_method("add_next_sibling", method!(Element::add_next_sibling, 1))?;
+    element_class.define_method("insert_child", method!(Element::insert_child, 2))?;
+    element_class.define_method("wrap", method!(Element::wrap, 1))?;
+    element_class.define_method("unwrap", method!(Element::unwrap, 0))?;
+    element_class.define_method("before_html", method!(Element::before_html, 1))?;
+    element_class.define_method("prepend_html", method!(Element::prepend_html, 1))?;
+    element_class.define_method("append_html", method!(Element::append_html, 1))?;
+    element_class.define_method("after_html", method!(Element::after_html, 1))?;
+    element_class.define_method("select", method!(Element::select, -1))?;
+    element_class.define_method("at", method!(Element::at, -1))?;
+    element_class.define_method("xpath", method!(Element::xpath, 1))?;
+    element_class.define_method("at!", method!(Element::at_bang, 1))?;
     element_class.define_method("child_elements", method!(Element::child_elements, 0))?;
-    element_class.define_method("text", method!(Element::text, 0))?;
+    element_class.define_method("child_nodes", method!(Element::child_nodes, 0))?;
+    element_class.define_method("each_node", method!(Element::each_node, 0))?;
+    element_class.define_method("freeze", method!(Element::freeze_node, 0))?;
+    element_class.define_method("each_element", method!(Element::each_element, 0))?;
+    element_class.define_method("traverse", method!(Element::traverse, 0))?;
+    element_class.define_method("text", method!(Element::text, -1))?;
+    element_class.define_method("own_text", method!(Element::own_text, 0))?;
+    element_class.define_method("paragraphs", method!(Element::paragraphs, 0))?;
+    element_class.define_method("text_length", method!(Element::text_length, 0))?;
+    element_class.define_method("link_density", method!(Element::link_density, 0))?;
     element_class.define_method("has_class?", method!(Element::has_class, -1))?;
-    element_class.define_method("classes", method!(Element::classes, 0))?;
+    element_class.define_method("classes", method!(Element::classes, -1))?;
+    element_class.define_method("node_id", method!(Element::node_id, 0))?;
+    element_class.define_method("document_position", method!(Element::document_position, 0))?;
+    element_class.define_method("source_position", method!(Element::source_position, 0))?;
+    element_class.define_method("css_path", method!(Element::css_path, 0))?;
+    element_class.define_method("xpath_path", method!(Element::xpath_path, 0))?;
+    element_class.define_method("rel_tokens", method!(Element::rel_tokens, 0))?;
+    element_class.define_method("rel?", method!(Element::has_rel, 1))?;
+    element_class.define_method("to_table", method!(Element::to_table, 0))?;
+    element_class.define_method("to_h", method!(Element::to_h, 0))?;
+    element_class.define_method("to_json", method!(Element::to_json, -1))?;
+    element_class.define_method("css", method!(Element::select, -1))?;
+    element_class.define_method("at_css", method!(Element::at, -1))?;
+    element_class.define_method("[]", method!(Element::attr, 1))?;
+    element_class.define_method("[]=", method!(Element::set_attr, 2))?;
+    element_class.define_method("children", method!(Element::child_nodes, 0))?;
+    element_class.define_method("content", method!(Element::content, 0))?;
+    element_class.define_method("to_html", method!(Element::html, -1))?;
+
+    let text_node_class = module.define_class("TextNode", ruby.class_object())?;
+    text_node_class.define_method("text", method!(TextNode::text, 0))?;
+    text_node_class.define_method("content", method!(TextNode::text, 0))?;
+    text_node_class.define_method("content=", method!(TextNode::set_text, 1))?;
+    text_node_class.define_method("html", method!(TextNode::html, 0))?;
+    text_node_class.define_method("freeze", method!(TextNode::freeze_node, 0))?;
+
+    let comment_class = module.define_class("Comment", ruby.class_object())?;
+    comment_class.define_method("text", method!(Comment::text, 0))?;
+    comment_class.define_method("content", method!(Comment::text, 0))?;
+    comment_class.define_method("html", method!(Comment::html, 0))?;
+    comment_class.define_method("freeze", method!(Comment::freeze_node, 0))?;
+
+    let doctype_class = module.define_class("Doctype", ruby.class_object())?;
+    doctype_class.define_method("name", method!(Doctype::name, 0))?;
+    doctype_class.define_method("public_id", method!(Doctype::public_id, 0))?;
+    doctype_class.define_method("system_id", method!(Doctype::system_id, 0))?;
+    doctype_class.define_method("html", method!(Doctype::html, 0))?;
+    doctype_class.define_method("freeze", method!(Doctype::freeze_node, 0))?;
+
+    // The methods below share state across the process (the thread pool,
+    // the defaults and in-progress parses) or haven't been checked for
+    // Ractor safety, so only the main Ractor can call them
+    //
+    // SAFETY: called from the thread loading the extension
+    unsafe { rb_sys::rb_ext_ractor_safe(false) };
+
+    module.define_singleton_method("parse_batch", function!(parse_batch, 3))?;
+    module.define_singleton_method("link_graph_batch", function!(link_graph_batch, 1))?;
+    module.define_singleton_method("configure_threads", function!(configure_threads, 1))?;
+    module.define_singleton_method("configure_defaults", function!(configure_defaults, 2))?;
+    module.define_singleton_method("defaults", function!(defaults, 0))?;
+    module.define_singleton_method("parse_async", function!(ParseHandle::spawn, -1))?;
+
+    let parse_handle_class = module.define_class("ParseHandle", ruby.class_object())?;
+    parse_handle_class.define_method("done?", method!(ParseHandle::is_done, 0))?;
+    parse_handle_class
+        .define_method("notification_fd", method!(ParseHandle::notification_fd, 0))?;
+    parse_handle_class.define_method("document", method!(ParseHandle::document, 0))?;
+
+    let parse_batch_class = module.define_class("ParseBatch", ruby.class_object())?;
+    parse_batch_class.define_singleton_method("new", function!(ParseBatch::new, 3))?;
+    parse_batch_class.define_method("done?", method!(ParseBatch::is_done, 0))?;
+    parse_batch_class.define_method("notification_fd", method!(ParseBatch::notification_fd, 0))?;
+    parse_batch_class.define_method("take_finished", method!(ParseBatch::take_finished, 0))?;
+
+    let sanitizer_class = module.define_class("Sanitizer", ruby.class_object())?;
+    sanitizer_class.define_singleton_method("new", function!(Sanitizer::new, -1))?;
+    sanitizer_class.define_method("sanitize", method!(Sanitizer::sanitize, 1))?;
+
+    let url_module = module.define_module("URL")?;
+    url_module.define_singleton_method("normalize", function!(normalize_url, -1))?;
+
+    let selector_class = module.define_class("Selector", ruby.class_object())?;
+    selector_class.define_singleton_method("parse", function!(CssSelector::parse, 1))?;
+    selector_class.define_method("to_s", method!(CssSelector::to_s, 0))?;
+    selector_class.define_method("to_str", method!(CssSelector::to_s, 0))?;
+
+    let pool_class = module.define_class("Pool", ruby.class_object())?;
+    pool_class.define_singleton_method("new", function!(Pool::new, 0))?;
+    pool_class.define_method("parse", method!(Pool::parse, -1))?;
+
+    let streaming_parser_class = module.define_class("StreamingParser", ruby.class_object())?;
+    streaming_parser_class.define_singleton_method("new", function!(StreamingParser::new, -1))?;
+    streaming_parser_class.define_method("<<", method!(StreamingParser::push, 1))?;
+    streaming_parser_class.define_method("finish", method!(StreamingParser::finish, 0))?;
 
     Ok(())
 }
 
-fn parse_fragment(fragment: String) -> Document {
-    Document::new(Html::parse_fragment(&fragment))
+fn parse_fragment(args: &[Value]) -> Result<Document, Error> {
+    let (fragment, options) = parse_args(args)?;
+    parse_string(RString::try_convert(fragment)?, true, options)
 }
 
-fn parse_document(document: String) -> Document {
-    Document::new(Html::parse_document(&document))
+fn parse_document(args: &[Value]) -> Result<Document, Error> {
+    let (document, options) = parse_args(args)?;
+
+    if document.respond_to("read", false)? {
+        parse_io(document, options)
+    } else {
+        parse_string(RString::try_convert(document)?, false, options)
+    }
 }
 
-#[derive(Clone)]
-#[magnus::wrap(class = "Sawzall::Document", free_immediately)]
-struct Document(Arc<Mutex<Html>>);
+fn parse_string(html: RString, fragment: bool, options: ParseOptions) -> Result<Document, Error> {
+    options
+        .limits
+        .check_input_bytes(html.len())
+        .map_err(parse_limit_error)?;
 
-impl Document {
-    fn new(html: Html) -> Self {
-        Self(Arc::new(Mutex::new(html)))
+    if options.sniff_encoding {
+        // SAFETY: the bytes are copied before Ruby gets a chance to modify them
+        let bytes = unsafe { html.as_slice() }.to_vec();
+        parse_bytes(&bytes, fragment, options)
+    } else {
+        let html = html_to_utf8(html, options.strict_encoding)?;
+        parse_utf8(&html, fragment, None, options)
     }
+}
 
-    fn with_locked_html<U, F>(&self, f: F) -> U
-    where
-        F: FnOnce(&Html) -> U,
+/// Parses a document from a file, which is memory-mapped rather than read
+/// into a Ruby string
+fn parse_file(args: &[Value]) -> Result<Document, Error> {
+    let (path, options) = parse_args(args)?;
+    // Like Ruby's own file methods, also accept a `Pathname`
+    let path = if path.respond_to("to_path", false)? {
+        path.funcall("to_path", ())?
+    } else {
+        path
+    };
+    let path = RString::try_convert(path)?.to_string()?;
+
+    let file = File::open(&path).map_err(|error| io_error(error, &path))?;
+    // SAFETY: the file is assumed not to change while it's being parsed, as
+    // there's no way to prevent it
+    let mmap = unsafe { mmap::Mmap::map(&file) }.map_err(|error| io_error(error, &path))?;
+
+    options
+        .limits
+        .check_input_bytes(mmap.len())
+        .map_err(parse_limit_error)?;
+    parse_bytes(&mmap, false, options)
+}
+
+/// Parses raw HTML, which is UTF-8 unless sniffing its encoding
+fn parse_bytes(bytes: &[u8], fragment: bool, options: ParseOptions) -> Result<Document, Error> {
+    if options.sniff_encoding {
+        let (html, charset) = sniffed_html_to_utf8(
+            bytes,
+            options.content_type.as_deref(),
+            options.strict_encoding,
+        )?;
+        parse_utf8(&html, fragment, Some(charset.name), options)
+    } else {
+        let html = bytes_to_utf8(bytes, options.strict_encoding)?;
+        parse_utf8(&html, fragment, None, options)
+    }
+}
+
+fn parse_utf8(
+    html: &str,
+    fragment: bool,
+    encoding: Option<&'static str>,
+    options: ParseOptions,
+) -> Result<Document, Error> {
+    let (parsed, repairs) = gvl::without_gvl_for(html.len(), || {
+        repair::parse(html, fragment, options.repair, options.limits)
+    })
+    .map_err(parse_limit_error)?;
+
+    Ok(Document {
+        repairs: repairs.into(),
+        encoding,
+        ..Document::parsed(parsed, options.weak_elements)
+    })
+}
+
+/// Raises the `Errno` exception matching an error from the OS
+fn io_error(error: std::io::Error, path: &str) -> Error {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+
+    let Some(errno) = error.raw_os_error() else {
+        return Error::new(ruby.exception_io_error(), format!("{error} @ {path}"));
+    };
+
+    match ruby
+        .exception_system_call_error()
+        .new_instance((path, errno))
     {
-        let html = self.0.lock().expect("failed to lock mutex");
+        Ok(exception) => exception.into(),
+        Err(error) => error,
+    }
+}
 
-        f(&html)
+/// Parses a document read from an IO in chunks, so that it is never held in
+/// memory as a whole besides the parsed tree
+///
+/// The IO is read as UTF-8 since it is decoded as it's being parsed, which
+/// rules out sniffing the encoding and repairing the HTML.
+fn parse_io(io: Value, options: ParseOptions) -> Result<Document, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    for (option, set) in [
+        ("sniff_encoding", options.sniff_encoding),
+        ("repair", options.repair != repair::Mode::None),
+    ] {
+        if set {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("{option} isn't supported when parsing an IO"),
+            ));
+        }
     }
 
-    fn select(&self, css_selector: String) -> Result<RArray, Error> {
-        self.with_locked_html(|html| select(css_selector, self.clone(), html.root_element()))
+    let mut parser = arena::StreamingParser::new(false, options.limits);
+    read_io(io, options.strict_encoding, options.limits, |text| {
+        gvl::without_gvl_for(text.len(), || parser.feed(text)).map_err(parse_limit_error)
+    })?;
+    let parsed = parser.finish().map_err(parse_limit_error)?;
+
+    Ok(Document::parsed(parsed, options.weak_elements))
+}
+
+/// Reads an IO as UTF-8 in chunks, passing each one to `f`
+fn read_io<F>(
+    io: Value,
+    strict_encoding: bool,
+    limits: parse_limits::ParseLimits,
+    mut f: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&str) -> Result<(), Error>,
+{
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let mut decoder = utf8_stream::Utf8Decoder::new(strict_encoding);
+    let mut len = 0;
+    // Reused across reads to avoid allocating a string for every chunk
+    let buffer = ruby.str_buf_new(IO_CHUNK_LEN);
+
+    while let Some(chunk) = io.funcall::<_, _, Option<RString>>("read", (IO_CHUNK_LEN, buffer))? {
+        len += chunk.len();
+        limits.check_input_bytes(len).map_err(parse_limit_error)?;
+
+        // SAFETY: the bytes are decoded before Ruby gets a chance to modify them
+        let text = decoder
+            .decode(unsafe { chunk.as_slice() })
+            .map_err(invalid_encoding_error)?;
+        f(&text)?;
     }
 
-    fn root_element(&self) -> Element {
-        self.with_locked_html(|html| Element {
-            id: html.root_element().id(),
-            document: self.clone(),
-        })
+    f(&decoder.finish().map_err(invalid_encoding_error)?)
+}
+
+fn each_token(rb_self: Value, html: Value) -> Result<Value, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+
+    if !ruby.block_given() {
+        return Ok(rb_self.enumeratorize("each_token", (html,)).as_value());
+    }
+
+    let mut tokenizer = tokens::EventTokenizer::new(|event| {
+        ruby.yield_value::<_, Value>(event_hash(&ruby, event)?)?;
+        Ok(())
+    });
+
+    if html.respond_to("read", false)? {
+        read_io(
+            html,
+            strict_encoding_option(None),
+            Default::default(),
+            |text| tokenizer.feed(text),
+        )?;
+    } else {
+        tokenizer.feed(&html_to_utf8(
+            RString::try_convert(html)?,
+            strict_encoding_option(None),
+        )?)?;
     }
+    tokenizer.finish()?;
+
+    Ok(rb_self)
 }
 
-fn select(
-    css_selector: String,
-    document: Document,
-    element_ref: ElementRef,
+fn event_hash(ruby: &Ruby, event: tokens::Event) -> Result<RHash, Error> {
+    let hash = RHash::new();
+
+    match event {
+        tokens::Event::StartTag {
+            name,
+            attributes,
+            self_closing,
+        } => {
+            let attributes_hash = RHash::new();
+            for (name, value) in attributes {
+                attributes_hash.aset(interned_str(&name), value)?;
+            }
+
+            hash.aset(ruby.to_symbol("type"), ruby.to_symbol("start_tag"))?;
+            hash.aset(ruby.to_symbol("name"), interned_str(&name))?;
+            hash.aset(ruby.to_symbol("attributes"), attributes_hash)?;
+            hash.aset(ruby.to_symbol("self_closing"), self_closing)?;
+        }
+        tokens::Event::EndTag { name } => {
+            hash.aset(ruby.to_symbol("type"), ruby.to_symbol("end_tag"))?;
+            hash.aset(ruby.to_symbol("name"), interned_str(&name))?;
+        }
+        tokens::Event::Text(text) => {
+            hash.aset(ruby.to_symbol("type"), ruby.to_symbol("text"))?;
+            hash.aset(ruby.to_symbol("text"), text)?;
+        }
+        tokens::Event::Comment(text) => {
+            hash.aset(ruby.to_symbol("type"), ruby.to_symbol("comment"))?;
+            hash.aset(ruby.to_symbol("text"), text)?;
+        }
+        tokens::Event::Doctype {
+            name,
+            public_id,
+            system_id,
+        } => {
+            hash.aset(ruby.to_symbol("type"), ruby.to_symbol("doctype"))?;
+            hash.aset(ruby.to_symbol("name"), name)?;
+            hash.aset(ruby.to_symbol("public_id"), public_id)?;
+            hash.aset(ruby.to_symbol("system_id"), system_id)?;
+        }
+    }
+
+    Ok(hash)
+}
+
+fn parse_xml(args: &[Value]) -> Result<Document, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let args = scan_args::<_, (), (), (), _, ()>(args)?;
+    let (xml,): (RString,) = args.required;
+    let (weak_elements, strict_encoding): (Option<bool>, Option<bool>) =
+        get_options(args.keywords, &["weak_elements", "strict_encoding"])?;
+
+    let xml = html_to_utf8(xml, strict_encoding_option(strict_encoding))?;
+    let html = xml::parse_xml(&xml)
+        .ok_or_else(|| Error::new(ruby.exception_arg_error(), "XML has no root element"))?;
+
+    Ok(Document::new(html, weak_elements_option(weak_elements)))
+}
+
+fn parse_html(html: &str, fragment: bool) -> parse_error::Parsed {
+    if fragment {
+        arena::parse_fragment(html)
+    } else {
+        arena::parse_document(html)
+    }
+}
+
+fn parse_batch(
+    htmls: RArray,
+    fragment: bool,
+    strict_encoding: Option<bool>,
 ) -> Result<RArray, Error> {
+    let htmls = htmls_to_utf8(htmls, strict_encoding_option(strict_encoding))?;
+
+    let len = htmls.iter().map(String::len).sum();
+    let parsed = gvl::without_gvl_for(len, || pool::map(htmls, |html| parse_html(&html, fragment)));
+
+    Ok(parsed
+        .into_iter()
+        .map(|parsed| Document::parsed(parsed, weak_elements_option(None)))
+        .collect())
+}
+
+fn link_graph_batch(documents: Vec<(String, Obj<Document>)>) -> Result<RArray, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let documents = documents
+        .into_iter()
+        .map(|(url, document)| Ok((parse_base_url(&url)?, document.html.clone())))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // The documents are locked from other threads, which mustn't wait for
+    // a document locked by a Ruby thread waiting for the GVL
+    let edges = gvl::without_gvl(|| {
+        pool::map(documents, |(url, html)| {
+            let html = html.read_blocking();
+
+            link_graph::outlinks(&html, &url)
+        })
+    });
+
+    let array = RArray::new();
+    for edge in edges.into_iter().flatten() {
+        let hash = RHash::new();
+        hash.aset(ruby.to_symbol("from_url"), edge.from_url)?;
+        hash.aset(ruby.to_symbol("to_url"), edge.to_url)?;
+        hash.aset(ruby.to_symbol("text"), edge.text)?;
+        hash.aset(ruby.to_symbol("rel"), edge.rel)?;
+        array.push(hash)?;
+    }
+
+    Ok(array)
+}
+
+fn configure_threads(threads: Option<usize>) -> Result<(), Error> {
     let ruby = Ruby::get().expect("called from non-ruby thread");
 
-    let selector = Selector::parse(&css_selector).map_err(|e| {
+    pool::configure(threads).map_err(|e| {
         Error::new(
-            ruby.exception_arg_error(),
-            format!("failed to parse selector {css_selector:?}\n{e}"),
+            ruby.exception_runtime_error(),
+            format!("failed to build thread pool: {e}"),
         )
-    })?;
+    })
+}
 
-    Ok(element_ref
-        .select(&selector)
-        .map(|matching_element_ref| Element {
-            id: matching_element_ref.id(),
-            document: document.clone(),
-        })
-        .collect())
+fn diff_documents(old: Obj<Document>, new: Obj<Document>) -> Result<RArray, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let array = RArray::new();
+
+    // A document is identical to itself, and can't be locked twice
+    if Arc::ptr_eq(&old.html, &new.html) {
+        return Ok(array);
+    }
+
+    let (old_html, new_html) = SharedHtml::read_pair(&old.html, &new.html);
+
+    let compare = || diff::diff(&old_html, &new_html);
+    let changes =
+        if old_html.tree.nodes().len() + new_html.tree.nodes().len() >= SELECT_WITHOUT_GVL_NODES {
+            gvl::without_gvl(compare)
+        } else {
+            compare()
+        };
+
+    let old_document = old.element_document();
+    let new_document = new.element_document();
+    let node = |html: &Html, id: NodeId, document: &DocumentRef| {
+        let node = html.tree.get(id).expect("changed nodes are in the tree");
+        wrap_node(&ruby, node, document)
+    };
+
+    for change in changes {
+        let hash = RHash::new();
+
+        match change {
+            diff::Change::Insert(id) => {
+                hash.aset(ruby.to_symbol("type"), ruby.to_symbol("insert"))?;
+                hash.aset(ruby.to_symbol("node"), node(&new_html, id, &new_document))?;
+            }
+            diff::Change::Delete(id) => {
+                hash.aset(ruby.to_symbol("type"), ruby.to_symbol("delete"))?;
+                hash.aset(ruby.to_symbol("node"), node(&old_html, id, &old_document))?;
+            }
+            diff::Change::Attribute {
+                old,
+                new,
+                name,
+                from,
+                to,
+            } => {
+                hash.aset(ruby.to_symbol("type"), ruby.to_symbol("attribute"))?;
+                hash.aset(
+                    ruby.to_symbol("old_node"),
+                    node(&old_html, old, &old_document),
+                )?;
+                hash.aset(ruby.to_symbol("node"), node(&new_html, new, &new_document))?;
+                hash.aset(ruby.to_symbol("name"), interned_str(&name))?;
+                hash.aset(ruby.to_symbol("from"), from)?;
+                hash.aset(ruby.to_symbol("to"), to)?;
+            }
+            diff::Change::Text { old, new, from, to } => {
+                hash.aset(ruby.to_symbol("type"), ruby.to_symbol("text"))?;
+                hash.aset(
+                    ruby.to_symbol("old_node"),
+                    node(&old_html, old, &old_document),
+                )?;
+                hash.aset(ruby.to_symbol("node"), node(&new_html, new, &new_document))?;
+                hash.aset(ruby.to_symbol("from"), from)?;
+                hash.aset(ruby.to_symbol("to"), to)?;
+            }
+        }
+
+        array.push(hash)?;
+    }
+
+    Ok(array)
 }
 
-#[magnus::wrap(class = "Sawzall::Element", free_immediately)]
-struct Element {
-    id: NodeId,
-    document: Document,
+fn is_void_element(name: String) -> bool {
+    content_model::is_void_element(&name)
 }
 
-impl Element {
-    fn with_element_ref<U, F>(&self, f: F) -> U
-    where
-        F: FnOnce(ElementRef) -> U,
-    {
-        let html = self.document.0.lock().expect("failed to lock mutex");
-        let element_ref = html
-            .tree
-            .get(self.id)
-            .and_then(ElementRef::wrap)
-            .expect("node with id {self.id} must be an element in the tree");
+fn is_raw_text_element(name: String) -> bool {
+    content_model::is_raw_text_element(&name)
+}
+
+fn is_valid_selector(css_selector: String) -> bool {
+    QuerySelector::parse(&css_selector, None).is_ok()
+}
 
-        f(element_ref)
+fn join(args: &[Value]) -> Result<Document, Error> {
+    let args = scan_args::<(RArray,), (Option<Value>,), (), (), (), ()>(args)?;
+    let (parts,) = args.required;
+    let (separator,) = args.optional;
+
+    let mut joined = Vec::with_capacity(parts.len() * 2);
+
+    for (index, part) in parts.into_iter().enumerate() {
+        if let Some(separator) = separator.filter(|_| index > 0) {
+            joined.push(separator);
+        }
+
+        joined.push(part);
     }
 
-    fn name(&self) -> String {
-        self.with_element_ref(|element_ref| element_ref.value().name().to_string())
+    concat(joined)
+}
+
+/// Builds a new fragment out of copies of the content of documents and of
+/// elements, with strings added as text so that they are escaped
+fn concat(parts: Vec<Value>) -> Result<Document, Error> {
+    let mut html = dom::empty_fragment();
+    let root_id = html.root_element().id();
+
+    for part in parts {
+        if let Ok(document) = <&Document>::try_convert(part) {
+            document.with_locked_html(|source| {
+                dom::insert(
+                    &mut html,
+                    root_id,
+                    dom::Position::BeforeEnd,
+                    dom::content(source),
+                )
+            });
+        } else if let Ok(element) = <&Element>::try_convert(part) {
+            element.with_element_ref(|element_ref| {
+                dom::insert(&mut html, root_id, dom::Position::BeforeEnd, [*element_ref])
+            })?;
+        } else {
+            let text = html_to_utf8(RString::try_convert(part)?, strict_encoding_option(None))?;
+
+            html.tree
+                .get_mut(root_id)
+                .expect("root element must be in the tree")
+                .append(Node::Text(scraper::node::Text { text: text.into() }));
+        }
     }
 
-    fn html(&self) -> String {
-        self.with_element_ref(|element_ref| element_ref.html())
+    Ok(Document::new(html, weak_elements_option(None)))
+}
+
+/// Sets the values of the `weak_elements:` and `strict_encoding:` options
+/// used when they aren't passed
+fn configure_defaults(weak_elements: bool, strict_encoding: bool) {
+    DEFAULT_WEAK_ELEMENTS.store(weak_elements, Ordering::Relaxed);
+    DEFAULT_STRICT_ENCODING.store(strict_encoding, Ordering::Relaxed);
+}
+
+fn defaults() -> Result<RHash, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let hash = RHash::new();
+
+    hash.aset(ruby.to_symbol("weak_elements"), weak_elements_option(None))?;
+    hash.aset(
+        ruby.to_symbol("strict_encoding"),
+        strict_encoding_option(None),
+    )?;
+
+    Ok(hash)
+}
+
+fn weak_elements_option(weak_elements: Option<bool>) -> bool {
+    weak_elements.unwrap_or_else(|| DEFAULT_WEAK_ELEMENTS.load(Ordering::Relaxed))
+}
+
+fn strict_encoding_option(strict_encoding: Option<bool>) -> bool {
+    strict_encoding.unwrap_or_else(|| DEFAULT_STRICT_ENCODING.load(Ordering::Relaxed))
+}
+
+/// Returns a frozen and deduplicated string like a `frozen_string_literal`,
+/// which Ruby only allocates the first time it sees a given value
+fn interned_str(value: &str) -> RString {
+    // SAFETY: the pointer and length come from a valid UTF-8 string, which
+    // Ruby copies if it needs to allocate
+    let interned = unsafe {
+        Value::from_raw(rb_sys::rb_enc_interned_str(
+            value.as_ptr().cast(),
+            value.len() as _,
+            rb_sys::rb_utf8_encoding(),
+        ))
+    };
+
+    RString::from_value(interned).expect("interned strings are strings")
+}
+
+/// Returns a tag, attribute or class name as an interned string, or as a
+/// symbol with `symbolize_names: true`
+fn name_value(ruby: &Ruby, name: &str, symbolize_names: bool) -> Value {
+    if symbolize_names {
+        ruby.to_symbol(name).as_value()
+    } else {
+        interned_str(name).as_value()
     }
+}
+
+/// Extracts the `symbolize_names:` option of methods returning names
+fn symbolize_names_option(args: &[Value]) -> Result<bool, Error> {
+    let args = scan_args::<(), (), (), (), _, ()>(args)?;
+    let (symbolize_names,): (Option<bool>,) = get_options(args.keywords, &["symbolize_names"])?;
+
+    Ok(symbolize_names.unwrap_or(false))
+}
 
-    fn inner_html(&self) -> String {
-        self.with_element_ref(|element_ref| element_ref.inner_html())
+/// Converts HTML passed from Ruby to UTF-8
+///
+/// Invalid byte sequences are replaced with U+FFFD like browsers do, so that
+/// every string returned to Ruby is valid UTF-8. With `strict_encoding`, they
+/// raise a `Sawzall::InvalidEncodingError` instead.
+fn html_to_utf8(html: RString, strict_encoding: bool) -> Result<String, Error> {
+    let ruby = Ruby::get_with(html);
+    // Left as is by Ruby when it can't be converted (e.g. binary strings)
+    let utf8 = if html.is_utf8_compatible_encoding() {
+        html
+    } else {
+        html.conv_enc(ruby.utf8_encoding())?
+    };
+
+    // SAFETY: the bytes are copied before Ruby gets a chance to modify them
+    let bytes = unsafe { utf8.as_slice() };
+
+    Ok(bytes_to_utf8(bytes, strict_encoding)?.into_owned())
+}
+
+/// Checks that bytes are UTF-8, replacing invalid byte sequences with U+FFFD
+/// unless `strict_encoding` is set
+fn bytes_to_utf8(bytes: &[u8], strict_encoding: bool) -> Result<Cow<'_, str>, Error> {
+    if !strict_encoding {
+        return Ok(String::from_utf8_lossy(bytes));
     }
 
-    fn attr(&self, attribute: String) -> Option<String> {
-        self.with_element_ref(|element_ref| element_ref.attr(&attribute).map(ToString::to_string))
+    match std::str::from_utf8(bytes) {
+        Ok(utf8) => Ok(Cow::Borrowed(utf8)),
+        Err(error) => Err(invalid_encoding_error(utf8_stream::InvalidByte(
+            error.valid_up_to(),
+        ))),
     }
+}
 
-    fn attrs(&self) -> RArray {
-        self.with_element_ref(|element_ref| {
-            element_ref
-                .value()
-                .attrs()
-                .map(|(key, value)| RArray::from_slice(&[RString::new(key), RString::new(value)]))
-                .collect()
-        })
+fn invalid_encoding_error(invalid_byte: utf8_stream::InvalidByte) -> Error {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    Error::new(
+        ruby.get_inner(&INVALID_ENCODING_ERROR),
+        format!("invalid byte sequence in UTF-8 at byte {}", invalid_byte.0),
+    )
+}
+
+/// Converts raw HTML to UTF-8 from the encoding it's sniffed to be in,
+/// ignoring the encoding of the Ruby string
+///
+/// Invalid byte sequences are replaced with U+FFFD, unless `strict_encoding`
+/// is set, in which case they raise a `Sawzall::InvalidEncodingError`.
+fn sniffed_html_to_utf8<'a>(
+    bytes: &'a [u8],
+    content_type: Option<&str>,
+    strict_encoding: bool,
+) -> Result<(Cow<'a, str>, charset::Charset), Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let sniffed = charset::sniff(bytes, content_type);
+    let bytes = &bytes[sniffed.bom_len..];
+
+    // Doesn't need transcoding, which would copy it
+    if sniffed.charset == charset::UTF_8 {
+        return Ok((bytes_to_utf8(bytes, strict_encoding)?, sniffed.charset));
     }
 
-    fn select(&self, css_selector: String) -> Result<RArray, Error> {
-        self.with_element_ref(|element_ref| {
-            select(css_selector, self.document.clone(), element_ref)
-        })
+    let encoding = ruby
+        .find_encoding(sniffed.charset.ruby_name)
+        .ok_or_else(|| {
+            Error::new(
+                ruby.exception_encoding_error(),
+                format!("unsupported encoding {}", sniffed.charset.name),
+            )
+        })?;
+    let encoded = ruby.enc_str_new(bytes, encoding);
+
+    if strict_encoding && !encoded.funcall::<_, _, bool>("valid_encoding?", ())? {
+        return Err(Error::new(
+            ruby.get_inner(&INVALID_ENCODING_ERROR),
+            format!("invalid byte sequence in {}", sniffed.charset.name),
+        ));
     }
 
-    fn child_elements(&self) -> RArray {
-        self.with_element_ref(|element_ref| {
-            element_ref
-                .child_elements()
-                .map(|child_element_ref| Element {
-                    id: child_element_ref.id(),
-                    document: self.document.clone(),
-                })
-                .collect()
-        })
+    let utf8: RString = encoded.funcall(
+        "encode",
+        (
+            ruby.utf8_encoding(),
+            kwargs!(&ruby, "invalid" => ruby.to_symbol("replace"), "undef" => ruby.to_symbol("replace")),
+        ),
+    )?;
+
+    // SAFETY: the bytes are copied before Ruby gets a chance to modify them
+    Ok((
+        Cow::Owned(unsafe { utf8.to_string_lossy() }.into_owned()),
+        sniffed.charset,
+    ))
+}
+
+fn htmls_to_utf8(htmls: RArray, strict_encoding: bool) -> Result<Vec<String>, Error> {
+    htmls
+        .into_iter()
+        .map(|html| html_to_utf8(RString::try_convert(html)?, strict_encoding))
+        .collect()
+}
+
+/// Lowercases an attribute name passed from Ruby like the parser does,
+/// raising if it couldn't be serialized
+fn attribute_name_arg(name: &str) -> Result<String, Error> {
+    if !dom::is_valid_attribute_name(name) {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            format!("invalid attribute name {name:?}"),
+        ));
     }
 
-    fn text(&self) -> String {
-        self.with_element_ref(html_to_plain::html_to_plain)
+    Ok(name.to_ascii_lowercase())
+}
+
+fn check_class_names(names: &[String]) -> Result<(), Error> {
+    match names.iter().find(|name| !dom::is_valid_class_name(name)) {
+        Some(name) => {
+            let ruby = Ruby::get().expect("called from non-ruby thread");
+
+            Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("invalid class name {name:?}"),
+            ))
+        }
+        None => Ok(()),
     }
+}
 
-    fn has_class(&self, args: &[Value]) -> Result<bool, Error> {
-        let args = scan_args::<_, (), (), (), _, ()>(args)?;
-        let (class,): (String,) = args.required;
-        let kwargs = get_kwargs::<_, (), _, ()>(args.keywords, &[], &["case_sensitive"])?;
-        let (case_sensitive,): (Option<bool>,) = kwargs.optional;
+/// Parses HTML passed from Ruby to be inserted into a document
+fn fragment_arg(html: RString) -> Result<Html, Error> {
+    Ok(arena::parse_fragment(&html_to_utf8(html, strict_encoding_option(None))?).html)
+}
 
-        let case_sensitivity = if case_sensitive.unwrap_or(true) {
-            CaseSensitivity::CaseSensitive
-        } else {
-            CaseSensitivity::AsciiCaseInsensitive
-        };
+/// Raises if the text would close its raw text element (e.g. a `<script>`)
+/// early once serialized, as the serializer doesn't escape it
+fn check_raw_text(element_name: &QualName, text: &str) -> Result<(), Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let name = &*element_name.local;
 
-        Ok(self.with_element_ref(|element_ref| {
-            element_ref.value().has_class(&class, case_sensitivity)
-        }))
+    if element_name.ns == ns!(html) && content_model::ends_raw_text(name, text) {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            format!("the content of <{name}> can't contain \"</{name}\""),
+        ));
     }
 
-    fn classes(&self) -> RArray {
-        self.with_element_ref(|element_ref| {
-            element_ref.value().classes().map(RString::new).collect()
-        })
+    Ok(())
+}
+
+/// Raises unless the element has a parent element, which is required to
+/// insert nodes next to it as documents are serialized from their root
+/// element
+fn check_parent_element(html: &Html, id: NodeId, action: &str) -> Result<(), Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+
+    let message = match html.tree.get(id).and_then(|node| node.parent()) {
+        Some(parent) if parent.value().is_element() => return Ok(()),
+        Some(_) => format!("can't {action} the root element"),
+        None => format!("can't {action} a removed element"),
+    };
+
+    Err(Error::new(ruby.exception_runtime_error(), message))
+}
+
+/// Extracts the keyword options passed to a method, raising an
+/// `ArgumentError` that lists the allowed ones for unknown keywords
+fn get_options<Opt: ScanArgsOpt>(keywords: RHash, allowed: &[&str]) -> Result<Opt, Error> {
+    let ruby = Ruby::get_with(keywords);
+    let mut unknown = Vec::new();
+
+    keywords.foreach(|key: Value, _: Value| {
+        let name = Symbol::from_value(key).map(Symbol::name).transpose()?;
+
+        if !name.is_some_and(|name| allowed.contains(&&*name)) {
+            unknown.push(key.inspect());
+        }
+
+        Ok(ForEach::Continue)
+    })?;
+
+    if !unknown.is_empty() {
+        let expected = allowed
+            .iter()
+            .map(|name| format!(":{name}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            format!(
+                "unknown keyword{} {}, expected one of {expected}",
+                if unknown.len() > 1 { "s" } else { "" },
+                unknown.join(", ")
+            ),
+        ));
+    }
+
+    Ok(get_kwargs::<_, (), _, ()>(keywords, &[], allowed)?.optional)
+}
+
+/// Options shared by [`parse_fragment`] and [`parse_document`]
+struct ParseOptions {
+    weak_elements: bool,
+    strict_encoding: bool,
+    repair: repair::Mode,
+    sniff_encoding: bool,
+    content_type: Option<String>,
+    limits: parse_limits::ParseLimits,
+}
+
+/// Extracts the HTML and the options passed to [`parse_fragment`] and
+/// [`parse_document`]
+fn parse_args(args: &[Value]) -> Result<(Value, ParseOptions), Error> {
+    let args = scan_args::<_, (), (), (), _, ()>(args)?;
+    let (html,): (Value,) = args.required;
+    #[allow(clippy::type_complexity)]
+    let (
+        weak_elements,
+        strict_encoding,
+        invalid,
+        repair,
+        sniff_encoding,
+        content_type,
+        max_input_bytes,
+        max_depth,
+        max_nodes,
+    ): (
+        Option<bool>,
+        Option<bool>,
+        Option<Symbol>,
+        Option<Symbol>,
+        Option<bool>,
+        Option<String>,
+        Option<usize>,
+        Option<usize>,
+        Option<usize>,
+    ) = get_options(
+        args.keywords,
+        &[
+            "weak_elements",
+            "strict_encoding",
+            "invalid",
+            "repair",
+            "sniff_encoding",
+            "content_type",
+            "max_input_bytes",
+            "max_depth",
+            "max_nodes",
+        ],
+    )?;
+
+    Ok((
+        html,
+        ParseOptions {
+            weak_elements: weak_elements_option(weak_elements),
+            strict_encoding: invalid_option(invalid, strict_encoding)?,
+            repair: repair_option(repair)?,
+            sniff_encoding: sniff_encoding.unwrap_or(false),
+            content_type,
+            limits: parse_limits::ParseLimits {
+                max_input_bytes,
+                max_depth,
+                max_nodes,
+            },
+        },
+    ))
+}
+
+fn parse_limit_error(exceeded: parse_limits::LimitExceeded) -> Error {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    Error::new(ruby.get_inner(&PARSE_LIMIT_ERROR), exceeded.to_string())
+}
+
+/// Whether invalid bytes raise rather than being replaced, from either the
+/// `invalid:` or the `strict_encoding:` option
+fn invalid_option(invalid: Option<Symbol>, strict_encoding: Option<bool>) -> Result<bool, Error> {
+    let Some(invalid) = invalid else {
+        return Ok(strict_encoding_option(strict_encoding));
+    };
+
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    if strict_encoding.is_some() {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "invalid and strict_encoding can't be combined",
+        ));
+    }
+
+    match &*invalid.name()? {
+        "replace" => Ok(false),
+        "raise" => Ok(true),
+        name => Err(Error::new(
+            ruby.exception_arg_error(),
+            format!("unknown invalid option :{name}, expected one of :replace, :raise"),
+        )),
+    }
+}
+
+fn repair_option(repair: Option<Symbol>) -> Result<repair::Mode, Error> {
+    let Some(repair) = repair else {
+        return Ok(repair::Mode::None);
+    };
+
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let name = repair.name()?;
+
+    repair::Mode::from_name(&name).ok_or_else(|| {
+        let expected = repair::Mode::ALL
+            .map(|mode| format!(":{}", mode.name()))
+            .join(", ");
+
+        Error::new(
+            ruby.exception_arg_error(),
+            format!("unknown repair mode :{name}, expected one of {expected}"),
+        )
+    })
+}
+
+/// Extracts the options of `Document#chunks`
+fn chunk_options(args: &[Value]) -> Result<chunk::Options, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let args = scan_args::<(), (), (), (), _, ()>(args)?;
+    let (max_tokens, overlap, by): (Option<usize>, Option<usize>, Option<Symbol>) =
+        get_options(args.keywords, &["max_tokens", "overlap", "by"])?;
+
+    let Some(max_tokens) = max_tokens else {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "missing keyword: :max_tokens",
+        ));
+    };
+    let overlap = overlap.unwrap_or(0);
+
+    if max_tokens == 0 {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            "max_tokens must be positive",
+        ));
+    }
+
+    if overlap >= max_tokens {
+        return Err(Error::new(
+            ruby.exception_arg_error(),
+            format!("overlap ({overlap}) must be less than max_tokens ({max_tokens})"),
+        ));
+    }
+
+    let boundary = match by {
+        None => chunk::Boundary::Headings,
+        Some(by) => {
+            let name = by.name()?;
+
+            chunk::Boundary::from_name(&name).ok_or_else(|| {
+                let expected = chunk::Boundary::ALL
+                    .map(|boundary| format!(":{}", boundary.name()))
+                    .join(", ");
+
+                Error::new(
+                    ruby.exception_arg_error(),
+                    format!("unknown chunk boundary :{name}, expected one of {expected}"),
+                )
+            })?
+        }
+    };
+
+    Ok(chunk::Options {
+        max_tokens,
+        overlap,
+        boundary,
+    })
+}
+
+#[derive(Clone, TypedData)]
+#[magnus(
+    class = "Sawzall::Document",
+    free_immediately,
+    mark,
+    size,
+    frozen_shareable
+)]
+struct Document {
+    html: Arc<SharedHtml>,
+    /// Values attached to the document's elements with `Element#annotation=`
+    annotations: Arc<Annotations>,
+    /// Whether elements only hold a weak reference to the document, so that
+    /// they don't keep it alive once it is garbage collected
+    weak_elements: bool,
+    /// What the `repair:` option of [`parse_document`] and
+    /// [`parse_fragment`] fixed before parsing
+    repairs: Arc<[repair::Repair]>,
+    /// What the parser had to recover from, empty for documents that weren't
+    /// parsed from HTML
+    errors: Arc<[parse_error::ParseError]>,
+    /// The encoding the HTML was sniffed to be in when parsed with
+    /// `sniff_encoding: true`
+    encoding: Option<&'static str>,
+}
+
+impl DataTypeFunctions for Document {
+    fn mark(&self, marker: &gc::Marker) {
+        self.annotations.mark(marker);
+    }
+
+    /// Counts the tree, which may be shared with elements holding the
+    /// document, as part of the document only
+    fn size(&self) -> usize {
+        let errors: usize = self
+            .errors
+            .iter()
+            .map(|error| match &error.message {
+                Cow::Borrowed(_) => 0,
+                Cow::Owned(message) => message.capacity(),
+            })
+            .sum();
+
+        mem::size_of::<Self>()
+            + self.html.memsize().unwrap_or(0)
+            + mem::size_of_val(&*self.errors)
+            + errors
+            + mem::size_of_val(&*self.repairs)
+    }
+}
+
+impl Document {
+    fn new(html: Html, weak_elements: bool) -> Self {
+        Self {
+            html: Arc::new(SharedHtml::new(html)),
+            annotations: Arc::default(),
+            weak_elements,
+            repairs: Arc::new([]),
+            errors: Arc::new([]),
+            encoding: None,
+        }
+    }
+
+    fn parsed(parsed: parse_error::Parsed, weak_elements: bool) -> Self {
+        let document = Document {
+            errors: parsed.errors.into(),
+            ..Document::new(parsed.html, weak_elements)
+        };
+        document.html.write().set_source_lines(parsed.lines);
+
+        document
+    }
+
+    fn deep_copy(&self) -> Self {
+        // Copying the tree keeps node ids, so annotations and source lines
+        // still apply
+        let (html, source_lines) = {
+            let html = self.html.read();
+            (dom::copy_html(&html), html.source_lines().clone())
+        };
+
+        let copy = Document {
+            annotations: Arc::new(self.annotations.copy()),
+            repairs: self.repairs.clone(),
+            errors: self.errors.clone(),
+            encoding: self.encoding,
+            ..Document::new(html, self.weak_elements)
+        };
+        copy.html.write().set_source_lines(source_lines);
+
+        copy
+    }
+
+    fn clone_document(&self, args: &[Value]) -> Result<Obj<Self>, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let args = scan_args::<(), (), (), (), _, ()>(args)?;
+        let (freeze,): (Option<bool>,) = get_options(args.keywords, &["freeze"])?;
+
+        let copy = ruby.obj_wrap(self.deep_copy());
+        if freeze.unwrap_or(false) {
+            return Ok(Self::freeze_document(copy));
+        }
+
+        Ok(copy)
+    }
+
+    /// Freezes the document along with its tree, so that its nodes can't
+    /// modify it either. Frozen documents can be shared between Ractors.
+    fn freeze_document(rb_self: Obj<Self>) -> Obj<Self> {
+        rb_self.html.freeze();
+        rb_self.freeze();
+
+        rb_self
+    }
+
+    fn element_document(&self) -> DocumentRef {
+        if self.weak_elements {
+            DocumentRef::Weak(
+                Arc::downgrade(&self.html),
+                Arc::downgrade(&self.annotations),
+            )
+        } else {
+            DocumentRef::Strong(self.clone())
+        }
+    }
+
+    fn with_locked_html<U, F>(&self, f: F) -> U
+    where
+        F: FnOnce(&Html) -> U,
+    {
+        let html = self.html.read();
+
+        f(&html)
+    }
+
+    fn with_locked_html_mut<U, F>(&self, f: F) -> Result<U, Error>
+    where
+        F: FnOnce(&mut Html) -> U,
+    {
+        let mut html = lock_unfrozen(&self.html)?;
+
+        Ok(f(&mut html))
+    }
+
+    fn select(&self, args: &[Value]) -> Result<RArray, Error> {
+        let (selector, attr_matches, detach) = select_args(args)?;
+        let html = self.html.read();
+
+        let matches = match selector.indexed(&html) {
+            Some(matches) => wrap_matches(
+                matches
+                    .filter(|element_ref| matches_attrs(attr_matches.as_ref(), element_ref))
+                    .collect(),
+                detach,
+                self.element_document(),
+            ),
+            None => select(
+                &selector,
+                attr_matches.as_ref(),
+                detach,
+                self.element_document(),
+                html.root_element(),
+            ),
+        };
+
+        Ok(matches)
+    }
+
+    fn root_element(&self) -> Element {
+        self.with_locked_html(|html| Element {
+            id: html.root_element().id(),
+            document: self.element_document(),
+        })
+    }
+
+    fn children(&self) -> RArray {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        self.with_locked_html(|html| {
+            let parent = if html.tree.root().value().is_fragment() {
+                *html.root_element()
+            } else {
+                html.tree.root()
+            };
+
+            parent
+                .children()
+                .filter_map(|child| wrap_node(&ruby, child, &self.element_document()))
+                .collect()
+        })
+    }
+
+    fn content(&self) -> String {
+        self.with_locked_html(|html| html.root_element().text().collect())
+    }
+
+    fn xpath(&self, expr: String) -> Result<Value, Error> {
+        self.with_locked_html(|html| {
+            evaluate_xpath(&expr, html, html.tree.root().id(), &self.element_document())
+        })
+    }
+
+    fn at(&self, args: &[Value]) -> Result<Value, Error> {
+        let (selector, null_object) = at_args(args)?;
+        let html = self.html.read();
+
+        let element_ref = match selector.indexed(&html) {
+            Some(mut matches) => matches.next(),
+            None => selector.select(html.root_element()).next(),
+        };
+        let element = element_ref.map(|element_ref| Element {
+            id: element_ref.id(),
+            document: self.element_document(),
+        });
+
+        element_or_null(element, null_object)
+    }
+
+    fn build_index(rb_self: Obj<Self>) -> Obj<Self> {
+        rb_self.html.write().build_selector_index();
+
+        rb_self
+    }
+
+    fn at_bang(&self, css_selector: String) -> Result<Element, Error> {
+        let selector = parse_selector(&css_selector)?;
+
+        self.with_locked_html(|html| {
+            let root_element = html.root_element();
+
+            match root_element.select(&selector).next() {
+                Some(element_ref) => Ok(Element {
+                    id: element_ref.id(),
+                    document: self.element_document(),
+                }),
+                None => Err(selector_not_found(
+                    &css_selector,
+                    self.element_document(),
+                    root_element,
+                )?),
+            }
+        })
+    }
+
+    fn element_for_id(&self, args: &[Value]) -> Result<Value, Error> {
+        let args = scan_args::<_, (), (), (), _, ()>(args)?;
+        let (node_id,): (usize,) = args.required;
+        let (null_object,): (Option<bool>,) = get_options(args.keywords, &["null_object"])?;
+
+        let element = self.with_locked_html(|html| {
+            html.tree
+                .nodes()
+                .nth(node_id)
+                .and_then(ElementRef::wrap)
+                .map(|element_ref| Element {
+                    id: element_ref.id(),
+                    document: self.element_document(),
+                })
+        });
+
+        element_or_null(element, null_object.unwrap_or(false))
+    }
+
+    fn element_by_id(&self, args: &[Value]) -> Result<Value, Error> {
+        let args = scan_args::<_, (), (), (), _, ()>(args)?;
+        let (id,): (String,) = args.required;
+        let (null_object,): (Option<bool>,) = get_options(args.keywords, &["null_object"])?;
+
+        let element = self.html.read().element_by_id(&id).map(|id| Element {
+            id,
+            document: self.element_document(),
+        });
+
+        element_or_null(element, null_object.unwrap_or(false))
+    }
+
+    fn find_by_text(&self, args: &[Value]) -> Result<RArray, Error> {
+        let args = scan_args::<_, (), (), (), _, ()>(args)?;
+        let (pattern,): (Value,) = args.required;
+        let (css_selector,): (Option<String>,) = get_options(args.keywords, &["selector"])?;
+
+        let pattern = match RRegexp::from_value(pattern) {
+            Some(regexp) => text_search::TextPattern::Regex(regexp_to_regex(regexp)?),
+            None => text_search::TextPattern::exact(&String::try_convert(pattern)?),
+        };
+        let selector = css_selector
+            .map(|css_selector| parse_selector(&css_selector))
+            .transpose()?;
+
+        Ok(self.with_locked_html(|html| {
+            let search =
+                || text_search::find_by_text(html.root_element(), &pattern, selector.as_ref());
+            let matches = if html.tree.nodes().len() >= SELECT_WITHOUT_GVL_NODES {
+                gvl::without_gvl(search)
+            } else {
+                search()
+            };
+
+            wrap_matches(matches, false, self.element_document())
+        }))
+    }
+
+    fn content_security_policies(&self) -> RArray {
+        self.with_locked_html(|html| {
+            csp::meta_policies(html)
+                .into_iter()
+                .map(|policy| policy.into_iter().collect::<RHash>())
+                .collect()
+        })
+    }
+
+    fn robots_directives(&self) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let directives = self.with_locked_html(robots::robots_directives);
+
+        let meta = RHash::new();
+        for (name, meta_directives) in directives.meta {
+            let hash = RHash::new();
+            hash.aset(ruby.to_symbol("noindex"), meta_directives.noindex)?;
+            hash.aset(ruby.to_symbol("nofollow"), meta_directives.nofollow)?;
+            hash.aset(ruby.to_symbol("noarchive"), meta_directives.noarchive)?;
+            hash.aset(ruby.to_symbol("nosnippet"), meta_directives.nosnippet)?;
+            hash.aset(ruby.to_symbol("noimageindex"), meta_directives.noimageindex)?;
+            hash.aset(ruby.to_symbol("notranslate"), meta_directives.notranslate)?;
+            hash.aset(ruby.to_symbol("max_snippet"), meta_directives.max_snippet)?;
+            hash.aset(
+                ruby.to_symbol("max_image_preview"),
+                meta_directives.max_image_preview,
+            )?;
+            hash.aset(
+                ruby.to_symbol("max_video_preview"),
+                meta_directives.max_video_preview,
+            )?;
+            hash.aset(
+                ruby.to_symbol("unavailable_after"),
+                meta_directives.unavailable_after,
+            )?;
+            meta.aset(name, hash)?;
+        }
+
+        let links = RArray::new();
+        for link_directives in directives.links {
+            let hash = RHash::new();
+            hash.aset(ruby.to_symbol("href"), link_directives.href)?;
+            hash.aset(ruby.to_symbol("nofollow"), link_directives.nofollow)?;
+            hash.aset(ruby.to_symbol("ugc"), link_directives.ugc)?;
+            hash.aset(ruby.to_symbol("sponsored"), link_directives.sponsored)?;
+            links.push(hash)?;
+        }
+
+        let result = RHash::new();
+        result.aset(ruby.to_symbol("meta"), meta)?;
+        result.aset(ruby.to_symbol("links"), links)?;
+
+        Ok(result)
+    }
+
+    fn indexability(&self, base_url: String) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let base_url = parse_base_url(&base_url)?;
+        let indexability = self.with_locked_html(|html| robots::indexability(html, &base_url));
+
+        let hash = RHash::new();
+        hash.aset(
+            ruby.to_symbol("indexable"),
+            indexability.verdict == robots::Verdict::Indexable,
+        )?;
+        hash.aset(
+            ruby.to_symbol("verdict"),
+            ruby.to_symbol(indexability.verdict.name()),
+        )?;
+        hash.aset(ruby.to_symbol("follow"), indexability.follow)?;
+        hash.aset(ruby.to_symbol("canonical_url"), indexability.canonical_url)?;
+        hash.aset(ruby.to_symbol("reasons"), indexability.reasons)?;
+
+        Ok(hash)
+    }
+
+    fn images(&self) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let images = self.with_locked_html(images::images);
+
+        let candidates = |candidates: Vec<images::ImageCandidate>| -> Result<RArray, Error> {
+            let array = RArray::new();
+            for candidate in candidates {
+                let hash = RHash::new();
+                hash.aset(ruby.to_symbol("url"), candidate.url)?;
+                hash.aset(ruby.to_symbol("width"), candidate.width)?;
+                hash.aset(ruby.to_symbol("height"), candidate.height)?;
+                hash.aset(ruby.to_symbol("density"), candidate.density)?;
+                array.push(hash)?;
+            }
+            Ok(array)
+        };
+
+        let result = RArray::new();
+        for image in images {
+            let sources = RArray::new();
+            for source in image.sources {
+                let hash = RHash::new();
+                hash.aset(ruby.to_symbol("srcset"), candidates(source.srcset)?)?;
+                hash.aset(ruby.to_symbol("sizes"), source.sizes)?;
+                hash.aset(ruby.to_symbol("media"), source.media)?;
+                hash.aset(ruby.to_symbol("type"), source.mime_type)?;
+                sources.push(hash)?;
+            }
+
+            let hash = RHash::new();
+            hash.aset(ruby.to_symbol("src"), image.src)?;
+            hash.aset(ruby.to_symbol("alt"), image.alt)?;
+            hash.aset(ruby.to_symbol("width"), image.width)?;
+            hash.aset(ruby.to_symbol("height"), image.height)?;
+            hash.aset(ruby.to_symbol("srcset"), candidates(image.srcset)?)?;
+            hash.aset(ruby.to_symbol("sizes"), image.sizes)?;
+            hash.aset(ruby.to_symbol("sources"), sources)?;
+            result.push(hash)?;
+        }
+
+        Ok(result)
+    }
+
+    fn forms(&self) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let forms = self.with_locked_html(forms::forms);
+
+        let result = RArray::new();
+        for form in forms {
+            let fields = RArray::new();
+            for field in form.fields {
+                let options = RArray::new();
+                for option in field.options {
+                    let hash = RHash::new();
+                    hash.aset(ruby.to_symbol("value"), option.value)?;
+                    hash.aset(ruby.to_symbol("text"), option.text)?;
+                    hash.aset(ruby.to_symbol("selected"), option.selected)?;
+                    hash.aset(ruby.to_symbol("disabled"), option.disabled)?;
+                    options.push(hash)?;
+                }
+
+                let hash = RHash::new();
+                hash.aset(ruby.to_symbol("type"), field.field_type)?;
+                hash.aset(ruby.to_symbol("name"), field.name)?;
+                hash.aset(ruby.to_symbol("value"), field.value)?;
+                hash.aset(ruby.to_symbol("checked"), field.checked)?;
+                hash.aset(ruby.to_symbol("disabled"), field.disabled)?;
+                hash.aset(ruby.to_symbol("options"), options)?;
+                fields.push(hash)?;
+            }
+
+            let hash = RHash::new();
+            hash.aset(ruby.to_symbol("action"), form.action)?;
+            hash.aset(ruby.to_symbol("method"), form.method)?;
+            hash.aset(ruby.to_symbol("enctype"), form.enctype)?;
+            hash.aset(ruby.to_symbol("fields"), fields)?;
+            result.push(hash)?;
+        }
+
+        Ok(result)
+    }
+
+    fn outline(&self) -> Result<RArray, Error> {
+        fn headings(ruby: &Ruby, list: Vec<outline::Heading>) -> Result<RArray, Error> {
+            let array = RArray::new();
+            for heading in list {
+                let hash = RHash::new();
+                hash.aset(ruby.to_symbol("level"), heading.level)?;
+                hash.aset(ruby.to_symbol("text"), heading.text)?;
+                hash.aset(ruby.to_symbol("id"), heading.id)?;
+                hash.aset(
+                    ruby.to_symbol("children"),
+                    headings(ruby, heading.children)?,
+                )?;
+                array.push(hash)?;
+            }
+            Ok(array)
+        }
+
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        headings(&ruby, self.with_locked_html(outline::outline))
+    }
+
+    fn chunks(&self, args: &[Value]) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let options = chunk_options(args)?;
+        let chunks = self.with_locked_html(|html| chunk::chunks(html, options));
+        let array = RArray::with_capacity(chunks.len());
+
+        for chunk in chunks {
+            let hash = RHash::new();
+            hash.aset(ruby.to_symbol("text"), chunk.text)?;
+            hash.aset(ruby.to_symbol("tokens"), chunk.tokens)?;
+            hash.aset(ruby.to_symbol("paths"), chunk.paths)?;
+            array.push(hash)?;
+        }
+
+        Ok(array)
+    }
+
+    fn probable_type(&self) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let classification = self.with_locked_html(classify::classify);
+
+        let evidence = RArray::with_capacity(classification.evidence.len());
+        for item in &classification.evidence {
+            let hash = RHash::new();
+            hash.aset(
+                ruby.to_symbol("type"),
+                ruby.to_symbol(item.page_type.name()),
+            )?;
+            hash.aset(ruby.to_symbol("weight"), item.weight)?;
+            hash.aset(ruby.to_symbol("reason"), item.description.as_str())?;
+            evidence.push(hash)?;
+        }
+
+        let scores = RHash::new();
+        for page_type in classify::PageType::ALL {
+            scores.aset(
+                ruby.to_symbol(page_type.name()),
+                classification.score(page_type),
+            )?;
+        }
+
+        let hash = RHash::new();
+        hash.aset(
+            ruby.to_symbol("type"),
+            ruby.to_symbol(classification.page_type.map_or("unknown", |t| t.name())),
+        )?;
+        hash.aset(ruby.to_symbol("evidence"), evidence)?;
+        hash.aset(ruby.to_symbol("scores"), scores)?;
+
+        Ok(hash)
+    }
+
+    fn strip(rb_self: Obj<Self>, args: &[Value]) -> Result<Obj<Self>, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let args = scan_args::<(), (), RArray, (), (), ()>(args)?;
+
+        let categories = if args.splat.is_empty() {
+            strip::Category::ALL.to_vec()
+        } else {
+            args.splat
+                .into_iter()
+                .map(|value| {
+                    let name = Symbol::try_convert(value)?.name()?;
+
+                    strip::Category::from_name(&name).ok_or_else(|| {
+                        let expected = strip::Category::ALL
+                            .map(|category| format!(":{}", category.name()))
+                            .join(", ");
+
+                        Error::new(
+                            ruby.exception_arg_error(),
+                            format!("unknown category :{name}, expected one of {expected}"),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()?
+        };
+
+        rb_self.with_locked_html_mut(|html| strip::strip(html, &categories))?;
+
+        Ok(rb_self)
+    }
+
+    fn remove(&self, css_selector: String) -> Result<RArray, Error> {
+        let selector = QuerySelector::parse(&css_selector, None)?;
+
+        let ids = self.with_locked_html_mut(|html| {
+            let ids: Vec<NodeId> = selector
+                .select(html.root_element())
+                .map(|element_ref| element_ref.id())
+                .collect();
+            dom::detach_all(html, &ids);
+
+            ids
+        })?;
+
+        let document = self.element_document();
+        Ok(ids
+            .into_iter()
+            .map(|id| Element {
+                id,
+                document: document.clone(),
+            })
+            .collect())
+    }
+
+    fn inline_css(rb_self: Obj<Self>) -> Result<Obj<Self>, Error> {
+        rb_self.with_locked_html_mut(css_inliner::inline_css)?;
+
+        Ok(rb_self)
+    }
+
+    fn absolutize_urls(rb_self: Obj<Self>, base_url: String) -> Result<Obj<Self>, Error> {
+        let base_url = parse_base_url(&base_url)?;
+
+        rb_self.with_locked_html_mut(|html| absolutize::absolutize_urls(html, &base_url))?;
+
+        Ok(rb_self)
+    }
+
+    fn base_uri(&self, args: &[Value]) -> Result<Option<String>, Error> {
+        let args = scan_args::<(), (Option<String>,), (), (), (), ()>(args)?;
+        let (document_url,) = args.optional;
+
+        let document_url = document_url
+            .map(|document_url| parse_base_url(&document_url))
+            .transpose()?;
+
+        Ok(self.with_locked_html(|html| {
+            absolutize::document_base_url(html, document_url.as_ref()).map(String::from)
+        }))
+    }
+
+    fn concat(rb_self: Obj<Self>, other: Value) -> Result<Document, Error> {
+        concat(vec![rb_self.as_value(), other])
+    }
+
+    fn is_equivalent_to(&self, args: &[Value]) -> Result<bool, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let args = scan_args::<(&Document,), (), (), (), _, ()>(args)?;
+        let (other,) = args.required;
+        let (ignore_whitespace, ignore_attr_order, ignore_comments): (
+            Option<bool>,
+            Option<bool>,
+            Option<bool>,
+        ) = get_options(
+            args.keywords,
+            &["ignore_whitespace", "ignore_attr_order", "ignore_comments"],
+        )?;
+
+        // Elements keep their attributes sorted by name, so the order they
+        // were written in can't be compared
+        if ignore_attr_order == Some(false) {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "ignore_attr_order: false isn't supported, attributes are kept sorted by name",
+            ));
+        }
+
+        let options = equivalence::Options {
+            ignore_whitespace: ignore_whitespace.unwrap_or(true),
+            ignore_comments: ignore_comments.unwrap_or(true),
+        };
+
+        // A document is equivalent to itself, and can't be locked twice
+        if Arc::ptr_eq(&self.html, &other.html) {
+            return Ok(true);
+        }
+
+        let (html, other_html) = SharedHtml::read_pair(&self.html, &other.html);
+        let compare = || equivalence::equivalent(html.tree.root(), other_html.tree.root(), options);
+
+        Ok(
+            if html.tree.nodes().len() + other_html.tree.nodes().len() >= SELECT_WITHOUT_GVL_NODES {
+                gvl::without_gvl(compare)
+            } else {
+                compare()
+            },
+        )
+    }
+
+    fn create_element(&self, args: &[Value]) -> Result<Element, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let args = scan_args::<(String,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (name,) = args.required;
+        let (attrs,) = args.optional;
+
+        if !dom::is_valid_element_name(&name) {
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                format!("invalid element name {name:?}"),
+            ));
+        }
+
+        let name = name.to_ascii_lowercase();
+        let mut attributes = Vec::new();
+
+        if let Some(attrs) = attrs {
+            attrs.foreach(|key: Value, value: String| {
+                let key = match Symbol::from_value(key) {
+                    Some(symbol) => symbol.name()?.into_owned(),
+                    None => String::try_convert(key)?,
+                };
+
+                attributes.push((attribute_name_arg(&key)?, value));
+
+                Ok(ForEach::Continue)
+            })?;
+        }
+
+        let id = self.with_locked_html_mut(|html| dom::create_element(html, &name, &attributes))?;
+
+        Ok(Element {
+            id,
+            document: self.element_document(),
+        })
+    }
+
+    fn create_text_node(&self, text: String) -> Result<TextNode, Error> {
+        let id = self.with_locked_html_mut(|html| dom::create_text(html, &text))?;
+
+        Ok(TextNode {
+            id,
+            document: self.element_document(),
+        })
+    }
+
+    fn each_node(rb_self: Obj<Self>) -> Result<Value, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("each_node", ()).as_value());
+        }
+
+        // Wrapping the nodes up front lets the block use the document without
+        // deadlocking
+        let document = rb_self.element_document();
+        let nodes: Vec<Value> = rb_self.with_locked_html(|html| {
+            html.tree
+                .root()
+                .descendants()
+                .filter_map(|node| wrap_node(&ruby, node, &document))
+                .collect()
+        });
+
+        for node in nodes {
+            ruby.yield_value::<_, Value>(node)?;
+        }
+
+        Ok(rb_self.as_value())
+    }
+
+    fn each_element(rb_self: Obj<Self>) -> Result<Value, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("each_element", ()).as_value());
+        }
+
+        // Only the ids are gathered up front, elements are wrapped as they're
+        // reached so that skipped ones cost nothing
+        let elements = rb_self.with_locked_html(|html| {
+            // Fragments leave out the `<html>` element they're wrapped in
+            if html.tree.root().value().is_fragment() {
+                dom::descendant_elements(*html.root_element())
+            } else {
+                dom::descendant_elements(html.tree.root())
+            }
+        });
+        yield_elements(&ruby, elements, &rb_self.element_document())?;
+
+        Ok(rb_self.as_value())
+    }
+
+    fn doctype(&self) -> Result<Option<RHash>, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let doctype = self.with_locked_html(|html| {
+            html.tree.root().children().find_map(|node| {
+                node.value().as_doctype().map(|doctype| {
+                    [doctype.name(), doctype.public_id(), doctype.system_id()]
+                        .map(|value| (!value.is_empty()).then(|| value.to_string()))
+                })
+            })
+        });
+
+        let Some([name, public_id, system_id]) = doctype else {
+            return Ok(None);
+        };
+
+        let hash = RHash::new();
+        hash.aset(ruby.to_symbol("name"), name)?;
+        hash.aset(ruby.to_symbol("public_id"), public_id)?;
+        hash.aset(ruby.to_symbol("system_id"), system_id)?;
+
+        Ok(Some(hash))
+    }
+
+    fn encoding(&self) -> Option<&'static str> {
+        self.encoding
+    }
+
+    fn quirks_mode(&self) -> Symbol {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        ruby.to_symbol(match self.with_locked_html(|html| html.quirks_mode) {
+            QuirksMode::Quirks => "quirks",
+            QuirksMode::LimitedQuirks => "limited_quirks",
+            QuirksMode::NoQuirks => "no_quirks",
+        })
+    }
+
+    fn is_quirks_mode(&self) -> bool {
+        self.with_locked_html(|html| html.quirks_mode == QuirksMode::Quirks)
+    }
+
+    fn comments(&self) -> RArray {
+        self.with_locked_html(|html| {
+            html.tree
+                .root()
+                .descendants()
+                .filter(|node| node.value().is_comment())
+                .map(|node| Comment {
+                    id: node.id(),
+                    document: self.element_document(),
+                })
+                .collect()
+        })
+    }
+
+    fn tag_counts(&self) -> Result<RHash, Error> {
+        let html = self.html.read();
+        let hash = RHash::new();
+
+        for (name, count) in html.tag_counts() {
+            hash.aset(interned_str(name), *count)?;
+        }
+
+        Ok(hash)
+    }
+
+    fn stats(&self) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let html = self.html.read();
+        let stats = html.stats();
+
+        let nodes = RHash::new();
+        nodes.aset(ruby.to_symbol("element"), stats.elements)?;
+        nodes.aset(ruby.to_symbol("text"), stats.texts)?;
+        nodes.aset(ruby.to_symbol("comment"), stats.comments)?;
+        nodes.aset(ruby.to_symbol("doctype"), stats.doctypes)?;
+        nodes.aset(
+            ruby.to_symbol("processing_instruction"),
+            stats.processing_instructions,
+        )?;
+
+        let tags = RHash::new();
+        for (name, count) in &stats.tags {
+            tags.aset(interned_str(name), *count)?;
+        }
+
+        let hash = RHash::new();
+        hash.aset(ruby.to_symbol("nodes"), nodes)?;
+        hash.aset(ruby.to_symbol("tags"), tags)?;
+        hash.aset(ruby.to_symbol("max_depth"), stats.max_depth)?;
+        hash.aset(ruby.to_symbol("text_length"), stats.text_length)?;
+
+        Ok(hash)
+    }
+
+    fn errors(&self) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let array = RArray::with_capacity(self.errors.len());
+
+        for error in self.errors.iter() {
+            let hash = RHash::new();
+            hash.aset(ruby.to_symbol("message"), &*error.message)?;
+            hash.aset(ruby.to_symbol("line"), error.line)?;
+            array.push(hash)?;
+        }
+
+        Ok(array)
+    }
+
+    fn repairs(&self) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let array = RArray::with_capacity(self.repairs.len());
+
+        for repair in self.repairs.iter() {
+            let hash = RHash::new();
+            hash.aset(ruby.to_symbol("type"), ruby.to_symbol(repair.kind.name()))?;
+            hash.aset(ruby.to_symbol("count"), repair.count)?;
+            array.push(hash)?;
+        }
+
+        Ok(array)
+    }
+
+    fn annotations(&self) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let hash = RHash::new();
+
+        let annotated: Vec<(NodeId, Value)> = self.with_locked_html(|html| {
+            html.tree
+                .root()
+                .descendants()
+                .filter(|node_ref| node_ref.value().is_element())
+                .filter_map(|node_ref| {
+                    let annotation = self.annotations.get(node_ref.id())?;
+
+                    Some((node_ref.id(), annotation))
+                })
+                .collect()
+        });
+
+        for (id, annotation) in annotated {
+            let element = Element {
+                id,
+                document: self.element_document(),
+            };
+            hash.aset(ruby.into_value(element), annotation)?;
+        }
+
+        Ok(hash)
+    }
+
+    fn to_html(&self, args: &[Value]) -> Result<String, Error> {
+        let args = scan_args::<(), (), (), (), _, ()>(args)?;
+        let (pretty, indent, minify, xhtml): (
+            Option<bool>,
+            Option<usize>,
+            Option<bool>,
+            Option<bool>,
+        ) = get_options(args.keywords, &["pretty", "indent", "minify", "xhtml"])?;
+
+        let options = serialize::Options {
+            indent: pretty.unwrap_or(false).then(|| indent.unwrap_or(2)),
+            minify: minify.unwrap_or(false),
+            xhtml: xhtml.unwrap_or(false),
+        };
+
+        if options.indent.is_some() && options.minify {
+            let ruby = Ruby::get().expect("called from non-ruby thread");
+
+            return Err(Error::new(
+                ruby.exception_arg_error(),
+                "pretty and minify can't be combined",
+            ));
+        }
+
+        Ok(self.with_locked_html(|html| serialize::to_html(html, &options)))
+    }
+
+    fn to_h(&self) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        self.with_locked_html(|html| snapshot_hash(&ruby, *html.root_element()))
+    }
+
+    fn to_json(&self, _args: &[Value]) -> String {
+        self.with_locked_html(|html| snapshot::to_json(*html.root_element()))
+    }
+
+    fn dump(&self, _level: i32) -> Result<String, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        let mode = self.with_locked_html(|html| {
+            if html.root_element().value().name.ns != ns!(html) {
+                None
+            } else if html.tree.root().value().is_fragment() {
+                Some("fragment")
+            } else {
+                Some("document")
+            }
+        });
+
+        // XML names lose their prefixes when serialized as HTML
+        let mode = mode.ok_or_else(|| {
+            Error::new(ruby.exception_type_error(), "XML documents can't be dumped")
+        })?;
+
+        let html = self.with_locked_html(|html| serialize::to_html(html, &Default::default()));
+
+        Ok(format!("{mode}\n{html}"))
+    }
+
+    fn load(data: String) -> Result<Self, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        let parsed = match data.split_once('\n') {
+            Some(("fragment", html)) => parse_html(html, true),
+            Some(("document", html)) => parse_html(html, false),
+            _ => {
+                return Err(Error::new(
+                    ruby.exception_arg_error(),
+                    "invalid dumped document",
+                ))
+            }
+        };
+
+        // Errors and lines of the serialized HTML aren't those of the
+        // original input
+        Ok(Document::new(parsed.html, weak_elements_option(None)))
+    }
+}
+
+/// Parses an absolute URL that relative URLs can be resolved against
+fn parse_base_url(url: &str) -> Result<Url, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+
+    Url::parse(url)
+        .ok()
+        .filter(|url| !url.cannot_be_a_base())
+        .ok_or_else(|| {
+            Error::new(
+                ruby.exception_arg_error(),
+                format!("invalid base URL {url:?}"),
+            )
+        })
+}
+
+fn normalize_url(args: &[Value]) -> Result<String, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let args = scan_args::<_, (), (), (), _, ()>(args)?;
+    let (url,): (String,) = args.required;
+    let (sort_query,): (Option<bool>,) = get_options(args.keywords, &["sort_query"])?;
+
+    let parsed_url = Url::parse(&url)
+        .map_err(|_| Error::new(ruby.exception_arg_error(), format!("invalid URL {url:?}")))?;
+
+    Ok(normalize_url::normalize_url(parsed_url, sort_query.unwrap_or(false)).into())
+}
+
+/// Extracts the selector and the `attr_matches:` and `detach:` options
+/// passed to `select`
+fn select_args(
+    args: &[Value],
+) -> Result<(QuerySelector, Option<attr_regex::AttrMatches>, bool), Error> {
+    let args = scan_args::<_, (), (), (), _, ()>(args)?;
+    let (css_selector,): (String,) = args.required;
+    let (detach, namespaces, attr_matches): (Option<bool>, Option<RHash>, Option<RHash>) =
+        get_options(args.keywords, &["detach", "namespaces", "attr_matches"])?;
+
+    Ok((
+        QuerySelector::parse(&css_selector, namespaces)?,
+        attr_matches.map(parse_attr_matches).transpose()?,
+        detach.unwrap_or(false),
+    ))
+}
+
+/// Converts the `attr_matches:` option, which maps attribute names to the
+/// `Regexp` their values must match
+fn parse_attr_matches(hash: RHash) -> Result<attr_regex::AttrMatches, Error> {
+    let mut attr_matches = attr_regex::AttrMatches::default();
+
+    hash.foreach(|name: Value, regexp: RRegexp| {
+        let name = match Symbol::from_value(name) {
+            Some(symbol) => symbol.name()?.into_owned(),
+            None => String::try_convert(name)?,
+        };
+        attr_matches.push(name, regexp_to_regex(regexp)?);
+
+        Ok(ForEach::Continue)
+    })?;
+
+    Ok(attr_matches)
+}
+
+/// Builds a Rust regex that matches like the given Ruby `Regexp`
+fn regexp_to_regex(regexp: RRegexp) -> Result<regex::Regex, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let source: String = regexp.funcall("source", ())?;
+    let options: u32 = regexp.funcall("options", ())?;
+
+    attr_regex::from_ruby(&source, options).map_err(|e| {
+        Error::new(
+            ruby.exception_arg_error(),
+            format!("unsupported regexp {}\n{e}", regexp.inspect()),
+        )
+    })
+}
+
+fn matches_attrs(attr_matches: Option<&attr_regex::AttrMatches>, element_ref: &ElementRef) -> bool {
+    attr_matches.map_or(true, |attr_matches| {
+        attr_matches.matches(element_ref.value())
+    })
+}
+
+/// Extracts the selector and the `null_object:` option passed to `at`
+fn at_args(args: &[Value]) -> Result<(QuerySelector, bool), Error> {
+    let args = scan_args::<_, (), (), (), _, ()>(args)?;
+    let (css_selector,): (String,) = args.required;
+    let (null_object, namespaces): (Option<bool>, Option<RHash>) =
+        get_options(args.keywords, &["null_object", "namespaces"])?;
+
+    Ok((
+        QuerySelector::parse(&css_selector, namespaces)?,
+        null_object.unwrap_or(false),
+    ))
+}
+
+/// A selector passed to `select` or `at`, which can use the namespace
+/// prefixes given with the `namespaces:` option
+enum QuerySelector {
+    Plain(Selector),
+    /// A selector that can be answered from the document's selector index
+    Simple(Selector, SimpleSelector),
+    /// A selector using the non-standard `:contains` pseudo-class
+    Contains(contains::ContainsSelector),
+    Namespaced(namespaces::NamespacedSelector),
+}
+
+impl QuerySelector {
+    fn parse(css_selector: &str, namespaces: Option<RHash>) -> Result<Self, Error> {
+        let Some(namespaces) = namespaces else {
+            if let Some(selector) = contains::ContainsSelector::parse(css_selector)
+                .map_err(|e| selector_error(css_selector, e))?
+            {
+                return Ok(Self::Contains(selector));
+            }
+
+            let selector = parse_selector(css_selector)?;
+
+            return Ok(match SimpleSelector::parse(css_selector) {
+                Some(simple) => Self::Simple(selector, simple),
+                None => Self::Plain(selector),
+            });
+        };
+
+        let mut prefixes = Vec::new();
+        namespaces.foreach(|prefix: Value, url: String| {
+            let prefix = match Symbol::from_value(prefix) {
+                Some(symbol) => symbol.name()?.into_owned(),
+                None => String::try_convert(prefix)?,
+            };
+            prefixes.push((prefix, url));
+
+            Ok(ForEach::Continue)
+        })?;
+
+        namespaces::NamespacedSelector::parse(css_selector, &prefixes)
+            .map(Self::Namespaced)
+            .map_err(|e| selector_error(css_selector, e))
+    }
+
+    /// Returns the descendants of `scope` matching the selector, in order
+    fn select<'a, 'b>(
+        &'b self,
+        scope: ElementRef<'a>,
+    ) -> Box<dyn Iterator<Item = ElementRef<'a>> + 'b>
+    where
+        'a: 'b,
+    {
+        match self {
+            Self::Plain(selector) | Self::Simple(selector, _) => Box::new(scope.select(selector)),
+            Self::Contains(selector) => Box::new(selector.select(scope)),
+            Self::Namespaced(selector) => Box::new(namespaces::select(scope, selector)),
+        }
+    }
+
+    /// Returns the elements of the document matching the selector from its
+    /// selector index, if it has one and the selector is simple enough
+    fn indexed<'a>(&self, html: &'a CachedHtml) -> Option<impl Iterator<Item = ElementRef<'a>>> {
+        let Self::Simple(_, selector) = self else {
+            return None;
+        };
+
+        let ids = html.selector_index()?.get(selector);
+
+        Some(
+            ids.iter()
+                .filter_map(|&id| html.tree.get(id).and_then(ElementRef::wrap)),
+        )
+    }
+}
+
+/// Returns the element found by a single-result accessor, falling back to a
+/// `Sawzall::NullElement` rather than `nil` with the `null_object:` option
+fn element_or_null(element: Option<Element>, null_object: bool) -> Result<Value, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+
+    match element {
+        Some(element) => Ok(ruby.into_value(element)),
+        None if null_object => ruby.get_inner(&NULL_ELEMENT).new_instance(()),
+        None => Ok(ruby.qnil().as_value()),
+    }
+}
+
+fn parse_selector(css_selector: &str) -> Result<Selector, Error> {
+    Selector::parse(css_selector).map_err(|e| selector_error(css_selector, e))
+}
+
+fn selector_error(css_selector: &str, e: impl fmt::Display) -> Error {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+
+    Error::new(
+        ruby.exception_arg_error(),
+        format!("failed to parse selector {css_selector:?}\n{e}"),
+    )
+}
+
+/// Builds the `Sawzall::NotFound` raised when nothing in `scope` matches the
+/// selector, pointing at the element matched by its longest matching prefix
+fn selector_not_found(
+    css_selector: &str,
+    document: DocumentRef,
+    scope: ElementRef,
+) -> Result<Error, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let options = RHash::new();
+    options.aset(ruby.to_symbol("selector"), css_selector)?;
+
+    let nearest_match = not_found::selector_prefixes(css_selector)
+        .into_iter()
+        .find_map(|prefix| {
+            let selector = Selector::parse(prefix).ok()?;
+            scope
+                .select(&selector)
+                .next()
+                .map(|element_ref| (prefix, element_ref))
+        });
+
+    let message = match nearest_match {
+        Some((prefix, element_ref)) => {
+            let snippet = not_found::snippet(&element_ref.html());
+            let message =
+                format!("no element matches {css_selector:?}, but {prefix:?} matches {snippet}");
+
+            options.aset(
+                ruby.to_symbol("nearest_match"),
+                Element {
+                    id: element_ref.id(),
+                    document,
+                },
+            )?;
+            options.aset(ruby.to_symbol("snippet"), snippet)?;
+
+            message
+        }
+        None => {
+            let snippet = not_found::snippet(&scope.html());
+            let message = format!("no element matches {css_selector:?} in {snippet}");
+
+            options.aset(ruby.to_symbol("snippet"), snippet)?;
+
+            message
+        }
+    };
+
+    not_found_error(message, options)
+}
+
+fn not_found_error(message: String, options: RHash) -> Result<Error, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+
+    Ok(ruby
+        .get_inner(&NOT_FOUND)
+        .new_instance((message, KwArgs(options)))?
+        .into())
+}
+
+/// Returns the descendants of `element_ref` matching the selector and
+/// `attr_matches`. When `detach` is set, each match is copied into its own
+/// document so that the original one can be freed.
+fn select(
+    selector: &QuerySelector,
+    attr_matches: Option<&attr_regex::AttrMatches>,
+    detach: bool,
+    document: DocumentRef,
+    element_ref: ElementRef,
+) -> RArray {
+    let search = || {
+        selector
+            .select(element_ref)
+            .filter(|element_ref| matches_attrs(attr_matches, element_ref))
+            .collect::<Vec<_>>()
+    };
+    let matches = if element_ref.tree().nodes().len() >= SELECT_WITHOUT_GVL_NODES {
+        gvl::without_gvl(search)
+    } else {
+        search()
+    };
+
+    wrap_matches(matches, detach, document)
+}
+
+/// Wraps the elements matched by `select`, copying each into its own
+/// document when `detach` is set
+fn wrap_matches(matches: Vec<ElementRef>, detach: bool, document: DocumentRef) -> RArray {
+    matches
+        .into_iter()
+        .map(|matching_element_ref| {
+            if detach {
+                let (html, id) = dom::copy_subtree(matching_element_ref);
+
+                Element {
+                    id,
+                    document: DocumentRef::Strong(Document::new(html, false)),
+                }
+            } else {
+                Element {
+                    id: matching_element_ref.id(),
+                    document: document.clone(),
+                }
+            }
+        })
+        .collect()
+}
+
+/// Builds the nested hashes of `Element#to_h`, with the same shape as
+/// [`snapshot::to_json`]. Like it, the tree is walked without recursion.
+fn snapshot_hash(ruby: &Ruby, node: NodeRef<Node>) -> Result<RHash, Error> {
+    let snapshot = RHash::new();
+    let mut parents: Vec<RArray> = Vec::new();
+
+    for edge in node.traverse() {
+        match edge {
+            Edge::Open(node) => {
+                if !matches!(node.value(), Node::Element(_) | Node::Text(_)) {
+                    continue;
+                }
+
+                let hash = if parents.is_empty() {
+                    snapshot
+                } else {
+                    RHash::new()
+                };
+
+                match node.value() {
+                    Node::Element(element) => {
+                        let attrs = RHash::new();
+                        for (name, value) in element.attrs.iter() {
+                            attrs.aset(
+                                interned_str(&dom::attribute_name(name)),
+                                RString::new(value),
+                            )?;
+                        }
+
+                        let children = RArray::new();
+                        hash.aset(ruby.to_symbol("name"), interned_str(element.name()))?;
+                        hash.aset(ruby.to_symbol("attrs"), attrs)?;
+                        hash.aset(ruby.to_symbol("children"), children)?;
+
+                        if let Some(parent) = parents.last() {
+                            parent.push(hash)?;
+                        }
+                        parents.push(children);
+                    }
+                    Node::Text(text) => {
+                        hash.aset(ruby.to_symbol("text"), RString::new(text))?;
+
+                        if let Some(parent) = parents.last() {
+                            parent.push(hash)?;
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            Edge::Close(node) => {
+                if node.value().is_element() {
+                    parents.pop();
+                }
+            }
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// Evaluates an XPath expression from `context`, returning nodes wrapped like
+/// [`wrap_node`] does and attributes as their values
+fn evaluate_xpath(
+    expr: &str,
+    html: &Html,
+    context: NodeId,
+    document: &DocumentRef,
+) -> Result<Value, Error> {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    let xpath_error = |message: String| {
+        Error::new(
+            ruby.exception_arg_error(),
+            format!("failed to evaluate XPath {expr:?}\n{message}"),
+        )
+    };
+
+    let value = xpath::XPath::parse(expr)
+        .map_err(|e| xpath_error(e.to_string()))?
+        .evaluate(html, context)
+        .map_err(|e| xpath_error(e.to_string()))?;
+
+    Ok(match value {
+        xpath::Value::Nodes(nodes) => {
+            let array = RArray::with_capacity(nodes.len());
+
+            for node in nodes {
+                let value = match node {
+                    xpath::XNode::Node(id) => html
+                        .tree
+                        .get(id)
+                        .and_then(|node| wrap_node(&ruby, node, document)),
+                    xpath::XNode::Attribute(..) => {
+                        Some(ruby.into_value(xpath::string_value(html, node)))
+                    }
+                };
+
+                if let Some(value) = value {
+                    array.push(value)?;
+                }
+            }
+
+            array.as_value()
+        }
+        xpath::Value::Boolean(boolean) => ruby.into_value(boolean),
+        xpath::Value::Number(number) => ruby.into_value(number),
+        xpath::Value::String(string) => ruby.into_value(string),
+    })
+}
+
+/// Wraps a node in the class for its type, or returns `None` for the
+/// document node itself and processing instructions
+fn wrap_node(ruby: &Ruby, node: NodeRef<Node>, document: &DocumentRef) -> Option<Value> {
+    let id = node.id();
+    let document = document.clone();
+
+    match node.value() {
+        Node::Element(_) => Some(ruby.into_value(Element { id, document })),
+        Node::Text(_) => Some(ruby.into_value(TextNode { id, document })),
+        Node::Comment(_) => Some(ruby.into_value(Comment { id, document })),
+        Node::Doctype(_) => Some(ruby.into_value(Doctype { id, document })),
+        _ => None,
+    }
+}
+
+/// Yields the elements listed by [`dom::descendant_elements`], skipping the
+/// descendants of those for which the block returns `:skip_children`
+fn yield_elements(
+    ruby: &Ruby,
+    elements: Vec<(NodeId, usize)>,
+    document: &DocumentRef,
+) -> Result<(), Error> {
+    let skip_children = ruby.to_symbol("skip_children");
+    let mut position = 0;
+
+    while let Some(&(id, after)) = elements.get(position) {
+        let element = Element {
+            id,
+            document: document.clone(),
+        };
+        let result = ruby.yield_value::<_, Value>(element)?;
+
+        position = if Symbol::from_value(result) == Some(skip_children) {
+            after
+        } else {
+            position + 1
+        };
+    }
+
+    Ok(())
+}
+
+/// Ruby values attached to elements, keyed by node so that they're found
+/// again from any wrapper of the same element
+///
+/// The values are marked by the document and by the nodes that keep it alive,
+/// so that they live as long as the document does.
+#[derive(Default)]
+struct Annotations(Mutex<HashMap<NodeId, Opaque<Value>>>);
+
+impl Annotations {
+    fn get(&self, id: NodeId) -> Option<Value> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let annotation = *self.0.lock().expect("failed to lock mutex").get(&id)?;
+
+        Some(ruby.get_inner(annotation))
+    }
+
+    fn set(&self, id: NodeId, annotation: Option<Value>) {
+        let mut annotations = self.0.lock().expect("failed to lock mutex");
+
+        match annotation {
+            Some(annotation) => annotations.insert(id, Opaque::from(annotation)),
+            None => annotations.remove(&id),
+        };
+    }
+
+    /// Copies the table, sharing the values themselves like `Hash#dup`
+    fn copy(&self) -> Self {
+        Self(Mutex::new(
+            self.0.lock().expect("failed to lock mutex").clone(),
+        ))
+    }
+
+    fn mark(&self, marker: &gc::Marker) {
+        for annotation in self.0.lock().expect("failed to lock mutex").values() {
+            marker.mark(*annotation);
+        }
+    }
+}
+
+/// The document an [`Element`] belongs to
+#[derive(Clone)]
+enum DocumentRef {
+    Strong(Document),
+    Weak(Weak<SharedHtml>, Weak<Annotations>),
+}
+
+impl DocumentRef {
+    fn upgrade(&self) -> Result<Arc<SharedHtml>, Error> {
+        self.html().ok_or_else(stale_document_error)
+    }
+
+    /// Returns the document's tree unless it was garbage collected
+    fn html(&self) -> Option<Arc<SharedHtml>> {
+        match self {
+            DocumentRef::Strong(document) => Some(document.html.clone()),
+            DocumentRef::Weak(html, _) => html.upgrade(),
+        }
+    }
+
+    /// Freezes the document's tree, which nodes could otherwise still modify
+    /// after being frozen themselves
+    fn freeze(&self) {
+        if let Some(html) = self.html() {
+            html.freeze();
+        }
+    }
+
+    fn annotations(&self) -> Result<Arc<Annotations>, Error> {
+        match self {
+            DocumentRef::Strong(document) => Ok(document.annotations.clone()),
+            DocumentRef::Weak(_, annotations) => {
+                annotations.upgrade().ok_or_else(stale_document_error)
+            }
+        }
+    }
+
+    /// Marks the annotations of a document the node keeps alive. Weak
+    /// references don't need to, since the document marks them itself.
+    fn mark(&self, marker: &gc::Marker) {
+        if let DocumentRef::Strong(document) = self {
+            document.annotations.mark(marker);
+        }
+    }
+
+    fn with_node_ref<U, F>(&self, id: NodeId, f: F) -> Result<U, Error>
+    where
+        F: FnOnce(NodeRef<Node>) -> U,
+    {
+        let document = self.upgrade()?;
+        let html = document.read();
+        let node_ref = html
+            .tree
+            .get(id)
+            .expect("node with id {id} must be in the tree");
+
+        Ok(f(node_ref))
+    }
+}
+
+/// Locks a document's tree for modifying it, raising a `FrozenError` if the
+/// document is frozen
+fn lock_unfrozen(html: &SharedHtml) -> Result<RwLockWriteGuard<'_, CachedHtml>, Error> {
+    let html = html.write();
+
+    if html.is_frozen() {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        return Err(Error::new(
+            ruby.exception_frozen_error(),
+            "can't modify frozen Sawzall::Document",
+        ));
+    }
+
+    Ok(html)
+}
+
+fn stale_document_error() -> Error {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+
+    Error::new(
+        ruby.get_inner(&STALE_DOCUMENT_ERROR),
+        "the element's document has been garbage collected",
+    )
+}
+
+#[derive(TypedData)]
+#[magnus(
+    class = "Sawzall::Element",
+    free_immediately,
+    mark,
+    size,
+    frozen_shareable
+)]
+struct Element {
+    id: NodeId,
+    document: DocumentRef,
+}
+
+impl DataTypeFunctions for Element {
+    fn mark(&self, marker: &gc::Marker) {
+        self.document.mark(marker);
+    }
+
+    /// Counts the element's own node, leaving its descendants to theirs and
+    /// the rest of the tree to its document
+    fn size(&self) -> usize {
+        let node = self
+            .document
+            .html()
+            .and_then(|html| html.node_memsize(self.id));
+
+        mem::size_of::<Self>() + node.unwrap_or(0)
+    }
+}
+
+impl Element {
+    fn with_element_ref<U, F>(&self, f: F) -> Result<U, Error>
+    where
+        F: FnOnce(ElementRef) -> U,
+    {
+        let document = self.document.upgrade()?;
+        let html = document.read();
+        let element_ref = html
+            .tree
+            .get(self.id)
+            .and_then(ElementRef::wrap)
+            .expect("node with id {self.id} must be an element in the tree");
+
+        Ok(f(element_ref))
+    }
+
+    fn with_element_mut<U, F>(&self, f: F) -> Result<U, Error>
+    where
+        F: FnOnce(&mut scraper::node::Element) -> U,
+    {
+        let document = self.document.upgrade()?;
+        let mut html = lock_unfrozen(&document)?;
+        let mut node = html
+            .tree
+            .get_mut(self.id)
+            .expect("node with id {self.id} must be in the tree");
+
+        let Node::Element(element) = node.value() else {
+            unreachable!("node with id {:?} must be an element", self.id);
+        };
+
+        Ok(f(element))
+    }
+
+    fn name(&self, args: &[Value]) -> Result<Value, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let symbolize_names = symbolize_names_option(args)?;
+
+        self.with_element_ref(|element_ref| {
+            name_value(&ruby, element_ref.value().name(), symbolize_names)
+        })
+    }
+
+    fn name_sym(&self) -> Result<Symbol, Error> {
+        let name = self.with_element_ref(|element_ref| interned_str(element_ref.value().name()))?;
+
+        // SAFETY: interning a String returns a Symbol
+        let symbol = unsafe { Value::from_raw(rb_sys::rb_str_intern(name.as_raw())) };
+
+        Ok(Symbol::from_value(symbol).expect("interned strings are symbols"))
+    }
+
+    fn set_attr(rb_self: Obj<Self>, name: String, value: String) -> Result<Obj<Self>, Error> {
+        let name = attribute_name_arg(&name)?;
+
+        rb_self.with_element_mut(|element| dom::set_attr(element, &name, &value))?;
+
+        Ok(rb_self)
+    }
+
+    fn remove_attr(rb_self: Obj<Self>, name: String) -> Result<Obj<Self>, Error> {
+        let name = attribute_name_arg(&name)?;
+
+        rb_self.with_element_mut(|element| dom::remove_attr(element, &name))?;
+
+        Ok(rb_self)
+    }
+
+    fn add_class(rb_self: Obj<Self>, args: &[Value]) -> Result<Obj<Self>, Error> {
+        let args = scan_args::<(), (), Vec<String>, (), (), ()>(args)?;
+
+        check_class_names(&args.splat)?;
+        rb_self.with_element_mut(|element| dom::add_classes(element, &args.splat))?;
+
+        Ok(rb_self)
+    }
+
+    fn remove_class(rb_self: Obj<Self>, args: &[Value]) -> Result<Obj<Self>, Error> {
+        let args = scan_args::<(), (), Vec<String>, (), (), ()>(args)?;
+
+        check_class_names(&args.splat)?;
+        rb_self.with_element_mut(|element| dom::remove_classes(element, &args.splat))?;
+
+        Ok(rb_self)
+    }
+
+    fn toggle_class(rb_self: Obj<Self>, args: &[Value]) -> Result<Obj<Self>, Error> {
+        let args = scan_args::<(String,), (Option<bool>,), (), (), (), ()>(args)?;
+        let (name,) = args.required;
+        let (force,) = args.optional;
+
+        check_class_names(std::slice::from_ref(&name))?;
+        rb_self.with_element_mut(|element| dom::toggle_class(element, &name, force))?;
+
+        Ok(rb_self)
+    }
+
+    fn remove(rb_self: Obj<Self>) -> Result<Obj<Self>, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let document = rb_self.document.upgrade()?;
+        let mut html = lock_unfrozen(&document)?;
+
+        let is_root = html
+            .tree
+            .get(rb_self.id)
+            .and_then(|node| node.parent())
+            .is_some_and(|parent| parent.value().is_document() || parent.value().is_fragment());
+
+        // The document is serialized and queried from its root element
+        if is_root {
+            return Err(Error::new(
+                ruby.exception_runtime_error(),
+                "can't remove the root element",
+            ));
+        }
+
+        html.tree
+            .get_mut(rb_self.id)
+            .expect("node with id {self.id} must be in the tree")
+            .detach();
+
+        Ok(rb_self)
+    }
+
+    fn replace_with(rb_self: Obj<Self>, replacement: Value) -> Result<Obj<Self>, Error> {
+        // Copied before locking the document as the replacement may belong to it
+        let replacement = match <&Element>::try_convert(replacement) {
+            Ok(element) => element.with_element_ref(dom::copy_subtree)?.0,
+            Err(_) => fragment_arg(RString::try_convert(replacement)?)?,
+        };
+
+        let document = rb_self.document.upgrade()?;
+        let mut html = lock_unfrozen(&document)?;
+
+        check_parent_element(&html, rb_self.id, "replace")?;
+        dom::insert(
+            &mut html,
+            rb_self.id,
+            dom::Position::BeforeBegin,
+            replacement.root_element().children(),
+        );
+        html.tree
+            .get_mut(rb_self.id)
+            .expect("node with id {self.id} must be in the tree")
+            .detach();
+
+        Ok(rb_self)
+    }
+
+    fn add_child(rb_self: Obj<Self>, child: Value) -> Result<Obj<Self>, Error> {
+        rb_self.insert_node(child, |_, _| (rb_self.id, dom::Position::BeforeEnd))?;
+
+        Ok(rb_self)
+    }
+
+    fn add_previous_sibling(rb_self: Obj<Self>, node: Value) -> Result<Obj<Self>, Error> {
+        rb_self.insert_node(node, |_, _| (rb_self.id, dom::Position::BeforeBegin))?;
+
+        Ok(rb_self)
+    }
+
+    fn add_next_sibling(rb_self: Obj<Self>, node: Value) -> Result<Obj<Self>, Error> {
+        rb_self.insert_node(node, |_, _| (rb_self.id, dom::Position::AfterEnd))?;
+
+        Ok(rb_self)
+    }
+
+    fn insert_child(rb_self: Obj<Self>, index: usize, node: Value) -> Result<Obj<Self>, Error> {
+        rb_self.insert_node(node, |html, moved| {
+            // The index is where the node ends up, so it doesn't count the
+            // node itself if it is already a child
+            html.tree
+                .get(rb_self.id)
+                .expect("node with id {self.id} must be in the tree")
+                .children()
+                .filter(|child| !moved.contains(&child.id()))
+                .nth(index)
+                .map_or((rb_self.id, dom::Position::BeforeEnd), |child| {
+                    (child.id(), dom::Position::BeforeBegin)
+                })
+        })?;
+
+        Ok(rb_self)
+    }
+
+    /// Inserts an element or text node, which is moved if it belongs to the
+    /// same document and copied otherwise, or a string of HTML at the
+    /// position returned by `target` (which gets the ids of moved nodes)
+    fn insert_node<F>(&self, node: Value, target: F) -> Result<(), Error>
+    where
+        F: FnOnce(&Html, &[NodeId]) -> (NodeId, dom::Position),
+    {
+        enum Source {
+            Moved(NodeId),
+            Copied(Html),
+            Text(String),
+        }
+
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let document = self.document.upgrade()?;
+
+        // Copied before locking the document as the node may also hold it
+        let source = if let Ok(element) = <&Element>::try_convert(node) {
+            if Arc::ptr_eq(&element.document.upgrade()?, &document) {
+                Source::Moved(element.id)
+            } else {
+                Source::Copied(element.with_element_ref(dom::copy_subtree)?.0)
+            }
+        } else if let Ok(text_node) = <&TextNode>::try_convert(node) {
+            if Arc::ptr_eq(&text_node.document.upgrade()?, &document) {
+                Source::Moved(text_node.id)
+            } else {
+                Source::Text(text_node.text()?)
+            }
+        } else {
+            Source::Copied(fragment_arg(RString::try_convert(node)?)?)
+        };
+
+        let mut html = lock_unfrozen(&document)?;
+        let moved = match &source {
+            Source::Moved(id) => vec![*id],
+            _ => Vec::new(),
+        };
+        let (target, position) = target(&html, &moved);
+
+        if matches!(
+            position,
+            dom::Position::BeforeBegin | dom::Position::AfterEnd
+        ) {
+            check_parent_element(&html, target, "insert next to")?;
+        }
+
+        let ids = match source {
+            Source::Moved(id) => {
+                let node = html
+                    .tree
+                    .get(id)
+                    .expect("node with id {id} must be in the tree");
+                let target = html
+                    .tree
+                    .get(target)
+                    .expect("node with id {target} must be in the tree");
+
+                if target.id() == id || target.ancestors().any(|ancestor| ancestor.id() == id) {
+                    return Err(Error::new(
+                        ruby.exception_arg_error(),
+                        "can't add an element to itself or its descendants",
+                    ));
+                }
+
+                if node
+                    .parent()
+                    .is_some_and(|parent| !parent.value().is_element())
+                {
+                    return Err(Error::new(
+                        ruby.exception_runtime_error(),
+                        "can't move the root element",
+                    ));
+                }
+
+                vec![id]
+            }
+            Source::Copied(fragment) => dom::content(&fragment)
+                .map(|node| dom::orphan_copy(&mut html, node))
+                .collect(),
+            Source::Text(text) => vec![dom::create_text(&mut html, &text)],
+        };
+
+        dom::place(&mut html, target, position, &ids);
+
+        Ok(())
+    }
+
+    fn wrap(rb_self: Obj<Self>, html: RString) -> Result<Obj<Self>, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let fragment = fragment_arg(html)?;
+        let wrapper = dom::content(&fragment)
+            .find_map(ElementRef::wrap)
+            .ok_or_else(|| {
+                Error::new(
+                    ruby.exception_arg_error(),
+                    "the HTML to wrap with must contain an element",
+                )
+            })?;
+
+        let document = rb_self.document.upgrade()?;
+        let mut html = lock_unfrozen(&document)?;
+
+        check_parent_element(&html, rb_self.id, "wrap")?;
+        dom::wrap(&mut html, rb_self.id, wrapper);
+
+        Ok(rb_self)
+    }
+
+    fn unwrap(rb_self: Obj<Self>) -> Result<Obj<Self>, Error> {
+        let document = rb_self.document.upgrade()?;
+        let mut html = lock_unfrozen(&document)?;
+
+        check_parent_element(&html, rb_self.id, "unwrap")?;
+        dom::unwrap(&mut html, rb_self.id);
+
+        Ok(rb_self)
+    }
+
+    fn before_html(rb_self: Obj<Self>, html: RString) -> Result<Obj<Self>, Error> {
+        rb_self.insert_html(html, dom::Position::BeforeBegin)?;
+
+        Ok(rb_self)
+    }
+
+    fn prepend_html(rb_self: Obj<Self>, html: RString) -> Result<Obj<Self>, Error> {
+        rb_self.insert_html(html, dom::Position::AfterBegin)?;
+
+        Ok(rb_self)
+    }
+
+    fn append_html(rb_self: Obj<Self>, html: RString) -> Result<Obj<Self>, Error> {
+        rb_self.insert_html(html, dom::Position::BeforeEnd)?;
+
+        Ok(rb_self)
+    }
+
+    fn after_html(rb_self: Obj<Self>, html: RString) -> Result<Obj<Self>, Error> {
+        rb_self.insert_html(html, dom::Position::AfterEnd)?;
+
+        Ok(rb_self)
+    }
+
+    fn insert_html(&self, html: RString, position: dom::Position) -> Result<(), Error> {
+        let fragment = fragment_arg(html)?;
+        let document = self.document.upgrade()?;
+        let mut html = lock_unfrozen(&document)?;
+
+        if matches!(
+            position,
+            dom::Position::BeforeBegin | dom::Position::AfterEnd
+        ) {
+            check_parent_element(&html, self.id, "insert next to")?;
+        }
+
+        dom::insert(
+            &mut html,
+            self.id,
+            position,
+            fragment.root_element().children(),
+        );
+
+        Ok(())
+    }
+
+    fn set_inner_html(&self, inner_html: RString) -> Result<(), Error> {
+        let inner_html = html_to_utf8(inner_html, strict_encoding_option(None))?;
+        let document = self.document.upgrade()?;
+        let mut html = lock_unfrozen(&document)?;
+
+        let node = html
+            .tree
+            .get(self.id)
+            .expect("node with id {self.id} must be in the tree");
+        let name = node
+            .value()
+            .as_element()
+            .expect("node with id {self.id} must be an element")
+            .name
+            .clone();
+        let children: Vec<NodeId> = node.children().map(|child| child.id()).collect();
+
+        check_raw_text(&name, &inner_html)?;
+        let fragment = content_model::parse_inner_html(&inner_html, name);
+
+        for id in children {
+            html.tree
+                .get_mut(id)
+                .expect("child must be in the tree")
+                .detach();
+        }
+        dom::insert(
+            &mut html,
+            self.id,
+            dom::Position::BeforeEnd,
+            fragment.root_element().children(),
+        );
+
+        Ok(())
+    }
+
+    fn html(&self, args: &[Value]) -> Result<String, Error> {
+        self.serialize(args, limits::Output::Html)
+    }
+
+    fn inner_html(&self, args: &[Value]) -> Result<String, Error> {
+        self.serialize(args, limits::Output::InnerHtml)
+    }
+
+    fn serialize(&self, args: &[Value], output: limits::Output) -> Result<String, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let args = scan_args::<(), (), (), (), _, ()>(args)?;
+        let (max_output_bytes, max_nodes, truncate): (Option<usize>, Option<usize>, Option<bool>) =
+            get_options(
+                args.keywords,
+                &["max_output_bytes", "max_nodes", "truncate"],
+            )?;
+        let limits = limits::Limits {
+            max_output_bytes,
+            max_nodes,
+            truncate: truncate.unwrap_or(false),
+        };
+
+        self.with_element_ref(|element_ref| limits::serialize_element(element_ref, output, limits))?
+            .map_err(|exceeded| {
+                let message = match exceeded {
+                    limits::Exceeded::OutputBytes(max_output_bytes) => {
+                        format!("output exceeds max_output_bytes ({max_output_bytes})")
+                    }
+                    limits::Exceeded::Nodes(max_nodes) => {
+                        format!("element has more than max_nodes ({max_nodes}) nodes")
+                    }
+                };
+
+                Error::new(ruby.get_inner(&OUTPUT_TOO_LARGE_ERROR), message)
+            })
+    }
+
+    fn attr(&self, attribute: String) -> Result<Option<String>, Error> {
+        self.with_element_ref(|element_ref| {
+            dom::attr(element_ref.value(), &attribute).map(ToString::to_string)
+        })
+    }
+
+    fn id(&self) -> Result<Option<RString>, Error> {
+        // Looked up once by scraper, when matching selectors or warming the
+        // document, rather than by searching the attributes
+        self.with_element_ref(|element_ref| element_ref.value().id().map(RString::new))
+    }
+
+    fn attr_ns(&self, namespace: String, local_name: String) -> Result<Option<String>, Error> {
+        self.with_element_ref(|element_ref| {
+            namespaces::attr_ns(element_ref.value(), &namespace, &local_name)
+                .map(ToString::to_string)
+        })
+    }
+
+    fn namespace(&self) -> Result<Option<RString>, Error> {
+        self.with_element_ref(|element_ref| {
+            let namespace = &element_ref.value().name.ns;
+
+            (!namespace.is_empty()).then(|| interned_str(namespace))
+        })
+    }
+
+    fn annotation(&self) -> Result<Option<Value>, Error> {
+        Ok(self.document.annotations()?.get(self.id))
+    }
+
+    fn set_annotation(&self, annotation: Option<Value>) -> Result<(), Error> {
+        let document = self.document.upgrade()?;
+        drop(lock_unfrozen(&document)?);
+        self.document.annotations()?.set(self.id, annotation);
+
+        Ok(())
+    }
+
+    fn attr_bang(&self, attribute: String) -> Result<String, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        self.with_element_ref(
+            |element_ref| match dom::attr(element_ref.value(), &attribute) {
+                Some(value) => Ok(value.to_string()),
+                None => {
+                    let snippet = not_found::snippet(&element_ref.html());
+                    let options = RHash::new();
+                    options.aset(ruby.to_symbol("attribute"), attribute.as_str())?;
+                    options.aset(ruby.to_symbol("snippet"), snippet.as_str())?;
+
+                    Err(not_found_error(
+                        format!("no {attribute:?} attribute on {snippet}"),
+                        options,
+                    )?)
+                }
+            },
+        )?
+    }
+
+    fn attrs(&self, args: &[Value]) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let symbolize_names = symbolize_names_option(args)?;
+
+        self.with_element_ref(|element_ref| {
+            element_ref
+                .value()
+                .attrs
+                .iter()
+                .map(|(key, value)| {
+                    RArray::from_slice(&[
+                        name_value(&ruby, &dom::attribute_name(key), symbolize_names),
+                        RString::new(value).as_value(),
+                    ])
+                })
+                .collect()
+        })
+    }
+
+    fn attributes(&self, args: &[Value]) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let symbolize_names = symbolize_names_option(args)?;
+
+        self.with_element_ref(|element_ref| {
+            let attrs = &element_ref.value().attrs;
+            let hash = ruby.hash_new_capa(attrs.len());
+
+            for (key, value) in attrs.iter() {
+                hash.aset(
+                    name_value(&ruby, &dom::attribute_name(key), symbolize_names),
+                    RString::new(value),
+                )?;
+            }
+
+            Ok(hash)
+        })?
+    }
+
+    fn data(&self, args: &[Value]) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let symbolize_names = symbolize_names_option(args)?;
+
+        self.with_element_ref(|element_ref| {
+            let hash = RHash::new();
+
+            for (name, value) in dom::data_attrs(element_ref.value()) {
+                hash.aset(name_value(&ruby, &name, symbolize_names), value)?;
+            }
+
+            Ok(hash)
+        })?
+    }
+
+    fn each_attr(rb_self: Obj<Self>) -> Result<Value, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("each_attr", ()).as_value());
+        }
+
+        // Copying the attributes lets the block use the document without
+        // deadlocking
+        let attributes: Vec<_> = rb_self.with_element_ref(|element_ref| {
+            element_ref
+                .value()
+                .attrs
+                .iter()
+                .map(|(key, value)| (dom::attribute_name(key), value.to_string()))
+                .collect()
+        })?;
+
+        for (key, value) in attributes {
+            ruby.yield_values::<_, Value>((interned_str(&key), RString::new(&value)))?;
+        }
+
+        Ok(rb_self.as_value())
+    }
+
+    fn attr_names(&self, args: &[Value]) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let symbolize_names = symbolize_names_option(args)?;
+
+        self.with_element_ref(|element_ref| {
+            element_ref
+                .value()
+                .attrs
+                .iter()
+                .map(|(key, _)| name_value(&ruby, &dom::attribute_name(key), symbolize_names))
+                .collect()
+        })
+    }
+
+    fn select(&self, args: &[Value]) -> Result<RArray, Error> {
+        let (selector, attr_matches, detach) = select_args(args)?;
+
+        self.with_element_ref(|element_ref| {
+            select(
+                &selector,
+                attr_matches.as_ref(),
+                detach,
+                self.document.clone(),
+                element_ref,
+            )
+        })
+    }
+
+    fn xpath(&self, expr: String) -> Result<Value, Error> {
+        let document = self.document.upgrade()?;
+        let html = document.read();
+
+        evaluate_xpath(&expr, &html, self.id, &self.document)
+    }
+
+    fn at(&self, args: &[Value]) -> Result<Value, Error> {
+        let (selector, null_object) = at_args(args)?;
+
+        let element = self.with_element_ref(|element_ref| {
+            selector
+                .select(element_ref)
+                .next()
+                .map(|matching_element_ref| Element {
+                    id: matching_element_ref.id(),
+                    document: self.document.clone(),
+                })
+        })?;
+
+        element_or_null(element, null_object)
+    }
+
+    fn at_bang(&self, css_selector: String) -> Result<Element, Error> {
+        let selector = parse_selector(&css_selector)?;
+
+        self.with_element_ref(|element_ref| match element_ref.select(&selector).next() {
+            Some(matching_element_ref) => Ok(Element {
+                id: matching_element_ref.id(),
+                document: self.document.clone(),
+            }),
+            None => Err(selector_not_found(
+                &css_selector,
+                self.document.clone(),
+                element_ref,
+            )?),
+        })?
+    }
+
+    fn child_elements(&self) -> Result<RArray, Error> {
+        self.with_element_ref(|element_ref| {
+            element_ref
+                .child_elements()
+                .map(|child_element_ref| Element {
+                    id: child_element_ref.id(),
+                    document: self.document.clone(),
+                })
+                .collect()
+        })
+    }
+
+    fn child_nodes(&self) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        self.with_element_ref(|element_ref| {
+            element_ref
+                .children()
+                .filter_map(|child| wrap_node(&ruby, child, &self.document))
+                .collect()
+        })
+    }
+
+    fn each_node(rb_self: Obj<Self>) -> Result<Value, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("each_node", ()).as_value());
+        }
+
+        let nodes: Vec<Value> = rb_self.with_element_ref(|element_ref| {
+            element_ref
+                .descendants()
+                .filter_map(|node| wrap_node(&ruby, node, &rb_self.document))
+                .collect()
+        })?;
+
+        for node in nodes {
+            ruby.yield_value::<_, Value>(node)?;
+        }
+
+        Ok(rb_self.as_value())
+    }
+
+    fn each_element(rb_self: Obj<Self>) -> Result<Value, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("each_element", ()).as_value());
+        }
+
+        let elements =
+            rb_self.with_element_ref(|element_ref| dom::descendant_elements(*element_ref))?;
+        yield_elements(&ruby, elements, &rb_self.document)?;
+
+        Ok(rb_self.as_value())
+    }
+
+    fn traverse(rb_self: Obj<Self>) -> Result<Value, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        if !ruby.block_given() {
+            return Ok(rb_self.enumeratorize("traverse", ()).as_value());
+        }
+
+        let events: Vec<(Symbol, Value)> = rb_self.with_element_ref(|element_ref| {
+            let (open, close) = (ruby.to_symbol("open"), ruby.to_symbol("close"));
+            // Each node is wrapped once so that both of its events yield the
+            // same object
+            let mut ancestors = Vec::new();
+            let mut events = Vec::new();
+
+            for edge in element_ref.traverse() {
+                match edge {
+                    Edge::Open(node) => {
+                        let node = wrap_node(&ruby, node, &rb_self.document);
+                        if let Some(node) = node {
+                            events.push((open, node));
+                        }
+                        ancestors.push(node);
+                    }
+                    Edge::Close(_) => {
+                        if let Some(node) = ancestors.pop().flatten() {
+                            events.push((close, node));
+                        }
+                    }
+                }
+            }
+
+            events
+        })?;
+
+        for event in events {
+            ruby.yield_values::<_, Value>(event)?;
+        }
+
+        Ok(rb_self.as_value())
+    }
+
+    fn text(&self, args: &[Value]) -> Result<String, Error> {
+        self.serialize(args, limits::Output::Text)
+    }
+
+    fn content(&self) -> Result<String, Error> {
+        self.with_element_ref(|element_ref| element_ref.text().collect())
+    }
+
+    fn own_text(&self) -> Result<String, Error> {
+        self.with_element_ref(|element_ref| {
+            element_ref
+                .children()
+                .filter_map(|child| child.value().as_text())
+                .map(|text| &**text)
+                .collect::<String>()
+                .trim()
+                .to_string()
+        })
+    }
+
+    fn text_length(&self) -> Result<usize, Error> {
+        self.with_element_ref(|element_ref| text_metrics::text_metrics(element_ref).text_length)
+    }
+
+    fn link_density(&self) -> Result<f64, Error> {
+        self.with_element_ref(|element_ref| text_metrics::text_metrics(element_ref).link_density())
+    }
+
+    fn paragraphs(&self) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let paragraphs = self.with_element_ref(html_to_plain::paragraphs)?;
+        let array = RArray::with_capacity(paragraphs.len());
+
+        for paragraph in paragraphs {
+            let hash = RHash::new();
+            hash.aset(ruby.to_symbol("text"), paragraph.text)?;
+            hash.aset(
+                ruby.to_symbol("element"),
+                Element {
+                    id: paragraph.element,
+                    document: self.document.clone(),
+                },
+            )?;
+            hash.aset(ruby.to_symbol("word_count"), paragraph.word_count)?;
+            hash.aset(ruby.to_symbol("link_density"), paragraph.link_density)?;
+            array.push(hash)?;
+        }
+
+        Ok(array)
+    }
+
+    fn has_class(&self, args: &[Value]) -> Result<bool, Error> {
+        let args = scan_args::<_, (), (), (), _, ()>(args)?;
+        let (class,): (String,) = args.required;
+        let (case_sensitive,): (Option<bool>,) = get_options(args.keywords, &["case_sensitive"])?;
+
+        let case_sensitivity = if case_sensitive.unwrap_or(true) {
+            CaseSensitivity::CaseSensitive
+        } else {
+            CaseSensitivity::AsciiCaseInsensitive
+        };
+
+        self.with_element_ref(|element_ref| element_ref.value().has_class(&class, case_sensitivity))
+    }
+
+    fn classes(&self, args: &[Value]) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let symbolize_names = symbolize_names_option(args)?;
+
+        self.with_element_ref(|element_ref| {
+            element_ref
+                .value()
+                .classes()
+                .map(|class| name_value(&ruby, class, symbolize_names))
+                .collect()
+        })
+    }
+
+    fn node_id(&self) -> Result<usize, Error> {
+        // Nodes are never removed from the tree's backing storage, so their
+        // position in it is stable for the lifetime of the document
+        self.with_element_ref(|element_ref| {
+            element_ref
+                .tree()
+                .nodes()
+                .position(|node| node.id() == element_ref.id())
+                .expect("element must be in its tree")
+        })
+    }
+
+    fn document_position(&self) -> Result<Option<usize>, Error> {
+        let document = self.document.upgrade()?;
+        let html = document.read();
+
+        Ok(html.position(self.id))
+    }
+
+    fn css_path(&self) -> Result<Option<String>, Error> {
+        let document = self.document.upgrade()?;
+        let html = document.read();
+
+        if html.position(self.id).is_none() {
+            return Ok(None);
+        }
+
+        Ok(html
+            .tree
+            .get(self.id)
+            .and_then(ElementRef::wrap)
+            .map(dom::unique_css_path))
+    }
+
+    fn xpath_path(&self) -> Result<Option<String>, Error> {
+        let document = self.document.upgrade()?;
+        let html = document.read();
+
+        if html.position(self.id).is_none() {
+            return Ok(None);
+        }
+
+        Ok(html
+            .tree
+            .get(self.id)
+            .and_then(ElementRef::wrap)
+            .map(xpath::path))
+    }
+
+    fn source_position(&self) -> Result<Option<RHash>, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let document = self.document.upgrade()?;
+        let html = document.read();
+
+        let Some(line) = html.source_lines().get(self.id) else {
+            return Ok(None);
+        };
+        let hash = RHash::new();
+        hash.aset(ruby.to_symbol("line"), line)?;
+
+        Ok(Some(hash))
+    }
+
+    fn rel_tokens(&self) -> Result<RArray, Error> {
+        self.with_element_ref(|element_ref| {
+            rel::rel_tokens(element_ref.attr("rel").unwrap_or_default())
+                .into_iter()
+                .map(|token| interned_str(&token))
+                .collect()
+        })
+    }
+
+    fn has_rel(&self, token: String) -> Result<bool, Error> {
+        self.with_element_ref(|element_ref| {
+            rel::has_rel_token(element_ref.attr("rel").unwrap_or_default(), &token)
+        })
+    }
+
+    fn to_h(&self) -> Result<RHash, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        self.with_element_ref(|element_ref| snapshot_hash(&ruby, *element_ref))?
+    }
+
+    fn to_json(&self, _args: &[Value]) -> Result<String, Error> {
+        self.with_element_ref(|element_ref| snapshot::to_json(*element_ref))
+    }
+
+    fn to_table(&self) -> Result<Option<RHash>, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        let Some(table) = self.with_element_ref(table::table)? else {
+            return Ok(None);
+        };
+
+        let hash = RHash::new();
+        hash.aset(ruby.to_symbol("head"), table.head)?;
+        hash.aset(ruby.to_symbol("body"), table.body)?;
+        hash.aset(ruby.to_symbol("foot"), table.foot)?;
+
+        Ok(Some(hash))
+    }
+
+    /// Freezes the element along with its document's tree, so that it can be
+    /// shared between Ractors
+    fn freeze_node(rb_self: Obj<Self>) -> Obj<Self> {
+        rb_self.document.freeze();
+        rb_self.freeze();
+
+        rb_self
+    }
+}
+
+#[derive(TypedData)]
+#[magnus(class = "Sawzall::TextNode", free_immediately, mark, frozen_shareable)]
+struct TextNode {
+    id: NodeId,
+    document: DocumentRef,
+}
+
+impl DataTypeFunctions for TextNode {
+    fn mark(&self, marker: &gc::Marker) {
+        self.document.mark(marker);
+    }
+}
+
+impl TextNode {
+    fn text(&self) -> Result<String, Error> {
+        self.document
+            .with_node_ref(self.id, |node_ref| match node_ref.value() {
+                Node::Text(text) => text.to_string(),
+                _ => unreachable!("node with id {:?} must be a text node", self.id),
+            })
+    }
+
+    fn set_text(&self, text: String) -> Result<(), Error> {
+        let document = self.document.upgrade()?;
+        let mut html = lock_unfrozen(&document)?;
+
+        let parent = html
+            .tree
+            .get(self.id)
+            .and_then(|node| node.parent())
+            .and_then(|parent| {
+                parent
+                    .value()
+                    .as_element()
+                    .map(|element| element.name.clone())
+            });
+        if let Some(parent) = parent {
+            check_raw_text(&parent, &text)?;
+        }
+
+        let mut node = html
+            .tree
+            .get_mut(self.id)
+            .expect("node with id {self.id} must be in the tree");
+
+        let Node::Text(node_text) = node.value() else {
+            unreachable!("node with id {:?} must be a text node", self.id);
+        };
+        node_text.text = text.into();
+
+        Ok(())
+    }
+
+    fn html(&self) -> Result<String, Error> {
+        self.document.with_node_ref(self.id, dom::text_html)
+    }
+
+    fn freeze_node(rb_self: Obj<Self>) -> Obj<Self> {
+        rb_self.document.freeze();
+        rb_self.freeze();
+
+        rb_self
+    }
+}
+
+#[derive(TypedData)]
+#[magnus(class = "Sawzall::Comment", free_immediately, mark, frozen_shareable)]
+struct Comment {
+    id: NodeId,
+    document: DocumentRef,
+}
+
+impl DataTypeFunctions for Comment {
+    fn mark(&self, marker: &gc::Marker) {
+        self.document.mark(marker);
+    }
+}
+
+impl Comment {
+    fn text(&self) -> Result<String, Error> {
+        self.document
+            .with_node_ref(self.id, |node_ref| match node_ref.value() {
+                Node::Comment(comment) => comment.to_string(),
+                _ => unreachable!("node with id {:?} must be a comment", self.id),
+            })
+    }
+
+    fn html(&self) -> Result<String, Error> {
+        Ok(format!("<!--{}-->", self.text()?))
+    }
+
+    fn freeze_node(rb_self: Obj<Self>) -> Obj<Self> {
+        rb_self.document.freeze();
+        rb_self.freeze();
+
+        rb_self
+    }
+}
+
+#[derive(TypedData)]
+#[magnus(class = "Sawzall::Doctype", free_immediately, mark, frozen_shareable)]
+struct Doctype {
+    id: NodeId,
+    document: DocumentRef,
+}
+
+impl DataTypeFunctions for Doctype {
+    fn mark(&self, marker: &gc::Marker) {
+        self.document.mark(marker);
+    }
+}
+
+impl Doctype {
+    fn with_doctype<U, F>(&self, f: F) -> Result<U, Error>
+    where
+        F: FnOnce(&scraper::node::Doctype) -> U,
+    {
+        self.document
+            .with_node_ref(self.id, |node_ref| match node_ref.value() {
+                Node::Doctype(doctype) => f(doctype),
+                _ => unreachable!("node with id {:?} must be a doctype", self.id),
+            })
+    }
+
+    fn name(&self) -> Result<RString, Error> {
+        self.with_doctype(|doctype| interned_str(doctype.name()))
+    }
+
+    fn public_id(&self) -> Result<Option<String>, Error> {
+        self.with_doctype(|doctype| {
+            Some(doctype.public_id())
+                .filter(|id| !id.is_empty())
+                .map(str::to_string)
+        })
+    }
+
+    fn system_id(&self) -> Result<Option<String>, Error> {
+        self.with_doctype(|doctype| {
+            Some(doctype.system_id())
+                .filter(|id| !id.is_empty())
+                .map(str::to_string)
+        })
+    }
+
+    fn html(&self) -> Result<String, Error> {
+        Ok(format!("<!DOCTYPE {}>", self.name()?))
+    }
+
+    fn freeze_node(rb_self: Obj<Self>) -> Obj<Self> {
+        rb_self.document.freeze();
+        rb_self.freeze();
+
+        rb_self
+    }
+}
+
+#[magnus::wrap(class = "Sawzall::Sanitizer", free_immediately)]
+struct Sanitizer(sanitizer::Policy);
+
+impl Sanitizer {
+    fn new(args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(), (), (), (), _, ()>(args)?;
+        let (elements, attributes, protocols): (
+            Option<Vec<String>>,
+            Option<RHash>,
+            Option<Vec<String>>,
+        ) = get_options(args.keywords, &["elements", "attributes", "protocols"])?;
+
+        let mut element_attributes = Vec::new();
+        let mut global_attributes = Vec::new();
+
+        if let Some(attributes) = attributes {
+            attributes.foreach(|key: Value, value: Vec<String>| {
+                let key = match Symbol::from_value(key) {
+                    Some(symbol) => symbol.name()?.into_owned(),
+                    None => String::try_convert(key)?,
+                };
+
+                if key == "all" {
+                    global_attributes.extend(value);
+                } else {
+                    element_attributes.push((key, value));
+                }
+
+                Ok(ForEach::Continue)
+            })?;
+        }
+
+        let protocols = protocols.unwrap_or_else(|| {
+            sanitizer::DEFAULT_PROTOCOLS
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        });
+
+        Ok(Self(sanitizer::Policy::new(
+            elements.unwrap_or_default(),
+            element_attributes,
+            global_attributes,
+            protocols,
+        )))
+    }
+
+    fn sanitize(&self, html: RString) -> Result<String, Error> {
+        Ok(self.0.sanitize(&html_to_utf8(html, false)?))
+    }
+}
+
+/// A CSS selector checked ahead of time, named so as not to clash with
+/// scraper's
+#[magnus::wrap(class = "Sawzall::Selector", free_immediately)]
+struct CssSelector {
+    source: String,
+}
+
+impl CssSelector {
+    fn parse(css_selector: String) -> Result<Self, Error> {
+        QuerySelector::parse(&css_selector, None)?;
+
+        Ok(Self {
+            source: css_selector,
+        })
+    }
+
+    fn to_s(&self) -> String {
+        self.source.clone()
+    }
+}
+
+#[magnus::wrap(class = "Sawzall::Pool", free_immediately)]
+struct Pool(arena::Arena);
+
+impl Pool {
+    fn new() -> Self {
+        Self(arena::Arena::default())
+    }
+
+    fn parse(&self, args: &[Value]) -> Result<Document, Error> {
+        let args = scan_args::<_, (), (), (), _, ()>(args)?;
+        let (html,): (RString,) = args.required;
+        let (fragment, weak_elements, strict_encoding): (Option<bool>, Option<bool>, Option<bool>) =
+            get_options(
+                args.keywords,
+                &["fragment", "weak_elements", "strict_encoding"],
+            )?;
+        let html = html_to_utf8(html, strict_encoding_option(strict_encoding))?;
+
+        let parsed = gvl::without_gvl_for(html.len(), || {
+            self.0.parse(&html, fragment.unwrap_or(false))
+        });
+
+        Ok(Document::parsed(
+            parsed,
+            weak_elements_option(weak_elements),
+        ))
+    }
+}
+
+/// Parses a document fed to it in chunks, e.g. as they arrive from an HTTP
+/// response, without buffering the whole of it
+#[magnus::wrap(class = "Sawzall::StreamingParser", free_immediately)]
+struct StreamingParser {
+    /// Taken once the parser finishes or fails
+    state: Mutex<Option<StreamingParserState>>,
+    weak_elements: bool,
+    limits: parse_limits::ParseLimits,
+}
+
+struct StreamingParserState {
+    parser: arena::StreamingParser,
+    decoder: utf8_stream::Utf8Decoder,
+    /// How many bytes were fed so far
+    len: usize,
+}
+
+impl StreamingParser {
+    fn new(args: &[Value]) -> Result<Self, Error> {
+        let args = scan_args::<(), (), (), (), _, ()>(args)?;
+        #[allow(clippy::type_complexity)]
+        let (
+            fragment,
+            weak_elements,
+            strict_encoding,
+            invalid,
+            max_input_bytes,
+            max_depth,
+            max_nodes,
+        ): (
+            Option<bool>,
+            Option<bool>,
+            Option<bool>,
+            Option<Symbol>,
+            Option<usize>,
+            Option<usize>,
+            Option<usize>,
+        ) = get_options(
+            args.keywords,
+            &[
+                "fragment",
+                "weak_elements",
+                "strict_encoding",
+                "invalid",
+                "max_input_bytes",
+                "max_depth",
+                "max_nodes",
+            ],
+        )?;
+        let limits = parse_limits::ParseLimits {
+            max_input_bytes,
+            max_depth,
+            max_nodes,
+        };
+
+        Ok(Self {
+            state: Mutex::new(Some(StreamingParserState {
+                parser: arena::StreamingParser::new(fragment.unwrap_or(false), limits),
+                decoder: utf8_stream::Utf8Decoder::new(invalid_option(invalid, strict_encoding)?),
+                len: 0,
+            })),
+            weak_elements: weak_elements_option(weak_elements),
+            limits,
+        })
+    }
+
+    /// Parses the next chunk, which is read as UTF-8 whatever its encoding
+    fn push(rb_self: Obj<Self>, chunk: RString) -> Result<Obj<Self>, Error> {
+        let mut guard = gvl::lock(&rb_self.state);
+        // Put back only if parsing the chunk succeeds
+        let mut state = guard.take().ok_or_else(finished_parser_error)?;
+
+        state.len += chunk.len();
+        rb_self
+            .limits
+            .check_input_bytes(state.len)
+            .map_err(parse_limit_error)?;
+
+        // SAFETY: the bytes are decoded before Ruby gets a chance to modify them
+        let text = state
+            .decoder
+            .decode(unsafe { chunk.as_slice() })
+            .map_err(invalid_encoding_error)?;
+        gvl::without_gvl_for(text.len(), || state.parser.feed(&text)).map_err(parse_limit_error)?;
+
+        *guard = Some(state);
+        drop(guard);
+
+        Ok(rb_self)
+    }
+
+    /// Parses what's left and returns the document
+    fn finish(&self) -> Result<Document, Error> {
+        let state = gvl::lock(&self.state)
+            .take()
+            .ok_or_else(finished_parser_error)?;
+
+        let mut parser = state.parser;
+        let rest = state.decoder.finish().map_err(invalid_encoding_error)?;
+        parser.feed(&rest).map_err(parse_limit_error)?;
+        let parsed = parser.finish().map_err(parse_limit_error)?;
+
+        Ok(Document::parsed(parsed, self.weak_elements))
+    }
+}
+
+fn finished_parser_error() -> Error {
+    let ruby = Ruby::get().expect("called from non-ruby thread");
+    Error::new(
+        ruby.exception_runtime_error(),
+        "parser already finished or failed",
+    )
+}
+
+enum ParseState {
+    Running(JoinHandle<parse_error::Parsed>),
+    Finished(Document),
+    Panicked,
+}
+
+#[magnus::wrap(class = "Sawzall::ParseHandle", free_immediately)]
+struct ParseHandle {
+    state: Mutex<ParseState>,
+    /// The default when parsing started, see [`weak_elements_option`]
+    weak_elements: bool,
+    /// Becomes readable (at EOF) once parsing is done, so that Ruby can wait
+    /// on it with `IO#wait_readable`, which fiber schedulers hook into
+    notifications: UnixStream,
+}
+
+impl ParseHandle {
+    fn spawn(args: &[Value]) -> Result<Self, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let args = scan_args::<_, (), (), (), _, ()>(args)?;
+        let (html,): (RString,) = args.required;
+        let (fragment, strict_encoding): (Option<bool>, Option<bool>) =
+            get_options(args.keywords, &["fragment", "strict_encoding"])?;
+        let fragment = fragment.unwrap_or(false);
+        let html = html_to_utf8(html, strict_encoding_option(strict_encoding))?;
+
+        let (notifications, notifier) = UnixStream::pair().map_err(|e| {
+            Error::new(
+                ruby.exception_runtime_error(),
+                format!("failed to create notification socket: {e}"),
+            )
+        })?;
+
+        let thread = thread::Builder::new()
+            .name("sawzall-parse".to_string())
+            .spawn(move || {
+                // Dropped once parsing is done (even if it panics), which wakes up waiters
+                let _notifier = notifier;
+
+                parse_html(&html, fragment)
+            })
+            .map_err(|e| {
+                Error::new(
+                    ruby.exception_runtime_error(),
+                    format!("failed to spawn parsing thread: {e}"),
+                )
+            })?;
+
+        Ok(Self {
+            state: Mutex::new(ParseState::Running(thread)),
+            weak_elements: weak_elements_option(None),
+            notifications,
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        match &*self.state.lock().expect("failed to lock mutex") {
+            ParseState::Running(thread) => thread.is_finished(),
+            ParseState::Finished(_) | ParseState::Panicked => true,
+        }
+    }
+
+    fn notification_fd(&self) -> i32 {
+        self.notifications.as_raw_fd()
+    }
+
+    /// Returns the parsed document, blocking until it is available
+    fn document(&self) -> Result<Document, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let mut state = self.state.lock().expect("failed to lock mutex");
+
+        if let ParseState::Running(_) = *state {
+            let ParseState::Running(thread) = std::mem::replace(&mut *state, ParseState::Panicked)
+            else {
+                unreachable!("state was just checked");
+            };
+
+            if let Ok(parsed) = thread.join() {
+                *state = ParseState::Finished(Document::parsed(parsed, self.weak_elements));
+            }
+        }
+
+        match &*state {
+            ParseState::Finished(document) => Ok(document.clone()),
+            _ => Err(Error::new(
+                ruby.exception_runtime_error(),
+                "parsing thread panicked",
+            )),
+        }
+    }
+}
+
+struct ParseBatchState {
+    results: Receiver<(usize, parse_error::Parsed)>,
+    received: usize,
+    disconnected: bool,
+}
+
+/// Parses a batch in the background, making documents available as soon as
+/// they are done rather than all at once
+#[magnus::wrap(class = "Sawzall::ParseBatch", free_immediately)]
+struct ParseBatch {
+    total: usize,
+    state: Mutex<ParseBatchState>,
+    /// The default when the batch started, see [`weak_elements_option`]
+    weak_elements: bool,
+    /// Receives a byte for each parsed document, and becomes readable (at
+    /// EOF) once the batch is done, so that Ruby can wait on it with
+    /// `IO#wait_readable`
+    notifications: UnixStream,
+}
+
+impl ParseBatch {
+    fn new(htmls: RArray, fragment: bool, strict_encoding: Option<bool>) -> Result<Self, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+        let htmls = htmls_to_utf8(htmls, strict_encoding_option(strict_encoding))?;
+        let runtime_error = |message: String| Error::new(ruby.exception_runtime_error(), message);
+
+        let (notifications, notifier) = UnixStream::pair()
+            .and_then(|(notifications, notifier)| {
+                notifications.set_nonblocking(true)?;
+                Ok((notifications, notifier))
+            })
+            .map_err(|e| runtime_error(format!("failed to create notification socket: {e}")))?;
+        let (sender, results) = mpsc::channel();
+        let total = htmls.len();
+
+        thread::Builder::new()
+            .name("sawzall-batch".to_string())
+            .spawn(move || {
+                pool::for_each(htmls, |index, html| {
+                    if sender.send((index, parse_html(&html, fragment))).is_ok() {
+                        let _ = (&notifier).write_all(&[0]);
+                    }
+                });
+            })
+            .map_err(|e| runtime_error(format!("failed to spawn parsing thread: {e}")))?;
+
+        Ok(Self {
+            total,
+            state: Mutex::new(ParseBatchState {
+                results,
+                received: 0,
+                disconnected: false,
+            }),
+            weak_elements: weak_elements_option(None),
+            notifications,
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        let state = self.state.lock().expect("failed to lock mutex");
+
+        state.received == self.total || state.disconnected
+    }
+
+    fn notification_fd(&self) -> i32 {
+        self.notifications.as_raw_fd()
+    }
+
+    /// Returns the `[index, document]` pairs parsed since the last call,
+    /// without blocking
+    fn take_finished(&self) -> Result<RArray, Error> {
+        let ruby = Ruby::get().expect("called from non-ruby thread");
+
+        // Drained before receiving so that documents sent afterwards still
+        // wake up the next wait
+        let mut buffer = [0; 64];
+        while matches!((&self.notifications).read(&mut buffer), Ok(read) if read > 0) {}
+
+        let mut state = self.state.lock().expect("failed to lock mutex");
+        let finished = RArray::new();
+
+        loop {
+            match state.results.try_recv() {
+                Ok((index, parsed)) => {
+                    state.received += 1;
+                    finished.push((index, Document::parsed(parsed, self.weak_elements)))?;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    state.disconnected = true;
+                    break;
+                }
+            }
+        }
+
+        if state.disconnected && state.received < self.total {
+            return Err(Error::new(
+                ruby.exception_runtime_error(),
+                "parsing thread panicked",
+            ));
+        }
+
+        Ok(finished)
     }
 }
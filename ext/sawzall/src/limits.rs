@@ -0,0 +1,171 @@
+use crate::html_to_plain::html_to_plain;
+use html5ever::serialize::{serialize, SerializeOpts, TraversalScope};
+use scraper::ElementRef;
+use std::io::{self, Write};
+
+/// Guards against accidentally building huge strings, e.g. by serializing
+/// the root element of a very large page
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Limits {
+    pub(crate) max_output_bytes: Option<usize>,
+    /// The most nodes the element may contain, including itself
+    pub(crate) max_nodes: Option<usize>,
+    /// Whether to cut the output at `max_output_bytes` instead of failing
+    pub(crate) truncate: bool,
+}
+
+/// The limit that was exceeded
+#[derive(Debug, PartialEq)]
+pub(crate) enum Exceeded {
+    OutputBytes(usize),
+    Nodes(usize),
+}
+
+/// Which part of an element to serialize
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Output {
+    Html,
+    InnerHtml,
+    Text,
+}
+
+/// Serializes an element within the given limits
+///
+/// HTML is written into a buffer that stops growing once it reaches
+/// `max_output_bytes`, so the full output is never built. Truncated output
+/// ends on a character boundary, which may be in the middle of a tag.
+pub(crate) fn serialize_element(
+    element: ElementRef,
+    output: Output,
+    limits: Limits,
+) -> Result<String, Exceeded> {
+    if let Some(max_nodes) = limits.max_nodes {
+        if element.descendants().nth(max_nodes).is_some() {
+            return Err(Exceeded::Nodes(max_nodes));
+        }
+    }
+
+    let traversal_scope = match output {
+        Output::Html => TraversalScope::IncludeNode,
+        Output::InnerHtml => TraversalScope::ChildrenOnly(None),
+        Output::Text => return limit_text(html_to_plain(element), limits),
+    };
+
+    let mut writer = LimitedWriter {
+        buffer: Vec::new(),
+        max_bytes: limits.max_output_bytes.unwrap_or(usize::MAX),
+    };
+    let opts = SerializeOpts {
+        scripting_enabled: false,
+        traversal_scope,
+        create_missing_parent: false,
+    };
+
+    match serialize(&mut writer, &element, opts) {
+        Ok(()) => {}
+        Err(_) if limits.truncate => {}
+        Err(_) => return Err(Exceeded::OutputBytes(writer.max_bytes)),
+    }
+
+    Ok(utf8_prefix(writer.buffer))
+}
+
+fn limit_text(text: String, limits: Limits) -> Result<String, Exceeded> {
+    match limits.max_output_bytes {
+        Some(max_bytes) if text.len() > max_bytes => {
+            if !limits.truncate {
+                return Err(Exceeded::OutputBytes(max_bytes));
+            }
+
+            Ok(utf8_prefix(text.as_bytes()[..max_bytes].to_vec()))
+        }
+        _ => Ok(text),
+    }
+}
+
+/// Converts bytes to a string, dropping a character cut off at the end
+fn utf8_prefix(mut bytes: Vec<u8>) -> String {
+    if let Err(error) = std::str::from_utf8(&bytes) {
+        bytes.truncate(error.valid_up_to());
+    }
+
+    String::from_utf8(bytes).expect("the bytes were truncated to valid UTF-8")
+}
+
+/// Buffers up to `max_bytes`, failing writes past it
+struct LimitedWriter {
+    buffer: Vec<u8>,
+    max_bytes: usize,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let available = self.max_bytes - self.buffer.len();
+
+        if bytes.len() > available {
+            self.buffer.extend_from_slice(&bytes[..available]);
+
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "output limit exceeded",
+            ));
+        }
+
+        self.buffer.extend_from_slice(bytes);
+
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::{Html, Selector};
+
+    #[test]
+    fn test_serialize_element() {
+        let html = Html::parse_fragment("<p>Café <b>au</b> lait</p>");
+        let paragraph = html.select(&Selector::parse("p").unwrap()).next().unwrap();
+        let limits = |max_output_bytes, max_nodes, truncate| Limits {
+            max_output_bytes,
+            max_nodes,
+            truncate,
+        };
+
+        assert_eq!(
+            Ok(paragraph.html()),
+            serialize_element(paragraph, Output::Html, limits(Some(27), Some(5), false)),
+            "output at the limits is allowed"
+        );
+        assert_eq!(
+            Err(Exceeded::OutputBytes(26)),
+            serialize_element(paragraph, Output::Html, limits(Some(26), None, false))
+        );
+        assert_eq!(
+            Err(Exceeded::Nodes(4)),
+            serialize_element(paragraph, Output::Text, limits(None, Some(4), true)),
+            "node limits can't be truncated"
+        );
+        assert_eq!(
+            Ok("<p>Caf".to_string()),
+            serialize_element(paragraph, Output::Html, limits(Some(7), None, true)),
+            "truncated characters are dropped"
+        );
+        assert_eq!(
+            Ok("Café <b".to_string()),
+            serialize_element(paragraph, Output::InnerHtml, limits(Some(8), None, true))
+        );
+        assert_eq!(
+            Ok("Caf".to_string()),
+            serialize_element(paragraph, Output::Text, limits(Some(4), None, true))
+        );
+        assert_eq!(
+            Err(Exceeded::OutputBytes(5)),
+            serialize_element(paragraph, Output::Text, limits(Some(5), None, false))
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::absolutize::document_base_url;
+use crate::dom;
+use crate::html_to_plain::html_to_plain;
+use crate::normalize_url::normalize_url;
+use crate::rel::rel_tokens;
+use lazy_static::lazy_static;
+use scraper::{Html, Selector};
+use url::Url;
+
+lazy_static! {
+    static ref LINK_SELECTOR: Selector = Selector::parse("a[href], area[href]").unwrap();
+}
+
+/// A link from one page to another
+#[derive(Debug, PartialEq)]
+pub(crate) struct Edge {
+    pub(crate) from_url: String,
+    pub(crate) to_url: String,
+    /// The link's text, or the `alt` of image map areas
+    pub(crate) text: String,
+    pub(crate) rel: Vec<String>,
+}
+
+/// Returns the links of a document fetched from `url` to other pages, in
+/// document order
+///
+/// Targets are resolved against the document's base URL and normalized with
+/// [`normalize_url`], so links within the page itself are left out. Only
+/// `http` and `https` targets are kept.
+pub(crate) fn outlinks(html: &Html, url: &Url) -> Vec<Edge> {
+    let base_url = document_base_url(html, Some(url)).unwrap_or_else(|| url.clone());
+    let page_url = normalize_url(url.clone(), false);
+
+    dom::select(html, &LINK_SELECTOR)
+        .filter_map(|link| {
+            let href = link.attr("href")?.trim();
+            let to_url = normalize_url(base_url.join(href).ok()?, false);
+
+            if !matches!(to_url.scheme(), "http" | "https") || to_url == page_url {
+                return None;
+            }
+
+            let text = match link.value().name() {
+                "area" => link.attr("alt").unwrap_or_default().trim().to_string(),
+                _ => html_to_plain(link),
+            };
+
+            Some(Edge {
+                from_url: page_url.to_string(),
+                to_url: to_url.into(),
+                text,
+                rel: link.attr("rel").map(rel_tokens).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outlinks() {
+        let html = Html::parse_document(
+            r##"<a href="/about" rel="Nofollow  noopener">About <b>us</b></a>
+                <a href="#top">Top</a>
+                <a href="post#comments">Comments</a>
+                <a href="mailto:me@example.com">Email</a>
+                <a href="https://other.example/">Other</a>
+                <map><area href="/map" alt=" Map "></map>
+                <a name="anchor">No href</a>"##,
+        );
+        let url = Url::parse("https://example.com/blog/post#intro").unwrap();
+        let edge = |to_url: &str, text: &str, rel: &[&str]| Edge {
+            from_url: "https://example.com/blog/post".to_string(),
+            to_url: to_url.to_string(),
+            text: text.to_string(),
+            rel: rel.iter().map(|token| token.to_string()).collect(),
+        };
+
+        assert_eq!(
+            vec![
+                edge(
+                    "https://example.com/about",
+                    "About us",
+                    &["nofollow", "noopener"]
+                ),
+                edge("https://other.example/", "Other", &[]),
+                edge("https://example.com/map", "Map", &[]),
+            ],
+            outlinks(&html, &url)
+        );
+    }
+
+    #[test]
+    fn test_outlinks_base() {
+        let html = Html::parse_document(r#"<base href="/docs/"><a href="guide">Guide</a>"#);
+        let url = Url::parse("https://example.com/blog/").unwrap();
+
+        assert_eq!(
+            vec!["https://example.com/docs/guide"],
+            outlinks(&html, &url)
+                .into_iter()
+                .map(|edge| edge.to_url)
+                .collect::<Vec<_>>()
+        );
+    }
+}
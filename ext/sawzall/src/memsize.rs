@@ -0,0 +1,110 @@
+use ego_tree::NodeId;
+use html5ever::{tendril::StrTendril, QualName};
+use scraper::{Html, Node};
+use std::borrow::Cow;
+use std::mem::size_of;
+
+/// Tendrils up to this many bytes long are stored inline instead of on the
+/// heap
+const MAX_INLINE_TENDRIL: usize = 8;
+
+/// What a node takes up in the tree besides its value: the ids of its
+/// parent, its siblings and its first and last children
+const NODE_LINKS: usize = 5 * size_of::<NodeId>();
+
+/// Tells the GC that `diff` bytes were allocated (or freed if negative)
+/// outside of its heap, so that it collects more often when large documents
+/// are alive. Must be called while holding the GVL.
+pub(crate) fn adjust_memory_usage(diff: isize) {
+    // SAFETY: the caller holds the GVL. Adjusting doesn't start a GC, only
+    // makes the next allocation more likely to, so this can also be called
+    // while one is running.
+    unsafe { rb_sys::rb_gc_adjust_memory_usage(diff as _) };
+}
+
+/// Estimates how many bytes a document takes up, including the nodes that
+/// were removed from the tree since they stay allocated until it's dropped
+pub(crate) fn html_size(html: &Html) -> usize {
+    let errors: usize = html
+        .errors
+        .iter()
+        .map(|error| match error {
+            Cow::Borrowed(_) => 0,
+            Cow::Owned(error) => error.capacity(),
+        })
+        .sum();
+
+    let nodes: usize = html.tree.nodes().map(|node| node_size(node.value())).sum();
+
+    size_of::<Html>() + html.errors.capacity() * size_of::<Cow<str>>() + errors + nodes
+}
+
+/// Estimates how many bytes a node takes up in the tree and on the heap
+pub(crate) fn node_size(node: &Node) -> usize {
+    NODE_LINKS + size_of::<Node>() + node_heap_size(node)
+}
+
+fn node_heap_size(node: &Node) -> usize {
+    match node {
+        Node::Document | Node::Fragment => 0,
+        Node::Doctype(doctype) => {
+            tendril_size(&doctype.name)
+                + tendril_size(&doctype.public_id)
+                + tendril_size(&doctype.system_id)
+        }
+        Node::Comment(comment) => tendril_size(&comment.comment),
+        Node::Text(text) => tendril_size(&text.text),
+        Node::Element(element) => {
+            let values: usize = element
+                .attrs
+                .iter()
+                .map(|(_, value)| tendril_size(value))
+                .sum();
+
+            element.attrs.capacity() * size_of::<(QualName, StrTendril)>() + values
+        }
+        Node::ProcessingInstruction(instruction) => {
+            tendril_size(&instruction.target) + tendril_size(&instruction.data)
+        }
+    }
+}
+
+fn tendril_size(tendril: &str) -> usize {
+    if tendril.len() > MAX_INLINE_TENDRIL {
+        tendril.len()
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html_size() {
+        let empty = html_size(&Html::parse_fragment(""));
+        let short = html_size(&Html::parse_fragment("<p>One</p>"));
+        let long = html_size(&Html::parse_fragment(&format!(
+            r#"<p class="{}">{}</p>"#,
+            "a".repeat(100),
+            "b".repeat(1000)
+        )));
+
+        assert!(empty < short);
+        assert!(short + 1100 <= long, "{short} + 1100 > {long}");
+    }
+
+    #[test]
+    fn test_node_heap_size() {
+        let html = Html::parse_fragment("<p>Short</p><p>A longer text</p>");
+        let texts: Vec<usize> = html
+            .tree
+            .nodes()
+            .filter(|node| node.value().is_text())
+            .map(|node| node_heap_size(node.value()))
+            .collect();
+
+        assert_eq!(vec![0, 13], texts, "short tendrils are inline");
+    }
+}
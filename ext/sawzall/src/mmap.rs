@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::ptr::{self, NonNull};
+use std::slice;
+
+/// A read-only memory map of a whole file, which lets it be parsed without
+/// first reading it into memory
+#[derive(Debug)]
+pub(crate) struct Mmap {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and unmapped only when dropped
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps `file` into memory for reading it sequentially
+    ///
+    /// # Safety
+    ///
+    /// The file mustn't be modified while it's mapped, since that changes the
+    /// mapped bytes, and reading past its end after truncating it crashes the
+    /// process with `SIGBUS`.
+    pub(crate) unsafe fn map(file: &File) -> io::Result<Self> {
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "file too large to map"))?;
+
+        // Zero-length mappings are rejected
+        if len == 0 {
+            return Ok(Self {
+                ptr: NonNull::dangling(),
+                len,
+            });
+        }
+
+        let ptr = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Only a hint, so failing is fine
+        libc::madvise(ptr, len, libc::MADV_SEQUENTIAL);
+
+        Ok(Self {
+            ptr: NonNull::new_unchecked(ptr.cast()),
+            len,
+        })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as `self`
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: the mapping isn't borrowed anymore
+            unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.len) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_map() {
+        let path = std::env::temp_dir().join(format!("sawzall-mmap-{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+
+        assert_eq!(
+            b"",
+            &*unsafe { Mmap::map(&File::open(&path).unwrap()) }.unwrap()
+        );
+
+        file.write_all(b"<p>Mapped</p>").unwrap();
+        let mmap = unsafe { Mmap::map(&File::open(&path).unwrap()) }.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(b"<p>Mapped</p>", &*mmap, "the mapping outlives the file");
+    }
+}
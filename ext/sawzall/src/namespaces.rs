@@ -0,0 +1,184 @@
+use html5ever::{LocalName, Namespace};
+use scraper::error::SelectorErrorKind;
+use scraper::node::Element;
+use scraper::selector::{CssLocalName, Simple};
+use scraper::ElementRef;
+use selectors::matching::{
+    self, MatchingContext, MatchingForInvalidation, MatchingMode, NeedsSelectorFlags, QuirksMode,
+    SelectorCaches,
+};
+use selectors::parser::{ParseRelative, SelectorParseErrorKind};
+use selectors::SelectorList;
+
+/// The prefix of the namespace unprefixed type selectors match, like in
+/// Nokogiri
+const DEFAULT_PREFIX: &str = "xmlns";
+
+/// A CSS selector that can use namespace prefixes, as in `svg|rect` or
+/// `[xlink|href]`, which plain [`scraper::Selector`]s reject
+#[derive(Debug)]
+pub(crate) struct NamespacedSelector {
+    selectors: SelectorList<Simple>,
+}
+
+impl NamespacedSelector {
+    /// Parses a selector, resolving its prefixes with `namespaces`, a list of
+    /// prefixes and namespace URIs. The `xmlns` prefix sets the namespace of
+    /// type selectors without a prefix.
+    pub(crate) fn parse<'i>(
+        selectors: &'i str,
+        namespaces: &[(String, String)],
+    ) -> Result<Self, SelectorErrorKind<'i>> {
+        let mut parser_input = cssparser::ParserInput::new(selectors);
+        let mut parser = cssparser::Parser::new(&mut parser_input);
+
+        SelectorList::parse(&Parser { namespaces }, &mut parser, ParseRelative::No)
+            .map(|selectors| Self { selectors })
+            .map_err(SelectorErrorKind::from)
+    }
+
+    /// Checks whether the element matches, with `:scope` matching `scope`
+    pub(crate) fn matches(&self, element: &ElementRef, scope: ElementRef) -> bool {
+        let mut caches = SelectorCaches::default();
+        let mut context = MatchingContext::new(
+            MatchingMode::Normal,
+            None,
+            &mut caches,
+            QuirksMode::NoQuirks,
+            NeedsSelectorFlags::No,
+            MatchingForInvalidation::No,
+        );
+        context.scope_element = Some(selectors::Element::opaque(&scope));
+
+        self.selectors
+            .slice()
+            .iter()
+            .any(|selector| matching::matches_selector(selector, 0, None, element, &mut context))
+    }
+}
+
+/// Returns the descendants of `scope` matching the selector, in order, like
+/// [`ElementRef::select`]
+pub(crate) fn select<'a, 'b>(
+    scope: ElementRef<'a>,
+    selector: &'b NamespacedSelector,
+) -> impl Iterator<Item = ElementRef<'a>> + 'b
+where
+    'a: 'b,
+{
+    scope
+        .descendants()
+        .skip(1)
+        .filter_map(ElementRef::wrap)
+        .filter(move |element| selector.matches(element, scope))
+}
+
+/// Returns the value of the attribute with the given local name in the given
+/// namespace
+pub(crate) fn attr_ns<'a>(
+    element: &'a Element,
+    namespace: &str,
+    local_name: &str,
+) -> Option<&'a str> {
+    element
+        .attrs
+        .iter()
+        .find(|(key, _)| &*key.ns == namespace && &*key.local == local_name)
+        .map(|(_, value)| &**value)
+}
+
+struct Parser<'a> {
+    namespaces: &'a [(String, String)],
+}
+
+impl Parser<'_> {
+    fn namespace(&self, prefix: &str) -> Option<Namespace> {
+        self.namespaces
+            .iter()
+            .find(|(candidate, _)| candidate == prefix)
+            .map(|(_, url)| Namespace::from(url.as_str()))
+    }
+}
+
+impl<'i> selectors::Parser<'i> for Parser<'_> {
+    type Impl = Simple;
+    type Error = SelectorParseErrorKind<'i>;
+
+    fn parse_is_and_where(&self) -> bool {
+        true
+    }
+
+    fn parse_has(&self) -> bool {
+        true
+    }
+
+    fn default_namespace(&self) -> Option<Namespace> {
+        self.namespace(DEFAULT_PREFIX)
+    }
+
+    fn namespace_for_prefix(&self, prefix: &CssLocalName) -> Option<Namespace> {
+        Some(prefix.0.clone())
+            .filter(|prefix| prefix != &LocalName::from(DEFAULT_PREFIX))
+            .and_then(|prefix| self.namespace(&prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Html;
+
+    const SVG: &str = "http://www.w3.org/2000/svg";
+    const XLINK: &str = "http://www.w3.org/1999/xlink";
+
+    fn names(html: &Html, selector: &str, namespaces: &[(&str, &str)]) -> Vec<String> {
+        let namespaces: Vec<(String, String)> = namespaces
+            .iter()
+            .map(|(prefix, url)| (prefix.to_string(), url.to_string()))
+            .collect();
+        let selector = NamespacedSelector::parse(selector, &namespaces).unwrap();
+
+        select(html.root_element(), &selector)
+            .map(|element| element.value().name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_namespaced_selector() {
+        let html = Html::parse_fragment(
+            r##"<a href="/page">Page</a><svg><a xlink:href="#icon"><title>Icon</title></a></svg>"##,
+        );
+
+        assert_eq!(vec!["a", "a"], names(&html, "a", &[]));
+        assert_eq!(vec!["a"], names(&html, "svg|a", &[("svg", SVG)]));
+        assert_eq!(
+            vec!["title"],
+            names(&html, "svg|a > svg|title", &[("svg", SVG)])
+        );
+        assert_eq!(vec!["a"], names(&html, "[xlink|href]", &[("xlink", XLINK)]));
+        assert_eq!(
+            vec!["svg", "a", "title"],
+            names(&html, "*", &[("xmlns", SVG)]),
+            "the xmlns prefix sets the default namespace"
+        );
+        assert_eq!(
+            vec!["a", "svg", "a", "title"],
+            names(&html, "*|*", &[("xmlns", SVG)])
+        );
+        assert!(
+            NamespacedSelector::parse("math|mi", &[]).is_err(),
+            "unknown prefixes are rejected"
+        );
+    }
+
+    #[test]
+    fn test_attr_ns() {
+        let html = Html::parse_fragment(r##"<svg><use xlink:href="#icon" href="#other"/></svg>"##);
+        let element = html.root_element().descendants().nth(2).unwrap();
+        let element = element.value().as_element().unwrap();
+
+        assert_eq!(Some("#icon"), attr_ns(element, XLINK, "href"));
+        assert_eq!(Some("#other"), attr_ns(element, "", "href"));
+        assert_eq!(None, attr_ns(element, SVG, "href"));
+    }
+}
@@ -0,0 +1,62 @@
+use url::Url;
+
+/// Normalizes a URL so that equivalent URLs compare equal, e.g. to
+/// deduplicate links
+///
+/// Parsing already lowercases the scheme and host and removes default ports,
+/// so this drops the fragment and an empty query (a trailing `?`). With
+/// `sort_query`, query parameters are also sorted by name, keeping repeated
+/// parameters in their original order. Parameters aren't decoded, so their
+/// encoding is left as is.
+pub(crate) fn normalize_url(mut url: Url, sort_query: bool) -> Url {
+    url.set_fragment(None);
+
+    match url.query() {
+        Some("") => url.set_query(None),
+        Some(query) if sort_query => {
+            let mut params: Vec<&str> =
+                query.split('&').filter(|param| !param.is_empty()).collect();
+            params.sort_by_key(|param| param.split('=').next());
+
+            let query = params.join("&");
+            url.set_query(Some(&query));
+        }
+        _ => {}
+    }
+
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(url: &str, sort_query: bool) -> String {
+        normalize_url(Url::parse(url).unwrap(), sort_query).into()
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(
+            "https://example.com/Path?b=2&a=1",
+            normalize("HTTPS://Example.COM:443/Path?b=2&a=1#top", false)
+        );
+        assert_eq!(
+            "http://example.com:8080/",
+            normalize("http://example.com:8080", false)
+        );
+        assert_eq!(
+            "https://example.com/",
+            normalize("https://example.com/?#", false)
+        );
+        assert_eq!(
+            "https://example.com/?a=1&a=0&b=%20&c",
+            normalize("https://example.com/?c&a=1&b=%20&&a=0", true),
+            "parameters are sorted by name only"
+        );
+        assert_eq!(
+            "mailto:me@example.com",
+            normalize("mailto:me@example.com", true)
+        );
+    }
+}
@@ -0,0 +1,92 @@
+/// Snippets in error messages are truncated to this many characters
+const SNIPPET_LEN: usize = 200;
+
+/// Returns the prefixes of a selector that end before one of its
+/// combinators, longest first
+///
+/// When a selector doesn't match anything, the longest of its prefixes that
+/// does match points at where the rest of the selector went wrong (e.g.
+/// `article .byline` for `article .byline a`). Selector lists have no
+/// meaningful prefixes.
+pub(crate) fn selector_prefixes(selector: &str) -> Vec<&str> {
+    let mut prefixes = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut in_combinator = false;
+
+    for (index, c) in selector.trim().char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match (quote, c) {
+            (_, '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(' | '[') => depth += 1,
+            (None, ')' | ']') => depth -= 1,
+            (None, ',') if depth == 0 => return Vec::new(),
+            _ => {}
+        }
+
+        let is_combinator = depth == 0
+            && quote.is_none()
+            && (c.is_ascii_whitespace() || matches!(c, '>' | '+' | '~'));
+
+        if is_combinator && !in_combinator && index > 0 {
+            prefixes.push(&selector.trim()[..index]);
+        }
+
+        in_combinator = is_combinator;
+    }
+
+    prefixes.reverse();
+    prefixes
+}
+
+/// Truncates HTML to be shown in an error message
+pub(crate) fn snippet(html: &str) -> String {
+    match html.char_indices().nth(SNIPPET_LEN) {
+        Some((index, _)) => format!("{}…", &html[..index]),
+        None => html.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selector_prefixes() {
+        assert_eq!(
+            vec!["article > .byline", "article"],
+            selector_prefixes(" article > .byline  a "),
+        );
+        assert_eq!(
+            vec!["ul li:nth-child(2n+1)", "ul"],
+            selector_prefixes("ul li:nth-child(2n+1) ~ li"),
+            "combinators in arguments are ignored"
+        );
+        assert_eq!(
+            vec![r#"a[title="x > y"]"#],
+            selector_prefixes(r#"a[title="x > y"] b"#),
+            "combinators in attribute values are ignored"
+        );
+        assert_eq!(Vec::<&str>::new(), selector_prefixes("h1"));
+        assert_eq!(Vec::<&str>::new(), selector_prefixes("h1 a, h2 a"));
+    }
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!("<p>Short</p>", snippet("<p>Short</p>"));
+
+        let long = format!("<p>{}</p>", "é".repeat(300));
+        let truncated = snippet(&long);
+
+        assert_eq!(SNIPPET_LEN + 1, truncated.chars().count());
+        assert!(truncated.ends_with("é…"));
+    }
+}
@@ -0,0 +1,129 @@
+use crate::dom;
+use crate::html_to_plain::html_to_plain;
+use lazy_static::lazy_static;
+use scraper::{ElementRef, Html, Selector};
+
+lazy_static! {
+    static ref HEADING_SELECTOR: Selector = Selector::parse("h1, h2, h3, h4, h5, h6").unwrap();
+    static ref ANCHOR_SELECTOR: Selector = Selector::parse("[id], a[name]").unwrap();
+}
+
+/// An `<h1>` to `<h6>` element along with the headings nested under it
+#[derive(Debug, PartialEq)]
+pub(crate) struct Heading {
+    pub(crate) level: u8,
+    pub(crate) text: String,
+    /// The heading's `id`, or the `id` or `name` of an anchor inside it
+    pub(crate) id: Option<String>,
+    pub(crate) children: Vec<Heading>,
+}
+
+/// Returns the document's headings in order, each nested under the closest
+/// preceding heading with a lower level
+pub(crate) fn outline(html: &Html) -> Vec<Heading> {
+    let mut outline = Vec::new();
+
+    for element in dom::select(html, &HEADING_SELECTOR) {
+        let heading = Heading {
+            level: element.value().name()[1..]
+                .parse()
+                .expect("heading names end with their level"),
+            text: html_to_plain(element),
+            id: anchor(element),
+            children: Vec::new(),
+        };
+
+        insert(&mut outline, heading);
+    }
+
+    outline
+}
+
+fn insert(headings: &mut Vec<Heading>, heading: Heading) {
+    match headings.last_mut() {
+        Some(last) if last.level < heading.level => insert(&mut last.children, heading),
+        _ => headings.push(heading),
+    }
+}
+
+fn anchor(heading: ElementRef) -> Option<String> {
+    heading
+        .value()
+        .id()
+        .or_else(|| {
+            let anchor = heading.select(&ANCHOR_SELECTOR).next()?;
+            anchor.value().id().or_else(|| anchor.attr("name"))
+        })
+        .map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heading(level: u8, text: &str, children: Vec<Heading>) -> Heading {
+        Heading {
+            level,
+            text: text.to_string(),
+            id: None,
+            children,
+        }
+    }
+
+    #[test]
+    fn test_outline() {
+        let html = Html::parse_document(
+            r#"<h1 id="title">Title</h1>
+               <h2><a name="intro"></a>Intro</h2>
+               <h4>Skipped a level</h4>
+               <h3>Details <span id="details">#</span></h3>
+               <h2>Usage</h2>
+               <h1>Appendix</h1>
+               <h6></h6>"#,
+        );
+
+        assert_eq!(
+            vec![
+                Heading {
+                    id: Some("title".to_string()),
+                    ..heading(
+                        1,
+                        "Title",
+                        vec![
+                            Heading {
+                                id: Some("intro".to_string()),
+                                ..heading(
+                                    2,
+                                    "Intro",
+                                    vec![
+                                        heading(4, "Skipped a level", vec![]),
+                                        Heading {
+                                            id: Some("details".to_string()),
+                                            ..heading(3, "Details #", vec![])
+                                        },
+                                    ]
+                                )
+                            },
+                            heading(2, "Usage", vec![]),
+                        ]
+                    )
+                },
+                heading(1, "Appendix", vec![heading(6, "", vec![])]),
+            ],
+            outline(&html)
+        );
+    }
+
+    #[test]
+    fn test_outline_starting_below_h1() {
+        let html = Html::parse_fragment("<h3>A</h3><h2>B</h2><h3>C</h3>");
+
+        assert_eq!(
+            vec![
+                heading(3, "A", vec![]),
+                heading(2, "B", vec![heading(3, "C", vec![])]),
+            ],
+            outline(&html)
+        );
+    }
+}
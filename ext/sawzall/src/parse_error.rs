@@ -0,0 +1,270 @@
+use crate::parse_limits::{LimitExceeded, ParseLimits};
+use ego_tree::NodeId;
+use html5ever::tendril::StrTendril;
+use html5ever::tree_builder::{ElementFlags, NextParserState, NodeOrText, QuirksMode, TreeSink};
+use html5ever::{Attribute, QualName};
+use scraper::{Html, HtmlTreeSink};
+use std::borrow::Cow;
+use std::cell::{Cell, Ref, RefCell};
+
+/// Something the parser had to recover from, like a stray end tag or an
+/// unquoted `<` in an attribute value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ParseError {
+    pub(crate) message: Cow<'static, str>,
+    /// The line of the input the parser was at when it noticed the error,
+    /// starting at 1. Errors about missing end tags are noticed later on, so
+    /// this is only approximate.
+    pub(crate) line: u64,
+}
+
+/// A tree along with what the parser noticed while building it
+#[derive(Debug, PartialEq)]
+pub(crate) struct Parsed {
+    pub(crate) html: Html,
+    pub(crate) errors: Vec<ParseError>,
+    pub(crate) lines: SourceLines,
+}
+
+/// The line of the input each element created by the parser came from
+///
+/// That's the line the parser was at once it read the element's start tag,
+/// i.e. the line the tag ends on. Elements the parser added on its own (like
+/// a missing `<body>`) get the line it was at when it added them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct SourceLines(Vec<(NodeId, u64)>);
+
+impl SourceLines {
+    /// Returns the line of an element created by the parser, or `None` for
+    /// other nodes and elements created afterwards
+    pub(crate) fn get(&self, id: NodeId) -> Option<u64> {
+        // Node ids are handed out in increasing order
+        self.0
+            .binary_search_by_key(&id, |&(id, _)| id)
+            .ok()
+            .map(|index| self.0[index].1)
+    }
+
+    /// Returns how many bytes the lines take up on the heap
+    pub(crate) fn heap_size(&self) -> usize {
+        self.0.capacity() * std::mem::size_of::<(NodeId, u64)>()
+    }
+}
+
+/// Builds the same [`Html`] as [`HtmlTreeSink`] while also collecting parse
+/// errors and the line of each element, which scraper doesn't keep track of
+///
+/// It also notes when the tree outgrows its [`ParseLimits`], which the
+/// parser can't be told to stop at. Whoever drives it should check
+/// [`ErrorSink::exceeded`] between chunks of input.
+pub(crate) struct ErrorSink {
+    sink: HtmlTreeSink,
+    line: Cell<u64>,
+    errors: RefCell<Vec<ParseError>>,
+    lines: RefCell<Vec<(NodeId, u64)>>,
+    limits: ParseLimits,
+    exceeded: Cell<Option<LimitExceeded>>,
+}
+
+impl ErrorSink {
+    pub(crate) fn new(html: Html, limits: ParseLimits) -> Self {
+        Self {
+            sink: HtmlTreeSink::new(html),
+            line: Cell::new(1),
+            errors: RefCell::new(Vec::new()),
+            lines: RefCell::new(Vec::new()),
+            limits,
+            exceeded: Cell::new(None),
+        }
+    }
+
+    /// The first limit the tree outgrew
+    pub(crate) fn exceeded(&self) -> Option<LimitExceeded> {
+        self.exceeded.get()
+    }
+
+    /// Checks the limits after adding a node to the tree, or text which may
+    /// have been merged into an existing node
+    fn check_limits(&self, node: Option<NodeId>) {
+        if self.exceeded.get().is_some() {
+            return;
+        }
+
+        let html = self.sink.0.borrow();
+
+        if let Some(max_nodes) = self.limits.max_nodes {
+            if html.tree.nodes().len() > max_nodes {
+                self.exceeded.set(Some(LimitExceeded::Nodes(max_nodes)));
+                return;
+            }
+        }
+
+        if let (Some(max_depth), Some(node)) = (self.limits.max_depth, node) {
+            // Only walks up as far as the limit
+            let mut ancestors = html.tree.get(node).expect("node not in tree").ancestors();
+
+            if ancestors.nth(max_depth).is_some() {
+                self.exceeded.set(Some(LimitExceeded::Depth(max_depth)));
+            }
+        }
+    }
+}
+
+fn appended_node(child: &NodeOrText<NodeId>) -> Option<NodeId> {
+    match child {
+        NodeOrText::AppendNode(node) => Some(*node),
+        NodeOrText::AppendText(_) => None,
+    }
+}
+
+impl TreeSink for ErrorSink {
+    type Output = Result<Parsed, LimitExceeded>;
+    type Handle = NodeId;
+    type ElemName<'a> = Ref<'a, QualName>;
+
+    fn finish(self) -> Self::Output {
+        match self.exceeded.get() {
+            Some(exceeded) => Err(exceeded),
+            None => Ok(Parsed {
+                html: self.sink.finish(),
+                errors: self.errors.into_inner(),
+                lines: SourceLines(self.lines.into_inner()),
+            }),
+        }
+    }
+
+    fn parse_error(&self, message: Cow<'static, str>) {
+        self.errors.borrow_mut().push(ParseError {
+            message: message.clone(),
+            line: self.line.get(),
+        });
+        self.sink.parse_error(message)
+    }
+
+    fn set_current_line(&self, line_number: u64) {
+        self.line.set(line_number);
+    }
+
+    fn get_document(&self) -> NodeId {
+        self.sink.get_document()
+    }
+
+    fn elem_name<'a>(&'a self, target: &'a NodeId) -> Ref<'a, QualName> {
+        self.sink.elem_name(target)
+    }
+
+    fn create_element(&self, name: QualName, attrs: Vec<Attribute>, flags: ElementFlags) -> NodeId {
+        let element = self.sink.create_element(name, attrs, flags);
+        self.lines.borrow_mut().push((element, self.line.get()));
+        self.check_limits(None);
+        element
+    }
+
+    fn create_comment(&self, text: StrTendril) -> NodeId {
+        let comment = self.sink.create_comment(text);
+        self.check_limits(None);
+        comment
+    }
+
+    fn create_pi(&self, target: StrTendril, data: StrTendril) -> NodeId {
+        let pi = self.sink.create_pi(target, data);
+        self.check_limits(None);
+        pi
+    }
+
+    fn append(&self, parent: &NodeId, child: NodeOrText<NodeId>) {
+        let node = appended_node(&child);
+        self.sink.append(parent, child);
+        self.check_limits(node);
+    }
+
+    fn append_based_on_parent_node(
+        &self,
+        element: &NodeId,
+        prev_element: &NodeId,
+        child: NodeOrText<NodeId>,
+    ) {
+        let node = appended_node(&child);
+        self.sink
+            .append_based_on_parent_node(element, prev_element, child);
+        self.check_limits(node);
+    }
+
+    fn append_doctype_to_document(
+        &self,
+        name: StrTendril,
+        public_id: StrTendril,
+        system_id: StrTendril,
+    ) {
+        self.sink
+            .append_doctype_to_document(name, public_id, system_id);
+        self.check_limits(None);
+    }
+
+    fn mark_script_already_started(&self, node: &NodeId) {
+        self.sink.mark_script_already_started(node)
+    }
+
+    fn pop(&self, node: &NodeId) {
+        self.sink.pop(node)
+    }
+
+    fn get_template_contents(&self, target: &NodeId) -> NodeId {
+        self.sink.get_template_contents(target)
+    }
+
+    fn same_node(&self, x: &NodeId, y: &NodeId) -> bool {
+        self.sink.same_node(x, y)
+    }
+
+    fn set_quirks_mode(&self, mode: QuirksMode) {
+        self.sink.set_quirks_mode(mode)
+    }
+
+    fn append_before_sibling(&self, sibling: &NodeId, new_node: NodeOrText<NodeId>) {
+        let node = appended_node(&new_node);
+        self.sink.append_before_sibling(sibling, new_node);
+        self.check_limits(node);
+    }
+
+    fn add_attrs_if_missing(&self, target: &NodeId, attrs: Vec<Attribute>) {
+        self.sink.add_attrs_if_missing(target, attrs)
+    }
+
+    fn associate_with_form(
+        &self,
+        target: &NodeId,
+        form: &NodeId,
+        nodes: (&NodeId, Option<&NodeId>),
+    ) {
+        self.sink.associate_with_form(target, form, nodes)
+    }
+
+    fn remove_from_parent(&self, target: &NodeId) {
+        self.sink.remove_from_parent(target)
+    }
+
+    fn reparent_children(&self, node: &NodeId, new_parent: &NodeId) {
+        self.sink.reparent_children(node, new_parent)
+    }
+
+    fn is_mathml_annotation_xml_integration_point(&self, handle: &NodeId) -> bool {
+        self.sink.is_mathml_annotation_xml_integration_point(handle)
+    }
+
+    fn complete_script(&self, node: &NodeId) -> NextParserState {
+        self.sink.complete_script(node)
+    }
+
+    fn allow_declarative_shadow_roots(&self, intended_parent: &NodeId) -> bool {
+        self.sink.allow_declarative_shadow_roots(intended_parent)
+    }
+
+    fn attach_declarative_shadow(
+        &self,
+        location: &NodeId,
+        attrs: Vec<Attribute>,
+    ) -> Result<(), String> {
+        self.sink.attach_declarative_shadow(location, attrs)
+    }
+}
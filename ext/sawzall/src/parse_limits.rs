@@ -0,0 +1,75 @@
+use std::fmt;
+
+/// Bounds on the tree built from untrusted HTML, so that pathological
+/// documents fail to parse instead of using up all the memory
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ParseLimits {
+    pub(crate) max_input_bytes: Option<usize>,
+    /// How deeply nodes may be nested, with the `<html>` element at depth 1
+    pub(crate) max_depth: Option<usize>,
+    /// The most nodes the tree may contain, counting the root and, for
+    /// fragments, the `<body>` context element
+    pub(crate) max_nodes: Option<usize>,
+}
+
+/// The limit that was exceeded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LimitExceeded {
+    InputBytes(usize),
+    Depth(usize),
+    Nodes(usize),
+}
+
+impl ParseLimits {
+    /// Whether the tree itself is limited, which requires checking it while
+    /// parsing
+    pub(crate) fn limits_tree(&self) -> bool {
+        self.max_depth.is_some() || self.max_nodes.is_some()
+    }
+
+    pub(crate) fn check_input_bytes(&self, len: usize) -> Result<(), LimitExceeded> {
+        match self.max_input_bytes {
+            Some(max_input_bytes) if len > max_input_bytes => {
+                Err(LimitExceeded::InputBytes(max_input_bytes))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::InputBytes(max_input_bytes) => {
+                write!(f, "input exceeds max_input_bytes ({max_input_bytes})")
+            }
+            LimitExceeded::Depth(max_depth) => {
+                write!(f, "document is nested deeper than max_depth ({max_depth})")
+            }
+            LimitExceeded::Nodes(max_nodes) => {
+                write!(f, "document has more than max_nodes ({max_nodes}) nodes")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_input_bytes() {
+        let limits = ParseLimits {
+            max_input_bytes: Some(4),
+            ..Default::default()
+        };
+
+        assert_eq!(limits.check_input_bytes(4), Ok(()));
+        assert_eq!(
+            limits.check_input_bytes(5),
+            Err(LimitExceeded::InputBytes(4))
+        );
+        assert_eq!(ParseLimits::default().check_input_bytes(usize::MAX), Ok(()));
+        assert!(!limits.limits_tree());
+    }
+}
@@ -0,0 +1,115 @@
+use lazy_static::lazy_static;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    static ref POOL: RwLock<Pool> = RwLock::new(Pool::Global);
+}
+
+/// Where batch work runs
+#[derive(Clone)]
+enum Pool {
+    /// Rayon's global pool, with one thread per CPU
+    Global,
+    /// The calling thread, without any parallelism
+    Disabled,
+    Custom(Arc<ThreadPool>),
+}
+
+/// Sets the number of threads used by [`map`]. `None` uses one thread per
+/// CPU while `Some(0)` and `Some(1)` disable threading entirely.
+pub(crate) fn configure(threads: Option<usize>) -> Result<(), ThreadPoolBuildError> {
+    let pool = match threads {
+        None => Pool::Global,
+        Some(0 | 1) => Pool::Disabled,
+        Some(threads) => Pool::Custom(Arc::new(
+            ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|index| format!("sawzall-{index}"))
+                .build()?,
+        )),
+    };
+
+    *POOL.write().expect("failed to lock pool") = pool;
+
+    Ok(())
+}
+
+/// Applies `f` to every item using the configured pool, preserving order
+pub(crate) fn map<T, U, F>(items: Vec<T>, f: F) -> Vec<U>
+where
+    T: Send,
+    U: Send,
+    F: Fn(T) -> U + Send + Sync,
+{
+    // Cloned so that reconfiguring doesn't wait for in-flight work
+    let pool = POOL.read().expect("failed to lock pool").clone();
+
+    match pool {
+        Pool::Global => items.into_par_iter().map(f).collect(),
+        Pool::Disabled => items.into_iter().map(f).collect(),
+        Pool::Custom(pool) => pool.install(|| items.into_par_iter().map(f).collect()),
+    }
+}
+
+/// Calls `f` with every item and its index using the configured pool, in no
+/// particular order
+pub(crate) fn for_each<T, F>(items: Vec<T>, f: F)
+where
+    T: Send,
+    F: Fn(usize, T) + Send + Sync,
+{
+    let pool = POOL.read().expect("failed to lock pool").clone();
+    let f = |(index, item)| f(index, item);
+
+    match pool {
+        Pool::Global => items.into_par_iter().enumerate().for_each(f),
+        Pool::Disabled => items.into_iter().enumerate().for_each(f),
+        Pool::Custom(pool) => pool.install(|| items.into_par_iter().enumerate().for_each(f)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        let items: Vec<usize> = (0..100).collect();
+        let expected: Vec<usize> = items.iter().map(|i| i * 2).collect();
+
+        for threads in [Some(4), Some(1), None] {
+            configure(threads).unwrap();
+
+            assert_eq!(
+                expected,
+                map(items.clone(), |i| i * 2),
+                "order is preserved with {threads:?} threads"
+            );
+        }
+
+        configure(Some(1)).unwrap();
+        let current = std::thread::current().id();
+        assert!(
+            map(vec![(); 10], |_| std::thread::current().id() == current)
+                .into_iter()
+                .all(|same| same),
+            "work runs on the calling thread when threading is disabled"
+        );
+        configure(None).unwrap();
+    }
+
+    #[test]
+    fn test_for_each() {
+        let seen = std::sync::Mutex::new(Vec::new());
+
+        for_each(vec!["a", "b", "c"], |index, item| {
+            seen.lock().unwrap().push((index, item));
+        });
+
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort();
+        assert_eq!(vec![(0, "a"), (1, "b"), (2, "c")], seen);
+    }
+}
@@ -0,0 +1,59 @@
+/// Splits a `rel` attribute into its unique, lowercased tokens in order of
+/// appearance
+///
+/// Tokens are separated by ASCII whitespace and compared ASCII
+/// case-insensitively ([WHATWG spec][1]).
+///
+/// [1]: https://html.spec.whatwg.org/multipage/links.html#attr-hyperlink-rel
+pub(crate) fn rel_tokens(rel: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+
+    for token in rel.split_ascii_whitespace() {
+        let token = token.to_ascii_lowercase();
+
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+
+    tokens
+}
+
+/// Checks whether a `rel` attribute contains the given token
+pub(crate) fn has_rel_token(rel: &str, token: &str) -> bool {
+    rel.split_ascii_whitespace()
+        .any(|candidate| candidate.eq_ignore_ascii_case(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rel_tokens() {
+        assert_eq!(Vec::<String>::new(), rel_tokens(""));
+
+        assert_eq!(
+            vec!["nofollow", "noopener"],
+            rel_tokens("  NoFollow\tnoopener\n"),
+            "tokens are split on ASCII whitespace and lowercased"
+        );
+
+        assert_eq!(
+            vec!["ugc", "nofollow"],
+            rel_tokens("ugc nofollow UGC"),
+            "duplicate tokens are removed"
+        );
+    }
+
+    #[test]
+    fn test_has_rel_token() {
+        assert!(has_rel_token("noopener NOFOLLOW", "nofollow"));
+        assert!(has_rel_token("noopener nofollow", "NoFollow"));
+        assert!(
+            !has_rel_token("nofollower", "nofollow"),
+            "partial tokens don't match"
+        );
+        assert!(!has_rel_token("", "nofollow"));
+    }
+}
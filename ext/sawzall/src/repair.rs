@@ -0,0 +1,285 @@
+use crate::parse_error::Parsed;
+use crate::parse_limits::{LimitExceeded, ParseLimits};
+use crate::{arena, dom};
+use ego_tree::NodeId;
+use scraper::{Html, Node};
+use std::borrow::Cow;
+
+/// Attribute values longer than this many bytes are removed by
+/// [`Mode::Aggressive`]
+const MAX_ATTRIBUTE_LEN: usize = 65_536;
+
+/// How much garbage to clean up around parsing HTML
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Mode {
+    None,
+    /// Fixes input the parser would turn into surprising trees, without
+    /// dropping any content
+    Conservative,
+    /// Also removes attributes too long to be anything but garbage (e.g.
+    /// inlined files or a missing closing quote)
+    Aggressive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Kind {
+    /// NUL bytes, which the parser drops or replaces inconsistently
+    NullBytes,
+    /// `<!--` without a matching `-->`, which turns the rest of the input
+    /// into a comment. It is ended before the next tag instead.
+    UnterminatedComments,
+    OverlongAttributes,
+}
+
+/// Something that was repaired, and how many times
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Repair {
+    pub(crate) kind: Kind,
+    pub(crate) count: usize,
+}
+
+impl Mode {
+    pub(crate) const ALL: [Mode; 3] = [Mode::None, Mode::Conservative, Mode::Aggressive];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Mode::None => "none",
+            Mode::Conservative => "conservative",
+            Mode::Aggressive => "aggressive",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+}
+
+impl Kind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Kind::NullBytes => "null_bytes",
+            Kind::UnterminatedComments => "unterminated_comments",
+            Kind::OverlongAttributes => "overlong_attributes",
+        }
+    }
+}
+
+/// Parses HTML after repairing it according to `mode`, returning what was
+/// repaired and the errors in what wasn't
+pub(crate) fn parse(
+    input: &str,
+    fragment: bool,
+    mode: Mode,
+    limits: ParseLimits,
+) -> Result<(Parsed, Vec<Repair>), LimitExceeded> {
+    let mut repairs = Vec::new();
+    let mut input = Cow::Borrowed(input);
+
+    if mode != Mode::None {
+        let null_bytes = input.bytes().filter(|&b| b == b'\0').count();
+
+        if null_bytes > 0 {
+            input = Cow::Owned(input.replace('\0', ""));
+            repairs.push(Repair {
+                kind: Kind::NullBytes,
+                count: null_bytes,
+            });
+        }
+
+        if let (Some(closed), count) = close_comments(&input) {
+            input = Cow::Owned(closed);
+            repairs.push(Repair {
+                kind: Kind::UnterminatedComments,
+                count,
+            });
+        }
+    }
+
+    let mut parsed = arena::parse_within(&input, fragment, limits)?;
+
+    if mode == Mode::Aggressive {
+        let count = remove_overlong_attributes(&mut parsed.html);
+
+        if count > 0 {
+            repairs.push(Repair {
+                kind: Kind::OverlongAttributes,
+                count,
+            });
+        }
+    }
+
+    Ok((parsed, repairs))
+}
+
+/// Ends unterminated comments before the next tag, returning the repaired
+/// input if anything changed along with the number of comments that were
+/// ended
+fn close_comments(input: &str) -> (Option<String>, usize) {
+    let mut output = String::new();
+    let mut copied = 0;
+    let mut position = 0;
+    let mut count = 0;
+
+    while let Some(start) = input[position..].find("<!--") {
+        let body = position + start + "<!--".len();
+
+        // `<!-->` and `<!--->` are complete (empty) comments
+        let end = ["-->", "--!>"]
+            .iter()
+            .filter_map(|end| {
+                input[body..]
+                    .find(end)
+                    .map(|index| body + index + end.len())
+            })
+            .chain(
+                [">", "->"]
+                    .iter()
+                    .filter_map(|end| input[body..].starts_with(end).then_some(body + end.len())),
+            )
+            .min();
+
+        if let Some(end) = end {
+            position = end;
+            continue;
+        }
+
+        // Without any tag left there is no markup for the comment to hide
+        let Some(index) = input[body..].find('<') else {
+            break;
+        };
+
+        output.push_str(&input[copied..body + index]);
+        output.push_str("-->");
+        copied = body + index;
+        position = body + index;
+        count += 1;
+    }
+
+    if count == 0 {
+        return (None, 0);
+    }
+
+    output.push_str(&input[copied..]);
+
+    (Some(output), count)
+}
+
+fn remove_overlong_attributes(html: &mut Html) -> usize {
+    let ids: Vec<NodeId> = html
+        .tree
+        .nodes()
+        .filter(|node| {
+            node.value().as_element().is_some_and(|element| {
+                element
+                    .attrs()
+                    .any(|(_, value)| value.len() > MAX_ATTRIBUTE_LEN)
+            })
+        })
+        .map(|node| node.id())
+        .collect();
+    let mut count = 0;
+
+    for id in ids {
+        let mut node = html.tree.get_mut(id).expect("ids come from the tree");
+        let Node::Element(element) = node.value() else {
+            unreachable!("only elements are collected");
+        };
+
+        let names: Vec<String> = element
+            .attrs
+            .iter()
+            .filter(|(_, value)| value.len() > MAX_ATTRIBUTE_LEN)
+            .map(|(key, _)| dom::attribute_name(key))
+            .collect();
+
+        for name in names {
+            dom::remove_attr(element, &name);
+            count += 1;
+        }
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_comments() {
+        assert_eq!(
+            (None, 0),
+            close_comments("<!-- a --><p>b</p><!----><!--><!---><!-- c --!>")
+        );
+        assert_eq!(
+            (None, 0),
+            close_comments("<p>Text</p><!-- trailing"),
+            "comments without markup after them are left as is"
+        );
+        assert_eq!(
+            (
+                Some("<!-- a --> <!-- b >--><p>c</p><!-- d -- >--><p>e</p>".to_string()),
+                2
+            ),
+            close_comments("<!-- a --> <!-- b ><p>c</p><!-- d -- ><p>e</p>")
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let input = format!(
+            "<p title='{}' id='x'>One\0</p><!-- Two<p>Three</p>",
+            "a".repeat(MAX_ATTRIBUTE_LEN + 1)
+        );
+
+        let (parsed, repairs) = parse(&input, true, Mode::None, ParseLimits::default()).unwrap();
+        assert_eq!(Vec::<Repair>::new(), repairs);
+        assert!(parsed
+            .html
+            .root_element()
+            .inner_html()
+            .ends_with("<!-- Two<p>Three</p>-->"));
+
+        let (parsed, repairs) =
+            parse(&input, true, Mode::Conservative, ParseLimits::default()).unwrap();
+        assert_eq!(
+            vec![
+                Repair {
+                    kind: Kind::NullBytes,
+                    count: 1
+                },
+                Repair {
+                    kind: Kind::UnterminatedComments,
+                    count: 1
+                },
+            ],
+            repairs
+        );
+        assert!(parsed
+            .html
+            .root_element()
+            .inner_html()
+            .ends_with("One</p><!-- Two--><p>Three</p>"));
+
+        let (parsed, repairs) =
+            parse(&input, false, Mode::Aggressive, ParseLimits::default()).unwrap();
+        assert_eq!(
+            Some(&Repair {
+                kind: Kind::OverlongAttributes,
+                count: 1
+            }),
+            repairs.last()
+        );
+        assert_eq!(
+            "<p id=\"x\">One</p><!-- Two--><p>Three</p>",
+            parsed
+                .html
+                .root_element()
+                .children()
+                .nth(1)
+                .and_then(scraper::ElementRef::wrap)
+                .unwrap()
+                .inner_html()
+        );
+    }
+}
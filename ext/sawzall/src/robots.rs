@@ -0,0 +1,436 @@
+use crate::absolutize::document_base_url;
+use crate::dom;
+use crate::normalize_url::normalize_url;
+use crate::rel::{has_rel_token, rel_tokens};
+use lazy_static::lazy_static;
+use scraper::{Html, Selector};
+use url::Url;
+
+/// Meta names whose directives apply to web search crawlers in general, as
+/// opposed to `googlebot-news` which only restricts Google News
+const INDEXING_META_NAMES: [&str; 2] = ["robots", "googlebot"];
+
+/// Meta names whose `content` is interpreted as a list of indexing directives
+///
+/// See [Google's documentation][1] for the supported directives.
+///
+/// [1]: https://developers.google.com/search/docs/crawling-indexing/robots-meta-tag
+const ROBOTS_META_NAMES: [&str; 3] = ["robots", "googlebot", "googlebot-news"];
+
+lazy_static! {
+    static ref ROBOTS_META_SELECTOR: Selector = Selector::parse("meta[name][content]").unwrap();
+    static ref LINK_SELECTOR: Selector = Selector::parse("a[rel], area[rel]").unwrap();
+    static ref CANONICAL_SELECTOR: Selector = Selector::parse("link[rel][href]").unwrap();
+}
+
+/// Directives declared through a single `<meta name="...">` (merged when the
+/// same name appears more than once)
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct MetaDirectives {
+    pub(crate) noindex: bool,
+    pub(crate) nofollow: bool,
+    pub(crate) noarchive: bool,
+    pub(crate) nosnippet: bool,
+    pub(crate) noimageindex: bool,
+    pub(crate) notranslate: bool,
+    pub(crate) max_snippet: Option<i64>,
+    pub(crate) max_image_preview: Option<String>,
+    pub(crate) max_video_preview: Option<i64>,
+    pub(crate) unavailable_after: Option<String>,
+}
+
+impl MetaDirectives {
+    fn apply(&mut self, content: &str) {
+        for directive in content.split(',') {
+            let (key, value) = match directive.split_once(':') {
+                Some((key, value)) => (key, Some(value.trim())),
+                None => (directive, None),
+            };
+
+            match (key.trim().to_ascii_lowercase().as_str(), value) {
+                ("none", None) => {
+                    self.noindex = true;
+                    self.nofollow = true;
+                }
+                ("noindex", None) => self.noindex = true,
+                ("nofollow", None) => self.nofollow = true,
+                ("noarchive" | "nocache", None) => self.noarchive = true,
+                ("nosnippet", None) => self.nosnippet = true,
+                ("noimageindex", None) => self.noimageindex = true,
+                ("notranslate", None) => self.notranslate = true,
+                ("max-snippet", Some(value)) => self.max_snippet = value.parse().ok(),
+                ("max-image-preview", Some(value)) => {
+                    self.max_image_preview = Some(value.to_ascii_lowercase())
+                }
+                ("max-video-preview", Some(value)) => self.max_video_preview = value.parse().ok(),
+                ("unavailable_after", Some(value)) => {
+                    self.unavailable_after = Some(value.to_string())
+                }
+                // `all` and unknown directives don't restrict anything
+                _ => {}
+            }
+        }
+    }
+}
+
+/// `rel` flags on a single link that affect how crawlers should follow it
+#[derive(Debug, PartialEq)]
+pub(crate) struct LinkDirectives {
+    pub(crate) href: Option<String>,
+    pub(crate) nofollow: bool,
+    pub(crate) ugc: bool,
+    pub(crate) sponsored: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RobotsDirectives {
+    /// Directives keyed by lowercased meta name, in order of first appearance
+    pub(crate) meta: Vec<(String, MetaDirectives)>,
+    /// Links carrying at least one of `nofollow`, `ugc` or `sponsored`
+    pub(crate) links: Vec<LinkDirectives>,
+}
+
+/// Extracts crawler directives from robots `<meta>` elements and link `rel` attributes
+pub(crate) fn robots_directives(html: &Html) -> RobotsDirectives {
+    let mut directives = RobotsDirectives::default();
+
+    for meta in dom::select(html, &ROBOTS_META_SELECTOR) {
+        let name = meta
+            .attr("name")
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if !ROBOTS_META_NAMES.contains(&name.as_str()) {
+            continue;
+        }
+
+        let content = meta.attr("content").unwrap_or_default();
+
+        match directives
+            .meta
+            .iter_mut()
+            .find(|(existing, _)| *existing == name)
+        {
+            Some((_, meta_directives)) => meta_directives.apply(content),
+            None => {
+                let mut meta_directives = MetaDirectives::default();
+                meta_directives.apply(content);
+                directives.meta.push((name, meta_directives));
+            }
+        }
+    }
+
+    for link in dom::select(html, &LINK_SELECTOR) {
+        let mut link_directives = LinkDirectives {
+            href: link.attr("href").map(ToString::to_string),
+            nofollow: false,
+            ugc: false,
+            sponsored: false,
+        };
+
+        for token in rel_tokens(link.attr("rel").unwrap_or_default()) {
+            match token.as_str() {
+                "nofollow" => link_directives.nofollow = true,
+                "ugc" => link_directives.ugc = true,
+                "sponsored" => link_directives.sponsored = true,
+                _ => {}
+            }
+        }
+
+        if link_directives.nofollow || link_directives.ugc || link_directives.sponsored {
+            directives.links.push(link_directives);
+        }
+    }
+
+    directives
+}
+
+/// Whether search engines should index a page
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Indexable,
+    /// A robots meta excludes the page
+    Noindex,
+    /// The page's canonical URL points to another page, which would be
+    /// indexed instead
+    Canonicalized,
+}
+
+impl Verdict {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Verdict::Indexable => "indexable",
+            Verdict::Noindex => "noindex",
+            Verdict::Canonicalized => "canonicalized",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Indexability {
+    pub(crate) verdict: Verdict,
+    /// Whether crawlers may follow the page's links
+    pub(crate) follow: bool,
+    /// The canonical URL the page declares, resolved and normalized, if it is
+    /// usable
+    pub(crate) canonical_url: Option<String>,
+    /// The signals that led to the verdict, in the order they were considered
+    pub(crate) reasons: Vec<String>,
+}
+
+/// Combines the robots metas and the canonical link of a page fetched from
+/// `url` into a single verdict
+///
+/// A `noindex` wins over everything else. Otherwise, the page is
+/// canonicalized when its `<link rel="canonical">` points to another URL.
+/// Like search engines do, canonical links outside of `<head>`, conflicting
+/// canonical links and canonical URLs that aren't `http` or `https` are
+/// ignored. URLs are compared after [`normalize_url`].
+pub(crate) fn indexability(html: &Html, url: &Url) -> Indexability {
+    let directives = robots_directives(html);
+    let mut reasons = Vec::new();
+    let mut noindex = false;
+    let mut follow = true;
+
+    for (name, meta_directives) in &directives.meta {
+        if !INDEXING_META_NAMES.contains(&name.as_str()) {
+            continue;
+        }
+
+        if meta_directives.noindex {
+            noindex = true;
+            reasons.push(format!("<meta name={name:?}> has noindex"));
+        }
+        if meta_directives.nofollow {
+            follow = false;
+            reasons.push(format!("<meta name={name:?}> has nofollow"));
+        }
+    }
+
+    let base_url = document_base_url(html, Some(url)).unwrap_or_else(|| url.clone());
+    let page_url = normalize_url(url.clone(), false);
+    let mut canonical_urls: Vec<Url> = Vec::new();
+
+    for link in dom::select(html, &CANONICAL_SELECTOR) {
+        if !has_rel_token(link.attr("rel").unwrap_or_default(), "canonical") {
+            continue;
+        }
+
+        let href = link.attr("href").unwrap_or_default().trim();
+        let in_head = link.ancestors().any(|ancestor| {
+            ancestor
+                .value()
+                .as_element()
+                .is_some_and(|e| e.name() == "head")
+        });
+
+        if !in_head {
+            reasons.push(format!(
+                "canonical link to {href:?} outside of <head> is ignored"
+            ));
+            continue;
+        }
+
+        match base_url.join(href) {
+            Ok(canonical_url) if matches!(canonical_url.scheme(), "http" | "https") => {
+                let canonical_url = normalize_url(canonical_url, false);
+
+                if !canonical_urls.contains(&canonical_url) {
+                    canonical_urls.push(canonical_url);
+                }
+            }
+            _ => reasons.push(format!("canonical link to {href:?} is not an HTTP URL")),
+        }
+    }
+
+    let canonical_url = match canonical_urls.as_slice() {
+        [canonical_url] => Some(canonical_url),
+        [] => None,
+        _ => {
+            reasons.push(format!(
+                "{} conflicting canonical links are ignored",
+                canonical_urls.len()
+            ));
+            None
+        }
+    };
+
+    let verdict = match canonical_url {
+        _ if noindex => Verdict::Noindex,
+        Some(canonical_url) if *canonical_url != page_url => {
+            reasons.push(format!("canonical link points to {canonical_url}"));
+            Verdict::Canonicalized
+        }
+        Some(_) => {
+            reasons.push("canonical link points to the page itself".to_string());
+            Verdict::Indexable
+        }
+        None => Verdict::Indexable,
+    };
+
+    Indexability {
+        verdict,
+        follow,
+        canonical_url: canonical_url.map(ToString::to_string),
+        reasons,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn robots_directives(input: &str) -> RobotsDirectives {
+        super::robots_directives(&Html::parse_document(input))
+    }
+
+    #[test]
+    fn test_robots_directives() {
+        assert_eq!(
+            RobotsDirectives::default(),
+            robots_directives("<p>nothing to see here</p>"),
+            "documents without directives are unrestricted"
+        );
+
+        let directives = robots_directives(
+            r#"<meta name="ROBOTS" content="NoIndex, max-snippet:50, max-image-preview:Large">
+               <meta name="robots" content="noarchive">
+               <meta name="googlebot" content="none">
+               <meta name="description" content="noindex">"#,
+        );
+        assert_eq!(
+            vec![
+                (
+                    "robots".to_string(),
+                    MetaDirectives {
+                        noindex: true,
+                        noarchive: true,
+                        max_snippet: Some(50),
+                        max_image_preview: Some("large".to_string()),
+                        ..Default::default()
+                    }
+                ),
+                (
+                    "googlebot".to_string(),
+                    MetaDirectives {
+                        noindex: true,
+                        nofollow: true,
+                        ..Default::default()
+                    }
+                ),
+            ],
+            directives.meta,
+            "meta directives are case-insensitive, merged by name, and ignore unrelated metas"
+        );
+
+        let directives = robots_directives(
+            r#"<a href="/one" rel="NoFollow ugc">one</a>
+               <a href="/two" rel="nofollower">two</a>
+               <a href="/three">three</a>
+               <a rel="sponsored">four</a>"#,
+        );
+        assert_eq!(
+            vec![
+                LinkDirectives {
+                    href: Some("/one".to_string()),
+                    nofollow: true,
+                    ugc: true,
+                    sponsored: false,
+                },
+                LinkDirectives {
+                    href: None,
+                    nofollow: false,
+                    ugc: false,
+                    sponsored: true,
+                },
+            ],
+            directives.links,
+            "only links with crawler-relevant rel tokens are returned"
+        );
+    }
+
+    fn indexability(input: &str, url: &str) -> Indexability {
+        super::indexability(&Html::parse_document(input), &Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_indexability() {
+        let url = "https://example.com/post?page=1#comments";
+
+        assert_eq!(
+            Indexability {
+                verdict: Verdict::Indexable,
+                follow: true,
+                canonical_url: None,
+                reasons: vec![],
+            },
+            indexability("<p>Post</p>", url)
+        );
+        assert_eq!(
+            Indexability {
+                verdict: Verdict::Indexable,
+                follow: true,
+                canonical_url: Some("https://example.com/post?page=1".to_string()),
+                reasons: vec!["canonical link points to the page itself".to_string()],
+            },
+            indexability(
+                r#"<link rel="Canonical" href="HTTPS://EXAMPLE.COM:443/post?page=1">"#,
+                url
+            ),
+            "canonical URLs are compared after normalization"
+        );
+        assert_eq!(
+            Indexability {
+                verdict: Verdict::Canonicalized,
+                follow: true,
+                canonical_url: Some("https://example.com/blog/post".to_string()),
+                reasons: vec!["canonical link points to https://example.com/blog/post".to_string()],
+            },
+            indexability(
+                r#"<base href="/blog/"><link rel="canonical" href="post#top">"#,
+                url
+            ),
+            "canonical URLs are resolved against the base URL"
+        );
+        assert_eq!(
+            Indexability {
+                verdict: Verdict::Noindex,
+                follow: false,
+                canonical_url: Some("https://example.com/other".to_string()),
+                reasons: vec![
+                    "<meta name=\"googlebot\"> has noindex".to_string(),
+                    "<meta name=\"googlebot\"> has nofollow".to_string(),
+                ],
+            },
+            indexability(
+                r#"<meta name="googlebot" content="none">
+                   <meta name="googlebot-news" content="noindex">
+                   <link rel="canonical" href="/other">"#,
+                url
+            ),
+            "noindex wins over the canonical link"
+        );
+        assert_eq!(
+            Indexability {
+                verdict: Verdict::Indexable,
+                follow: true,
+                canonical_url: None,
+                reasons: vec![
+                    "canonical link to \"ftp://example.com/\" is not an HTTP URL".to_string(),
+                    "canonical link to \"/body\" outside of <head> is ignored".to_string(),
+                    "2 conflicting canonical links are ignored".to_string(),
+                ],
+            },
+            indexability(
+                r#"<head>
+                     <link rel="canonical" href="ftp://example.com/">
+                     <link rel="canonical" href="/one">
+                     <link rel="canonical" href="/two">
+                   </head>
+                   <body><link rel="canonical" href="/body"></body>"#,
+                url
+            ),
+        );
+    }
+}
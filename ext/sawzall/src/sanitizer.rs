@@ -0,0 +1,282 @@
+use crate::dom::{attribute_name, URL_ATTRIBUTES};
+use ego_tree::NodeRef;
+use html5ever::serialize::{serialize, Serialize, SerializeOpts, Serializer, TraversalScope};
+use scraper::{Html, Node};
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// Elements whose contents are removed along with them when they aren't
+/// allowed, as their text isn't meant to be displayed. Other disallowed
+/// elements are replaced by their children.
+///
+/// Adapted from the [sanitize gem][1].
+///
+/// [1]: https://github.com/rgrove/sanitize/blob/v7.0.0/lib/sanitize/config/default.rb#L72
+const REMOVE_CONTENTS_ELEMENTS: [&str; 10] = [
+    "iframe",
+    "math",
+    "noembed",
+    "noframes",
+    "noscript",
+    "plaintext",
+    "script",
+    "style",
+    "svg",
+    "xmp",
+];
+
+/// Protocols allowed in URL attributes when none are configured
+pub(crate) const DEFAULT_PROTOCOLS: [&str; 3] = ["http", "https", "mailto"];
+
+/// Allowlist describing which parts of an HTML fragment are kept by [`Policy::sanitize`]
+pub(crate) struct Policy {
+    elements: HashSet<String>,
+    attributes: HashMap<String, HashSet<String>>,
+    global_attributes: HashSet<String>,
+    protocols: HashSet<String>,
+}
+
+impl Policy {
+    /// Creates a new policy. Names are matched ASCII case-insensitively.
+    pub(crate) fn new(
+        elements: impl IntoIterator<Item = String>,
+        attributes: impl IntoIterator<Item = (String, Vec<String>)>,
+        global_attributes: impl IntoIterator<Item = String>,
+        protocols: impl IntoIterator<Item = String>,
+    ) -> Self {
+        let mut policy_attributes: HashMap<String, HashSet<String>> = HashMap::new();
+        for (element, element_attributes) in attributes {
+            policy_attributes
+                .entry(element.to_ascii_lowercase())
+                .or_default()
+                .extend(element_attributes.iter().map(|a| a.to_ascii_lowercase()));
+        }
+
+        Self {
+            elements: elements
+                .into_iter()
+                .map(|e| e.to_ascii_lowercase())
+                .collect(),
+            attributes: policy_attributes,
+            global_attributes: global_attributes
+                .into_iter()
+                .map(|a| a.to_ascii_lowercase())
+                .collect(),
+            protocols: protocols
+                .into_iter()
+                .map(|p| p.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Parses `html` as a fragment and serializes it back, keeping only what
+    /// the policy allows. Comments, doctypes and processing instructions are
+    /// always removed.
+    pub(crate) fn sanitize(&self, html: &str) -> String {
+        let fragment = Html::parse_fragment(html);
+        let sanitized = Sanitized {
+            policy: self,
+            node: *fragment.root_element(),
+        };
+
+        let mut output = Vec::new();
+        let opts = SerializeOpts {
+            // Ensures the contents of `<noscript>` are escaped if it is allowed
+            scripting_enabled: false,
+            traversal_scope: TraversalScope::ChildrenOnly(None),
+            ..Default::default()
+        };
+        serialize(&mut output, &sanitized, opts).expect("writing to a Vec can't fail");
+
+        String::from_utf8(output).expect("serializer must produce valid UTF-8")
+    }
+
+    fn allows_element(&self, name: &str) -> bool {
+        self.elements.contains(name)
+    }
+
+    fn allows_attribute(&self, element: &str, attribute: &str, value: &str) -> bool {
+        let allowed = self.global_attributes.contains(attribute)
+            || self
+                .attributes
+                .get(element)
+                .is_some_and(|attributes| attributes.contains(attribute));
+
+        allowed && (!URL_ATTRIBUTES.contains(&attribute) || self.allows_url(value))
+    }
+
+    /// Relative URLs are always allowed, absolute URLs must use an allowed protocol
+    fn allows_url(&self, url: &str) -> bool {
+        match url_scheme(url) {
+            Some(scheme) => self.protocols.contains(&scheme),
+            None => true,
+        }
+    }
+
+    fn serialize_node<S: Serializer>(
+        &self,
+        node: NodeRef<Node>,
+        serializer: &mut S,
+    ) -> io::Result<()> {
+        match node.value() {
+            Node::Text(text) => serializer.write_text(text),
+            Node::Element(element) => {
+                let name = element.name();
+
+                if self.allows_element(name) {
+                    let attrs = element
+                        .attrs
+                        .iter()
+                        .filter(|(key, value)| {
+                            self.allows_attribute(name, &attribute_name(key), value)
+                        })
+                        .map(|(key, value)| (key, &value[..]));
+
+                    serializer.start_elem(element.name.clone(), attrs)?;
+                    self.serialize_children(node, serializer)?;
+                    serializer.end_elem(element.name.clone())
+                } else if REMOVE_CONTENTS_ELEMENTS.contains(&name) {
+                    Ok(())
+                } else {
+                    self.serialize_children(node, serializer)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn serialize_children<S: Serializer>(
+        &self,
+        node: NodeRef<Node>,
+        serializer: &mut S,
+    ) -> io::Result<()> {
+        for child in node.children() {
+            self.serialize_node(child, serializer)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Sanitized<'a> {
+    policy: &'a Policy,
+    node: NodeRef<'a, Node>,
+}
+
+impl Serialize for Sanitized<'_> {
+    fn serialize<S: Serializer>(&self, serializer: &mut S, _: TraversalScope) -> io::Result<()> {
+        self.policy.serialize_children(self.node, serializer)
+    }
+}
+
+/// Extracts the lowercased scheme of an absolute URL, mimicking how browsers
+/// ignore surrounding whitespace and embedded tabs and newlines ([URL spec][1])
+///
+/// [1]: https://url.spec.whatwg.org/#concept-basic-url-parser
+fn url_scheme(url: &str) -> Option<String> {
+    let url: String = url
+        .trim_matches(|c: char| c <= ' ')
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+
+    let (scheme, _) = url.split_once(':')?;
+
+    let mut chars = scheme.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+
+    valid.then(|| scheme.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy::new(
+            ["p", "a", "b", "img"].map(String::from),
+            [
+                ("a".to_string(), vec!["href".to_string()]),
+                (
+                    "img".to_string(),
+                    vec!["src".to_string(), "alt".to_string()],
+                ),
+            ],
+            ["title".to_string()],
+            DEFAULT_PROTOCOLS.map(String::from),
+        )
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let policy = policy();
+
+        assert_eq!("", policy.sanitize(""));
+
+        assert_eq!(
+            "<p>Hello <b>world</b></p>",
+            policy.sanitize("<p>Hello <b>world</b></p>"),
+            "allowed elements are kept"
+        );
+
+        assert_eq!(
+            "<p>Hello world</p>",
+            policy.sanitize("<p>Hello <span><i>world</i></span></p>"),
+            "disallowed elements are replaced by their children"
+        );
+
+        assert_eq!(
+            "<p>Hello</p>",
+            policy.sanitize("<p>Hello<script>alert(1)</script><style>p {}</style></p>"),
+            "the contents of script-like elements are removed"
+        );
+
+        assert_eq!(
+            "<p title=\"greeting\">Hello</p>",
+            policy.sanitize("<p title=greeting class=big onclick=alert(1)>Hello</p>"),
+            "only allowed attributes are kept"
+        );
+
+        assert_eq!(
+            "<p>Hello</p>",
+            policy.sanitize("<p href='/'>Hello<!-- comment --></p>"),
+            "per-element attributes don't apply to other elements and comments are removed"
+        );
+
+        assert_eq!(
+            "<a href=\"https://example.com\">one</a><a href=\"/relative\">two</a><a>three</a><a>four</a>",
+            policy.sanitize(
+                "<a href='https://example.com'>one</a>\
+                 <a href='/relative'>two</a>\
+                 <a href='javascript:alert(1)'>three</a>\
+                 <a href=' JAVA\tSCRIPT:alert(1)'>four</a>"
+            ),
+            "URL attributes must use allowed protocols"
+        );
+
+        assert_eq!(
+            "&lt;b&gt;not bold&lt;/b&gt;",
+            policy.sanitize("<textarea><b>not bold</b></textarea>"),
+            "text is escaped"
+        );
+
+        assert_eq!(
+            "<img alt=\"A\" src=\"a.png\">",
+            policy.sanitize("<img src='a.png' alt='A'></img>"),
+            "void elements don't get closing tags"
+        );
+    }
+
+    #[test]
+    fn test_url_scheme() {
+        assert_eq!(Some("https".to_string()), url_scheme("HTTPS://example.com"));
+        assert_eq!(
+            Some("javascript".to_string()),
+            url_scheme("\u{1}java\nscript:x")
+        );
+        assert_eq!(None, url_scheme("/path:with-colon"));
+        assert_eq!(None, url_scheme("page.html"));
+        assert_eq!(None, url_scheme("1http://example.com"));
+    }
+}
@@ -0,0 +1,134 @@
+use ego_tree::NodeId;
+use scraper::ElementRef;
+use std::collections::HashMap;
+
+/// A selector made of a single type or class selector, e.g. `a` or
+/// `.product`, which can be answered from a [`SelectorIndex`]
+#[derive(Debug, PartialEq)]
+pub(crate) enum SimpleSelector {
+    Tag(String),
+    Class(String),
+}
+
+impl SimpleSelector {
+    /// Recognizes the simple selectors among those that parse, leaving out
+    /// anything unusual (escapes, non-ASCII names, uppercase tag names) that
+    /// would need the full matching rules
+    pub(crate) fn parse(css_selector: &str) -> Option<Self> {
+        let css_selector = css_selector.trim_matches(|c: char| c.is_ascii_whitespace());
+
+        match css_selector.strip_prefix('.') {
+            Some(class) if is_identifier(class) => Some(Self::Class(class.to_string())),
+            Some(_) => None,
+            // Tag names are matched case-insensitively for HTML elements
+            // only, so lowercase names match the same elements either way
+            None if is_identifier(css_selector)
+                && !css_selector.bytes().any(|b| b.is_ascii_uppercase()) =>
+            {
+                Some(Self::Tag(css_selector.to_string()))
+            }
+            None => None,
+        }
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut bytes = name.bytes();
+
+    bytes
+        .next()
+        .is_some_and(|b| b.is_ascii_alphabetic() || b == b'_')
+        && bytes.all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The descendants of an element by tag name and by class, in tree order
+#[derive(Debug, Default)]
+pub(crate) struct SelectorIndex {
+    tags: HashMap<String, Vec<NodeId>>,
+    classes: HashMap<String, Vec<NodeId>>,
+}
+
+impl SelectorIndex {
+    pub(crate) fn new(scope: ElementRef) -> Self {
+        let mut index = Self::default();
+
+        for element in scope.descendants().skip(1).filter_map(ElementRef::wrap) {
+            index
+                .tags
+                .entry(element.value().name().to_string())
+                .or_default()
+                .push(element.id());
+
+            for class in element.value().classes() {
+                let ids = index.classes.entry(class.to_string()).or_default();
+
+                // Repeated classes are only matched once
+                if ids.last() != Some(&element.id()) {
+                    ids.push(element.id());
+                }
+            }
+        }
+
+        index
+    }
+
+    /// Returns the elements matching the selector, in tree order
+    pub(crate) fn get(&self, selector: &SimpleSelector) -> &[NodeId] {
+        let ids = match selector {
+            SimpleSelector::Tag(name) => self.tags.get(name),
+            SimpleSelector::Class(class) => self.classes.get(class),
+        };
+
+        ids.map_or(&[], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::{Html, Selector};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Some(SimpleSelector::Tag("a".to_string())),
+            SimpleSelector::parse(" a ")
+        );
+        assert_eq!(
+            Some(SimpleSelector::Class("product-card".to_string())),
+            SimpleSelector::parse(".product-card")
+        );
+
+        for css_selector in [
+            "A", "a.b", "a b", ".a.b", ".", "", "*", ".\\31 a", "#a", ".é",
+        ] {
+            assert_eq!(
+                None,
+                SimpleSelector::parse(css_selector),
+                "{css_selector:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_get() {
+        let html = Html::parse_document(
+            r#"<p class="a b a">One <a class="b">two</a></p><svg><a class="B"></a></svg>"#,
+        );
+        let index = SelectorIndex::new(html.root_element());
+
+        for css_selector in ["html", "p", "a", "svg", ".a", ".b", ".B", "div"] {
+            let expected = html
+                .root_element()
+                .select(&Selector::parse(css_selector).unwrap())
+                .map(|element| element.id())
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                expected,
+                index.get(&SimpleSelector::parse(css_selector).unwrap()),
+                "{css_selector:?}"
+            );
+        }
+    }
+}
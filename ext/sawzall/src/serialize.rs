@@ -0,0 +1,579 @@
+use crate::content_model;
+use crate::dom;
+use crate::html_to_plain::{is_block_element, is_preformatted_element};
+use ego_tree::iter::Edge;
+use ego_tree::NodeRef;
+use html5ever::{namespace_url, ns};
+use scraper::node::Element;
+use scraper::{Html, Node};
+
+/// Elements that go on their own line when pretty-printing on top of the
+/// block-level ones, so that the document's structure, its metadata and
+/// tables are laid out too
+const PRETTY_BLOCK_ELEMENTS: [&str; 20] = [
+    "base", "body", "caption", "col", "colgroup", "head", "html", "link", "meta", "noscript",
+    "script", "style", "tbody", "td", "template", "tfoot", "th", "thead", "title", "tr",
+];
+
+/// How to serialize a document
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Options {
+    /// Puts block elements on their own lines, indented by this many spaces
+    /// per level
+    pub(crate) indent: Option<usize>,
+    /// Collapses whitespace, drops comments and leaves attribute values
+    /// unquoted where possible
+    pub(crate) minify: bool,
+    /// Writes well-formed XML, with void elements self-closed, every
+    /// attribute value quoted and namespaces declared
+    pub(crate) xhtml: bool,
+}
+
+/// Serializes a document, or the content of a fragment
+pub(crate) fn to_html(html: &Html, options: &Options) -> String {
+    let nodes = if html.tree.root().value().is_fragment() {
+        html.root_element().children().collect()
+    } else {
+        html.tree.root().children().collect()
+    };
+
+    match options.indent {
+        Some(indent) => pretty(nodes, indent, options),
+        None if options.minify || options.xhtml => minify(nodes, options),
+        None if html.tree.root().value().is_fragment() => html.root_element().inner_html(),
+        None => html.html(),
+    }
+}
+
+fn is_pretty_block(node: NodeRef<Node>) -> bool {
+    node.value().as_element().is_some_and(|element| {
+        is_block_element(element.name()) || PRETTY_BLOCK_ELEMENTS.contains(&element.name())
+    })
+}
+
+/// Checks whether the element's content must be written as is, since
+/// whitespace is significant in it
+fn is_verbatim(element: &Element) -> bool {
+    is_preformatted_element(element.name()) || content_model::is_raw_text_element(element.name())
+}
+
+/// Siblings laid out one per line at the same depth
+struct Level<'a> {
+    nodes: Vec<NodeRef<'a, Node>>,
+    next: usize,
+    depth: usize,
+    /// The element the nodes are the children of, closed after them
+    parent: Option<NodeRef<'a, Node>>,
+}
+
+/// Writes block elements on their own lines, indented by depth, along with
+/// runs of the inline content between them
+///
+/// Block elements containing other ones are laid out recursively, while
+/// others are written on a single line with leading and trailing whitespace
+/// removed from their content. Whitespace within inline content and
+/// preformatted elements is kept, so only insignificant whitespace changes.
+/// The tree is walked with an explicit stack to handle deep nesting.
+fn pretty(nodes: Vec<NodeRef<Node>>, indent: usize, options: &Options) -> String {
+    let mut out = String::new();
+    let mut levels = vec![Level {
+        nodes,
+        next: 0,
+        depth: 0,
+        parent: None,
+    }];
+
+    let line = |out: &mut String, depth: usize, content: &str| {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.extend(std::iter::repeat(' ').take(depth * indent));
+        out.push_str(content);
+    };
+
+    while let Some(level) = levels.last_mut() {
+        let Some(&node) = level.nodes.get(level.next) else {
+            let level = levels.pop().expect("levels can't be empty");
+
+            if let Some(parent) = level.parent {
+                let mut close = String::new();
+                close_tag(&mut close, parent, options);
+                line(&mut out, level.depth - 1, &close);
+            }
+            continue;
+        };
+        let depth = level.depth;
+
+        if !is_pretty_block(node) {
+            let mut run = String::new();
+
+            while let Some(&node) = level.nodes.get(level.next) {
+                if is_pretty_block(node) {
+                    break;
+                }
+                write_subtree(&mut run, node, options);
+                level.next += 1;
+            }
+
+            let run = trim_whitespace(&run);
+            if !run.is_empty() {
+                line(&mut out, depth, run);
+            }
+            continue;
+        }
+
+        level.next += 1;
+        let element = node.value().as_element().expect("blocks are elements");
+        let mut content = String::new();
+        open_tag(&mut content, node, options);
+
+        if is_verbatim(element) {
+            for child in node.children() {
+                write_subtree(&mut content, child, options);
+            }
+            close_tag(&mut content, node, options);
+            line(&mut out, depth, &content);
+        } else if node.children().any(is_pretty_block) {
+            line(&mut out, depth, &content);
+            levels.push(Level {
+                nodes: node.children().collect(),
+                next: 0,
+                depth: depth + 1,
+                parent: Some(node),
+            });
+        } else {
+            let mut inner = String::new();
+            for child in node.children() {
+                write_subtree(&mut inner, child, options);
+            }
+            content.push_str(trim_whitespace(&inner));
+            close_tag(&mut content, node, options);
+            line(&mut out, depth, &content);
+        }
+    }
+
+    out
+}
+
+/// Trims ASCII whitespace, which unlike e.g. non-breaking spaces collapses
+/// when rendered
+fn trim_whitespace(html: &str) -> &str {
+    html.trim_matches(|c: char| c.is_ascii_whitespace())
+}
+
+/// Serializes a node and its descendants like html5ever does, or as XHTML
+fn write_subtree(out: &mut String, node: NodeRef<Node>, options: &Options) {
+    for edge in node.traverse() {
+        match edge {
+            Edge::Open(node) => match node.value() {
+                Node::Element(_) => open_tag(out, node, options),
+                Node::Text(text) => write_text(out, node, text, options),
+                Node::Comment(comment) => {
+                    out.push_str("<!--");
+                    out.push_str(comment);
+                    out.push_str("-->");
+                }
+                Node::Doctype(doctype) => {
+                    out.push_str("<!DOCTYPE ");
+                    out.push_str(doctype.name());
+                    out.push('>');
+                }
+                Node::ProcessingInstruction(instruction) => {
+                    out.push_str("<?");
+                    out.push_str(&instruction.target);
+                    out.push(' ');
+                    out.push_str(&instruction.data);
+                    out.push_str(if options.xhtml { "?>" } else { ">" });
+                }
+                Node::Document | Node::Fragment => {}
+            },
+            Edge::Close(node) => close_tag(out, node, options),
+        }
+    }
+}
+
+/// Writes the content of a text node, escaped unless it's in a raw text
+/// element. XML has no raw text, so it's always escaped in XHTML.
+fn write_text(out: &mut String, node: NodeRef<Node>, text: &str, options: &Options) {
+    let escaped = options.xhtml
+        || node
+            .parent()
+            .and_then(|parent| parent.value().as_element())
+            .map_or(true, |parent| content_model::escapes_text(parent.name()));
+
+    if escaped {
+        escape(out, text, false, options);
+    } else {
+        out.push_str(text);
+    }
+}
+
+/// Writes the nodes with runs of whitespace collapsed to a single space and
+/// without comments, unless only XHTML is asked for
+///
+/// Whitespace-only text next to block elements is dropped altogether, as it
+/// isn't rendered, while the content of preformatted and raw text elements
+/// is kept as is.
+fn minify(nodes: Vec<NodeRef<Node>>, options: &Options) -> String {
+    if !options.minify {
+        let mut out = String::new();
+        for node in nodes {
+            write_subtree(&mut out, node, options);
+        }
+        return out;
+    }
+
+    let mut out = String::new();
+    // How many preformatted or raw text elements the current node is in
+    let mut verbatim_depth = 0;
+
+    for edge in nodes.into_iter().flat_map(|node| node.traverse()) {
+        match edge {
+            Edge::Open(node) => match node.value() {
+                Node::Element(element) => {
+                    if is_verbatim(element) {
+                        verbatim_depth += 1;
+                    }
+                    open_tag(&mut out, node, options);
+                }
+                Node::Text(text) if verbatim_depth > 0 => write_text(&mut out, node, text, options),
+                Node::Text(text) => {
+                    if text.bytes().all(|byte| byte.is_ascii_whitespace())
+                        && is_block_whitespace(node)
+                    {
+                        continue;
+                    }
+                    write_text(&mut out, node, &collapse_whitespace(text), options);
+                }
+                Node::Comment(_) => {}
+                _ => write_subtree(&mut out, node, options),
+            },
+            Edge::Close(node) => {
+                if let Node::Element(element) = node.value() {
+                    if is_verbatim(element) {
+                        verbatim_depth -= 1;
+                    }
+                    close_tag(&mut out, node, options);
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// Checks whether whitespace is at the start or the end of a block, or
+/// between it and a block element, where it isn't rendered
+fn is_block_whitespace(node: NodeRef<Node>) -> bool {
+    let in_block = node.parent().map_or(true, |parent| {
+        !parent.value().is_element() || is_pretty_block(parent)
+    });
+    // Comments don't separate anything as they are dropped
+    let is_boundary = |sibling: Option<NodeRef<Node>>| sibling.map_or(true, is_pretty_block);
+
+    in_block
+        && (is_boundary(node.prev_siblings().find(|node| !node.value().is_comment()))
+            || is_boundary(node.next_siblings().find(|node| !node.value().is_comment())))
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut after_whitespace = false;
+
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            if !after_whitespace {
+                collapsed.push(' ');
+            }
+            after_whitespace = true;
+        } else {
+            collapsed.push(c);
+            after_whitespace = false;
+        }
+    }
+
+    collapsed
+}
+
+/// Writes an element's start tag, leaving out the quotes around attribute
+/// values that don't need them when minifying
+///
+/// In XHTML, HTML names are lowercased and elements whose namespace differs
+/// from their parent's, like `<html>` itself or `<svg>`, declare it.
+fn open_tag(out: &mut String, node: NodeRef<Node>, options: &Options) {
+    let element = node.value().as_element().expect("only elements have tags");
+    let unquoted = options.minify && !options.xhtml;
+    let lowercase = options.xhtml && element.name.ns == ns!(html);
+
+    out.push('<');
+    if lowercase {
+        out.push_str(&element.name().to_ascii_lowercase());
+    } else {
+        out.push_str(element.name());
+    }
+
+    let inherited_ns = node
+        .parent()
+        .and_then(|parent| parent.value().as_element())
+        .map(|parent| &parent.name.ns);
+    if options.xhtml
+        && inherited_ns != Some(&element.name.ns)
+        && !element.name.ns.is_empty()
+        && element.attr("xmlns").is_none()
+    {
+        out.push_str(" xmlns=\"");
+        escape(out, &element.name.ns, true, options);
+        out.push('"');
+    }
+
+    for (name, value) in element.attrs.iter() {
+        out.push(' ');
+        if lowercase {
+            out.push_str(&dom::attribute_name(name).to_ascii_lowercase());
+        } else {
+            out.push_str(&dom::attribute_name(name));
+        }
+
+        if unquoted && value.is_empty() {
+            continue;
+        }
+
+        // Characters that would end or change the meaning of an unquoted
+        // value, see https://html.spec.whatwg.org/#unquoted
+        let quoted = !unquoted
+            || value.chars().any(|c| {
+                c.is_ascii_whitespace() || matches!(c, '"' | '\'' | '=' | '<' | '>' | '`')
+            });
+
+        out.push('=');
+        if quoted {
+            out.push('"');
+        }
+        escape(out, value, true, options);
+        if quoted {
+            out.push('"');
+        }
+    }
+
+    if options.xhtml && is_void(element) {
+        out.push_str(" />");
+    } else {
+        out.push('>');
+    }
+}
+
+fn close_tag(out: &mut String, node: NodeRef<Node>, options: &Options) {
+    if let Node::Element(element) = node.value() {
+        if !is_void(element) {
+            out.push_str("</");
+            if options.xhtml && element.name.ns == ns!(html) {
+                out.push_str(&element.name().to_ascii_lowercase());
+            } else {
+                out.push_str(element.name());
+            }
+            out.push('>');
+        }
+    }
+}
+
+/// Checks whether the element is written without a closing tag, which only
+/// HTML ones are
+fn is_void(element: &Element) -> bool {
+    element.name.ns == ns!(html) && content_model::is_void_element(element.name())
+}
+
+/// Escapes text or an attribute value like html5ever does, and in XHTML
+/// without the entities that XML doesn't define
+fn escape(out: &mut String, text: &str, attribute: bool, options: &Options) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '\u{a0}' if options.xhtml => out.push_str("&#160;"),
+            '\u{a0}' => out.push_str("&nbsp;"),
+            '"' if attribute => out.push_str("&quot;"),
+            '<' if !attribute || options.xhtml => out.push_str("&lt;"),
+            '>' if !attribute => out.push_str("&gt;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pretty_fragment(input: &str) -> String {
+        to_html(
+            &Html::parse_fragment(input),
+            &Options {
+                indent: Some(2),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_write_subtree() {
+        let input = concat!(
+            r#"<p class="a&quot;b">One &amp; <b>two</b>&nbsp;&lt;3<br><!-- c --></p>"#,
+            r#"<script>if (a < b) {}</script><textarea>&lt;b&gt;</textarea><img src="x">"#
+        );
+        let html = Html::parse_fragment(input);
+        let mut out = String::new();
+
+        for node in html.root_element().children() {
+            write_subtree(&mut out, node, &Options::default());
+        }
+
+        assert_eq!(html.root_element().inner_html(), out);
+    }
+
+    #[test]
+    fn test_pretty() {
+        assert_eq!(
+            concat!(
+                "<ul>\n",
+                "  <li>One <b>bold</b> item</li>\n",
+                "  <li>\n",
+                "    Two\n",
+                "    <ul>\n",
+                "      <li>Nested</li>\n",
+                "    </ul>\n",
+                "  </li>\n",
+                "</ul>\n",
+                "<p>Text</p>"
+            ),
+            pretty_fragment(
+                "<ul>\n<li>  One <b>bold</b> item\n</li><li>Two<ul><li>Nested</li></ul></li></ul>\n\n<p>Text</p>"
+            )
+        );
+        assert_eq!(
+            "<div>\n  <pre>  keep\n   this </pre>\n  <p>Text</p>\n</div>",
+            pretty_fragment("<div><pre>  keep\n   this </pre><p>Text</p></div>"),
+            "preformatted content is kept"
+        );
+        assert_eq!(
+            "<div>\n  Before <i>inline</i>\n  <hr>\n  after\n</div>",
+            pretty_fragment("<div>Before <i>inline</i><hr>after</div>"),
+            "void elements have no closing tag"
+        );
+        assert_eq!(
+            concat!(
+                "<!DOCTYPE html>\n",
+                "<html>\n",
+                "  <head>\n",
+                "    <title>Title</title>\n",
+                "  </head>\n",
+                "  <body>\n",
+                "    <table>\n",
+                "      <tbody>\n",
+                "        <tr>\n",
+                "          <td>Cell</td>\n",
+                "        </tr>\n",
+                "      </tbody>\n",
+                "    </table>\n",
+                "  </body>\n",
+                "</html>"
+            ),
+            to_html(
+                &Html::parse_document(
+                    "<!DOCTYPE html><title>Title</title><table><tr><td>Cell</td></tr></table>"
+                ),
+                &Options {
+                    indent: Some(2),
+                    ..Default::default()
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_minify() {
+        let minify = |input: &str| {
+            to_html(
+                &Html::parse_fragment(input),
+                &Options {
+                    minify: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert_eq!(
+            r#"<ul><li class=a>One <b>two</b> three </li><li title="a b">Four</li></ul>"#,
+            minify(
+                "<ul>\n  <li class=\"a\">One\n   <b>two</b>  three </li>\n  <!-- x -->\n  <li title='a b'>Four</li>\n</ul>"
+            )
+        );
+        assert_eq!(
+            "<pre>  keep\n  <b> this </b></pre><textarea> and  this </textarea><script> a  =  1 </script>",
+            minify("<pre>  keep\n  <b> this </b></pre><textarea> and  this </textarea><script> a  =  1 </script>"),
+            "whitespace-sensitive content is kept"
+        );
+        assert_eq!(
+            r#"<input data-x="a=b" disabled value=a&amp;b><a href=/a/ title="&quot;">x</a> <i>y</i>"#,
+            minify(
+                r#"<input disabled="" value="a&amp;b" data-x="a=b"><a href="/a/" title='"'>x</a> <i>y</i>"#
+            ),
+            "values that need quotes keep them"
+        );
+    }
+
+    #[test]
+    fn test_xhtml() {
+        let xhtml = |html: &Html, minify: bool| {
+            to_html(
+                html,
+                &Options {
+                    xhtml: true,
+                    minify,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert_eq!(
+            concat!(
+                r#"<p class="a">One<br />two&#160;<input disabled="" /> <!-- c --></p>"#,
+                r#"<script>if (a &lt; b) {}</script>"#,
+                r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 1 1"><path d="M0"></path></svg>"#
+            ),
+            xhtml(
+                &Html::parse_fragment(concat!(
+                    "<P CLASS=a>One<BR>two&nbsp;<input disabled> <!-- c --></P>",
+                    "<script>if (a < b) {}</script><svg viewbox='0 0 1 1'><path d=M0 /></svg>"
+                )),
+                false
+            )
+        );
+        assert_eq!(
+            concat!(
+                r#"<!DOCTYPE html><html xmlns="http://www.w3.org/1999/xhtml"><head></head>"#,
+                r#"<body><p class="a">One <img src="a.png" /></p></body></html>"#
+            ),
+            xhtml(
+                &Html::parse_document("<!DOCTYPE html><p class=a>One\n <!-- c --><img src=a.png>"),
+                true
+            ),
+            "attribute values stay quoted when minifying"
+        );
+    }
+
+    #[test]
+    fn test_pretty_deep_nesting() {
+        let html = Html::parse_fragment(&"<div>".repeat(20_000));
+
+        assert_eq!(
+            20_000 * 2 - 1,
+            to_html(
+                &html,
+                &Options {
+                    indent: Some(0),
+                    ..Default::default()
+                }
+            )
+            .lines()
+            .count()
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::gvl;
+use crate::memsize;
+use crate::stats::CachedHtml;
+use ego_tree::NodeId;
+use scraper::Html;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A document's tree, which any number of threads can read at once as long
+/// as it isn't being modified
+///
+/// Elements look up their id and classes the first time they're used, so
+/// reading the tree may write to it. Those lookups are all done up front
+/// when the tree is parsed, so that it can then be shared safely. Modifying
+/// the tree undoes them, and the next reader does them again before sharing
+/// it.
+pub(crate) struct SharedHtml {
+    html: RwLock<CachedHtml>,
+    /// How many bytes the tree was reported to the GC as taking up, see
+    /// [`memsize::adjust_memory_usage`]
+    reported: usize,
+}
+
+// SAFETY: `Html` isn't `Sync` because reading it may write to it in two
+// ways: elements look up their id and classes the first time they're used,
+// and cloning a string the tree owns marks its buffer as shared. Readers only
+// share the tree once every lookup is done (see `read_with`), and nodes are
+// copied with new strings rather than cloned (see `dom::copy_value`).
+unsafe impl Sync for SharedHtml {}
+
+/// Read access to the tree, shared with any other readers
+pub(crate) type ReadGuard<'a> = RwLockReadGuard<'a, CachedHtml>;
+
+impl SharedHtml {
+    /// Wraps a tree, telling the GC how large it is. Only the size it has
+    /// now is reported, changes made to it afterwards aren't.
+    pub(crate) fn new(html: Html) -> Self {
+        let mut html = CachedHtml::new(html);
+        html.warm();
+
+        let reported = html.memsize();
+        memsize::adjust_memory_usage(reported as isize);
+
+        Self {
+            html: RwLock::new(html),
+            reported,
+        }
+    }
+
+    /// Locks the tree for reading, waiting for it without holding the GVL
+    pub(crate) fn read(&self) -> ReadGuard<'_> {
+        self.read_with(gvl::read, gvl::write)
+    }
+
+    /// Locks the tree for reading from a thread that doesn't hold the GVL
+    pub(crate) fn read_blocking(&self) -> ReadGuard<'_> {
+        self.read_with(
+            |lock| lock.read().expect("failed to lock document"),
+            |lock| lock.write().expect("failed to lock document"),
+        )
+    }
+
+    /// Locks two different trees for reading, in the same order whichever
+    /// comes first so that two threads locking them both ways around don't
+    /// wait for each other
+    pub(crate) fn read_pair<'a>(a: &'a Self, b: &'a Self) -> (ReadGuard<'a>, ReadGuard<'a>) {
+        if (a as *const Self) < (b as *const Self) {
+            let a = a.read();
+            (a, b.read())
+        } else {
+            let b = b.read();
+            (a.read(), b)
+        }
+    }
+
+    /// Locks the tree for modifying it, waiting for it without holding the
+    /// GVL
+    pub(crate) fn write(&self) -> RwLockWriteGuard<'_, CachedHtml> {
+        gvl::write(&self.html)
+    }
+
+    /// Stops the tree from being modified, so that it can be read from any
+    /// number of threads and Ractors at once
+    pub(crate) fn freeze(&self) {
+        let mut html = self.write();
+        // Frozen trees are never modified, so they can always be shared
+        html.warm();
+        html.freeze();
+    }
+
+    /// Estimates how many bytes the tree takes up, or returns `None` if it's
+    /// being modified, since the GC can't wait for it
+    pub(crate) fn memsize(&self) -> Option<usize> {
+        Some(self.html.try_read().ok()?.memsize())
+    }
+
+    /// Estimates how many bytes a node takes up, like [`Self::memsize`]
+    pub(crate) fn node_memsize(&self, id: NodeId) -> Option<usize> {
+        let html = self.html.try_read().ok()?;
+
+        html.tree
+            .get(id)
+            .map(|node| memsize::node_size(node.value()))
+    }
+
+    /// Warms the tree first if it was modified, while no one else is reading
+    /// it. The lock is then taken again for reading (downgrading it isn't
+    /// stable in the Rust versions supported), warming the tree again if it
+    /// was modified in between.
+    fn read_with<'a, R, W>(&'a self, read: R, write: W) -> ReadGuard<'a>
+    where
+        R: Fn(&'a RwLock<CachedHtml>) -> RwLockReadGuard<'a, CachedHtml>,
+        W: Fn(&'a RwLock<CachedHtml>) -> RwLockWriteGuard<'a, CachedHtml>,
+    {
+        loop {
+            let html = read(&self.html);
+            if html.is_warm() {
+                return html;
+            }
+
+            drop(html);
+            write(&self.html).warm();
+        }
+    }
+}
+
+impl Drop for SharedHtml {
+    /// Documents are only dropped while holding the GVL, either when they
+    /// are garbage collected or by the methods that created them
+    fn drop(&mut self) {
+        memsize::adjust_memory_usage(-(self.reported as isize));
+    }
+}
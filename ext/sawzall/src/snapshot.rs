@@ -0,0 +1,119 @@
+use crate::dom;
+use ego_tree::iter::Edge;
+use ego_tree::NodeRef;
+use scraper::Node;
+use std::fmt::Write;
+
+/// Serializes a subtree as nested JSON objects, to snapshot it e.g. for
+/// diffing
+///
+/// Elements become `{"name": ..., "attrs": {...}, "children": [...]}` and
+/// text nodes `{"text": ...}`, while comments and other nodes are left out.
+/// The tree is walked without recursion so that deeply nested documents
+/// don't overflow the stack.
+pub(crate) fn to_json(node: NodeRef<Node>) -> String {
+    let mut json = String::new();
+    // Whether the next child written at each level is its parent's first
+    let mut first_child = vec![true];
+
+    for edge in node.traverse() {
+        match edge {
+            Edge::Open(node) => {
+                if !matches!(node.value(), Node::Element(_) | Node::Text(_)) {
+                    continue;
+                }
+
+                let first = first_child.last_mut().expect("stack can't be empty");
+                if !std::mem::replace(first, false) {
+                    json.push(',');
+                }
+
+                match node.value() {
+                    Node::Element(element) => {
+                        json.push_str("{\"name\":");
+                        write_string(&mut json, element.name());
+                        json.push_str(",\"attrs\":{");
+
+                        for (index, (name, value)) in element.attrs.iter().enumerate() {
+                            if index > 0 {
+                                json.push(',');
+                            }
+                            write_string(&mut json, &dom::attribute_name(name));
+                            json.push(':');
+                            write_string(&mut json, value);
+                        }
+
+                        json.push_str("},\"children\":[");
+                        first_child.push(true);
+                    }
+                    Node::Text(text) => {
+                        json.push_str("{\"text\":");
+                        write_string(&mut json, text);
+                        json.push('}');
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            Edge::Close(node) => {
+                if node.value().is_element() {
+                    json.push_str("]}");
+                    first_child.pop();
+                }
+            }
+        }
+    }
+
+    json
+}
+
+fn write_string(json: &mut String, value: &str) {
+    json.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => {
+                write!(json, "\\u{:04x}", c as u32).expect("writing to a String can't fail");
+            }
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Html;
+
+    #[test]
+    fn test_to_json() {
+        let html = Html::parse_fragment(
+            "<p class=\"intro\" data-x='say \"hi\"'>Hello<br>\n<b>world</b><!-- note --></p>",
+        );
+        let p = html.root_element().first_child().unwrap();
+
+        assert_eq!(
+            concat!(
+                r#"{"name":"p","attrs":{"class":"intro","data-x":"say \"hi\""},"children":["#,
+                r#"{"text":"Hello"},{"name":"br","attrs":{},"children":[]},{"text":"\n"},"#,
+                r#"{"name":"b","attrs":{},"children":[{"text":"world"}]}]}"#
+            ),
+            to_json(p)
+        );
+        assert_eq!(
+            r#"{"text":"tab\tbell\u0007"}"#,
+            to_json(
+                Html::parse_fragment("tab\tbell\u{7}")
+                    .root_element()
+                    .first_child()
+                    .unwrap()
+            )
+        );
+    }
+}
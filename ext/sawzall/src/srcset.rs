@@ -0,0 +1,103 @@
+/// An image candidate from a `srcset` attribute
+#[derive(Debug, PartialEq)]
+pub(crate) struct Candidate<'a> {
+    pub(crate) url: &'a str,
+    /// Unparsed descriptors such as `2x` or `100w`
+    pub(crate) descriptors: Vec<&'a str>,
+}
+
+/// Splits a `srcset` attribute into its candidates ([HTML spec][1])
+///
+/// [1]: https://html.spec.whatwg.org/multipage/images.html#parsing-a-srcset-attribute
+pub(crate) fn parse_srcset(srcset: &str) -> Vec<Candidate<'_>> {
+    let mut candidates = Vec::new();
+    let mut rest = srcset;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+
+        if rest.is_empty() {
+            break;
+        }
+
+        let url_end = rest
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let mut url = &rest[..url_end];
+        rest = &rest[url_end..];
+
+        // A URL ending with a comma has no descriptors
+        let descriptors = if url.ends_with(',') {
+            url = url.trim_end_matches(',');
+            Vec::new()
+        } else {
+            let mut depth = 0usize;
+            let descriptors_end = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+
+                    c == ',' && depth == 0
+                })
+                .map_or(rest.len(), |(index, _)| index);
+
+            let descriptors = rest[..descriptors_end].split_ascii_whitespace().collect();
+            rest = &rest[descriptors_end..];
+            descriptors
+        };
+
+        candidates.push(Candidate { url, descriptors });
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate<'a>(url: &'a str, descriptors: &[&'a str]) -> Candidate<'a> {
+        Candidate {
+            url,
+            descriptors: descriptors.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_parse_srcset() {
+        assert_eq!(Vec::<Candidate>::new(), parse_srcset(" , "));
+
+        assert_eq!(
+            vec![candidate("a.png", &["1x"]), candidate("/b.png", &["2x"])],
+            parse_srcset("a.png 1x,/b.png   2x")
+        );
+
+        assert_eq!(
+            vec![
+                candidate("a.png", &[]),
+                candidate("b.png", &["100w", "50h"])
+            ],
+            parse_srcset(" a.png, b.png 100w 50h ,"),
+            "descriptors are optional"
+        );
+
+        assert_eq!(
+            vec![candidate("data:image/png;base64,AAAA", &["1x"])],
+            parse_srcset("data:image/png;base64,AAAA 1x"),
+            "commas inside URLs are kept"
+        );
+
+        assert_eq!(
+            vec![
+                candidate("a.png", &["future(1,", "2)"]),
+                candidate("b.png", &[])
+            ],
+            parse_srcset("a.png future(1, 2), b.png"),
+            "commas inside parentheses don't end descriptors"
+        );
+    }
+}
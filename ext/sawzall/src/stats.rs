@@ -0,0 +1,388 @@
+use crate::memsize;
+use crate::parse_error::SourceLines;
+use crate::selector_index::SelectorIndex;
+use crate::text_metrics;
+use ego_tree::{iter::Edge, NodeId};
+use scraper::{ElementRef, Html, Node};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::OnceLock;
+
+/// A parsed document along with statistics computed from it on demand
+///
+/// Each statistic is computed at most once, and they are all dropped
+/// whenever the document is borrowed mutably since it may have changed.
+#[derive(Debug)]
+pub(crate) struct CachedHtml {
+    html: Html,
+    stats: OnceLock<Stats>,
+    positions: OnceLock<HashMap<NodeId, usize>>,
+    ids: OnceLock<HashMap<String, NodeId>>,
+    memsize: OnceLock<usize>,
+    selector_index: OnceLock<SelectorIndex>,
+    /// Whether the selector index was asked for, after which it's rebuilt
+    /// the next time it's used whenever the tree changes
+    indexed: bool,
+    /// Whether every element's id and classes, which scraper only looks up
+    /// the first time they're used, were looked up since the tree last
+    /// changed
+    warm: bool,
+    /// Set once the Ruby document is frozen, after which neither it nor its
+    /// nodes may modify the tree
+    frozen: bool,
+    /// Kept when the tree changes, since nodes keep their ids and new ones
+    /// have none
+    source_lines: SourceLines,
+}
+
+impl CachedHtml {
+    pub(crate) fn new(html: Html) -> Self {
+        Self {
+            html,
+            stats: OnceLock::new(),
+            positions: OnceLock::new(),
+            ids: OnceLock::new(),
+            memsize: OnceLock::new(),
+            selector_index: OnceLock::new(),
+            indexed: false,
+            warm: false,
+            frozen: false,
+            source_lines: SourceLines::default(),
+        }
+    }
+
+    /// Returns the first element in tree order with the given `id` attribute,
+    /// like the DOM's `getElementById`
+    pub(crate) fn element_by_id(&self, id: &str) -> Option<NodeId> {
+        self.ids.get_or_init(|| ids(&self.html)).get(id).copied()
+    }
+
+    /// Indexes the elements below the root element by tag name and class
+    pub(crate) fn build_selector_index(&mut self) {
+        self.indexed = true;
+        self.selector_index();
+    }
+
+    /// Returns the selector index if one was built
+    pub(crate) fn selector_index(&self) -> Option<&SelectorIndex> {
+        self.indexed.then(|| {
+            self.selector_index
+                .get_or_init(|| SelectorIndex::new(self.html.root_element()))
+        })
+    }
+
+    /// Looks up every element's id and classes, so that reading the tree
+    /// doesn't write to it anymore until it's borrowed mutably
+    pub(crate) fn warm(&mut self) {
+        if self.warm {
+            return;
+        }
+
+        for node in self.html.tree.nodes() {
+            if let Some(element) = node.value().as_element() {
+                element.id();
+                element.classes().for_each(drop);
+            }
+        }
+
+        self.warm = true;
+    }
+
+    pub(crate) fn is_warm(&self) -> bool {
+        self.warm
+    }
+
+    pub(crate) fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub(crate) fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Returns the line of the input each element created by the parser came
+    /// from
+    pub(crate) fn source_lines(&self) -> &SourceLines {
+        &self.source_lines
+    }
+
+    pub(crate) fn set_source_lines(&mut self, source_lines: SourceLines) {
+        self.source_lines = source_lines;
+    }
+
+    /// Returns how many elements of each name the document contains, in the
+    /// order the names first appear
+    pub(crate) fn tag_counts(&self) -> &[(String, usize)] {
+        &self.stats().tags
+    }
+
+    pub(crate) fn stats(&self) -> &Stats {
+        self.stats.get_or_init(|| stats(&self.html))
+    }
+
+    /// Estimates how many bytes the tree and the lines its elements came
+    /// from take up
+    pub(crate) fn memsize(&self) -> usize {
+        *self
+            .memsize
+            .get_or_init(|| memsize::html_size(&self.html) + self.source_lines.heap_size())
+    }
+
+    /// Returns the index of the node in a preorder traversal of the document,
+    /// or `None` if it was removed from it
+    pub(crate) fn position(&self, id: NodeId) -> Option<usize> {
+        self.positions
+            .get_or_init(|| positions(&self.html))
+            .get(&id)
+            .copied()
+    }
+}
+
+impl Deref for CachedHtml {
+    type Target = Html;
+
+    fn deref(&self) -> &Html {
+        &self.html
+    }
+}
+
+impl DerefMut for CachedHtml {
+    fn deref_mut(&mut self) -> &mut Html {
+        self.stats.take();
+        self.positions.take();
+        self.ids.take();
+        self.memsize.take();
+        self.selector_index.take();
+        self.warm = false;
+
+        &mut self.html
+    }
+}
+
+/// What a document is made of, leaving out the `<html>` element fragments
+/// are wrapped in from the elements
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Stats {
+    pub(crate) elements: usize,
+    pub(crate) texts: usize,
+    pub(crate) comments: usize,
+    pub(crate) doctypes: usize,
+    pub(crate) processing_instructions: usize,
+    /// How many elements of each name there are, in the order the names
+    /// first appear
+    pub(crate) tags: Vec<(String, usize)>,
+    /// How deeply the most nested node is, counted like the `max_depth`
+    /// parse limit with the `<html>` element at depth 1
+    pub(crate) max_depth: usize,
+    /// The number of bytes of non-whitespace text, see
+    /// [`text_metrics::text_length`]
+    pub(crate) text_length: usize,
+}
+
+fn stats(html: &Html) -> Stats {
+    let mut stats = Stats::default();
+    let mut indices: HashMap<&str, usize> = HashMap::new();
+    let skip = html
+        .tree
+        .root()
+        .value()
+        .is_fragment()
+        .then(|| html.root_element().id());
+    // The depth of the next node opened, the root's being 0
+    let mut depth = 0;
+
+    for edge in html.tree.root().traverse() {
+        let node = match edge {
+            Edge::Open(node) => node,
+            Edge::Close(_) => {
+                depth -= 1;
+                continue;
+            }
+        };
+
+        stats.max_depth = stats.max_depth.max(depth);
+        depth += 1;
+
+        match node.value() {
+            Node::Element(element) if Some(node.id()) != skip => {
+                stats.elements += 1;
+
+                let name = element.name();
+                match indices.get(name) {
+                    Some(&index) => stats.tags[index].1 += 1,
+                    None => {
+                        indices.insert(name, stats.tags.len());
+                        stats.tags.push((name.to_string(), 1));
+                    }
+                }
+            }
+            Node::Text(text) => {
+                stats.texts += 1;
+                stats.text_length += text_metrics::text_length(text);
+            }
+            Node::Comment(_) => stats.comments += 1,
+            Node::Doctype(_) => stats.doctypes += 1,
+            Node::ProcessingInstruction(_) => stats.processing_instructions += 1,
+            _ => {}
+        }
+    }
+
+    stats
+}
+
+fn positions(html: &Html) -> HashMap<NodeId, usize> {
+    html.tree
+        .root()
+        .descendants()
+        .enumerate()
+        .map(|(position, node)| (node.id(), position))
+        .collect()
+}
+
+fn ids(html: &Html) -> HashMap<String, NodeId> {
+    let mut ids = HashMap::new();
+
+    for element in html.tree.root().descendants().filter_map(ElementRef::wrap) {
+        match element.value().id() {
+            Some(id) if !id.is_empty() => {
+                ids.entry(id.to_string()).or_insert(element.id());
+            }
+            _ => {}
+        }
+    }
+
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Selector;
+
+    fn counts(pairs: &[(&str, usize)]) -> Vec<(String, usize)> {
+        pairs
+            .iter()
+            .map(|(name, count)| (name.to_string(), *count))
+            .collect()
+    }
+
+    #[test]
+    fn test_tag_counts() {
+        let fragment = Html::parse_fragment("<p>One <b>two</b></p><p>Three</p>");
+        let document = Html::parse_document("<title>Title</title><p>Text</p>");
+
+        assert_eq!(counts(&[("p", 2), ("b", 1)]), stats(&fragment).tags);
+        assert_eq!(
+            counts(&[
+                ("html", 1),
+                ("head", 1),
+                ("title", 1),
+                ("body", 1),
+                ("p", 1)
+            ]),
+            stats(&document).tags
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let document =
+            Html::parse_document("<!DOCTYPE html><!-- a --><p>One <b>two</b></p>\n<p>Thrée</p>");
+        let fragment = Html::parse_fragment("<p>One <b>two</b></p>");
+
+        assert_eq!(
+            Stats {
+                elements: 6,
+                texts: 4,
+                comments: 1,
+                doctypes: 1,
+                processing_instructions: 0,
+                tags: counts(&[("html", 1), ("head", 1), ("body", 1), ("p", 2), ("b", 1)]),
+                max_depth: 5,
+                text_length: 12,
+            },
+            stats(&document)
+        );
+        assert_eq!(2, stats(&fragment).elements);
+        assert_eq!(4, stats(&fragment).max_depth);
+        assert_eq!(0, stats(&Html::parse_fragment("")).elements);
+    }
+
+    #[test]
+    fn test_cached_html() {
+        let mut html = CachedHtml::new(Html::parse_fragment("<p>One</p><p>Two</p>"));
+
+        assert_eq!(counts(&[("p", 2)]), html.tag_counts());
+
+        let id = html
+            .select(&Selector::parse("p").unwrap())
+            .next()
+            .unwrap()
+            .id();
+        html.tree.get_mut(id).unwrap().detach();
+
+        assert_eq!(
+            counts(&[("p", 1)]),
+            html.tag_counts(),
+            "mutable borrows drop the counts"
+        );
+    }
+
+    #[test]
+    fn test_position() {
+        let mut html = CachedHtml::new(Html::parse_fragment("<p>One</p><p>Two</p>"));
+        let ids: Vec<NodeId> = html
+            .select(&Selector::parse("p").unwrap())
+            .map(|element| element.id())
+            .collect();
+
+        assert_eq!(
+            vec![Some(2), Some(4)],
+            vec![html.position(ids[0]), html.position(ids[1])]
+        );
+
+        html.tree.get_mut(ids[0]).unwrap().detach();
+
+        assert_eq!(
+            None,
+            html.position(ids[0]),
+            "removed nodes have no position"
+        );
+        assert_eq!(Some(2), html.position(ids[1]));
+    }
+
+    #[test]
+    fn test_element_by_id() {
+        let mut html = CachedHtml::new(Html::parse_fragment(
+            r#"<p id="a">One</p><p id="a">Two</p><p id="">Three</p><b id="b"></b>"#,
+        ));
+        let first = html.element_by_id("a").unwrap();
+
+        assert_eq!(
+            Some("One".to_string()),
+            ElementRef::wrap(html.tree.get(first).unwrap()).map(|p| p.inner_html()),
+            "the first element with the id wins"
+        );
+        assert_eq!(None, html.element_by_id(""));
+        assert_eq!(None, html.element_by_id("c"));
+
+        html.tree.get_mut(first).unwrap().detach();
+        assert_ne!(
+            Some(first),
+            html.element_by_id("a"),
+            "mutable borrows drop the index"
+        );
+    }
+
+    #[test]
+    fn test_warm() {
+        let mut html = CachedHtml::new(Html::parse_fragment("<p id=a class=b>One</p>"));
+
+        assert!(!html.is_warm());
+        html.warm();
+        assert!(html.is_warm());
+
+        let _: &mut Html = &mut html;
+        assert!(!html.is_warm(), "mutable borrows may add elements");
+    }
+}
@@ -0,0 +1,116 @@
+use ego_tree::NodeId;
+use scraper::{Html, Node};
+
+/// Categories of nodes that can be removed by [`strip`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Category {
+    /// `<script>` and `<noscript>` elements
+    Scripts,
+    /// `<style>` elements and stylesheet `<link>` elements
+    Styles,
+    /// Comment nodes
+    Comments,
+    /// `<form>` elements
+    Forms,
+}
+
+impl Category {
+    pub(crate) const ALL: [Category; 4] = [
+        Category::Scripts,
+        Category::Styles,
+        Category::Comments,
+        Category::Forms,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Category::Scripts => "scripts",
+            Category::Styles => "styles",
+            Category::Comments => "comments",
+            Category::Forms => "forms",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|category| category.name() == name)
+    }
+
+    fn matches(self, node: &Node) -> bool {
+        match (self, node) {
+            (Category::Scripts, Node::Element(element)) => {
+                matches!(element.name(), "script" | "noscript")
+            }
+            (Category::Styles, Node::Element(element)) => match element.name() {
+                "style" => true,
+                "link" => element.attr("rel").is_some_and(|rel| {
+                    rel.split_ascii_whitespace()
+                        .any(|token| token.eq_ignore_ascii_case("stylesheet"))
+                }),
+                _ => false,
+            },
+            (Category::Comments, Node::Comment(_)) => true,
+            (Category::Forms, Node::Element(element)) => element.name() == "form",
+            _ => false,
+        }
+    }
+}
+
+/// Detaches every node matching one of the given categories (along with its
+/// descendants) from the tree
+pub(crate) fn strip(html: &mut Html, categories: &[Category]) {
+    let node_ids: Vec<NodeId> = html
+        .tree
+        .root()
+        .descendants()
+        .filter(|node| {
+            categories
+                .iter()
+                .any(|category| category.matches(node.value()))
+        })
+        .map(|node| node.id())
+        .collect();
+
+    for node_id in node_ids {
+        if let Some(mut node) = html.tree.get_mut(node_id) {
+            node.detach();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(input: &str, categories: &[Category]) -> String {
+        let mut html = Html::parse_fragment(input);
+        super::strip(&mut html, categories);
+        html.root_element().inner_html()
+    }
+
+    #[test]
+    fn test_strip() {
+        let input = r#"<link href="a.css" rel="Stylesheet"><link href="a.ico" rel="icon"><style>p {}</style><p>Hello<!-- comment --><script>alert(1)</script></p><form><input name="q"></form>"#;
+
+        assert_eq!(input, strip(input, &[]), "nothing is removed by default");
+
+        assert_eq!(
+            r#"<link href="a.ico" rel="icon"><p>Hello</p>"#,
+            strip(input, &Category::ALL),
+            "every category can be removed at once"
+        );
+
+        assert_eq!(
+            r#"<link href="a.css" rel="Stylesheet"><link href="a.ico" rel="icon"><style>p {}</style><p>Hello</p><form><input name="q"></form>"#,
+            strip(input, &[Category::Scripts, Category::Comments]),
+            "only the given categories are removed"
+        );
+    }
+
+    #[test]
+    fn test_category_from_name() {
+        assert_eq!(Some(Category::Forms), Category::from_name("forms"));
+        assert_eq!(None, Category::from_name("images"));
+    }
+}
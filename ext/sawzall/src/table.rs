@@ -0,0 +1,262 @@
+use crate::html_to_plain::html_to_plain;
+use crate::images::parse_dimension;
+use scraper::ElementRef;
+
+/// A `<table>` laid out on a grid, with cells spanning several rows or
+/// columns repeated in each slot they cover
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Table {
+    pub(crate) head: Vec<Row>,
+    pub(crate) body: Vec<Row>,
+    pub(crate) foot: Vec<Row>,
+}
+
+/// The text of each cell in a row, with `None` for slots no cell covers
+pub(crate) type Row = Vec<Option<String>>;
+
+#[derive(Clone)]
+struct Cell {
+    text: String,
+    header: bool,
+}
+
+/// Lays out a `<table>` element, returning `None` for other elements
+///
+/// Rows are grouped by their `<thead>`, `<tbody>` or `<tfoot>`. When there
+/// is no `<thead>`, leading rows made only of `<th>` cells are treated as
+/// the head. Cells of nested tables are ignored.
+pub(crate) fn table(table: ElementRef) -> Option<Table> {
+    if table.value().name() != "table" {
+        return None;
+    }
+
+    let mut head = Vec::new();
+    let mut body = Vec::new();
+    let mut foot = Vec::new();
+    // Rows directly inside the table, which the parser wraps in a `<tbody>`
+    // but edited trees may still contain
+    let mut loose_rows = Vec::new();
+
+    for child in table.children().filter_map(ElementRef::wrap) {
+        let group = match child.value().name() {
+            "tr" => {
+                loose_rows.push(child);
+                continue;
+            }
+            "thead" => &mut head,
+            "tbody" => &mut body,
+            "tfoot" => &mut foot,
+            _ => continue,
+        };
+
+        let rows: Vec<ElementRef> = child
+            .children()
+            .filter_map(ElementRef::wrap)
+            .filter(|row| row.value().name() == "tr")
+            .collect();
+
+        group.extend(layout(&rows));
+    }
+
+    body.extend(layout(&loose_rows));
+
+    if head.is_empty() {
+        let header_rows = body
+            .iter()
+            .take_while(|row| {
+                row.iter().any(Option::is_some) && row.iter().flatten().all(|cell| cell.header)
+            })
+            .count();
+
+        head = body.drain(..header_rows).collect();
+    }
+
+    let width = head
+        .iter()
+        .chain(&body)
+        .chain(&foot)
+        .map(Vec::len)
+        .max()
+        .unwrap_or(0);
+
+    let finish = |rows: Vec<Vec<Option<Cell>>>| -> Vec<Row> {
+        rows.into_iter()
+            .map(|mut row| {
+                row.resize(width, None);
+                row.into_iter()
+                    .map(|cell| cell.map(|cell| cell.text))
+                    .collect()
+            })
+            .collect()
+    };
+
+    Some(Table {
+        head: finish(head),
+        body: finish(body),
+        foot: finish(foot),
+    })
+}
+
+/// Places the cells of a row group on a grid following their `colspan` and
+/// `rowspan` ([HTML spec][1]). Cells can't span past the end of their group.
+///
+/// [1]: https://html.spec.whatwg.org/multipage/tables.html#forming-a-table
+fn layout(rows: &[ElementRef]) -> Vec<Vec<Option<Cell>>> {
+    let mut grid: Vec<Vec<Option<Cell>>> = vec![Vec::new(); rows.len()];
+
+    for (y, row) in rows.iter().enumerate() {
+        let mut x = 0;
+
+        for cell in row.children().filter_map(ElementRef::wrap) {
+            let header = match cell.value().name() {
+                "th" => true,
+                "td" => false,
+                _ => continue,
+            };
+
+            // Skip slots taken by cells from previous rows
+            while grid[y].get(x).is_some_and(Option::is_some) {
+                x += 1;
+            }
+
+            let colspan = span(cell.attr("colspan"), 1000).max(1);
+            // A rowspan of 0 extends the cell to the end of the group
+            let rowspan = match span(cell.attr("rowspan"), 65534) {
+                0 => rows.len() - y,
+                rowspan => rowspan.min(rows.len() - y),
+            };
+
+            let cell = Cell {
+                text: html_to_plain(cell),
+                header,
+            };
+
+            for slots in &mut grid[y..y + rowspan] {
+                if slots.len() < x + colspan {
+                    slots.resize(x + colspan, None);
+                }
+
+                for slot in &mut slots[x..x + colspan] {
+                    slot.get_or_insert_with(|| cell.clone());
+                }
+            }
+
+            x += colspan;
+        }
+    }
+
+    grid
+}
+
+/// Parses a `colspan` or `rowspan` attribute, which defaults to 1
+fn span(value: Option<&str>, max: u32) -> usize {
+    value
+        .and_then(parse_dimension)
+        .map_or(1, |span| span.min(max)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::{Html, Selector};
+
+    fn table(input: &str) -> Option<Table> {
+        let html = Html::parse_fragment(input);
+        let element = html
+            .select(&Selector::parse("table, p").unwrap())
+            .next()
+            .unwrap();
+
+        super::table(element)
+    }
+
+    fn row(cells: &[Option<&str>]) -> Row {
+        cells
+            .iter()
+            .map(|cell| cell.map(ToString::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            Some(Table {
+                head: vec![row(&[Some("Name"), Some("Age")])],
+                body: vec![row(&[Some("Alice"), Some("30")]), row(&[Some("Bob"), None])],
+                foot: vec![row(&[Some("Total"), Some("1")])],
+            }),
+            table(
+                "<table>
+                   <tfoot><tr><td>Total</td><td>1</td></tr></tfoot>
+                   <thead><tr><th>Name</th><th>Age</th></tr></thead>
+                   <tr><td>Alice</td><td>30</td></tr>
+                   <tr><td>Bob</td></tr>
+                 </table>"
+            ),
+            "rows are grouped and padded"
+        );
+
+        assert_eq!(
+            Some(Table {
+                head: vec![row(&[Some("Name"), Some("Age")])],
+                body: vec![row(&[Some("Alice"), Some("30")])],
+                ..Default::default()
+            }),
+            table("<table><tr><th>Name</th><th>Age</th></tr><tr><th>Alice</th><td>30</td></tr></table>"),
+            "leading rows of headers are the head when there is no thead"
+        );
+
+        assert_eq!(None, table("<p>Not a table</p>"));
+    }
+
+    #[test]
+    fn test_table_spans() {
+        assert_eq!(
+            Some(Table {
+                body: vec![
+                    row(&[Some("A"), Some("B"), Some("B"), Some("C")]),
+                    row(&[Some("A"), Some("D"), Some("E"), Some("C")]),
+                    row(&[Some("F"), Some("F"), Some("F"), Some("C")]),
+                ],
+                ..Default::default()
+            }),
+            table(
+                r#"<table>
+                     <tr><td rowspan="2">A</td><td colspan="2">B</td><td rowspan="0">C</td></tr>
+                     <tr><td>D</td><td>E</td></tr>
+                     <tr><td colspan="3">F</td></tr>
+                   </table>"#
+            )
+        );
+
+        assert_eq!(
+            Some(Table {
+                body: vec![
+                    row(&[Some("A"), Some("B")]),
+                    row(&[None, Some("B")]),
+                    row(&[Some("C"), Some("B")])
+                ],
+                foot: vec![row(&[Some("D"), None])],
+                ..Default::default()
+            }),
+            table(
+                r#"<table>
+                     <tbody><tr><td>A</td><td rowspan="5">B</td></tr><tr></tr><tr><td>C</td></tr></tbody>
+                     <tfoot><tr><td colspan="0">D</td></tr></tfoot>
+                   </table>"#
+            ),
+            "spans are limited to their group and invalid ones are ignored"
+        );
+    }
+
+    #[test]
+    fn test_table_nested() {
+        assert_eq!(
+            Some(Table {
+                body: vec![row(&[Some("Outer\nInner")])],
+                ..Default::default()
+            }),
+            table("<table><tr><td>Outer <table><tr><td>Inner</td></tr></table></td></tr></table>")
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use ego_tree::iter::Edge;
+use scraper::{ElementRef, Node};
+
+/// How much text an element contains and how much of it is in links, the
+/// usual signals to tell content from boilerplate
+#[derive(Debug, PartialEq)]
+pub(crate) struct TextMetrics {
+    /// The number of bytes of non-whitespace text in the element, so that
+    /// indentation doesn't count
+    pub(crate) text_length: usize,
+    /// The part of `text_length` that is inside `<a>` elements
+    pub(crate) link_text_length: usize,
+}
+
+impl TextMetrics {
+    /// Returns the share of the text that is in links, or 0 without text
+    pub(crate) fn link_density(&self) -> f64 {
+        if self.text_length == 0 {
+            0.0
+        } else {
+            self.link_text_length as f64 / self.text_length as f64
+        }
+    }
+}
+
+/// Measures an element's text in a single traversal, without building it
+pub(crate) fn text_metrics(element: ElementRef) -> TextMetrics {
+    let mut metrics = TextMetrics {
+        text_length: 0,
+        link_text_length: 0,
+    };
+    let mut link_depth = 0;
+
+    for edge in element.traverse() {
+        match edge {
+            Edge::Open(node) => match node.value() {
+                Node::Text(text) => {
+                    let length = text_length(text);
+
+                    metrics.text_length += length;
+                    if link_depth > 0 {
+                        metrics.link_text_length += length;
+                    }
+                }
+                Node::Element(child) if child.name() == "a" => link_depth += 1,
+                _ => {}
+            },
+            Edge::Close(node) => match node.value() {
+                Node::Element(child) if child.name() == "a" => link_depth -= 1,
+                _ => {}
+            },
+        }
+    }
+
+    metrics
+}
+
+/// Returns the number of bytes of non-whitespace text in `text`
+pub(crate) fn text_length(text: &str) -> usize {
+    text.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(char::len_utf8)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::{Html, Selector};
+
+    fn metrics(html: &str, selector: &str) -> TextMetrics {
+        let html = Html::parse_fragment(html);
+        let element = html
+            .select(&Selector::parse(selector).unwrap())
+            .next()
+            .unwrap();
+
+        text_metrics(element)
+    }
+
+    #[test]
+    fn test_text_metrics() {
+        let nav = metrics(
+            "<nav>\n  <a href='/'>Home</a>\n  <a href='/blog'>Blog</a> | Café\n</nav>",
+            "nav",
+        );
+        assert_eq!(
+            TextMetrics {
+                text_length: 14,
+                link_text_length: 8
+            },
+            nav,
+            "whitespace isn't counted and other characters count their bytes"
+        );
+        assert_eq!(8.0 / 14.0, nav.link_density());
+
+        assert_eq!(
+            1.0,
+            metrics("<a href='/'>Nested <b>link</b></a>", "a").link_density(),
+            "text is all in links when the element is one"
+        );
+        assert_eq!(0.0, metrics("<div> <br> </div>", "div").link_density());
+    }
+}
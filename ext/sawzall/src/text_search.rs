@@ -0,0 +1,134 @@
+use ego_tree::iter::Edge;
+use regex::Regex;
+use scraper::{ElementRef, Node, Selector};
+
+/// What the text of the elements searched for must match
+#[derive(Debug)]
+pub(crate) enum TextPattern {
+    /// The whole text, compared after squishing whitespace on both sides
+    Exact(String),
+    Regex(Regex),
+}
+
+impl TextPattern {
+    pub(crate) fn exact(text: &str) -> Self {
+        Self::Exact(squish(text))
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            TextPattern::Exact(expected) => *expected == squish(text),
+            TextPattern::Regex(regex) => regex.is_match(&squish(text)),
+        }
+    }
+}
+
+/// Returns the descendants of `scope` whose text, with whitespace squished,
+/// matches the pattern, in document order
+///
+/// With a selector, every element it matches whose text matches is
+/// returned. Without one, only the innermost such elements are, since
+/// their ancestors' text usually matches too.
+///
+/// The text of the whole subtree is gathered in a single walk, with each
+/// element's text being the part of it added between its start and end.
+pub(crate) fn find_by_text<'a>(
+    scope: ElementRef<'a>,
+    pattern: &TextPattern,
+    selector: Option<&Selector>,
+) -> Vec<ElementRef<'a>> {
+    let mut text = String::new();
+    // The open elements, each with where its text starts, its position in
+    // the document and whether one of its descendants matched
+    let mut open: Vec<(usize, usize, bool)> = Vec::new();
+    let mut matches = Vec::new();
+
+    for (position, edge) in scope.traverse().enumerate() {
+        match edge {
+            Edge::Open(node) => match node.value() {
+                Node::Element(_) => open.push((text.len(), position, false)),
+                Node::Text(t) => text.push_str(t),
+                _ => {}
+            },
+            Edge::Close(node) => {
+                let Some(element) = ElementRef::wrap(node) else {
+                    continue;
+                };
+                let Some((start, position, descendant_matched)) = open.pop() else {
+                    continue;
+                };
+                // The scope itself isn't one of its descendants
+                if open.is_empty() {
+                    break;
+                }
+
+                let matched = match selector {
+                    Some(selector) => selector.matches(&element) && pattern.matches(&text[start..]),
+                    None => !descendant_matched && pattern.matches(&text[start..]),
+                };
+                if matched {
+                    matches.push((position, element));
+                }
+
+                if let Some(parent) = open.last_mut() {
+                    parent.2 |= matched || descendant_matched;
+                }
+            }
+        }
+    }
+
+    // Elements close after their descendants
+    matches.sort_unstable_by_key(|&(position, _)| position);
+    matches.into_iter().map(|(_, element)| element).collect()
+}
+
+fn squish(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Html;
+
+    fn find(html: &str, pattern: &TextPattern, selector: Option<&str>) -> Vec<String> {
+        let html = Html::parse_fragment(html);
+        let selector = selector.map(|selector| Selector::parse(selector).unwrap());
+
+        find_by_text(html.root_element(), pattern, selector.as_ref())
+            .into_iter()
+            .map(|element| element.html())
+            .collect()
+    }
+
+    #[test]
+    fn test_find_by_text() {
+        let html = "<dl><dt>\n  ISBN </dt><dd>978-0</dd><dt><b>ISBN</b></dt><dd>978-1</dd></dl>";
+
+        assert_eq!(
+            vec!["<dt>\n  ISBN </dt>", "<b>ISBN</b>"],
+            find(html, &TextPattern::exact("ISBN"), None)
+        );
+        assert_eq!(
+            vec!["<dt>\n  ISBN </dt>", "<dt><b>ISBN</b></dt>"],
+            find(html, &TextPattern::exact(" ISBN"), Some("dt"))
+        );
+        assert_eq!(
+            vec!["<dd>978-0</dd>", "<dd>978-1</dd>"],
+            find(
+                html,
+                &TextPattern::Regex(Regex::new(r"^\d{3}-").unwrap()),
+                None
+            )
+        );
+        assert_eq!(
+            vec![html.to_string()],
+            find(html, &TextPattern::Regex(Regex::new("0.*1").unwrap()), None)
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            find("ISBN", &TextPattern::exact("ISBN"), None),
+            "the scope isn't searched"
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::states::RawKind;
+use html5ever::tokenizer::{
+    BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerResult,
+};
+use std::cell::RefCell;
+use std::mem;
+
+/// Something found by the tokenizer
+#[derive(Debug, PartialEq)]
+pub(crate) enum Event {
+    StartTag {
+        name: String,
+        attributes: Vec<(String, String)>,
+        self_closing: bool,
+    },
+    EndTag {
+        name: String,
+    },
+    /// The text between two other events, with character references decoded
+    Text(String),
+    Comment(String),
+    Doctype {
+        name: Option<String>,
+        public_id: Option<String>,
+        system_id: Option<String>,
+    },
+}
+
+/// Tokenizes HTML without building a tree, passing each event to a callback
+/// as soon as it's found
+///
+/// The input can be fed in chunks, so that huge documents are processed in
+/// constant memory. Without a tree builder, nothing tells the tokenizer the
+/// content of e.g. `<script>` is text, so it switches to reading it as text
+/// itself after the start tag. Nothing else about the document is fixed up:
+/// end tags aren't implied, and stray ones are reported as is.
+pub(crate) struct EventTokenizer<F, E> {
+    tokenizer: Tokenizer<EventSink<F, E>>,
+    input: BufferQueue,
+}
+
+struct EventSink<F, E> {
+    emit: RefCell<F>,
+    /// Text is reported once the next event is found, as it may be split
+    /// across several tokens
+    text: RefCell<String>,
+    /// The first error returned by the callback, after which events are
+    /// dropped
+    error: RefCell<Option<E>>,
+}
+
+impl<F, E> EventTokenizer<F, E>
+where
+    F: FnMut(Event) -> Result<(), E>,
+{
+    pub(crate) fn new(emit: F) -> Self {
+        let sink = EventSink {
+            emit: RefCell::new(emit),
+            text: RefCell::new(String::new()),
+            error: RefCell::new(None),
+        };
+
+        Self {
+            tokenizer: Tokenizer::new(sink, Default::default()),
+            input: BufferQueue::default(),
+        }
+    }
+
+    /// Tokenizes the next chunk of input, stopping at the first error
+    /// returned by the callback
+    pub(crate) fn feed(&mut self, input: &str) -> Result<(), E> {
+        self.input.push_back(StrTendril::from_slice(input));
+
+        while let TokenizerResult::Script(()) = self.tokenizer.feed(&self.input) {}
+
+        self.tokenizer.sink.take_error()
+    }
+
+    pub(crate) fn finish(self) -> Result<(), E> {
+        self.tokenizer.end();
+        self.tokenizer.sink.flush_text();
+        self.tokenizer.sink.take_error()
+    }
+}
+
+impl<F, E> EventSink<F, E>
+where
+    F: FnMut(Event) -> Result<(), E>,
+{
+    fn emit(&self, event: Event) {
+        if self.error.borrow().is_some() {
+            return;
+        }
+
+        if let Err(error) = (self.emit.borrow_mut())(event) {
+            *self.error.borrow_mut() = Some(error);
+        }
+    }
+
+    fn flush_text(&self) {
+        let text = mem::take(&mut *self.text.borrow_mut());
+
+        if !text.is_empty() {
+            self.emit(Event::Text(text));
+        }
+    }
+
+    fn take_error(&self) -> Result<(), E> {
+        match self.error.borrow_mut().take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<F, E> TokenSink for EventSink<F, E>
+where
+    F: FnMut(Event) -> Result<(), E>,
+{
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let event = match token {
+            Token::CharacterTokens(text) => {
+                self.text.borrow_mut().push_str(&text);
+                return TokenSinkResult::Continue;
+            }
+            // The tree builder drops NULs outside of raw text, where the
+            // tokenizer replaces them with U+FFFD instead
+            Token::NullCharacterToken | Token::ParseError(_) | Token::EOFToken => {
+                return TokenSinkResult::Continue;
+            }
+            Token::TagToken(tag) => match tag.kind {
+                TagKind::StartTag => Event::StartTag {
+                    name: tag.name.to_string(),
+                    attributes: tag
+                        .attrs
+                        .into_iter()
+                        .map(|attr| (attr.name.local.to_string(), attr.value.to_string()))
+                        .collect(),
+                    self_closing: tag.self_closing,
+                },
+                TagKind::EndTag => Event::EndTag {
+                    name: tag.name.to_string(),
+                },
+            },
+            Token::CommentToken(text) => Event::Comment(text.to_string()),
+            Token::DoctypeToken(doctype) => Event::Doctype {
+                name: doctype.name.map(|name| name.to_string()),
+                public_id: doctype.public_id.map(|id| id.to_string()),
+                system_id: doctype.system_id.map(|id| id.to_string()),
+            },
+        };
+
+        self.flush_text();
+
+        let result = match &event {
+            Event::StartTag { name, .. } => content_state(name),
+            _ => TokenSinkResult::Continue,
+        };
+        self.emit(event);
+
+        result
+    }
+}
+
+/// How the tree builder would have the tokenizer read the content of an
+/// element
+fn content_state(name: &str) -> TokenSinkResult<()> {
+    match name {
+        "title" | "textarea" => TokenSinkResult::RawData(RawKind::Rcdata),
+        "iframe" | "noembed" | "noframes" | "noscript" | "style" | "xmp" => {
+            TokenSinkResult::RawData(RawKind::Rawtext)
+        }
+        "script" => TokenSinkResult::RawData(RawKind::ScriptData),
+        "plaintext" => TokenSinkResult::Plaintext,
+        _ => TokenSinkResult::Continue,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(chunks: &[&str]) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut tokenizer = EventTokenizer::new(|event| {
+            events.push(event);
+            Ok::<_, ()>(())
+        });
+
+        for chunk in chunks {
+            tokenizer.feed(chunk).unwrap();
+        }
+        tokenizer.finish().unwrap();
+
+        events
+    }
+
+    fn start_tag(name: &str, attributes: &[(&str, &str)]) -> Event {
+        Event::StartTag {
+            name: name.to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            self_closing: false,
+        }
+    }
+
+    fn end_tag(name: &str) -> Event {
+        Event::EndTag {
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        assert_eq!(
+            vec![
+                Event::Doctype {
+                    name: Some("html".to_string()),
+                    public_id: None,
+                    system_id: None,
+                },
+                start_tag("p", &[("class", "a&b")]),
+                Event::Text("One & two".to_string()),
+                Event::Comment(" three ".to_string()),
+                end_tag("p"),
+                Event::StartTag {
+                    name: "br".to_string(),
+                    attributes: Vec::new(),
+                    self_closing: true,
+                },
+                start_tag("script", &[]),
+                Event::Text("a <b> c".to_string()),
+                end_tag("script"),
+                end_tag("div"),
+            ],
+            events(&[
+                "<!DOCTYPE html><P class='a&amp;b'>One &am",
+                "p; two<!-- three --></p><br/><script>a <b",
+                "> c</script></div>"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_stops_at_errors() {
+        let mut count = 0;
+        let mut tokenizer = EventTokenizer::new(|_| {
+            count += 1;
+            if count == 2 {
+                Err("stop")
+            } else {
+                Ok(())
+            }
+        });
+
+        assert_eq!(Err("stop"), tokenizer.feed("<a><b><c>"));
+        assert_eq!(Ok(()), tokenizer.finish());
+        assert_eq!(2, count);
+    }
+}
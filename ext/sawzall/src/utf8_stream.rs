@@ -0,0 +1,128 @@
+/// Decodes UTF-8 that arrives in chunks, e.g. read from an IO, whose
+/// boundaries may split characters
+///
+/// Invalid byte sequences are replaced with U+FFFD the same way
+/// [`String::from_utf8_lossy`] would replace them in the whole input, unless
+/// decoding strictly.
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Decoder {
+    strict: bool,
+    /// The start of a character cut off by the end of the previous chunk
+    pending: Vec<u8>,
+    /// How many bytes were decoded so far
+    offset: usize,
+}
+
+/// The offset of the first invalid byte when decoding strictly
+#[derive(Debug, PartialEq)]
+pub(crate) struct InvalidByte(pub(crate) usize);
+
+impl Utf8Decoder {
+    pub(crate) fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn decode(&mut self, bytes: &[u8]) -> Result<String, InvalidByte> {
+        let input = if self.pending.is_empty() {
+            bytes.to_vec()
+        } else {
+            let mut input = std::mem::take(&mut self.pending);
+            input.extend_from_slice(bytes);
+            input
+        };
+
+        let mut output = String::with_capacity(input.len());
+        let mut rest = &input[..];
+
+        while !rest.is_empty() {
+            let error = match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    output.push_str(valid);
+                    break;
+                }
+                Err(error) => error,
+            };
+
+            let (valid, invalid) = rest.split_at(error.valid_up_to());
+            // SAFETY: the bytes up to `valid_up_to` were just validated
+            output.push_str(unsafe { std::str::from_utf8_unchecked(valid) });
+
+            let Some(len) = error.error_len() else {
+                // Possibly completed by the next chunk
+                self.pending = invalid.to_vec();
+                break;
+            };
+
+            if self.strict {
+                return Err(InvalidByte(self.offset + input.len() - invalid.len()));
+            }
+
+            output.push('\u{FFFD}');
+            rest = &invalid[len..];
+        }
+
+        self.offset += input.len() - self.pending.len();
+
+        Ok(output)
+    }
+
+    /// Decodes what's left of a character cut off by the end of the input
+    pub(crate) fn finish(self) -> Result<String, InvalidByte> {
+        if self.pending.is_empty() {
+            Ok(String::new())
+        } else if self.strict {
+            Err(InvalidByte(self.offset))
+        } else {
+            Ok('\u{FFFD}'.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_chunks(chunks: &[&[u8]], strict: bool) -> Result<String, InvalidByte> {
+        let mut decoder = Utf8Decoder::new(strict);
+        let mut output = String::new();
+
+        for chunk in chunks {
+            output.push_str(&decoder.decode(chunk)?);
+        }
+
+        output.push_str(&decoder.finish()?);
+        Ok(output)
+    }
+
+    #[test]
+    fn test_decode() {
+        let input = "<p>café ☕</p>".as_bytes();
+
+        for split in 0..input.len() {
+            let (a, b) = input.split_at(split);
+            assert_eq!(
+                Ok("<p>café ☕</p>".to_string()),
+                decode_chunks(&[a, b], true)
+            );
+        }
+
+        let invalid: &[u8] = b"caf\xE9 \xE2\x98 ok\xE2";
+        for split in 0..invalid.len() {
+            let (a, b) = invalid.split_at(split);
+            assert_eq!(
+                Ok(String::from_utf8_lossy(invalid).into_owned()),
+                decode_chunks(&[a, b], false),
+                "split at {split}"
+            );
+        }
+
+        assert_eq!(Err(InvalidByte(3)), decode_chunks(&[b"ca", b"f\xE9"], true));
+        assert_eq!(
+            Err(InvalidByte(5)),
+            decode_chunks(&[b"caf\xC3\xA9", b"\xE2"], true)
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use html5ever::tendril::TendrilSink;
+use scraper::{Html, HtmlTreeSink};
+
+/// Parses XML (e.g. sitemaps and feeds) into the same kind of tree as HTML,
+/// so that it can be queried with the same API
+///
+/// Element names keep their case and namespaces, and CDATA sections become
+/// text. Like the HTML parser, the XML parser recovers from malformed input
+/// rather than failing. Returns `None` when the input has no root element.
+pub(crate) fn parse_xml(input: &str) -> Option<Html> {
+    let html = xml5ever::driver::parse_document(
+        HtmlTreeSink::new(Html::new_document()),
+        Default::default(),
+    )
+    .one(input);
+
+    html.tree
+        .root()
+        .children()
+        .any(|child| child.value().is_element())
+        .then_some(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Selector;
+
+    #[test]
+    fn test_parse_xml() {
+        let html = parse_xml(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <rss xmlns:atom="http://www.w3.org/2005/Atom">
+              <channel>
+                <atom:link href="https://example.com/feed" rel="self"/>
+                <item><title>One &amp; two</title><pubDate>Mon, 01 Jan 2024</pubDate></item>
+                <item><title><![CDATA[<b>Three</b>]]></title></item>
+              </channel>
+            </rss>"#,
+        )
+        .unwrap();
+        let select = |selector: &str| -> Vec<String> {
+            html.select(&Selector::parse(selector).unwrap())
+                .map(|element| element.text().collect())
+                .collect()
+        };
+
+        assert_eq!("rss", html.root_element().value().name());
+        assert_eq!(vec!["One & two", "<b>Three</b>"], select("item > title"));
+        assert_eq!(vec!["Mon, 01 Jan 2024"], select("pubDate"));
+        assert_eq!(
+            Vec::<String>::new(),
+            select("pubdate"),
+            "names are case-sensitive"
+        );
+
+        let link = html
+            .select(&Selector::parse("link").unwrap())
+            .next()
+            .unwrap();
+        assert_eq!("http://www.w3.org/2005/Atom", &*link.value().name.ns);
+        assert_eq!(Some("self"), link.attr("rel"));
+    }
+
+    #[test]
+    fn test_parse_xml_without_root_element() {
+        assert!(parse_xml("").is_none());
+        assert!(parse_xml("<!-- nothing -->").is_none());
+    }
+}
@@ -0,0 +1,1865 @@
+//! An XPath 1.0 engine over parsed documents
+//!
+//! Everything but variables and namespace prefixes is supported, along with
+//! the `ends-with()`, `lower-case()` and `upper-case()` functions from XPath
+//! 2.0. Element and attribute names are compared as they are stored, which
+//! is lowercase for HTML documents. Doctypes aren't part of the XPath data
+//! model, so they are never selected.
+
+use crate::dom;
+use ego_tree::{NodeId, NodeRef};
+use scraper::{ElementRef, Html, Node};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A node as seen by XPath: either a node of the tree, or one of an element's
+/// attributes, which aren't nodes of their own in the tree
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum XNode {
+    Node(NodeId),
+    /// An element and the index of the attribute in its attribute list
+    Attribute(NodeId, usize),
+}
+
+/// The result of evaluating an expression
+#[derive(Debug, PartialEq)]
+pub(crate) enum Value {
+    /// Nodes in document order
+    Nodes(Vec<XNode>),
+    Boolean(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParseError {
+    message: String,
+    /// Where in the expression the error is, in bytes
+    position: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct EvaluationError(String);
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A parsed XPath expression, which can be evaluated against any document
+#[derive(Debug)]
+pub(crate) struct XPath(Expr);
+
+impl XPath {
+    pub(crate) fn parse(input: &str) -> Result<Self, ParseError> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            index: 0,
+            end: input.len(),
+        };
+        let expr = parser.expr()?;
+
+        match parser.peek() {
+            None => Ok(Self(expr)),
+            Some(_) => Err(parser.error("unexpected token")),
+        }
+    }
+
+    /// Evaluates the expression with `context` as the context node
+    pub(crate) fn evaluate(&self, html: &Html, context: NodeId) -> Result<Value, EvaluationError> {
+        let evaluator = Evaluator {
+            html,
+            order: document_order(html),
+        };
+
+        evaluator.evaluate(
+            &self.0,
+            &Context {
+                node: XNode::Node(context),
+                position: 1,
+                size: 1,
+            },
+        )
+    }
+}
+
+/// Returns the string-value of a node: the text of its descendant text nodes
+/// for the root and elements, or its own text for other nodes
+pub(crate) fn string_value(html: &Html, node: XNode) -> String {
+    match node {
+        XNode::Node(id) => {
+            let node = html.tree.get(id).expect("xpath nodes are in the document");
+
+            match node.value() {
+                Node::Text(text) => text.to_string(),
+                Node::Comment(comment) => comment.to_string(),
+                Node::ProcessingInstruction(instruction) => instruction.data.to_string(),
+                Node::Doctype(_) => String::new(),
+                Node::Document | Node::Fragment | Node::Element(_) => node
+                    .descendants()
+                    .filter_map(|descendant| descendant.value().as_text())
+                    .map(|text| &**text)
+                    .collect(),
+            }
+        }
+        XNode::Attribute(id, index) => attribute(html, id, index)
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Returns an absolute location path selecting only `element`, made of the
+/// names of its ancestors starting from the root element, e.g.
+/// `/html/body/div[2]/p`
+///
+/// Like [`dom::css_path`], positions are only added where siblings share a
+/// name.
+pub(crate) fn path(element: ElementRef) -> String {
+    let mut steps: Vec<String> = [*element]
+        .into_iter()
+        .chain(element.ancestors())
+        .filter_map(ElementRef::wrap)
+        .map(|element| {
+            let name = element.value().name();
+            let same_name = |sibling: &ElementRef| sibling.value().name() == name;
+            let position = element
+                .prev_siblings()
+                .filter_map(ElementRef::wrap)
+                .filter(same_name)
+                .count();
+
+            if position == 0
+                && !element
+                    .next_siblings()
+                    .filter_map(ElementRef::wrap)
+                    .any(|sibling| same_name(&sibling))
+            {
+                format!("/{name}")
+            } else {
+                format!("/{name}[{}]", position + 1)
+            }
+        })
+        .collect();
+
+    steps.reverse();
+    steps.concat()
+}
+
+fn attribute(html: &Html, id: NodeId, index: usize) -> Option<(String, &str)> {
+    let element = html.tree.get(id)?.value().as_element()?;
+    let (name, value) = element.attrs.get(index)?;
+
+    Some((dom::attribute_name(name), &**value))
+}
+
+/// Maps every node of the tree to its position in document order
+fn document_order(html: &Html) -> HashMap<NodeId, usize> {
+    html.tree
+        .root()
+        .descendants()
+        .enumerate()
+        .map(|(index, node)| (node.id(), index))
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Slash,
+    DoubleSlash,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Dot,
+    DoubleDot,
+    At,
+    Comma,
+    DoubleColon,
+    Pipe,
+    Plus,
+    Minus,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    /// `*` as a name test
+    Star,
+    /// `*` as an operator
+    Multiply,
+    And,
+    Or,
+    Div,
+    Mod,
+    Literal(String),
+    Number(f64),
+    Name(String),
+}
+
+/// Splits an expression into tokens along with their position, telling names
+/// and `*` apart from operators as described in the [spec][1]
+///
+/// [1]: https://www.w3.org/TR/1999/REC-xpath-19991116/#exprlex
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let mut tokens: Vec<(Token, usize)> = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        // Names and `*` are operators when they follow something that can end
+        // an operand
+        let after_operand = tokens.last().is_some_and(|(token, _)| {
+            !matches!(
+                token,
+                Token::At
+                    | Token::DoubleColon
+                    | Token::LeftParen
+                    | Token::LeftBracket
+                    | Token::Comma
+                    | Token::Slash
+                    | Token::DoubleSlash
+                    | Token::Pipe
+                    | Token::Plus
+                    | Token::Minus
+                    | Token::Equal
+                    | Token::NotEqual
+                    | Token::Less
+                    | Token::LessOrEqual
+                    | Token::Greater
+                    | Token::GreaterOrEqual
+                    | Token::Multiply
+                    | Token::And
+                    | Token::Or
+                    | Token::Div
+                    | Token::Mod
+            )
+        });
+
+        chars.next();
+        let next = chars.peek().map(|&(_, c)| c);
+
+        let token = match c {
+            '/' if next == Some('/') => {
+                chars.next();
+                Token::DoubleSlash
+            }
+            '/' => Token::Slash,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '.' if next == Some('.') => {
+                chars.next();
+                Token::DoubleDot
+            }
+            '.' if next.is_some_and(|c| c.is_ascii_digit()) => number(input, position, &mut chars),
+            '.' => Token::Dot,
+            '@' => Token::At,
+            ',' => Token::Comma,
+            ':' if next == Some(':') => {
+                chars.next();
+                Token::DoubleColon
+            }
+            '|' => Token::Pipe,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '=' => Token::Equal,
+            '!' if next == Some('=') => {
+                chars.next();
+                Token::NotEqual
+            }
+            '<' if next == Some('=') => {
+                chars.next();
+                Token::LessOrEqual
+            }
+            '<' => Token::Less,
+            '>' if next == Some('=') => {
+                chars.next();
+                Token::GreaterOrEqual
+            }
+            '>' => Token::Greater,
+            '*' if after_operand => Token::Multiply,
+            '*' => Token::Star,
+            '"' | '\'' => {
+                let start = position + 1;
+                let end = input[start..]
+                    .find(c)
+                    .map(|end| start + end)
+                    .ok_or(ParseError {
+                        message: "unterminated string literal".to_string(),
+                        position,
+                    })?;
+                while chars.next_if(|&(index, _)| index <= end).is_some() {}
+
+                Token::Literal(input[start..end].to_string())
+            }
+            '$' => {
+                return Err(ParseError {
+                    message: "variables are not supported".to_string(),
+                    position,
+                })
+            }
+            c if c.is_ascii_digit() => number(input, position, &mut chars),
+            c if is_name_start(c) => {
+                let mut end = position + c.len_utf8();
+                while let Some((index, c)) = chars.next_if(|&(_, c)| is_name_char(c)) {
+                    end = index + c.len_utf8();
+                }
+
+                // A single colon makes a qualified name, or `prefix:*`
+                if input[end..].starts_with(':') && !input[end..].starts_with("::") {
+                    chars.next();
+                    end += 1;
+
+                    while let Some((index, c)) =
+                        chars.next_if(|&(_, c)| is_name_char(c) || c == '*')
+                    {
+                        end = index + c.len_utf8();
+                    }
+                }
+
+                let name = &input[position..end];
+
+                match name {
+                    "and" if after_operand => Token::And,
+                    "or" if after_operand => Token::Or,
+                    "div" if after_operand => Token::Div,
+                    "mod" if after_operand => Token::Mod,
+                    _ => Token::Name(name.to_string()),
+                }
+            }
+            _ => {
+                return Err(ParseError {
+                    message: format!("unexpected character {c:?}"),
+                    position,
+                })
+            }
+        };
+
+        tokens.push((token, position));
+    }
+
+    Ok(tokens)
+}
+
+fn number(
+    input: &str,
+    start: usize,
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+) -> Token {
+    let mut end = start + 1;
+    let mut seen_dot = input[start..].starts_with('.');
+
+    while let Some((index, _)) =
+        chars.next_if(|&(_, c)| c.is_ascii_digit() || (c == '.' && !seen_dot))
+    {
+        seen_dot |= input[index..].starts_with('.');
+        end = index + 1;
+    }
+
+    Token::Number(input[start..end].parse().unwrap_or(f64::NAN))
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '\u{B7}')
+}
+
+#[derive(Debug)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Compare(Comparison, Box<Expr>, Box<Expr>),
+    Arithmetic(Operator, Box<Expr>, Box<Expr>),
+    Negate(Box<Expr>),
+    Union(Box<Expr>, Box<Expr>),
+    Path(Start, Vec<Step>),
+    Filter(Box<Expr>, Vec<Expr>),
+    Literal(String),
+    Number(f64),
+    Function(Function, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+/// Where a location path starts
+#[derive(Debug)]
+enum Start {
+    Root,
+    Context,
+    /// The nodes of a filter expression, as in `(//a)[1]/b`
+    Expr(Box<Expr>),
+}
+
+#[derive(Debug)]
+struct Step {
+    axis: Axis,
+    test: NodeTest,
+    predicates: Vec<Expr>,
+}
+
+impl Step {
+    /// `//` as a step, short for `/descendant-or-self::node()/`
+    fn descendant_or_self() -> Self {
+        Self {
+            axis: Axis::DescendantOrSelf,
+            test: NodeTest::Node,
+            predicates: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Axis {
+    Ancestor,
+    AncestorOrSelf,
+    Attribute,
+    Child,
+    Descendant,
+    DescendantOrSelf,
+    Following,
+    FollowingSibling,
+    Parent,
+    Preceding,
+    PrecedingSibling,
+    SelfNode,
+}
+
+impl Axis {
+    const ALL: [Axis; 12] = [
+        Axis::Ancestor,
+        Axis::AncestorOrSelf,
+        Axis::Attribute,
+        Axis::Child,
+        Axis::Descendant,
+        Axis::DescendantOrSelf,
+        Axis::Following,
+        Axis::FollowingSibling,
+        Axis::Parent,
+        Axis::Preceding,
+        Axis::PrecedingSibling,
+        Axis::SelfNode,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Axis::Ancestor => "ancestor",
+            Axis::AncestorOrSelf => "ancestor-or-self",
+            Axis::Attribute => "attribute",
+            Axis::Child => "child",
+            Axis::Descendant => "descendant",
+            Axis::DescendantOrSelf => "descendant-or-self",
+            Axis::Following => "following",
+            Axis::FollowingSibling => "following-sibling",
+            Axis::Parent => "parent",
+            Axis::Preceding => "preceding",
+            Axis::PrecedingSibling => "preceding-sibling",
+            Axis::SelfNode => "self",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|axis| axis.name() == name)
+    }
+}
+
+#[derive(Debug)]
+enum NodeTest {
+    /// `*`, which matches the axis' principal node type
+    Any,
+    Name(String),
+    Node,
+    Text,
+    Comment,
+    ProcessingInstruction(Option<String>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Last,
+    Position,
+    Count,
+    Id,
+    LocalName,
+    Name,
+    String,
+    Concat,
+    StartsWith,
+    EndsWith,
+    Contains,
+    SubstringBefore,
+    SubstringAfter,
+    Substring,
+    StringLength,
+    NormalizeSpace,
+    Translate,
+    LowerCase,
+    UpperCase,
+    Boolean,
+    Not,
+    True,
+    False,
+    Lang,
+    Number,
+    Sum,
+    Floor,
+    Ceiling,
+    Round,
+}
+
+impl Function {
+    const ALL: [Function; 29] = [
+        Function::Last,
+        Function::Position,
+        Function::Count,
+        Function::Id,
+        Function::LocalName,
+        Function::Name,
+        Function::String,
+        Function::Concat,
+        Function::StartsWith,
+        Function::EndsWith,
+        Function::Contains,
+        Function::SubstringBefore,
+        Function::SubstringAfter,
+        Function::Substring,
+        Function::StringLength,
+        Function::NormalizeSpace,
+        Function::Translate,
+        Function::LowerCase,
+        Function::UpperCase,
+        Function::Boolean,
+        Function::Not,
+        Function::True,
+        Function::False,
+        Function::Lang,
+        Function::Number,
+        Function::Sum,
+        Function::Floor,
+        Function::Ceiling,
+        Function::Round,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Last => "last",
+            Function::Position => "position",
+            Function::Count => "count",
+            Function::Id => "id",
+            Function::LocalName => "local-name",
+            Function::Name => "name",
+            Function::String => "string",
+            Function::Concat => "concat",
+            Function::StartsWith => "starts-with",
+            Function::EndsWith => "ends-with",
+            Function::Contains => "contains",
+            Function::SubstringBefore => "substring-before",
+            Function::SubstringAfter => "substring-after",
+            Function::Substring => "substring",
+            Function::StringLength => "string-length",
+            Function::NormalizeSpace => "normalize-space",
+            Function::Translate => "translate",
+            Function::LowerCase => "lower-case",
+            Function::UpperCase => "upper-case",
+            Function::Boolean => "boolean",
+            Function::Not => "not",
+            Function::True => "true",
+            Function::False => "false",
+            Function::Lang => "lang",
+            Function::Number => "number",
+            Function::Sum => "sum",
+            Function::Floor => "floor",
+            Function::Ceiling => "ceiling",
+            Function::Round => "round",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|function| function.name() == name)
+    }
+
+    /// The smallest and largest number of arguments the function takes
+    fn arity(self) -> (usize, usize) {
+        match self {
+            Function::Last | Function::Position | Function::True | Function::False => (0, 0),
+            Function::LocalName
+            | Function::Name
+            | Function::String
+            | Function::StringLength
+            | Function::NormalizeSpace
+            | Function::Number => (0, 1),
+            Function::Count
+            | Function::Id
+            | Function::LowerCase
+            | Function::UpperCase
+            | Function::Boolean
+            | Function::Not
+            | Function::Lang
+            | Function::Sum
+            | Function::Floor
+            | Function::Ceiling
+            | Function::Round => (1, 1),
+            Function::StartsWith
+            | Function::EndsWith
+            | Function::Contains
+            | Function::SubstringBefore
+            | Function::SubstringAfter => (2, 2),
+            Function::Substring => (2, 3),
+            Function::Translate => (3, 3),
+            Function::Concat => (2, usize::MAX),
+        }
+    }
+}
+
+/// A recursive descent parser following the grammar of the [spec][1]
+///
+/// [1]: https://www.w3.org/TR/1999/REC-xpath-19991116/#section-Expressions
+struct Parser<'a> {
+    tokens: &'a [(Token, usize)],
+    index: usize,
+    /// The length of the expression, where errors at the end are reported
+    end: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.index + n).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.index += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &Token, description: &str) -> Result<(), ParseError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {description}")))
+        }
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        let position = match self.tokens.get(self.index) {
+            Some((_, position)) => *position,
+            None => self.end,
+        };
+        let message = match self.peek() {
+            Some(_) => message.to_string(),
+            None => format!("{message}, found end of expression"),
+        };
+
+        ParseError { message, position }
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.and_expr()?;
+
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and_expr()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.equality_expr()?;
+
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.equality_expr()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn equality_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.relational_expr()?;
+
+        loop {
+            let comparison = match self.peek() {
+                Some(Token::Equal) => Comparison::Equal,
+                Some(Token::NotEqual) => Comparison::NotEqual,
+                _ => return Ok(expr),
+            };
+            self.index += 1;
+
+            expr = Expr::Compare(
+                comparison,
+                Box::new(expr),
+                Box::new(self.relational_expr()?),
+            );
+        }
+    }
+
+    fn relational_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.additive_expr()?;
+
+        loop {
+            let comparison = match self.peek() {
+                Some(Token::Less) => Comparison::Less,
+                Some(Token::LessOrEqual) => Comparison::LessOrEqual,
+                Some(Token::Greater) => Comparison::Greater,
+                Some(Token::GreaterOrEqual) => Comparison::GreaterOrEqual,
+                _ => return Ok(expr),
+            };
+            self.index += 1;
+
+            expr = Expr::Compare(comparison, Box::new(expr), Box::new(self.additive_expr()?));
+        }
+    }
+
+    fn additive_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.multiplicative_expr()?;
+
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Plus) => Operator::Add,
+                Some(Token::Minus) => Operator::Subtract,
+                _ => return Ok(expr),
+            };
+            self.index += 1;
+
+            expr = Expr::Arithmetic(
+                operator,
+                Box::new(expr),
+                Box::new(self.multiplicative_expr()?),
+            );
+        }
+    }
+
+    fn multiplicative_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.unary_expr()?;
+
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Multiply) => Operator::Multiply,
+                Some(Token::Div) => Operator::Divide,
+                Some(Token::Mod) => Operator::Modulo,
+                _ => return Ok(expr),
+            };
+            self.index += 1;
+
+            expr = Expr::Arithmetic(operator, Box::new(expr), Box::new(self.unary_expr()?));
+        }
+    }
+
+    fn unary_expr(&mut self) -> Result<Expr, ParseError> {
+        if self.eat(&Token::Minus) {
+            return Ok(Expr::Negate(Box::new(self.unary_expr()?)));
+        }
+
+        let mut expr = self.path_expr()?;
+
+        while self.eat(&Token::Pipe) {
+            expr = Expr::Union(Box::new(expr), Box::new(self.path_expr()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn path_expr(&mut self) -> Result<Expr, ParseError> {
+        let is_filter_expr = match self.peek() {
+            Some(Token::LeftParen | Token::Literal(_) | Token::Number(_)) => true,
+            Some(Token::Name(name)) => {
+                self.peek_nth(1) == Some(&Token::LeftParen) && node_type(name).is_none()
+            }
+            _ => false,
+        };
+
+        if !is_filter_expr {
+            return self.location_path();
+        }
+
+        let primary = self.primary_expr()?;
+        let mut predicates = Vec::new();
+        while self.peek() == Some(&Token::LeftBracket) {
+            predicates.push(self.predicate()?);
+        }
+
+        let expr = if predicates.is_empty() {
+            primary
+        } else {
+            Expr::Filter(Box::new(primary), predicates)
+        };
+
+        let mut steps = Vec::new();
+        match self.peek() {
+            Some(Token::Slash) => self.index += 1,
+            Some(Token::DoubleSlash) => {
+                self.index += 1;
+                steps.push(Step::descendant_or_self());
+            }
+            _ => return Ok(expr),
+        }
+        self.relative_location_path(&mut steps)?;
+
+        Ok(Expr::Path(Start::Expr(Box::new(expr)), steps))
+    }
+
+    fn primary_expr(&mut self) -> Result<Expr, ParseError> {
+        match self.next() {
+            Some(Token::LeftParen) => {
+                let expr = self.expr()?;
+                self.expect(&Token::RightParen, "\")\"")?;
+                Ok(expr)
+            }
+            Some(Token::Literal(literal)) => Ok(Expr::Literal(literal)),
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Name(name)) => {
+                self.index -= 1;
+                let start = self.index;
+                let function = Function::from_name(&name)
+                    .ok_or_else(|| self.error(&format!("unknown function {name}()")))?;
+                self.index += 2;
+
+                let mut arguments = Vec::new();
+                if !self.eat(&Token::RightParen) {
+                    loop {
+                        arguments.push(self.expr()?);
+
+                        if self.eat(&Token::RightParen) {
+                            break;
+                        }
+                        self.expect(&Token::Comma, "\",\" or \")\"")?;
+                    }
+                }
+
+                let (min, max) = function.arity();
+                if arguments.len() < min || arguments.len() > max {
+                    self.index = start;
+                    return Err(self.error(&format!(
+                        "wrong number of arguments for {name}() ({})",
+                        arguments.len()
+                    )));
+                }
+
+                Ok(Expr::Function(function, arguments))
+            }
+            _ => {
+                self.index -= 1;
+                Err(self.error("expected an expression"))
+            }
+        }
+    }
+
+    fn location_path(&mut self) -> Result<Expr, ParseError> {
+        let mut steps = Vec::new();
+
+        let start = match self.peek() {
+            Some(Token::Slash) => {
+                self.index += 1;
+
+                // A lone `/` selects the root
+                if !self.starts_step() {
+                    return Ok(Expr::Path(Start::Root, steps));
+                }
+
+                Start::Root
+            }
+            Some(Token::DoubleSlash) => {
+                self.index += 1;
+                steps.push(Step::descendant_or_self());
+                Start::Root
+            }
+            _ => Start::Context,
+        };
+
+        self.relative_location_path(&mut steps)?;
+
+        Ok(Expr::Path(start, steps))
+    }
+
+    fn starts_step(&self) -> bool {
+        matches!(
+            self.peek(),
+            Some(Token::Dot | Token::DoubleDot | Token::At | Token::Star | Token::Name(_))
+        )
+    }
+
+    fn relative_location_path(&mut self, steps: &mut Vec<Step>) -> Result<(), ParseError> {
+        loop {
+            steps.push(self.step()?);
+
+            match self.peek() {
+                Some(Token::Slash) => self.index += 1,
+                Some(Token::DoubleSlash) => {
+                    self.index += 1;
+                    steps.push(Step::descendant_or_self());
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<Step, ParseError> {
+        if self.eat(&Token::Dot) {
+            return Ok(Step {
+                axis: Axis::SelfNode,
+                test: NodeTest::Node,
+                predicates: Vec::new(),
+            });
+        }
+        if self.eat(&Token::DoubleDot) {
+            return Ok(Step {
+                axis: Axis::Parent,
+                test: NodeTest::Node,
+                predicates: Vec::new(),
+            });
+        }
+
+        let axis = if self.eat(&Token::At) {
+            Axis::Attribute
+        } else {
+            match (self.peek(), self.peek_nth(1)) {
+                (Some(Token::Name(name)), Some(Token::DoubleColon)) => {
+                    let axis = Axis::from_name(name)
+                        .ok_or_else(|| self.error(&format!("unknown axis {name}")))?;
+                    self.index += 2;
+                    axis
+                }
+                _ => Axis::Child,
+            }
+        };
+
+        let test = match self.next() {
+            Some(Token::Star) => NodeTest::Any,
+            Some(Token::Name(name)) if self.peek() == Some(&Token::LeftParen) => {
+                self.index -= 1;
+                let test = node_type(&name)
+                    .ok_or_else(|| self.error(&format!("unknown node type {name}()")))?;
+                self.index += 2;
+
+                let test = match (test, self.peek()) {
+                    (NodeTest::ProcessingInstruction(_), Some(Token::Literal(target))) => {
+                        let target = target.clone();
+                        self.index += 1;
+                        NodeTest::ProcessingInstruction(Some(target))
+                    }
+                    (test, _) => test,
+                };
+                self.expect(&Token::RightParen, "\")\"")?;
+
+                test
+            }
+            Some(Token::Name(name)) if name.contains(':') => {
+                self.index -= 1;
+                return Err(self.error("namespace prefixes are not supported"));
+            }
+            Some(Token::Name(name)) => NodeTest::Name(name),
+            _ => {
+                self.index -= 1;
+                return Err(self.error("expected a step"));
+            }
+        };
+
+        let mut predicates = Vec::new();
+        while self.peek() == Some(&Token::LeftBracket) {
+            predicates.push(self.predicate()?);
+        }
+
+        Ok(Step {
+            axis,
+            test,
+            predicates,
+        })
+    }
+
+    fn predicate(&mut self) -> Result<Expr, ParseError> {
+        self.expect(&Token::LeftBracket, "\"[\"")?;
+        let expr = self.expr()?;
+        self.expect(&Token::RightBracket, "\"]\"")?;
+
+        Ok(expr)
+    }
+}
+
+fn node_type(name: &str) -> Option<NodeTest> {
+    match name {
+        "node" => Some(NodeTest::Node),
+        "text" => Some(NodeTest::Text),
+        "comment" => Some(NodeTest::Comment),
+        "processing-instruction" => Some(NodeTest::ProcessingInstruction(None)),
+        _ => None,
+    }
+}
+
+struct Context {
+    node: XNode,
+    /// The 1-based position of the node in the node-set being filtered
+    position: usize,
+    size: usize,
+}
+
+struct Evaluator<'a> {
+    html: &'a Html,
+    order: HashMap<NodeId, usize>,
+}
+
+impl Evaluator<'_> {
+    fn evaluate(&self, expr: &Expr, context: &Context) -> Result<Value, EvaluationError> {
+        Ok(match expr {
+            Expr::Or(left, right) => Value::Boolean(
+                self.boolean(self.evaluate(left, context)?)
+                    || self.boolean(self.evaluate(right, context)?),
+            ),
+            Expr::And(left, right) => Value::Boolean(
+                self.boolean(self.evaluate(left, context)?)
+                    && self.boolean(self.evaluate(right, context)?),
+            ),
+            Expr::Compare(comparison, left, right) => Value::Boolean(self.compare(
+                *comparison,
+                self.evaluate(left, context)?,
+                self.evaluate(right, context)?,
+            )),
+            Expr::Arithmetic(operator, left, right) => {
+                let left = self.number(self.evaluate(left, context)?);
+                let right = self.number(self.evaluate(right, context)?);
+
+                Value::Number(match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide => left / right,
+                    Operator::Modulo => left % right,
+                })
+            }
+            Expr::Negate(expr) => Value::Number(-self.number(self.evaluate(expr, context)?)),
+            Expr::Union(left, right) => {
+                let mut nodes = self.nodes(self.evaluate(left, context)?, "|")?;
+                nodes.extend(self.nodes(self.evaluate(right, context)?, "|")?);
+
+                Value::Nodes(self.sort(nodes))
+            }
+            Expr::Path(start, steps) => {
+                let mut nodes = match start {
+                    Start::Root => vec![XNode::Node(self.html.tree.root().id())],
+                    Start::Context => vec![context.node],
+                    Start::Expr(expr) => self.nodes(self.evaluate(expr, context)?, "/")?,
+                };
+
+                for step in steps {
+                    let mut selected = Vec::new();
+                    for node in nodes {
+                        selected.extend(self.step(step, node)?);
+                    }
+                    nodes = self.sort(selected);
+                }
+
+                Value::Nodes(nodes)
+            }
+            Expr::Filter(expr, predicates) => {
+                let nodes = self.nodes(self.evaluate(expr, context)?, "[]")?;
+
+                Value::Nodes(self.filter(nodes, predicates)?)
+            }
+            Expr::Literal(literal) => Value::String(literal.clone()),
+            Expr::Number(number) => Value::Number(*number),
+            Expr::Function(function, arguments) => self.call(*function, arguments, context)?,
+        })
+    }
+
+    /// Selects the nodes along the step's axis that match its node test and
+    /// predicates
+    fn step(&self, step: &Step, node: XNode) -> Result<Vec<XNode>, EvaluationError> {
+        let nodes = self
+            .axis(step.axis, node)
+            .into_iter()
+            .filter(|&node| self.matches(&step.test, step.axis, node))
+            .collect();
+
+        self.filter(nodes, &step.predicates)
+    }
+
+    /// Returns the nodes along an axis, in proximity order: document order
+    /// for forward axes and reverse document order for reverse axes
+    fn axis(&self, axis: Axis, node: XNode) -> Vec<XNode> {
+        let tree_nodes = |nodes: &mut dyn Iterator<Item = NodeRef<Node>>| -> Vec<XNode> {
+            nodes.map(|node| XNode::Node(node.id())).collect()
+        };
+
+        let (id, attribute) = match node {
+            XNode::Node(id) => (id, false),
+            XNode::Attribute(id, _) => (id, true),
+        };
+        let tree_node = self
+            .html
+            .tree
+            .get(id)
+            .expect("xpath nodes are in the document");
+
+        match (axis, attribute) {
+            (Axis::SelfNode, _) => vec![node],
+            (Axis::Parent, false) => tree_nodes(&mut tree_node.parent().into_iter()),
+            (Axis::Parent, true) => vec![XNode::Node(id)],
+            (Axis::Ancestor, false) => tree_nodes(&mut tree_node.ancestors()),
+            (Axis::Ancestor, true) => {
+                tree_nodes(&mut std::iter::once(tree_node).chain(tree_node.ancestors()))
+            }
+            (Axis::AncestorOrSelf, _) => {
+                let mut nodes = vec![node];
+                nodes.extend(self.axis(Axis::Ancestor, node));
+                nodes
+            }
+            (Axis::Child, false) => tree_nodes(&mut tree_node.children()),
+            (Axis::Descendant, false) => tree_nodes(&mut tree_node.descendants().skip(1)),
+            (Axis::DescendantOrSelf, false) => tree_nodes(&mut tree_node.descendants()),
+            (Axis::FollowingSibling, false) => tree_nodes(&mut tree_node.next_siblings()),
+            (Axis::PrecedingSibling, false) => tree_nodes(&mut tree_node.prev_siblings()),
+            (Axis::Attribute, false) => match tree_node.value() {
+                Node::Element(element) => (0..element.attrs.len())
+                    .map(|index| XNode::Attribute(id, index))
+                    .collect(),
+                _ => Vec::new(),
+            },
+            (Axis::Following, _) => {
+                // The descendants of an attribute's element come after it
+                let mut nodes = if attribute {
+                    tree_nodes(&mut tree_node.descendants().skip(1))
+                } else {
+                    Vec::new()
+                };
+
+                for ancestor in std::iter::once(tree_node).chain(tree_node.ancestors()) {
+                    for sibling in ancestor.next_siblings() {
+                        nodes.extend(tree_nodes(&mut sibling.descendants()));
+                    }
+                }
+
+                nodes
+            }
+            (Axis::Preceding, _) => {
+                let mut nodes = Vec::new();
+
+                for ancestor in std::iter::once(tree_node).chain(tree_node.ancestors()) {
+                    for sibling in ancestor.prev_siblings() {
+                        let mut subtree = tree_nodes(&mut sibling.descendants());
+                        subtree.reverse();
+                        nodes.extend(subtree);
+                    }
+                }
+
+                nodes
+            }
+            (
+                Axis::Child
+                | Axis::Descendant
+                | Axis::FollowingSibling
+                | Axis::PrecedingSibling
+                | Axis::Attribute,
+                true,
+            ) => Vec::new(),
+            (Axis::DescendantOrSelf, true) => vec![node],
+        }
+    }
+
+    fn matches(&self, test: &NodeTest, axis: Axis, node: XNode) -> bool {
+        let id = match node {
+            XNode::Node(id) => id,
+            XNode::Attribute(id, index) => {
+                return match test {
+                    NodeTest::Any => axis == Axis::Attribute,
+                    NodeTest::Node => true,
+                    NodeTest::Name(name) => {
+                        axis == Axis::Attribute
+                            && attribute(self.html, id, index)
+                                .is_some_and(|(attribute_name, _)| attribute_name == *name)
+                    }
+                    _ => false,
+                }
+            }
+        };
+
+        let value = self
+            .html
+            .tree
+            .get(id)
+            .expect("xpath nodes are in the document")
+            .value();
+
+        match test {
+            // Attributes are the principal node type of the attribute axis,
+            // which only contains attributes
+            NodeTest::Any => value.is_element() && axis != Axis::Attribute,
+            NodeTest::Name(name) => {
+                axis != Axis::Attribute
+                    && value
+                        .as_element()
+                        .is_some_and(|element| element.name() == name)
+            }
+            NodeTest::Node => !value.is_doctype(),
+            NodeTest::Text => value.is_text(),
+            NodeTest::Comment => value.is_comment(),
+            NodeTest::ProcessingInstruction(target) => match value {
+                Node::ProcessingInstruction(instruction) => target
+                    .as_ref()
+                    .map_or(true, |target| *target == *instruction.target),
+                _ => false,
+            },
+        }
+    }
+
+    /// Keeps the nodes for which each predicate holds in turn, with positions
+    /// in the order of `nodes`
+    fn filter(
+        &self,
+        mut nodes: Vec<XNode>,
+        predicates: &[Expr],
+    ) -> Result<Vec<XNode>, EvaluationError> {
+        for predicate in predicates {
+            let size = nodes.len();
+            let mut kept = Vec::new();
+
+            for (index, node) in nodes.into_iter().enumerate() {
+                let position = index + 1;
+                let context = Context {
+                    node,
+                    position,
+                    size,
+                };
+
+                let keep = match self.evaluate(predicate, &context)? {
+                    Value::Number(number) => number == position as f64,
+                    value => self.boolean(value),
+                };
+
+                if keep {
+                    kept.push(node);
+                }
+            }
+
+            nodes = kept;
+        }
+
+        Ok(nodes)
+    }
+
+    fn sort(&self, mut nodes: Vec<XNode>) -> Vec<XNode> {
+        nodes.sort_by(|a, b| self.document_order(*a, *b));
+        nodes.dedup();
+        nodes
+    }
+
+    fn document_order(&self, a: XNode, b: XNode) -> Ordering {
+        let key = |node: XNode| match node {
+            XNode::Node(id) => (self.order.get(&id).copied(), 0),
+            XNode::Attribute(id, index) => (self.order.get(&id).copied(), index + 1),
+        };
+
+        key(a).cmp(&key(b))
+    }
+
+    fn nodes(&self, value: Value, operator: &str) -> Result<Vec<XNode>, EvaluationError> {
+        match value {
+            Value::Nodes(nodes) => Ok(nodes),
+            _ => Err(EvaluationError(format!(
+                "expected a node-set for {operator:?}"
+            ))),
+        }
+    }
+
+    fn boolean(&self, value: Value) -> bool {
+        match value {
+            Value::Nodes(nodes) => !nodes.is_empty(),
+            Value::Boolean(boolean) => boolean,
+            Value::Number(number) => number != 0.0 && !number.is_nan(),
+            Value::String(string) => !string.is_empty(),
+        }
+    }
+
+    fn number(&self, value: Value) -> f64 {
+        match value {
+            Value::Boolean(boolean) => f64::from(u8::from(boolean)),
+            Value::Number(number) => number,
+            value => string_to_number(&self.string(value)),
+        }
+    }
+
+    fn string(&self, value: Value) -> String {
+        match value {
+            Value::Nodes(nodes) => nodes
+                .first()
+                .map(|&node| string_value(self.html, node))
+                .unwrap_or_default(),
+            Value::Boolean(boolean) => boolean.to_string(),
+            Value::Number(number) => number_to_string(number),
+            Value::String(string) => string,
+        }
+    }
+
+    /// Compares two values, where comparisons with node-sets hold when they
+    /// hold for any of their nodes' string-values
+    fn compare(&self, comparison: Comparison, left: Value, right: Value) -> bool {
+        match (left, right) {
+            (Value::Nodes(left), Value::Nodes(right)) => {
+                let right: Vec<String> = right
+                    .into_iter()
+                    .map(|node| string_value(self.html, node))
+                    .collect();
+
+                left.into_iter().any(|node| {
+                    let left = string_value(self.html, node);
+                    right.iter().any(|right| {
+                        self.compare_atoms(
+                            comparison,
+                            Value::String(left.clone()),
+                            Value::String(right.clone()),
+                        )
+                    })
+                })
+            }
+            (Value::Nodes(nodes), Value::Boolean(boolean)) => self.compare_atoms(
+                comparison,
+                Value::Boolean(!nodes.is_empty()),
+                Value::Boolean(boolean),
+            ),
+            (Value::Boolean(boolean), Value::Nodes(nodes)) => self.compare_atoms(
+                comparison,
+                Value::Boolean(boolean),
+                Value::Boolean(!nodes.is_empty()),
+            ),
+            (Value::Nodes(nodes), other) => nodes.into_iter().any(|node| {
+                let string = Value::String(string_value(self.html, node));
+                self.compare_atoms(comparison, string, self.clone_atom(&other))
+            }),
+            (other, Value::Nodes(nodes)) => nodes.into_iter().any(|node| {
+                let string = Value::String(string_value(self.html, node));
+                self.compare_atoms(comparison, self.clone_atom(&other), string)
+            }),
+            (left, right) => self.compare_atoms(comparison, left, right),
+        }
+    }
+
+    fn clone_atom(&self, value: &Value) -> Value {
+        match value {
+            Value::Boolean(boolean) => Value::Boolean(*boolean),
+            Value::Number(number) => Value::Number(*number),
+            Value::String(string) => Value::String(string.clone()),
+            Value::Nodes(nodes) => Value::Nodes(nodes.clone()),
+        }
+    }
+
+    fn compare_atoms(&self, comparison: Comparison, left: Value, right: Value) -> bool {
+        match comparison {
+            Comparison::Equal | Comparison::NotEqual => {
+                let equal = match (&left, &right) {
+                    (Value::Boolean(_), _) | (_, Value::Boolean(_)) => {
+                        self.boolean(left) == self.boolean(right)
+                    }
+                    (Value::Number(_), _) | (_, Value::Number(_)) => {
+                        self.number(left) == self.number(right)
+                    }
+                    _ => self.string(left) == self.string(right),
+                };
+
+                equal == matches!(comparison, Comparison::Equal)
+            }
+            _ => {
+                let left = self.number(left);
+                let right = self.number(right);
+
+                match comparison {
+                    Comparison::Less => left < right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::Greater => left > right,
+                    _ => left >= right,
+                }
+            }
+        }
+    }
+
+    fn call(
+        &self,
+        function: Function,
+        arguments: &[Expr],
+        context: &Context,
+    ) -> Result<Value, EvaluationError> {
+        let argument = |index: usize| self.evaluate(&arguments[index], context);
+        let string =
+            |index: usize| -> Result<String, EvaluationError> { Ok(self.string(argument(index)?)) };
+        // Functions like `string()` default to the context node
+        let string_or_context = || -> Result<String, EvaluationError> {
+            match arguments.first() {
+                Some(_) => string(0),
+                None => Ok(string_value(self.html, context.node)),
+            }
+        };
+        let node_or_context = |name: &str| -> Result<Option<XNode>, EvaluationError> {
+            match arguments.first() {
+                Some(_) => Ok(self.nodes(argument(0)?, name)?.first().copied()),
+                None => Ok(Some(context.node)),
+            }
+        };
+
+        Ok(match function {
+            Function::Last => Value::Number(context.size as f64),
+            Function::Position => Value::Number(context.position as f64),
+            Function::Count => Value::Number(self.nodes(argument(0)?, "count()")?.len() as f64),
+            Function::Id => {
+                let ids: Vec<String> = match argument(0)? {
+                    Value::Nodes(nodes) => nodes
+                        .into_iter()
+                        .map(|node| string_value(self.html, node))
+                        .collect(),
+                    value => vec![self.string(value)],
+                };
+                let ids: Vec<&str> = ids.iter().flat_map(|ids| ids.split_whitespace()).collect();
+
+                Value::Nodes(
+                    self.html
+                        .tree
+                        .root()
+                        .descendants()
+                        .filter(|node| {
+                            node.value()
+                                .as_element()
+                                .and_then(|element| element.id())
+                                .is_some_and(|id| ids.contains(&id))
+                        })
+                        .map(|node| XNode::Node(node.id()))
+                        .collect(),
+                )
+            }
+            Function::LocalName | Function::Name => {
+                let name = node_or_context(function.name())?
+                    .and_then(|node| self.name(node))
+                    .unwrap_or_default();
+
+                Value::String(match function {
+                    Function::LocalName => match name.split_once(':') {
+                        Some((_, local_name)) => local_name.to_string(),
+                        None => name,
+                    },
+                    _ => name,
+                })
+            }
+            Function::String => Value::String(string_or_context()?),
+            Function::Concat => Value::String(
+                (0..arguments.len())
+                    .map(string)
+                    .collect::<Result<String, _>>()?,
+            ),
+            Function::StartsWith => Value::Boolean(string(0)?.starts_with(&string(1)?)),
+            Function::EndsWith => Value::Boolean(string(0)?.ends_with(&string(1)?)),
+            Function::Contains => Value::Boolean(string(0)?.contains(&string(1)?)),
+            Function::SubstringBefore => {
+                let (haystack, needle) = (string(0)?, string(1)?);
+
+                Value::String(
+                    haystack
+                        .split_once(&needle)
+                        .map(|(before, _)| before.to_string())
+                        .unwrap_or_default(),
+                )
+            }
+            Function::SubstringAfter => {
+                let (haystack, needle) = (string(0)?, string(1)?);
+
+                Value::String(
+                    haystack
+                        .split_once(&needle)
+                        .map(|(_, after)| after.to_string())
+                        .unwrap_or_default(),
+                )
+            }
+            Function::Substring => {
+                let string = string(0)?;
+                let start = round(self.number(argument(1)?));
+                let end = match arguments.get(2) {
+                    Some(_) => start + round(self.number(argument(2)?)),
+                    None => f64::INFINITY,
+                };
+
+                Value::String(
+                    string
+                        .chars()
+                        .enumerate()
+                        .filter(|&(index, _)| {
+                            let position = (index + 1) as f64;
+                            position >= start && position < end
+                        })
+                        .map(|(_, c)| c)
+                        .collect(),
+                )
+            }
+            Function::StringLength => Value::Number(string_or_context()?.chars().count() as f64),
+            Function::NormalizeSpace => Value::String(
+                string_or_context()?
+                    .split_ascii_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            Function::Translate => {
+                let (string, from, to) = (string(0)?, string(1)?, string(2)?);
+                let to: Vec<char> = to.chars().collect();
+
+                Value::String(
+                    string
+                        .chars()
+                        .filter_map(|c| match from.chars().position(|from| from == c) {
+                            Some(index) => to.get(index).copied(),
+                            None => Some(c),
+                        })
+                        .collect(),
+                )
+            }
+            Function::LowerCase => Value::String(string(0)?.to_lowercase()),
+            Function::UpperCase => Value::String(string(0)?.to_uppercase()),
+            Function::Boolean => Value::Boolean(self.boolean(argument(0)?)),
+            Function::Not => Value::Boolean(!self.boolean(argument(0)?)),
+            Function::True => Value::Boolean(true),
+            Function::False => Value::Boolean(false),
+            Function::Lang => {
+                let lang = string(0)?.to_ascii_lowercase();
+
+                Value::Boolean(self.lang(context.node).is_some_and(|node_lang| {
+                    let node_lang = node_lang.to_ascii_lowercase();
+                    node_lang == lang
+                        || node_lang
+                            .strip_prefix(&lang)
+                            .is_some_and(|rest| rest.starts_with('-'))
+                }))
+            }
+            Function::Number => Value::Number(match arguments.first() {
+                Some(_) => self.number(argument(0)?),
+                None => string_to_number(&string_value(self.html, context.node)),
+            }),
+            Function::Sum => Value::Number(
+                self.nodes(argument(0)?, "sum()")?
+                    .into_iter()
+                    .map(|node| string_to_number(&string_value(self.html, node)))
+                    .sum(),
+            ),
+            Function::Floor => Value::Number(self.number(argument(0)?).floor()),
+            Function::Ceiling => Value::Number(self.number(argument(0)?).ceil()),
+            Function::Round => Value::Number(round(self.number(argument(0)?))),
+        })
+    }
+
+    fn name(&self, node: XNode) -> Option<String> {
+        match node {
+            XNode::Node(id) => match self.html.tree.get(id)?.value() {
+                Node::Element(element) => Some(element.name().to_string()),
+                Node::ProcessingInstruction(instruction) => Some(instruction.target.to_string()),
+                _ => None,
+            },
+            XNode::Attribute(id, index) => attribute(self.html, id, index).map(|(name, _)| name),
+        }
+    }
+
+    /// Returns the language of the node, from the `lang` or `xml:lang`
+    /// attribute of its closest element declaring one
+    fn lang(&self, node: XNode) -> Option<&str> {
+        let (XNode::Node(id) | XNode::Attribute(id, _)) = node;
+        let node = self.html.tree.get(id)?;
+
+        std::iter::once(node)
+            .chain(node.ancestors())
+            .filter_map(|node| node.value().as_element())
+            .find_map(|element| {
+                element
+                    .attrs
+                    .iter()
+                    .find(|(name, _)| &*name.local == "lang")
+                    .map(|(_, value)| &**value)
+            })
+    }
+}
+
+/// Rounds like XPath's `round()`, to the closest integer with halves rounded
+/// up
+fn round(number: f64) -> f64 {
+    if number.is_finite() {
+        (number + 0.5).floor()
+    } else {
+        number
+    }
+}
+
+/// Converts a string to a number, which is NaN unless it is an optional minus
+/// sign followed by digits with an optional decimal point
+fn string_to_number(string: &str) -> f64 {
+    let string = string.trim_matches(|c: char| c.is_ascii_whitespace());
+    let digits = string.strip_prefix('-').unwrap_or(string);
+    let is_number = digits.chars().any(|c| c.is_ascii_digit())
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && digits.matches('.').count() <= 1;
+
+    if is_number {
+        string.parse().unwrap_or(f64::NAN)
+    } else {
+        f64::NAN
+    }
+}
+
+/// Formats a number like XPath's `string()`, without an exponent and
+/// without a fractional part for integers
+fn number_to_string(number: f64) -> String {
+    if number.is_nan() {
+        "NaN".to_string()
+    } else if number.is_infinite() {
+        if number > 0.0 {
+            "Infinity"
+        } else {
+            "-Infinity"
+        }
+        .to_string()
+    } else if number == 0.0 {
+        "0".to_string()
+    } else {
+        number.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scraper::Selector;
+
+    const HTML: &str = r#"<!DOCTYPE html>
+        <html lang="en-US">
+          <body>
+            <h1 id="title">Products</h1>
+            <ul class="products">
+              <li data-price="10">Hammer <b>sale</b></li>
+              <li data-price="25">Saw</li>
+              <li data-price="5" class="featured">Nails</li>
+            </ul>
+            <!-- footer -->
+            <p>See <a href="/more">more</a></p>
+          </body>
+        </html>"#;
+
+    fn evaluate(expr: &str) -> Value {
+        let html = Html::parse_document(HTML);
+        XPath::parse(expr)
+            .unwrap()
+            .evaluate(&html, html.tree.root().id())
+            .unwrap()
+    }
+
+    /// Returns the string-values of the nodes an expression selects
+    fn strings(expr: &str) -> Vec<String> {
+        let html = Html::parse_document(HTML);
+        let Value::Nodes(nodes) = XPath::parse(expr)
+            .unwrap()
+            .evaluate(&html, html.tree.root().id())
+            .unwrap()
+        else {
+            panic!("{expr} doesn't return nodes");
+        };
+
+        nodes
+            .into_iter()
+            .map(|node| string_value(&html, node).trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(vec!["Products"], strings("/html/body/h1"));
+        assert_eq!(vec!["Hammer sale", "Saw", "Nails"], strings("//li"));
+        assert_eq!(vec!["Saw"], strings("//ul/li[2]"));
+        assert_eq!(vec!["Nails"], strings("//li[last()]"));
+        assert_eq!(vec!["Hammer sale"], strings("(//li)[1]"));
+        assert_eq!(vec!["sale"], strings("(//li)[1]/b"));
+        assert_eq!(vec!["10", "25", "5"], strings("//li/@data-price"));
+        assert_eq!(vec!["Products", "more"], strings("//h1 | //a"));
+        assert_eq!(vec!["Products"], strings("//ul/preceding-sibling::*"));
+        assert_eq!(
+            vec!["Saw", "Nails"],
+            strings("//li[1]/following-sibling::li")
+        );
+        assert_eq!(vec!["Saw"], strings("//li[3]/preceding-sibling::li[1]"));
+        assert_eq!(vec!["Nails"], strings("//b/following::li[2]"));
+        assert_eq!(vec!["Products"], strings("//a/preceding::h1"));
+        assert_eq!(
+            vec!["products"],
+            strings("//b/ancestor::ul/@class"),
+            "attributes of ancestors"
+        );
+        assert_eq!(vec!["Hammer sale"], strings("//b/.."));
+        assert_eq!(vec!["Hammer"], strings("//li[1]/text()"));
+        assert_eq!(vec!["footer"], strings("//comment()"));
+        assert_eq!(vec!["Saw"], strings("//li[@data-price > 20]"));
+        assert_eq!(vec!["Nails"], strings("//li[@class = 'featured']"));
+        assert_eq!(vec!["Hammer sale"], strings("//li[b]"));
+        assert_eq!(vec!["Hammer sale"], strings("//li[contains(., 'sale')]"));
+        assert_eq!(vec!["Saw", "Nails"], strings("//li[position() > 1]"));
+        assert_eq!(vec!["Saw"], strings("//li[not(b)][1]"));
+        assert_eq!(vec!["Products"], strings("id('title')"));
+        assert_eq!(vec!["more"], strings("//*[lang('en')]//a"));
+        assert_eq!(vec!["/more"], strings("//a/attribute::href"));
+        assert_eq!(vec!["more"], strings("//@href/.."));
+        assert_eq!(
+            Vec::<String>::new(),
+            strings("//li/@*[self::*]"),
+            "* doesn't match attributes outside of the attribute axis"
+        );
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(Value::Number(3.0), evaluate("count(//li)"));
+        assert_eq!(Value::Number(40.0), evaluate("sum(//li/@data-price)"));
+        assert_eq!(Value::Boolean(true), evaluate("//li/@data-price = 25"));
+        assert_eq!(Value::Boolean(true), evaluate("//li/@data-price != 25"));
+        assert_eq!(Value::Boolean(false), evaluate("//li/@data-price > 25"));
+        assert_eq!(
+            Value::String("Products".to_string()),
+            evaluate("string(//h1)")
+        );
+        assert_eq!(
+            Value::String("Hammer sale".to_string()),
+            evaluate("normalize-space(//li)")
+        );
+        assert_eq!(Value::String("h1".to_string()), evaluate("name(//*[@id])"));
+        assert_eq!(Value::Number(7.0), evaluate("1 + 2 * 3"));
+        assert_eq!(Value::Number(1.0), evaluate("7 mod 3"));
+        assert_eq!(Value::Number(-2.5), evaluate("-5 div 2"));
+        assert_eq!(Value::Number(3.0), evaluate("round(2.5)"));
+        assert_eq!(
+            Value::String("2.5".to_string()),
+            evaluate("string(5 div 2)")
+        );
+        assert_eq!(
+            Value::String("Infinity".to_string()),
+            evaluate("string(1 div 0)")
+        );
+        assert_eq!(
+            Value::String("NaN".to_string()),
+            evaluate("string(number('1e3'))")
+        );
+        assert_eq!(
+            Value::String("234".to_string()),
+            evaluate("substring('12345', 1.5, 2.6)")
+        );
+        assert_eq!(
+            Value::String("BAr".to_string()),
+            evaluate("translate('bar', 'abc', 'AB')")
+        );
+        assert_eq!(
+            Value::String("ab".to_string()),
+            evaluate("concat('a', \"b\")")
+        );
+        assert_eq!(
+            Value::String("x".to_string()),
+            evaluate("substring-before('x-y', '-')")
+        );
+        assert_eq!(
+            Value::Boolean(true),
+            evaluate("starts-with('abc', 'ab') and true()")
+        );
+        assert_eq!(Value::Boolean(true), evaluate("//h1 = 'Products'"));
+        assert_eq!(Value::Boolean(false), evaluate("boolean(//table)"));
+    }
+
+    #[test]
+    fn test_path() {
+        for html in [Html::parse_document(HTML), Html::parse_fragment(HTML)] {
+            for selector in ["h1", "li", "b", "a"] {
+                for element in html.select(&Selector::parse(selector).unwrap()) {
+                    let path = path(element);
+
+                    assert_eq!(
+                        Value::Nodes(vec![XNode::Node(element.id())]),
+                        XPath::parse(&path)
+                            .unwrap()
+                            .evaluate(&html, html.tree.root().id())
+                            .unwrap(),
+                        "{path} selects only the element"
+                    );
+                }
+            }
+        }
+
+        let html = Html::parse_document(HTML);
+        let li = html
+            .select(&Selector::parse("li + li").unwrap())
+            .next()
+            .unwrap();
+        assert_eq!("/html/body/ul/li[2]", path(li));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |expr: &str| XPath::parse(expr).unwrap_err().to_string();
+
+        assert_eq!(
+            "expected a step, found end of expression at position 5",
+            error("//li/")
+        );
+        assert_eq!("unknown function foo() at position 0", error("foo(1)"));
+        assert_eq!(
+            "unknown axis sideways at position 2",
+            error("//sideways::li")
+        );
+        assert_eq!(
+            "wrong number of arguments for contains() (1) at position 0",
+            error("contains('a')")
+        );
+        assert_eq!(
+            "variables are not supported at position 9",
+            error("//li[. = $x]")
+        );
+        assert_eq!("unexpected token at position 4", error("//li]"));
+        assert_eq!("unterminated string literal at position 0", error("'abc"));
+
+        let html = Html::parse_document(HTML);
+        assert_eq!(
+            Err(EvaluationError("expected a node-set for \"|\"".to_string())),
+            XPath::parse("1 | //li")
+                .unwrap()
+                .evaluate(&html, html.tree.root().id())
+        );
+    }
+}
@@ -1,13 +1,67 @@
 # frozen_string_literal: true
 
+require "io/wait"
 require_relative "sawzall/version"
 require_relative "sawzall/extension"
 
 module Sawzall
   # Parses the given string as an HTML fragment
   #
-  # @!method self.parse_fragment(html)
+  # Strings in other encodings are converted to UTF-8, and invalid byte
+  # sequences are replaced with U+FFFD (`"\uFFFD"`) like browsers do. Every
+  # string returned from the document is therefore valid UTF-8.
+  #
+  # Large inputs are parsed without holding the GVL, so other threads keep
+  # running in the meantime.
+  #
+  # @!method self.parse_fragment(html, weak_elements: false, strict_encoding: false, invalid: nil, repair: :none, sniff_encoding: false, content_type: nil, max_input_bytes: nil, max_depth: nil, max_nodes: nil)
   # @param html [String]
+  # @param weak_elements [Boolean]
+  #   whether the document's elements only hold a weak reference to it. By
+  #   default, an element keeps its whole document alive. With this option,
+  #   the document can be garbage collected while some of its elements are
+  #   still referenced, after which using them raises
+  #   {Sawzall::StaleDocumentError}. Defaults to
+  #   {Sawzall::Configuration#weak_elements}.
+  # @param strict_encoding [Boolean]
+  #   whether to raise a {Sawzall::InvalidEncodingError} when `html` can't be
+  #   converted to valid UTF-8 instead of replacing the invalid bytes.
+  #   Defaults to {Sawzall::Configuration#strict_encoding}.
+  # @param invalid [Symbol, nil]
+  #   what to do with invalid bytes instead of using `strict_encoding:`:
+  #   `:replace` replaces them with U+FFFD and `:raise` raises a
+  #   {Sawzall::InvalidEncodingError} with the offset of the first one.
+  # @param repair [Symbol]
+  #   how much to clean up garbage the parser would turn into surprising
+  #   trees, reported by {Sawzall::Document#repairs}:
+  #
+  #   - `:none` parses the HTML as is
+  #   - `:conservative` removes NUL bytes and ends comments missing their
+  #     `-->` before the next tag, instead of letting them swallow the rest
+  #     of the document
+  #   - `:aggressive` also removes attribute values longer than 64 KiB
+  # @param sniff_encoding [Boolean]
+  #   whether to treat `html` as raw bytes, e.g. a crawled response body,
+  #   and detect their encoding like browsers do instead of trusting the
+  #   string's. A byte order mark wins, then the charset of `content_type`,
+  #   then a `<meta charset>` or `<meta http-equiv="Content-Type">` in the
+  #   first 1024 bytes. Without any of them, the bytes are UTF-8 if they are
+  #   valid UTF-8 and windows-1252 otherwise. The encoding is reported by
+  #   {Sawzall::Document#encoding}.
+  # @param content_type [String, nil]
+  #   the `Content-Type` header the HTML was served with, used when sniffing
+  # @param max_input_bytes [Integer, nil]
+  #   the most bytes `html` may contain
+  # @param max_depth [Integer, nil]
+  #   how deeply nodes may be nested, with the `<html>` element at depth 1
+  # @param max_nodes [Integer, nil]
+  #   the most nodes the document may contain
+  #
+  #   Set these limits when parsing untrusted HTML so that pathological input
+  #   raises a {Sawzall::ParseLimitError} instead of using up all the memory.
+  #   Parsing stops shortly after a limit is exceeded.
+  # @raise [ArgumentError] if `repair` isn't one of the modes above
+  # @raise [Sawzall::ParseLimitError] if a limit is exceeded
   # @return [Sawzall::Document]
   #
   # @example
@@ -16,11 +70,37 @@ module Sawzall
   #     .select("h1")
   #     .first
   #     .attr("id") #=> "title"
+  #
+  # @example Invalid bytes
+  #   Sawzall.parse_fragment("<p>caf\xE9</p>").select("p").first.text #=> "caf\uFFFD"
+  #
+  # @example Sniffing the encoding
+  #   body = "<meta charset=latin1><p>caf\xE9</p>".b
+  #   doc = Sawzall.parse_fragment(body, sniff_encoding: true)
+  #   doc.at("p").text #=> "café"
+  #   doc.encoding #=> "windows-1252"
+  #   Sawzall.parse_fragment(body, sniff_encoding: true, content_type: "text/html; charset=utf-8").encoding #=> "UTF-8"
 
   # Parses the given string as a complete HTML document
   #
-  # @!method self.parse_document(html)
-  # @param html [String]
+  # `html` can also be an IO (or anything else responding to `read`), which
+  # is read and parsed in chunks instead of reading it into a string first.
+  # It is read as UTF-8, so `sniff_encoding:` and `repair:` aren't supported.
+  #
+  # @!method self.parse_document(html, weak_elements: false, strict_encoding: false, invalid: nil, repair: :none, sniff_encoding: false, content_type: nil, max_input_bytes: nil, max_depth: nil, max_nodes: nil)
+  # @param html [String, IO]
+  # @param weak_elements [Boolean] see {Sawzall.parse_fragment}
+  # @param strict_encoding [Boolean] see {Sawzall.parse_fragment}
+  # @param invalid [Symbol, nil] see {Sawzall.parse_fragment}
+  # @param repair [Symbol] see {Sawzall.parse_fragment}
+  # @param sniff_encoding [Boolean] see {Sawzall.parse_fragment}
+  # @param content_type [String, nil] see {Sawzall.parse_fragment}
+  # @param max_input_bytes [Integer, nil] see {Sawzall.parse_fragment}
+  # @param max_depth [Integer, nil] see {Sawzall.parse_fragment}
+  # @param max_nodes [Integer, nil] see {Sawzall.parse_fragment}
+  # @raise [ArgumentError] if `repair` isn't a valid mode, or if
+  #   `sniff_encoding:` or `repair:` are used with an IO
+  # @raise [Sawzall::ParseLimitError] if a limit is exceeded
   # @return [Sawzall::Document]
   #
   # @example
@@ -42,12 +122,520 @@ module Sawzall
   #     .first
   #     .text #=> "Page Title"
 
+  # Parses the file at the given path as a complete HTML document
+  #
+  # The file is memory-mapped rather than read into a string, which avoids
+  # copying it when processing many large files. It is read as UTF-8 unless
+  # sniffing its encoding, and mustn't be modified while it is being parsed.
+  #
+  # @!method self.parse_file(path, weak_elements: false, strict_encoding: false, invalid: nil, repair: :none, sniff_encoding: false, content_type: nil, max_input_bytes: nil, max_depth: nil, max_nodes: nil)
+  # @param path [String, Pathname]
+  # @param weak_elements [Boolean] see {Sawzall.parse_fragment}
+  # @param strict_encoding [Boolean] see {Sawzall.parse_fragment}
+  # @param invalid [Symbol, nil] see {Sawzall.parse_fragment}
+  # @param repair [Symbol] see {Sawzall.parse_fragment}
+  # @param sniff_encoding [Boolean] see {Sawzall.parse_fragment}
+  # @param content_type [String, nil] see {Sawzall.parse_fragment}
+  # @param max_input_bytes [Integer, nil] see {Sawzall.parse_fragment}
+  # @param max_depth [Integer, nil] see {Sawzall.parse_fragment}
+  # @param max_nodes [Integer, nil] see {Sawzall.parse_fragment}
+  # @raise [SystemCallError] if the file can't be opened, e.g.
+  #   `Errno::ENOENT` if it doesn't exist
+  # @raise [ArgumentError] if `repair` isn't a valid mode
+  # @raise [Sawzall::ParseLimitError] if a limit is exceeded
+  # @return [Sawzall::Document]
+  #
+  # @example
+  #   file = Tempfile.create(["page", ".html"])
+  #   file.write("<title>Page Title</title>")
+  #   file.close
+  #
+  #   Sawzall.parse_file(file.path).at("title").text #=> "Page Title"
+
+  # Tokenizes HTML without building a tree, yielding each tag, text, comment
+  # and doctype as it's found
+  #
+  # Memory use doesn't grow with the size of the document, especially when
+  # reading from an IO, which makes this suited to counting or extracting
+  # things from huge documents. Since no tree is built, nothing is fixed up:
+  # end tags aren't implied and stray ones are yielded as is. The content of
+  # elements like `<script>` and `<title>` is yielded as text.
+  #
+  # Events are hashes with a `:type` and:
+  #
+  # - `:start_tag`: `:name`, `:attributes` and `:self_closing`
+  # - `:end_tag`: `:name`
+  # - `:text` and `:comment`: `:text`
+  # - `:doctype`: `:name`, `:public_id` and `:system_id`
+  #
+  # @example
+  #   Sawzall.each_token("<p class=intro>Hi &amp; bye</p>").to_a #=> [{type: :start_tag, name: "p", attributes: {"class" => "intro"}, self_closing: false}, {type: :text, text: "Hi & bye"}, {type: :end_tag, name: "p"}]
+  #
+  # @example Counting links
+  #   html = "<a href=/one>One</a><a href=/two>Two</a>"
+  #   Sawzall.each_token(html).count { |event| event[:type] == :start_tag && event[:name] == "a" } #=> 2
+  #
+  # @!method self.each_token(html)
+  # @param html [String, IO]
+  # @yieldparam event [Hash]
+  # @return [Sawzall, Enumerator]
+
+  # Parses the given string as an XML document, such as a sitemap or an
+  # RSS or Atom feed, for use with the same API as HTML documents
+  #
+  # Element and attribute names keep their case, so selectors must match
+  # it, and CDATA sections become text. Like HTML, malformed XML is parsed
+  # on a best-effort basis rather than rejected. The encoding is taken from
+  # the string rather than the XML declaration. Elements are serialized as
+  # HTML by {Sawzall::Element#html}.
+  #
+  # @!method self.parse_xml(xml, weak_elements: false, strict_encoding: false)
+  # @param xml [String]
+  # @param weak_elements [Boolean] see {Sawzall.parse_fragment}
+  # @param strict_encoding [Boolean] see {Sawzall.parse_fragment}
+  # @raise [ArgumentError] if `xml` has no root element
+  # @return [Sawzall::Document]
+  #
+  # @example
+  #   feed = Sawzall.parse_xml(<<~XML)
+  #     <?xml version="1.0" encoding="UTF-8"?>
+  #     <rss version="2.0">
+  #       <channel>
+  #         <item><title>First post</title><pubDate>Mon, 01 Jan 2024 00:00:00 GMT</pubDate></item>
+  #         <item><title><![CDATA[Second & last]]></title></item>
+  #       </channel>
+  #     </rss>
+  #   XML
+  #   feed.root_element.name #=> "rss"
+  #   feed.select("item title").map(&:text) #=> ["First post", "Second & last"]
+  #   feed.at("pubDate").text #=> "Mon, 01 Jan 2024 00:00:00 GMT"
+
+  # Builds a new fragment out of the given parts, optionally separated by
+  # `separator`
+  #
+  # Documents contribute copies of their content (the `<body>` of complete
+  # documents) and elements copies of themselves, leaving the originals
+  # untouched. Strings are added as text, so they are escaped rather than
+  # parsed.
+  #
+  # @!method self.join(parts, separator = nil)
+  # @param parts [Array<Sawzall::Document, Sawzall::Element, String>]
+  # @param separator [Sawzall::Document, Sawzall::Element, String, nil]
+  # @return [Sawzall::Document]
+  # @raise [TypeError] if a part isn't a document, an element or a string
+  #
+  # @example
+  #   items = Sawzall.parse_fragment("<li>One</li><li>Two</li>").select("li")
+  #   Sawzall.join(items, "\n").to_html #=> "<li>One</li>\n<li>Two</li>"
+  #   Sawzall.join(["Tom & Jerry", items.first]).to_html #=> "Tom &amp; Jerry<li>One</li>"
+
+  # Checks whether elements with the given name are void, i.e. can't have
+  # content and are serialized without a closing tag
+  #
+  # @!method self.void_element?(name)
+  # @param name [String] compared case-insensitively
+  # @return [Boolean]
+  #
+  # @example
+  #   Sawzall.void_element?("br") #=> true
+  #   Sawzall.void_element?("p") #=> false
+
+  # Checks whether the content of elements with the given name is parsed as
+  # text rather than markup, like `<script>`, `<style>`, `<textarea>` and
+  # `<title>`
+  #
+  # The text of `<textarea>` and `<title>` is escaped when serialized, while
+  # the text of the others is written as is. See
+  # {Sawzall::Element#inner_html=} for how that's handled when changing it.
+  #
+  # @!method self.raw_text_element?(name)
+  # @param name [String] compared case-insensitively
+  # @return [Boolean]
+  #
+  # @example
+  #   Sawzall.raw_text_element?("script") #=> true
+  #   Sawzall.raw_text_element?("textarea") #=> true
+  #   Sawzall.raw_text_element?("div") #=> false
+
+  # Checks whether the given string is a CSS selector that {Sawzall::Document#select}
+  # and friends accept, without needing a document
+  #
+  # @!method self.valid_selector?(css_selector)
+  # @param css_selector [String]
+  # @return [Boolean]
+  #
+  # @example
+  #   Sawzall.valid_selector?("article > p.intro") #=> true
+  #   Sawzall.valid_selector?("article >") #=> false
+
+  # Compares two documents, returning what changed from `old` to `new` in
+  # document order
+  #
+  # Children are matched up the way a line-based diff matches lines: identical
+  # subtrees first, then the remaining nodes of the same type and name, which
+  # are compared in turn. Attributes are compared regardless of their order.
+  # Text made only of whitespace is ignored, so reindenting the markup isn't
+  # a change.
+  #
+  # Each change is a hash with a `:type` and the `:node` it applies to:
+  #
+  # - `:insert` for a node of `new`, along with its descendants, that isn't in
+  #   `old`
+  # - `:delete` for a node of `old`, along with its descendants, that isn't
+  #   in `new`
+  # - `:attribute` when the `:name` attribute of an element went `:from` one
+  #   value `:to` another, either of which is `nil` if it was added or removed
+  # - `:text` when a text node or a comment went `:from` one text `:to`
+  #   another
+  #
+  # The last two also have the matching `:old_node` of `old`.
+  #
+  # @!method self.diff(old, new)
+  # @param old [Sawzall::Document]
+  # @param new [Sawzall::Document]
+  # @return [Array<Hash{Symbol => Object}>]
+  #
+  # @example
+  #   old = Sawzall.parse_fragment('<p class="a">Price: <b>10</b></p>')
+  #   new = Sawzall.parse_fragment('<p class="b">Price: <b>12</b></p><p>New</p>')
+  #   Sawzall.diff(old, new).map { it.slice(:type, :name, :from, :to) } #=> [{type: :attribute, name: "class", from: "a", to: "b"}, {type: :text, from: "10", to: "12"}, {type: :insert}]
+  #   Sawzall.diff(old, new).last[:node].text #=> "New"
+
+  # Returns the options used when they aren't passed to a method, as set with
+  # {Sawzall.configure}
+  #
+  # @!method self.defaults
+  # @return [Hash{Symbol => Object}]
+  #
+  # @example
+  #   Sawzall.defaults #=> {weak_elements: false, strict_encoding: false}
+
+  # Starts parsing the given string on a background thread and returns
+  # immediately
+  #
+  # This lets parsing overlap with other work, like IO in a fiber scheduler,
+  # without spawning Ruby threads.
+  #
+  # @!method self.parse_async(html, fragment: false, strict_encoding: false)
+  # @param html [String]
+  # @param fragment [Boolean] whether to parse `html` as a fragment instead of
+  #   a complete document
+  # @param strict_encoding [Boolean] see {Sawzall.parse_fragment}
+  # @return [Sawzall::ParseHandle]
+  #
+  # @example
+  #   handle = Sawzall.parse_async("<title>Page Title</title>")
+  #   handle.wait.select("title").first.text #=> "Page Title"
+
+  # The result of {Sawzall.parse_async}
+  class ParseHandle
+    # Returns whether parsing is done, in which case {#wait} returns
+    # immediately
+    #
+    # @!method done?
+    # @return [Boolean]
+
+    # Waits for parsing to finish and returns the parsed document
+    #
+    # Waiting is done with `IO#wait_readable` so other fibers can run in the
+    # meantime when a fiber scheduler is set.
+    #
+    # @return [Sawzall::Document]
+    def wait
+      @document ||= begin
+        IO.for_fd(notification_fd, autoclose: false).wait_readable unless done?
+        document
+      end
+    end
+
+    private :notification_fd, :document
+  end
+
+  # Settings for the native extension, see {Sawzall.configure}
+  class Configuration
+    # The number of threads used by batch APIs like {Sawzall.parse_fragments}.
+    # Defaults to `nil`, which uses one thread per CPU. Set it to `1` to
+    # disable threading entirely and do the work on the calling thread.
+    #
+    # @return [Integer, nil]
+    attr_accessor :threads
+
+    # The `weak_elements:` option used when parsing without passing it,
+    # including by {Sawzall.parse_async} and batch APIs which don't take it.
+    # Defaults to `false`.
+    #
+    # @return [Boolean]
+    attr_accessor :weak_elements
+
+    # The `strict_encoding:` option used when parsing without passing it.
+    # Defaults to `false`.
+    #
+    # @return [Boolean]
+    attr_accessor :strict_encoding
+
+    def initialize
+      @weak_elements = false
+      @strict_encoding = false
+    end
+  end
+
+  class << self
+    # Parses each of the given strings as an HTML fragment, in parallel using
+    # the thread pool set up with {Sawzall.configure}
+    #
+    # When a `queue` is given, each document is pushed to it as an
+    # `[index, document]` pair as soon as it is parsed (so not necessarily in
+    # order), letting consumers start before the whole batch is done. The
+    # calling thread waits without holding the GVL in the meantime.
+    #
+    # @param htmls [Array<String>]
+    # @param queue [Thread::Queue, nil]
+    # @param strict_encoding [Boolean] see {Sawzall.parse_fragment}
+    # @return [Array<Sawzall::Document>, Thread::Queue]
+    #   the documents in the same order as `htmls`, or `queue` once every
+    #   document has been pushed to it
+    #
+    # @example
+    #   docs = Sawzall.parse_fragments(["<p>One</p>", "<p>Two</p>"])
+    #   docs.map { |doc| doc.select("p").first.text } #=> ["One", "Two"]
+    #
+    # @example Streaming results
+    #   queue = Sawzall.parse_fragments(["<p>One</p>", "<p>Two</p>"], queue: Thread::Queue.new)
+    #   results = Array.new(queue.size) { queue.pop }
+    #   results.sort_by(&:first).map { |_, doc| doc.to_html } #=> ["<p>One</p>", "<p>Two</p>"]
+    def parse_fragments(htmls, queue: nil, strict_encoding: nil)
+      if queue
+        stream_batch(htmls, true, strict_encoding, queue)
+      else
+        parse_batch(htmls, true, strict_encoding)
+      end
+    end
+
+    # Parses each of the given strings as a complete HTML document, in
+    # parallel using the thread pool set up with {Sawzall.configure}
+    #
+    # @param htmls [Array<String>]
+    # @param queue [Thread::Queue, nil] see {Sawzall.parse_fragments}
+    # @param strict_encoding [Boolean] see {Sawzall.parse_fragment}
+    # @return [Array<Sawzall::Document>, Thread::Queue]
+    def parse_documents(htmls, queue: nil, strict_encoding: nil)
+      if queue
+        stream_batch(htmls, false, strict_encoding, queue)
+      else
+        parse_batch(htmls, false, strict_encoding)
+      end
+    end
+
+    # Returns the links between pages across a batch of documents, e.g. to
+    # analyze a site's structure or audit its internal linking. Documents
+    # are processed in parallel using the thread pool set up with
+    # {Sawzall.configure}.
+    #
+    # Each edge has the `:from_url` of the page, the `:to_url` of the link
+    # resolved against the page's base URL (see {Sawzall::Document#base_uri}),
+    # the link's `:text` and its `:rel` tokens. Both URLs are normalized with
+    # {Sawzall::URL.normalize}, so links within a page are left out, as are
+    # links to anything but `http` and `https` URLs. Edges are in the order of the
+    # documents and of the links in each one.
+    #
+    # @param documents_with_urls [Hash{String => Sawzall::Document}, Array<Array(String, Sawzall::Document)>]
+    #   the absolute URL each document was fetched from
+    # @return [Array<Hash{Symbol => Object}>]
+    # @raise [ArgumentError] if a URL isn't a valid absolute URL
+    #
+    # @example
+    #   home = Sawzall.parse_document(<<~HTML)
+    #     <a href="/about">About us</a>
+    #     <a href="#main">Skip to content</a>
+    #   HTML
+    #   about = Sawzall.parse_document('<a href="https://example.org/" rel="nofollow">Partner</a>')
+    #   edges = Sawzall.link_graph("https://example.com/" => home, "https://example.com/about" => about)
+    #   edges.first #=> {from_url: "https://example.com/", to_url: "https://example.com/about", text: "About us", rel: []}
+    #   edges.last.values_at(:to_url, :rel) #=> ["https://example.org/", ["nofollow"]]
+    def link_graph(documents_with_urls)
+      link_graph_batch(documents_with_urls.to_a)
+    end
+
+    # Sorts elements of the same document in document order, e.g. after
+    # merging the results of several queries. Elements that were removed
+    # from the document come last, in their original order.
+    #
+    # Positions are computed once and reused until the document is modified,
+    # so this is about as fast as sorting integers.
+    #
+    # @param elements [Array<Sawzall::Element>]
+    # @return [Array<Sawzall::Element>]
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<h2>Two</h2><h1>One</h1><p>Text</p>")
+    #   headings = doc.select("p") + doc.select("h1") + doc.select("h2")
+    #   Sawzall.sort_by_position(headings).map(&:name) #=> ["h2", "h1", "p"]
+    def sort_by_position(elements)
+      elements.each_with_index.sort_by do |element, index|
+        position = element.document_position
+        position ? [0, position, index] : [1, index, index]
+      end.map(&:first)
+    end
+
+    # Converts HTML-ish input to a parsed fragment, for APIs that accept either
+    # HTML or something already parsed
+    #
+    # Documents are returned as is, while elements and objects responding to
+    # `to_html` are parsed from their HTML. Whitespace around strings is
+    # stripped so that heredocs don't add leading or trailing text.
+    #
+    # @param html [String, Sawzall::Document, Sawzall::Element, #to_html]
+    # @return [Sawzall::Document]
+    # @raise [TypeError] if `html` can't be converted to a string
+    #
+    # @example
+    #   doc = Sawzall.Fragment(<<~HTML)
+    #     <p>Hello</p>
+    #   HTML
+    #   doc.to_html #=> "<p>Hello</p>"
+    #   Sawzall.Fragment(doc).equal?(doc) #=> true
+    def Fragment(html)
+      return html if html.is_a?(Document)
+
+      parse_fragment(html_ish_string(html).strip)
+    end
+
+    # Converts HTML-ish input to a parsed document like {Sawzall.Fragment},
+    # but parsing strings as complete documents
+    #
+    # @param html [String, Sawzall::Document, Sawzall::Element, #to_html]
+    # @return [Sawzall::Document]
+    # @raise [TypeError] if `html` can't be converted to a string
+    def Document(html)
+      return html if html.is_a?(Document)
+
+      parse_document(html_ish_string(html))
+    end
+
+    # Returns the current configuration
+    #
+    # @return [Sawzall::Configuration]
+    def configuration
+      @configuration ||= Configuration.new
+    end
+
+    # Yields the configuration so it can be changed, then applies it
+    #
+    # @example
+    #   Sawzall.configure { |config| config.threads = 4 }
+    #
+    # @yieldparam config [Sawzall::Configuration]
+    # @return [void]
+    def configure
+      yield configuration
+      configure_threads(configuration.threads)
+      configure_defaults(configuration.weak_elements, configuration.strict_encoding)
+    end
+
+    private :configure_threads, :configure_defaults, :parse_batch, :link_graph_batch
+
+    private
+
+    def html_ish_string(html)
+      case html
+      when String then html
+      when Element then html.html
+      else html.respond_to?(:to_html) ? html.to_html : String(html)
+      end
+    end
+
+    def stream_batch(htmls, fragment, strict_encoding, queue)
+      batch = ParseBatch.new(htmls, fragment, strict_encoding)
+      notifications = IO.for_fd(batch.notification_fd, autoclose: false)
+
+      until batch.done?
+        notifications.wait_readable
+        batch.take_finished.each { |result| queue << result }
+      end
+
+      queue
+    end
+  end
+
+  # @!parse
+  #   # Streams the results of {Sawzall.parse_fragments} and
+  #   # {Sawzall.parse_documents} when given a queue
+  #   # @api private
+  #   class ParseBatch; end
+
+  # @!parse
+  #   # Raised when using an element whose document was parsed with
+  #   # `weak_elements: true` and has since been garbage collected
+  #   class StaleDocumentError < StandardError; end
+
+  # @!parse
+  #   # Raised when serializing an element would exceed the `max_output_bytes:`
+  #   # or `max_nodes:` given to {Sawzall::Element#html},
+  #   # {Sawzall::Element#inner_html} or {Sawzall::Element#text}
+  #   class OutputTooLargeError < StandardError; end
+
+  # @!parse
+  #   # Raised when parsing HTML with invalid bytes using `invalid: :raise` or
+  #   # `strict_encoding: true`
+  #   class InvalidEncodingError < EncodingError; end
+
+  # @!parse
+  #   # Raised when parsing HTML that exceeds the `max_input_bytes:`,
+  #   # `max_depth:` or `max_nodes:` given to {Sawzall.parse_fragment} or
+  #   # {Sawzall.parse_document}
+  #   class ParseLimitError < StandardError; end
+
+  # Raised by strict accessors like {Sawzall::Document#at!} when there is
+  # nothing to return, with details to help find out why
+  class NotFound < StandardError
+    # The selector that didn't match anything
+    #
+    # @return [String, nil]
+    attr_reader :selector
+
+    # The attribute that was missing
+    #
+    # @return [String, nil]
+    attr_reader :attribute
+
+    # The element matched by the longest part of the selector that does
+    # match, e.g. the `.byline` element when `article .byline a` doesn't match
+    #
+    # @return [Sawzall::Element, nil]
+    attr_reader :nearest_match
+
+    # The (truncated) HTML of the nearest match, or of where the selector was
+    # applied when no part of it matches
+    #
+    # @return [String, nil]
+    attr_reader :snippet
+
+    def initialize(message = nil, selector: nil, attribute: nil, nearest_match: nil, snippet: nil)
+      super(message)
+      @selector = selector
+      @attribute = attribute
+      @nearest_match = nearest_match
+      @snippet = snippet
+    end
+  end
+
   # @!parse
   #   class Document
   #     # Returns the elements that match the given [CSS selector][mdn]
   #     #
   #     # [mdn]: https://developer.mozilla.org/en-US/docs/Web/CSS/CSS_selectors
   #     #
+  #     # Elements are always returned in document order and only once, even
+  #     # when they match several selectors of a list like `"h2, h1"`. Use
+  #     # {Sawzall.sort_by_position} to restore that order after merging the
+  #     # results of several calls.
+  #     #
+  #     # The non-standard `:contains("text")` pseudo-class matches elements
+  #     # whose text includes the given text, compared case-insensitively. It
+  #     # can only be used on the last compound selector, e.g.
+  #     # `li:contains("sale") > a` isn't supported, and not together with
+  #     # `namespaces:`.
+  #     #
   #     # @example
   #     #   doc = Sawzall.parse_fragment(<<~HTML)
   #     #     <h1>Heading</h1>
@@ -57,11 +645,125 @@ module Sawzall
   #     #   matches = doc.select("p")
   #     #   matches.map(&:text) #=> ["Paragraph 1", "Paragraph 2"]
   #     #
-  #     # @!method select(css_selector)
+  #     # @example Keeping only the matches in memory
+  #     #   doc = Sawzall.parse_document("<main><article><h1>Title</h1></article></main>")
+  #     #   article = doc.select("article", detach: true).first
+  #     #   doc = nil # the page can now be garbage collected
+  #     #   article.html #=> "<article><h1>Title</h1></article>"
+  #     #
+  #     # @example Selecting inline SVG with namespace prefixes
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <a href="/home">Home</a>
+  #     #     <svg><a xlink:href="#logo"><title>Logo</title></a></svg>
+  #     #   HTML
+  #     #   namespaces = {svg: "http://www.w3.org/2000/svg", xlink: "http://www.w3.org/1999/xlink"}
+  #     #   doc.select("svg|a", namespaces:).map { it.attr("xlink:href") } #=> ["#logo"]
+  #     #   doc.select("a[xlink|href]", namespaces:).size #=> 1
+  #     #
+  #     # @example Filtering by attribute values
+  #     #   doc = Sawzall.parse_fragment('<a href="/2024/05/launch">Launch</a><a href="/about">About</a>')
+  #     #   doc.select("a", attr_matches: {href: %r{\d{4}/\d{2}}}).map(&:text) #=> ["Launch"]
+  #     #
+  #     # @example Selecting by text
+  #     #   doc = Sawzall.parse_fragment("<li>Apples</li><li>Pears <b>on sale</b></li>")
+  #     #   doc.select('li:contains("SALE")').map(&:text) #=> ["Pears on sale"]
+  #     #
+  #     # @!method select(css_selector, detach: false, namespaces: nil, attr_matches: nil)
   #     # @param css_selector [String]
-  #     # @raise [ArgumentError] if the CSS selector is invalid
+  #     # @param detach [Boolean]
+  #     #   whether to copy each match (and its descendants) into its own
+  #     #   document instead of referencing this one, so that it can be freed
+  #     #   while the matches are still in use
+  #     # @param namespaces [Hash{String, Symbol => String}, nil]
+  #     #   namespace URIs by prefix, for selectors like `svg|rect` or
+  #     #   `[xlink|href]`. The `xmlns` prefix sets the namespace type
+  #     #   selectors without a prefix match.
+  #     # @param attr_matches [Hash{String, Symbol => Regexp}, nil]
+  #     #   regexps that the values of the given attributes must match, checked
+  #     #   without creating a Ruby object for each candidate. Elements missing
+  #     #   one of the attributes don't match. Ruby-only regexp features like
+  #     #   lookarounds and backreferences aren't supported.
+  #     # @raise [ArgumentError] if the CSS selector is invalid or uses an
+  #     #   undeclared prefix, or if a regexp isn't supported
   #     # @return [Array<Sawzall::Element>]
   #
+  #     # Returns the first element that matches the given CSS selector
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+  #     #   doc.at("p").text #=> "One"
+  #     #   doc.at("h1") #=> nil
+  #     #
+  #     # @example Chaining with a null object
+  #     #   doc = Sawzall.parse_fragment("<p>No title</p>")
+  #     #   doc.at("header", null_object: true).at("h1").text #=> ""
+  #     #
+  #     # @!method at(css_selector, null_object: false, namespaces: nil)
+  #     # @param css_selector [String]
+  #     # @param null_object [Boolean]
+  #     #   whether to return a {Sawzall::NullElement} instead of `nil` when
+  #     #   nothing matches
+  #     # @param namespaces [Hash{String, Symbol => String}, nil] see {#select}
+  #     # @raise [ArgumentError] if the CSS selector is invalid
+  #     # @return [Sawzall::Element, Sawzall::NullElement, nil]
+  #
+  #     # Returns the first element that matches the given CSS selector, or
+  #     # raises a {Sawzall::NotFound} pointing at the element matched by the
+  #     # longest part of the selector that does match
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<article><p class='byline'>By Jane</p></article>")
+  #     #   doc.at!("article .byline").text #=> "By Jane"
+  #     #   doc.at!("article .byline a") rescue $!.message #=> "no element matches \"article .byline a\", but \"article .byline\" matches <p class=\"byline\">By Jane</p>"
+  #     #
+  #     # @!method at!(css_selector)
+  #     # @param css_selector [String]
+  #     # @raise [ArgumentError] if the CSS selector is invalid
+  #     # @raise [Sawzall::NotFound] if nothing matches
+  #     # @return [Sawzall::Element]
+  #
+  #     # Indexes the document's elements by tag name and class, so that
+  #     # {#select} and {#at} answer selectors made of a single lowercase tag
+  #     # name or class (e.g. `"a"` or `".product"`) without walking the whole
+  #     # tree
+  #     #
+  #     # Worth it when selecting repeatedly from a large document. Modifying
+  #     # the document discards the index, which is rebuilt the next time it's
+  #     # used.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment('<p class="product">One</p><p class="product">Two</p>').build_index!
+  #     #   doc.select(".product").map(&:text) #=> ["One", "Two"]
+  #     #
+  #     # @!method build_index!
+  #     # @return [self]
+  #
+  #     # Evaluates an XPath 1.0 expression against the document
+  #     #
+  #     # Node-sets are returned as arrays of elements, text nodes and
+  #     # comments in document order, with attributes as their values. Other
+  #     # expressions return a `Float`, a `String` or a boolean. Everything
+  #     # but variables and namespace prefixes is supported, along with the
+  #     # `ends-with()`, `lower-case()` and `upper-case()` functions from
+  #     # XPath 2.0. Names are lowercase in HTML documents.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <ul>
+  #     #       <li data-price="10">Hammer</li>
+  #     #       <li data-price="25">Saw <b>sale</b></li>
+  #     #     </ul>
+  #     #   HTML
+  #     #   doc.xpath("//li[@data-price > 20]").map(&:text) #=> ["Saw sale"]
+  #     #   doc.xpath("//li[contains(., 'sale')]/@data-price") #=> ["25"]
+  #     #   doc.xpath("//b/ancestor::li/preceding-sibling::li/text()").map(&:text) #=> ["Hammer"]
+  #     #   doc.xpath("sum(//li/@data-price)") #=> 35.0
+  #     #
+  #     # @!method xpath(expr)
+  #     # @param expr [String]
+  #     # @raise [ArgumentError] if the expression is invalid
+  #     # @return [Array<Sawzall::Element, Sawzall::TextNode, Sawzall::Comment, String>, Float, String, Boolean]
+  #
   #     # Returns the document's root element
   #     #
   #     # @example
@@ -71,74 +773,1434 @@ module Sawzall
   #     #
   #     # @!method root_element
   #     # @return [Sawzall::Element]
-  #   end
-
-  class Element
-    # @!group 1) Querying
-
-    # Returns the element's name in lowercase
-    #
-    # @example
-    #   doc = Sawzall.parse_fragment("<p>Paragraph</p>")
-    #   doc.select("p").first.name #=> "p"
+  #
+  #     # Returns the element identified by the given {Sawzall::Element#node_id}
+  #     # or `nil` if there is no such element
+  #     #
+  #     # Identifiers are only meaningful for the document that produced them.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+  #     #   node_id = doc.select("h1").first.node_id
+  #     #   doc.element_for_id(node_id).name #=> "h1"
+  #     #
+  #     # @!method element_for_id(node_id, null_object: false)
+  #     # @param node_id [Integer]
+  #     # @param null_object [Boolean] see {Sawzall::Document#at}
+  #     # @return [Sawzall::Element, Sawzall::NullElement, nil]
+  #
+  #     # Returns the first element in document order whose `id` attribute is
+  #     # `id`, like the DOM's `getElementById`, or `nil` if there is none
+  #     #
+  #     # The ids are indexed the first time this is called, so that later
+  #     # lookups don't walk the tree like selecting `#id` does. Modifying the
+  #     # document discards the index.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment('<p id="a">One</p><p id="a">Two</p>')
+  #     #   doc.element_by_id("a").text #=> "One"
+  #     #   doc.element_by_id("b") #=> nil
+  #     #
+  #     # @!method element_by_id(id, null_object: false)
+  #     # @param id [String]
+  #     # @param null_object [Boolean] see {Sawzall::Document#at}
+  #     # @return [Sawzall::Element, Sawzall::NullElement, nil]
+  #
+  #     # Returns the elements whose text matches the given string or regexp,
+  #     # in document order
+  #     #
+  #     # Strings must match the whole text, and both are compared with
+  #     # leading and trailing whitespace removed and other runs of whitespace
+  #     # collapsed to a space. Regexps are matched against that text too.
+  #     #
+  #     # Without a selector, only the innermost matching elements are
+  #     # returned, since the text of their ancestors often matches as well.
+  #     # With one, every element it matches whose text matches is.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <dl>
+  #     #       <dt> ISBN </dt><dd>978-0-00-000000-0</dd>
+  #     #       <dt><b>Pages</b></dt><dd>320</dd>
+  #     #     </dl>
+  #     #   HTML
+  #     #   doc.find_by_text("ISBN").map(&:name) #=> ["dt"]
+  #     #   doc.find_by_text("Pages").map(&:name) #=> ["b"]
+  #     #   doc.find_by_text("Pages", selector: "dt").map(&:name) #=> ["dt"]
+  #     #   doc.find_by_text(/\A\d+\z/).map(&:text) #=> ["320"]
+  #     #
+  #     # @!method find_by_text(pattern, selector: nil)
+  #     # @param pattern [String, Regexp] see {Sawzall::Document#select} for
+  #     #   the regexps that are supported
+  #     # @param selector [String, nil] a CSS selector the elements must match
+  #     # @raise [ArgumentError] if the CSS selector or regexp is invalid
+  #     # @return [Array<Sawzall::Element>]
+  #
+  #     # Returns the Content Security Policies declared with
+  #     # `<meta http-equiv="Content-Security-Policy">`, in document order
+  #     #
+  #     # Each policy maps lowercased directive names to their values. Only the
+  #     # first occurrence of a directive within a policy is kept, as described
+  #     # in the [spec][csp].
+  #     #
+  #     # [csp]: https://w3c.github.io/webappsec-csp/#parse-serialized-policy
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document(<<~HTML)
+  #     #     <meta http-equiv="Content-Security-Policy" content="default-src 'self'; img-src * data:">
+  #     #   HTML
+  #     #   doc.content_security_policies #=> [{"default-src" => ["'self'"], "img-src" => ["*", "data:"]}]
+  #     #
+  #     # @!method content_security_policies
+  #     # @return [Array<Hash{String => Array<String>}>]
+  #
+  #     # Returns the crawler directives declared by the document
+  #     #
+  #     # `meta` contains the directives of `robots`, `googlebot` and
+  #     # `googlebot-news` meta tags keyed by (lowercased) name. Repeated tags
+  #     # with the same name are merged. `links` contains every `<a>` and
+  #     # `<area>` whose `rel` includes `nofollow`, `ugc` or `sponsored`.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document(<<~HTML)
+  #     #     <meta name="robots" content="noindex, max-snippet:50">
+  #     #     <a href="/comments" rel="ugc nofollow">Comments</a>
+  #     #   HTML
+  #     #   directives = doc.robots_directives
+  #     #   directives[:meta]["robots"][:noindex] #=> true
+  #     #   directives[:meta]["robots"][:nofollow] #=> false
+  #     #   directives[:meta]["robots"][:max_snippet] #=> 50
+  #     #   directives[:links] #=> [{href: "/comments", nofollow: true, ugc: true, sponsored: false}]
+  #     #
+  #     # @!method robots_directives
+  #     # @return [Hash{Symbol => Object}]
+  #
+  #     # Combines the document's robots metas and canonical link into a
+  #     # single verdict on whether search engines should index it
+  #     #
+  #     # The `:verdict` is `:noindex` when a `robots` or `googlebot` meta has
+  #     # `noindex` (or `none`), which wins over everything else. Otherwise it
+  #     # is `:canonicalized` when the page's `<link rel="canonical">` points
+  #     # to another URL, and `:indexable` when it doesn't. Like search
+  #     # engines do, canonical links outside of `<head>`, conflicting
+  #     # canonical links and ones that aren't `http` or `https` URLs are
+  #     # ignored.
+  #     #
+  #     # The result also has `:indexable`, `:follow` (whether crawlers may
+  #     # follow the page's links), the `:canonical_url` when it is usable,
+  #     # and the `:reasons` for the verdict. URLs are compared after
+  #     # {Sawzall::URL.normalize}.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document(<<~HTML)
+  #     #     <link rel="canonical" href="/shoes">
+  #     #     <meta name="robots" content="nofollow">
+  #     #   HTML
+  #     #   result = doc.indexability("https://example.com/shoes?utm_source=mail")
+  #     #   result.slice(:indexable, :verdict, :follow) #=> {indexable: false, verdict: :canonicalized, follow: false}
+  #     #   result[:canonical_url] #=> "https://example.com/shoes"
+  #     #   doc.indexability("https://example.com/shoes")[:verdict] #=> :indexable
+  #     #
+  #     # @!method indexability(base_url)
+  #     # @param base_url [String] the absolute URL the document was fetched
+  #     #   from
+  #     # @raise [ArgumentError] if `base_url` isn't a valid absolute URL
+  #     # @return [Hash{Symbol => Object}]
+  #
+  #     # Returns the document's `<img>` elements along with their parsed
+  #     # `srcset` candidates
+  #     #
+  #     # Each candidate has a `:url` and the `:width`, `:height` and
+  #     # `:density` from its descriptors (`nil` when not specified).
+  #     # Candidates with invalid descriptors are skipped, as they are by
+  #     # browsers. For images in a `<picture>`, `:sources` lists the
+  #     # preceding `<source>` elements.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <picture>
+  #     #       <source srcset="hero.avif" type="image/avif">
+  #     #       <img src="hero.jpg" alt="Hero" width="800" srcset="hero-2x.jpg 2x">
+  #     #     </picture>
+  #     #   HTML
+  #     #   image = doc.images.first
+  #     #   image.slice(:src, :alt, :width, :height) #=> {src: "hero.jpg", alt: "Hero", width: 800, height: nil}
+  #     #   image[:srcset] #=> [{url: "hero-2x.jpg", width: nil, height: nil, density: 2.0}]
+  #     #   image[:sources].first[:type] #=> "image/avif"
+  #     #
+  #     # @!method images
+  #     # @return [Array<Hash{Symbol => Object}>]
+  #
+  #     # Returns the document's forms along with their fields
+  #     #
+  #     # Each form has its `:action` (`nil` when missing), along with its
+  #     # `:method` and `:enctype` normalized to their lowercase keywords.
+  #     # Its `:fields` are the buttons, inputs, selects and textareas that
+  #     # belong to it, including ones outside of it that reference it with a
+  #     # `form` attribute.
+  #     #
+  #     # Each field has a `:type` (like the DOM's `type` property, e.g.
+  #     # `"checkbox"` or `"select-one"`), a `:name`, its default `:value`
+  #     # (`nil` for selects), whether it is `:checked` and `:disabled`, and
+  #     # the `:options` of selects. Options are `:selected` as they would be
+  #     # in a browser, where a single select without a selected option picks
+  #     # its first enabled one.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <form action="/search" method="POST">
+  #     #       <input name="q" value="rust">
+  #     #       <select name="sort"><option>relevance</option><option value="new">newest</option></select>
+  #     #     </form>
+  #     #   HTML
+  #     #   form = doc.forms.first
+  #     #   form.slice(:action, :method) #=> {action: "/search", method: "post"}
+  #     #   form[:fields].map { it.slice(:type, :name, :value) } #=> [{type: "text", name: "q", value: "rust"}, {type: "select-one", name: "sort", value: nil}]
+  #     #   form[:fields].last[:options].map { it.slice(:value, :selected) } #=> [{value: "relevance", selected: true}, {value: "new", selected: false}]
+  #     #
+  #     # @!method forms
+  #     # @return [Array<Hash{Symbol => Object}>]
+  #
+  #     # Returns the document's `<h1>` to `<h6>` headings as a tree, e.g. to
+  #     # build a table of contents
+  #     #
+  #     # Each heading has its `:level`, `:text` (see {Element#text}), `:id`
+  #     # and `:children`, which are the headings that follow it until the next
+  #     # one with the same or a lower level. The `:id` is the heading's own
+  #     # `id`, or the `id` or `name` of an anchor inside it, and `nil` without
+  #     # either.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <h1 id="guide">Guide</h1>
+  #     #     <h2 id="install">Install</h2>
+  #     #     <h2><a name="usage"></a>Usage</h2>
+  #     #   HTML
+  #     #   guide = doc.outline.first
+  #     #   guide.slice(:level, :text, :id) #=> {level: 1, text: "Guide", id: "guide"}
+  #     #   guide[:children].map { it[:id] } #=> ["install", "usage"]
+  #     #
+  #     # @!method outline
+  #     # @return [Array<Hash{Symbol => Object}>]
+  #
+  #     # Guesses what kind of page the document is from its structure
+  #     #
+  #     # The `:type` is one of `:error`, `:login`, `:product`, `:article` and
+  #     # `:listing`, or `:unknown` when nothing points clearly to any of them.
+  #     # It's the type with the highest score, ties going to the earlier one
+  #     # in that list. The `:evidence` lists each signal that was found with
+  #     # the `:type` it points to, its `:weight` and the `:reason`, and
+  #     # `:scores` adds up the weights for each type.
+  #     #
+  #     # The signals are heuristics, e.g. a password field, Open Graph and
+  #     # schema.org types, "add to cart" buttons, long paragraphs or repeated
+  #     # items with links, so treat the result as a hint.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document(<<~HTML)
+  #     #     <title>Sign in</title>
+  #     #     <form><input name="email"><input type="password"></form>
+  #     #   HTML
+  #     #   result = doc.probable_type
+  #     #   result[:type] #=> :login
+  #     #   result[:evidence].first #=> {type: :login, weight: 3, reason: "has a password field"}
+  #     #   result[:scores][:login] #=> 4
+  #     #
+  #     # @!method probable_type
+  #     # @return [Hash{Symbol => Object}]
+  #
+  #     # Splits the text of the document's content into chunks of at most
+  #     # `max_tokens` tokens, e.g. to embed them for retrieval
+  #     #
+  #     # The text is split into paragraphs as with {Element#paragraphs}, which
+  #     # are kept whole when they fit and separated by blank lines. With
+  #     # `by: :headings` each heading starts a new chunk, so a chunk only
+  #     # covers one section, while `by: :paragraphs` fills chunks regardless
+  #     # of headings. Tokens are approximated as words, i.e. runs of
+  #     # non-whitespace characters.
+  #     #
+  #     # Each chunk has its `:text`, its number of `:tokens` and the `:paths`
+  #     # of the elements the text comes from, as CSS selectors that can be
+  #     # passed to {#select}.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document(<<~HTML)
+  #     #     <h1>Install</h1>
+  #     #     <p>Run the installer.</p>
+  #     #     <h1>Usage</h1>
+  #     #     <p>Call the command with a file.</p>
+  #     #   HTML
+  #     #   chunks = doc.chunks(max_tokens: 50)
+  #     #   chunks.map { it[:text] } #=> ["Install\n\nRun the installer.", "Usage\n\nCall the command with a file."]
+  #     #   chunks.first[:paths] #=> ["html > body > h1:nth-of-type(1)", "html > body > p:nth-of-type(1)"]
+  #     #   doc.chunks(max_tokens: 3, overlap: 1).map { it[:text] } #=> ["Install", "Run the installer.", "Usage", "Call the command", "command with a", "a file."]
+  #     #
+  #     # @!method chunks(max_tokens:, overlap: 0, by: :headings)
+  #     # @param max_tokens [Integer] the most tokens in a chunk
+  #     # @param overlap [Integer] how many tokens from the end of a chunk to
+  #     #   repeat at the start of the next one, unless a heading starts it
+  #     # @param by [Symbol] `:headings` or `:paragraphs`
+  #     # @raise [ArgumentError] if `max_tokens` isn't positive, `overlap`
+  #     #   isn't less than `max_tokens` or `by` is unknown
+  #     # @return [Array<Hash{Symbol => Object}>]
+  #
+  #     # Removes whole categories of nodes from the document in a single pass
+  #     #
+  #     # - `:scripts` removes `<script>` and `<noscript>` elements
+  #     # - `:styles` removes `<style>` elements and stylesheet `<link>` elements
+  #     # - `:comments` removes comments
+  #     # - `:forms` removes `<form>` elements
+  #     #
+  #     # All categories are removed when none are given. Elements previously
+  #     # obtained from removed subtrees are no longer part of the document.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <style>p { color: red }</style>
+  #     #     <p>Hello<!-- greeting --></p>
+  #     #     <script>track()</script>
+  #     #   HTML
+  #     #   doc.strip!(:scripts, :styles, :comments).root_element.inner_html #=> "\n<p>Hello</p>\n\n"
+  #     #
+  #     # @!method strip!(*categories)
+  #     # @param categories [Array<Symbol>] any of `:scripts`, `:styles`, `:comments` and `:forms`
+  #     # @raise [ArgumentError] if a category is unknown
+  #     # @return [self]
+  #
+  #     # Removes every element {#select} returns for the given CSS selector,
+  #     # along with its descendants, locking the document once rather than
+  #     # once per element like calling {Element#remove} on each match.
+  #     # Elements inside other matches stay in them.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <p>Article</p>
+  #     #     <div class="ad-slot">Ad</div>
+  #     #   HTML
+  #     #   doc.remove!(".ad-slot").map(&:text) #=> ["Ad"]
+  #     #   doc.to_html.strip #=> "<p>Article</p>"
+  #     #
+  #     # @!method remove!(css_selector)
+  #     # @param css_selector [String]
+  #     # @raise [ArgumentError] if the CSS selector is invalid
+  #     # @return [Array<Sawzall::Element>] the removed elements
+  #
+  #     # Moves the rules of the document's `<style>` elements into the `style`
+  #     # attribute of the elements they match, as is commonly done for HTML
+  #     # emails
+  #     #
+  #     # Declarations are merged following the cascade, so existing inline
+  #     # styles and `!important` declarations take precedence. Rules that
+  #     # can't be inlined (like `@media` queries and `:hover` selectors) are
+  #     # left in their `<style>` element, which is removed once empty.
+  #     # `<style>` elements for media other than `all` or `screen` are left
+  #     # untouched.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <style>p { color: red; margin: 0 } a:hover { color: blue }</style>
+  #     #     <p style="color: green">Hello</p>
+  #     #   HTML
+  #     #   doc.inline_css!.to_html #=> "<style>a:hover { color: blue }\n</style>\n<p style=\"color: green; margin: 0\">Hello</p>\n"
+  #     #
+  #     # @!method inline_css!
+  #     # @return [self]
+  #
+  #     # Rewrites relative URLs in attributes such as `href`, `src`, `srcset`
+  #     # and `poster` into absolute URLs
+  #     #
+  #     # URLs are resolved against the document's `<base href>` when it has
+  #     # one, and against `base_url` otherwise. Values that can't be resolved
+  #     # are left as is.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment(<<~HTML)
+  #     #     <a href="../about">About</a>
+  #     #     <img src="/logo.png" srcset="logo-2x.png 2x">
+  #     #   HTML
+  #     #   doc.absolutize_urls!("https://example.com/blog/feed.xml")
+  #     #   doc.select("a").first.attr("href") #=> "https://example.com/about"
+  #     #   doc.select("img").first.attrs #=> [["src", "https://example.com/logo.png"], ["srcset", "https://example.com/blog/logo-2x.png 2x"]]
+  #     #
+  #     # @!method absolutize_urls!(base_url)
+  #     # @param base_url [String] an absolute URL
+  #     # @raise [ArgumentError] if `base_url` isn't a valid absolute URL
+  #     # @return [self]
+  #
+  #     # Returns the URL that relative URLs in the document resolve against:
+  #     # the first `<base href>` resolved against `document_url`, or
+  #     # `document_url` itself when the document has no (valid) base element
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document("<head><base href='/docs/'></head>")
+  #     #   doc.base_uri("https://example.com/blog/post") #=> "https://example.com/docs/"
+  #     #   doc.base_uri #=> nil
+  #     #   Sawzall.parse_document("<p>No base</p>").base_uri("https://example.com/") #=> "https://example.com/"
+  #     #
+  #     # @!method base_uri(document_url = nil)
+  #     # @param document_url [String, nil] the absolute URL the document was fetched from
+  #     # @raise [ArgumentError] if `document_url` isn't a valid absolute URL
+  #     # @return [String, nil] `nil` if the base URL can't be determined
+  #
+  #     # Returns the document's `<!DOCTYPE>` as its `:name`, `:public_id` and
+  #     # `:system_id`, each `nil` when missing
+  #     #
+  #     # HTML5 documents have an `"html"` doctype without identifiers, while
+  #     # legacy ones such as HTML 4.01 Transitional have a public identifier.
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_document("<!DOCTYPE html><p>Hi</p>").doctype #=> {name: "html", public_id: nil, system_id: nil}
+  #     #   legacy = Sawzall.parse_document(<<~HTML)
+  #     #     <!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01 Transitional//EN" "http://www.w3.org/TR/html4/loose.dtd">
+  #     #   HTML
+  #     #   legacy.doctype[:public_id] #=> "-//W3C//DTD HTML 4.01 Transitional//EN"
+  #     #   Sawzall.parse_document("<p>Hi</p>").doctype #=> nil
+  #     #
+  #     # @!method doctype
+  #     # @return [Hash{Symbol => String, nil}, nil] `nil` without a doctype, e.g. for fragments
+  #
+  #     # Returns the name of the encoding the document was sniffed to be in
+  #     # when parsed with `sniff_encoding: true`, as in the Encoding Standard,
+  #     # or `nil` when the encoding of the string was trusted instead
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_document("<meta charset=shift_jis>".b, sniff_encoding: true).encoding #=> "Shift_JIS"
+  #     #   Sawzall.parse_document("<meta charset=shift_jis>").encoding #=> nil
+  #     #
+  #     # @!method encoding
+  #     # @return [String, nil]
+  #
+  #     # Returns the mode the parser picked from the doctype, which browsers
+  #     # use to decide whether to emulate legacy layout bugs
+  #     #
+  #     # Documents without a doctype or with an obsolete one are in `:quirks`
+  #     # mode, while some legacy transitional doctypes get
+  #     # `:limited_quirks`. Fragments are always in `:no_quirks` mode.
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_document("<!DOCTYPE html><p>Hi</p>").quirks_mode #=> :no_quirks
+  #     #   Sawzall.parse_document("<p>Hi</p>").quirks_mode #=> :quirks
+  #     #   legacy = Sawzall.parse_document(<<~HTML)
+  #     #     <!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01 Transitional//EN" "http://www.w3.org/TR/html4/loose.dtd">
+  #     #   HTML
+  #     #   legacy.quirks_mode #=> :limited_quirks
+  #     #
+  #     # @!method quirks_mode
+  #     # @return [Symbol] `:no_quirks`, `:limited_quirks` or `:quirks`
+  #
+  #     # Whether the document is in full quirks mode, see {#quirks_mode}
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_document("<p>Hi</p>").quirks_mode? #=> true
+  #     #
+  #     # @!method quirks_mode?
+  #     # @return [Boolean]
+  #
+  #     # Returns every comment in the document, in document order, including
+  #     # ones outside of the `<html>` element
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document("<!-- generated --><p>Text<!-- {\"id\": 1} --></p>")
+  #     #   doc.comments.map(&:text) #=> [" generated ", " {\"id\": 1} "]
+  #     #
+  #     # @!method comments
+  #     # @return [Array<Sawzall::Comment>]
+  #
+  #     # Yields every node of the document in document order, including
+  #     # the doctype, text nodes and comments. Returns an `Enumerator`
+  #     # without a block.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document("<!DOCTYPE html><title>Title</title>")
+  #     #   doc.each_node.map(&:class) #=> [Sawzall::Doctype, Sawzall::Element, Sawzall::Element, Sawzall::Element, Sawzall::TextNode, Sawzall::Element]
+  #     #   doc.each_node.grep(Sawzall::TextNode).map(&:text) #=> ["Title"]
+  #     #
+  #     # @!method each_node
+  #     # @yieldparam node [Sawzall::Element, Sawzall::TextNode, Sawzall::Comment, Sawzall::Doctype]
+  #     # @return [self, Enumerator]
+  #
+  #     # Yields every element of the document in document order, leaving
+  #     # out the descendants of those for which the block returns
+  #     # `:skip_children`. Elements that are skipped are never wrapped, so
+  #     # pruning large subtrees is cheap. Fragments don't yield the `<html>`
+  #     # element they are wrapped in. Returns an `Enumerator` without a
+  #     # block.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<nav><a>Home</a></nav><main><a>Post</a></main>")
+  #     #   names = []
+  #     #   doc.each_element { |element| names << element.name; :skip_children if element.name == "nav" }
+  #     #   names #=> ["nav", "main", "a"]
+  #     #
+  #     # @!method each_element
+  #     # @yieldparam element [Sawzall::Element]
+  #     # @yieldreturn [:skip_children, Object]
+  #     # @return [self, Enumerator]
+  #
+  #     # Returns how many elements of each name the document contains, in the
+  #     # order the names first appear. Fragments don't count the `<html>`
+  #     # element they are wrapped in.
+  #     #
+  #     # The counts are computed once and reused until the document is
+  #     # modified, so heuristics can call this freely.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul>")
+  #     #   doc.tag_counts #=> {"ul" => 1, "li" => 2}
+  #     #   doc.tag_counts.fetch("li", 0) > 1 #=> true
+  #     #
+  #     # @!method tag_counts
+  #     # @return [Hash{String => Integer}]
+  #
+  #     # Profiles the document in a single pass, e.g. to spot unusual pages in
+  #     # a crawl. Returns the number of nodes of each type, {#tag_counts},
+  #     # how deeply the most nested node is, counted like the `max_depth:`
+  #     # option of {Sawzall.parse_document}, and the `text_length` of the
+  #     # whole document as {Sawzall::Element#text_length} measures it.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<ul><li>One</li><!-- two --></ul>")
+  #     #   doc.stats[:nodes] #=> {element: 2, text: 1, comment: 1, doctype: 0, processing_instruction: 0}
+  #     #   doc.stats.slice(:tags, :max_depth, :text_length) #=> {tags: {"ul" => 1, "li" => 1}, max_depth: 4, text_length: 3}
+  #     #
+  #     # @!method stats
+  #     # @return [Hash{Symbol => Object}]
+  #
+  #     # Returns what the `repair:` option of {Sawzall.parse_document} or
+  #     # {Sawzall.parse_fragment} fixed before parsing the document, e.g. to
+  #     # log it or to retry with a stricter mode when a parse looks wrong
+  #     #
+  #     # @example
+  #     #   html = "<p>One</p><!-- ad slot <p>Two</p>"
+  #     #   Sawzall.parse_fragment(html).select("p").size #=> 1
+  #     #   doc = Sawzall.parse_fragment(html, repair: :conservative)
+  #     #   doc.select("p").size #=> 2
+  #     #   doc.repairs #=> [{type: :unterminated_comments, count: 1}]
+  #     #
+  #     # @!method repairs
+  #     # @return [Array<Hash{Symbol => Object}>] hashes with the `:type` of
+  #     #   repair (`:null_bytes`, `:unterminated_comments` or
+  #     #   `:overlong_attributes`) and how many times it was made (`:count`)
+  #
+  #     # Returns the errors the parser recovered from, e.g. to flag malformed
+  #     # markup in a validation job
+  #     #
+  #     # Each error has a `:message` and the `:line` of the input the parser
+  #     # was at. Errors are often only noticed later on, e.g. a missing end
+  #     # tag when the parent closes, so lines are approximate. Documents not
+  #     # parsed from HTML, like those from {Sawzall.parse_xml}, have none.
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_fragment("<p>Valid</p>").errors #=> []
+  #     #   Sawzall.parse_fragment("<ul>\n  <li a=1 a=2>One</li>\n</p></ul>").errors.map { it[:line] } #=> [2, 3]
+  #     #   Sawzall.parse_document("<p>No doctype").errors #=> [{message: "Unexpected token", line: 1}]
+  #     #
+  #     # @!method errors
+  #     # @return [Array<Hash{Symbol => Object}>]
+  #
+  #     # Returns the elements of the document that have an
+  #     # {Sawzall::Element#annotation}, in document order, with their values.
+  #     # Removed elements are left out.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<p>One</p><p>Two</p><p>Three</p>")
+  #     #   doc.select("p").each_with_index { |p, index| p.annotation = index if index.odd? }
+  #     #   doc.annotations.map { |p, index| [p.text, index] } #=> [["Two", 1]]
+  #     #
+  #     # @!method annotations
+  #     # @return [Hash{Sawzall::Element => Object}]
+  #
+  #     # Serializes the document back to HTML
+  #     #
+  #     # With `pretty: true`, block elements are put on their own lines and
+  #     # indented by `indent` spaces per level, and whitespace around them is
+  #     # dropped. Runs of inline content are kept on one line and the
+  #     # content of whitespace-sensitive elements like `<pre>`, `<textarea>`
+  #     # and `<script>` is written as is.
+  #     #
+  #     # With `minify: true`, runs of whitespace are collapsed to a single
+  #     # space, whitespace around block elements is dropped, as are comments,
+  #     # and attribute values are left unquoted where that's unambiguous.
+  #     # Whitespace-sensitive content is kept as is here too.
+  #     #
+  #     # With `xhtml: true`, the output is well-formed XML that can be
+  #     # embedded in e.g. Atom feeds: void elements are self-closed, HTML
+  #     # names are lowercased, attribute values are always quoted and
+  #     # namespaces are declared where they change, as on `<html>` and
+  #     # `<svg>`. `&nbsp;` is written as `&#160;` and the content of
+  #     # `<script>` and `<style>` is escaped. It can be combined with the
+  #     # other options.
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_fragment("<p>Hello<br>world").to_html #=> "<p>Hello<br>world</p>"
+  #     #   Sawzall.parse_document("<title>Hi</title>").to_html #=> "<html><head><title>Hi</title></head><body></body></html>"
+  #     #
+  #     # @example Pretty-printing
+  #     #   doc = Sawzall.parse_fragment("<ul><li>One <b>two</b></li><li><pre> three </pre></li></ul>")
+  #     #   doc.to_html(pretty: true) #=> "<ul>\n  <li>One <b>two</b></li>\n  <li>\n    <pre> three </pre>\n  </li>\n</ul>"
+  #     #   doc.to_html(pretty: true, indent: 0) #=> "<ul>\n<li>One <b>two</b></li>\n<li>\n<pre> three </pre>\n</li>\n</ul>"
+  #     #
+  #     # @example Minifying
+  #     #   doc = Sawzall.parse_fragment("<ul>\n  <!-- Items -->\n  <li class=\"item\">One  <b>two</b></li>\n</ul>")
+  #     #   doc.to_html(minify: true) #=> "<ul><li class=item>One <b>two</b></li></ul>"
+  #     #
+  #     # @example XHTML
+  #     #   Sawzall.parse_fragment("<p>One<br>two&nbsp;<input disabled>").to_html(xhtml: true) #=> "<p>One<br />two&#160;<input disabled=\"\" /></p>"
+  #     #
+  #     # @!method to_html(pretty: false, indent: 2, minify: false, xhtml: false)
+  #     # @param pretty [Boolean]
+  #     # @param indent [Integer] the number of spaces per level when pretty
+  #     # @param minify [Boolean] can't be combined with `pretty`
+  #     # @param xhtml [Boolean]
+  #     # @return [String]
+  #     # @raise [ArgumentError] if both `pretty` and `minify` are set
+  #
+  #     # Returns the {#root_element} as nested hashes, see
+  #     # {Sawzall::Element#to_h}
+  #     #
+  #     # @!method to_h
+  #     # @return [Hash{Symbol => Object}]
+  #
+  #     # Returns the {#root_element} as JSON, see {Sawzall::Element#to_json}
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_document("<title>Hi</title>").to_json #=> "{\"name\":\"html\",\"attrs\":{},\"children\":[{\"name\":\"head\",\"attrs\":{},\"children\":[{\"name\":\"title\",\"attrs\":{},\"children\":[{\"text\":\"Hi\"}]}]},{\"name\":\"body\",\"attrs\":{},\"children\":[]}]}"
+  #     #
+  #     # @!method to_json(*)
+  #     # @return [String]
+  #
+  #     # Returns a deep copy of the document, so that changing one doesn't
+  #     # affect the other, e.g. to rewrite a template parsed once in several
+  #     # jobs
+  #     #
+  #     # Elements keep their {Sawzall::Element#node_id} in the copy, and
+  #     # {Sawzall::Element#annotation}s are copied shallowly like the values
+  #     # of `Hash#dup`.
+  #     #
+  #     # @example
+  #     #   template = Sawzall.parse_fragment("<h1>Title</h1>")
+  #     #   page = template.dup
+  #     #   page.at("h1").inner_html = "Hello"
+  #     #   [template.to_html, page.to_html] #=> ["<h1>Title</h1>", "<h1>Hello</h1>"]
+  #     #
+  #     # @!method dup
+  #     # @return [Sawzall::Document]
+  #
+  #     # Same as {#dup}, freezing the copy with `freeze: true`
+  #     #
+  #     # @!method clone(freeze: false)
+  #     # @return [Sawzall::Document]
+  #
+  #     # Freezes the document along with its tree, so that neither it nor its
+  #     # nodes can modify it anymore and raise a `FrozenError` instead
+  #     #
+  #     # Frozen documents can be shared with other Ractors, e.g. to extract
+  #     # data from one document in parallel. Use {#dup} to get a copy that
+  #     # can be modified again. Other Ractors can parse documents and call
+  #     # the methods of documents and their nodes, but not configure Sawzall
+  #     # or use {Sawzall.parse_async}, {Sawzall::ParseBatch},
+  #     # {Sawzall::StreamingParser}, {Sawzall::Sanitizer} or
+  #     # {Sawzall::Selector}.
+  #     #
+  #     # @example
+  #     #   doc = Ractor.make_shareable(Sawzall.parse_fragment("<p>One</p><p>Two</p>"))
+  #     #   Ractor.new(doc) { it.select("p").map(&:text) }.take #=> ["One", "Two"]
+  #     #
+  #     # @!method freeze
+  #     # @return [Sawzall::Document]
+  #
+  #     # Serializes the document for `Marshal`, e.g. to store it in a cache,
+  #     # as its HTML along with whether it was parsed as a fragment or a full
+  #     # document. Loading it parses the HTML again, so elements of the
+  #     # original document don't belong to the copy, and neither {#repairs},
+  #     # {#errors} nor {Sawzall::Element#annotation}s are kept.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<p>Hello<br>world")
+  #     #   Marshal.load(Marshal.dump(doc)).to_html #=> "<p>Hello<br>world</p>"
+  #     #
+  #     # @!method _dump(level)
+  #     # @param level [Integer] ignored
+  #     # @return [String]
+  #     # @raise [TypeError] for documents parsed with {Sawzall.parse_xml},
+  #     #   which can't be serialized as HTML without losing namespace prefixes
+  #
+  #     # Parses a document serialized with {#_dump}
+  #     #
+  #     # @!method self._load(data)
+  #     # @param data [String]
+  #     # @return [Sawzall::Document]
+  #     # @raise [ArgumentError] if `data` wasn't returned by {#_dump}
+  #
+  #     # Creates an element that belongs to the document without being part
+  #     # of it yet, so that it can be built up and then inserted with
+  #     # {Sawzall::Element#add_child}
+  #     #
+  #     # The name and attribute names are lowercased like when parsing.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<nav></nav>")
+  #     #   link = doc.create_element("a", href: "/", class: "home")
+  #     #   link.add_child("Home")
+  #     #   doc.at("nav").add_child(link)
+  #     #   doc.to_html #=> "<nav><a class=\"home\" href=\"/\">Home</a></nav>"
+  #     #
+  #     # @!method create_element(name, attrs = {})
+  #     # @param name [String]
+  #     # @param attrs [Hash{String, Symbol => String}]
+  #     # @return [Sawzall::Element]
+  #     # @raise [ArgumentError] if the element or an attribute name is invalid
+  #
+  #     # Creates a text node that belongs to the document without being part
+  #     # of it yet, to be inserted with {Sawzall::Element#add_child} and
+  #     # similar methods. The text is kept as is, and escaped when serialized.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<p>World</p>")
+  #     #   doc.at("p").insert_child(0, doc.create_text_node("Hello <3 "))
+  #     #   doc.to_html #=> "<p>Hello &lt;3 World</p>"
+  #     #
+  #     # @!method create_text_node(text)
+  #     # @param text [String]
+  #     # @return [Sawzall::TextNode]
+  #
+  #     # Returns a new fragment with copies of this document's content
+  #     # followed by `other`, see {Sawzall.join}
+  #     #
+  #     # @example
+  #     #   header = Sawzall.parse_fragment("<h1>Title</h1>")
+  #     #   (header + "<script>").to_html #=> "<h1>Title</h1>&lt;script&gt;"
+  #     #
+  #     # @!method +(other)
+  #     # @param other [Sawzall::Document, Sawzall::Element, String]
+  #     # @return [Sawzall::Document]
+  #
+  #     # Returns whether this document has the same content as `other`,
+  #     # for asserting on generated HTML without depending on how it's
+  #     # formatted. Use {Sawzall.diff} to find out what differs.
+  #     #
+  #     # With `ignore_whitespace`, text is compared with runs of whitespace
+  #     # collapsed and trimmed, and text made only of whitespace is left
+  #     # out. With `ignore_comments`, comments are left out and the text
+  #     # around them is joined. Attributes are kept sorted by name, so their
+  #     # order never matters and `ignore_attr_order: false` raises an
+  #     # `ArgumentError` since the order they were written in is lost.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment('<ul class="nav" id="menu"><li>Home</li></ul>')
+  #     #   doc.equivalent_to?(Sawzall.parse_fragment("<ul id=menu class=nav>\n  <li> Home </li> <!-- todo -->\n</ul>\n")) #=> true
+  #     #   doc.equivalent_to?(Sawzall.parse_fragment("<ul class=nav id=menu>\n  <li>Home</li>\n</ul>"), ignore_whitespace: false) #=> false
+  #     #
+  #     # @!method equivalent_to?(other, ignore_whitespace: true, ignore_attr_order: true, ignore_comments: true)
+  #     # @param other [Sawzall::Document]
+  #     # @param ignore_whitespace [Boolean]
+  #     # @param ignore_attr_order [Boolean]
+  #     # @param ignore_comments [Boolean]
+  #     # @return [Boolean]
+  #
+  #     # @!group Nokogiri compatibility
+  #
+  #     # These methods have the names and return values of their Nokogiri
+  #     # counterparts so that code written against Nokogiri can switch over
+  #     # with few changes. Node sets are plain arrays.
+  #
+  #     # Same as {#select}
+  #     #
+  #     # @!method css(css_selector, detach: false, namespaces: nil)
+  #     # @return [Array<Sawzall::Element>]
+  #
+  #     # Same as {#at}
+  #     #
+  #     # @!method at_css(css_selector, null_object: false, namespaces: nil)
+  #     # @return [Sawzall::Element, Sawzall::NullElement, nil]
+  #
+  #     # Returns the top-level nodes of a fragment, or the doctype and
+  #     # `<html>` element of a document
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_fragment("<p>One</p> two").children.map(&:class) #=> [Sawzall::Element, Sawzall::TextNode]
+  #     #   Sawzall.parse_document("<!DOCTYPE html><p>One</p>").children.map(&:class) #=> [Sawzall::Doctype, Sawzall::Element]
+  #     #
+  #     # @!method children
+  #     # @return [Array<Sawzall::Element, Sawzall::TextNode, Sawzall::Comment, Sawzall::Doctype>]
+  #
+  #     # Returns the text of every text node in the document, concatenated
+  #     # as is, unlike {Sawzall::Element#text}
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_fragment("<p>One</p>\n<p>Two</p>").content #=> "One\nTwo"
+  #     #
+  #     # @!method content
+  #     # @return [String]
+  #
+  #     # @!endgroup
+  #   end
+
+  # @!parse
+  #   class Sanitizer
+  #     # Creates a sanitizer that only keeps the given elements and attributes
+  #     #
+  #     # Disallowed elements are replaced by their children, except for
+  #     # elements like `<script>` or `<style>` which are removed along with
+  #     # their contents. Comments are always removed.
+  #     #
+  #     # URL attributes (e.g. `href` and `src`) are removed if they contain an
+  #     # absolute URL that doesn't use one of the allowed protocols.
+  #     #
+  #     # @example
+  #     #   sanitizer = Sawzall::Sanitizer.new(
+  #     #     elements: ["p", "a"],
+  #     #     attributes: {"a" => ["href"], :all => ["title"]},
+  #     #     protocols: ["https"]
+  #     #   )
+  #     #   html = sanitizer.sanitize("<p title='t' class='c'><a href='https://example.com'>ok</a></p>")
+  #     #   html #=> "<p title=\"t\"><a href=\"https://example.com\">ok</a></p>"
+  #     #   html = sanitizer.sanitize("<a href='javascript:alert(1)'>bad</a><script>alert(2)</script>")
+  #     #   html #=> "<a>bad</a>"
+  #     #
+  #     # @!method self.new(elements: [], attributes: {}, protocols: ["http", "https", "mailto"])
+  #     # @param elements [Array<String>] names of the elements to keep
+  #     # @param attributes [Hash{String, Symbol => Array<String>}]
+  #     #   names of the attributes to keep, keyed by element name. Attributes
+  #     #   listed under `:all` are kept on every allowed element.
+  #     # @param protocols [Array<String>] protocols allowed in URL attributes
+  #     # @return [Sawzall::Sanitizer]
+  #
+  #     # Parses the given string as an HTML fragment and returns it with
+  #     # everything the sanitizer doesn't allow removed
+  #     #
+  #     # @example
+  #     #   sanitizer = Sawzall::Sanitizer.new(elements: ["b"])
+  #     #   sanitizer.sanitize("<b onclick='alert(1)'>bold</b> <i>italic</i>") #=> "<b>bold</b> italic"
+  #     #
+  #     # @!method sanitize(html)
+  #     # @param html [String]
+  #     # @return [String]
+  #   end
+
+  # @!parse
+  #   # A CSS selector that was checked to be valid, e.g. when loading
+  #   # user-supplied scraping rules rather than when they're first used
+  #   #
+  #   # Selectors convert implicitly to strings, so they can be passed to
+  #   # {Sawzall::Document#select} and friends.
+  #   class Selector
+  #     # Checks that the given string is a valid CSS selector
+  #     #
+  #     # @example
+  #     #   Sawzall::Selector.parse("p.intro").to_s #=> "p.intro"
+  #     #   Sawzall::Selector.parse("p.") rescue $!.class #=> ArgumentError
+  #     #
+  #     # @!method self.parse(css_selector)
+  #     # @param css_selector [String]
+  #     # @raise [ArgumentError] if the CSS selector is invalid
+  #     # @return [Sawzall::Selector]
+  #
+  #     # @!method to_s
+  #     # @return [String] the selector as it was given
+  #   end
+
+  # @!parse
+  #   # Parses many documents in a row, sizing each document's allocations
+  #   # after the previous one
+  #   #
+  #   # Reusing a pool in hot loops that parse thousands of similar fragments
+  #   # (e.g. feed entries) avoids repeatedly growing each document as it is
+  #   # parsed. Documents don't depend on the pool that parsed them.
+  #   class Pool
+  #     # Creates an empty pool
+  #     #
+  #     # @!method self.new
+  #     # @return [Sawzall::Pool]
+  #
+  #     # Parses the given string like {Sawzall.parse_document}, or like
+  #     # {Sawzall.parse_fragment} with `fragment: true`
+  #     #
+  #     # @example
+  #     #   pool = Sawzall::Pool.new
+  #     #   entries = ["<p>One</p>", "<p>Two</p>"].map { pool.parse(it, fragment: true) }
+  #     #   entries.map { it.select("p").first.text } #=> ["One", "Two"]
+  #     #
+  #     # @!method parse(html, fragment: false, weak_elements: false, strict_encoding: false)
+  #     # @param html [String]
+  #     # @param fragment [Boolean]
+  #     # @param weak_elements [Boolean] see {Sawzall.parse_fragment}
+  #     # @param strict_encoding [Boolean] see {Sawzall.parse_fragment}
+  #     # @return [Sawzall::Document]
+  #   end
+
+  # @!parse
+  #   # Parses a document that arrives in chunks, e.g. the body of a streamed
+  #   # HTTP response, without first buffering it in a string
+  #   #
+  #   # Chunks are read as UTF-8 whatever their encoding, and may split
+  #   # characters. Raising an error, e.g. a {Sawzall::ParseLimitError}, ends
+  #   # the parse.
+  #   #
+  #   # @example
+  #   #   parser = Sawzall::StreamingParser.new
+  #   #   parser << "<title>Str" << "eamed</title>"
+  #   #   parser.finish.at("title").text #=> "Streamed"
+  #   class StreamingParser
+  #     # Creates a parser for a document, or a fragment with `fragment: true`
+  #     #
+  #     # @!method self.new(fragment: false, weak_elements: false, strict_encoding: false, invalid: nil, max_input_bytes: nil, max_depth: nil, max_nodes: nil)
+  #     # @param fragment [Boolean]
+  #     # @param weak_elements [Boolean] see {Sawzall.parse_fragment}
+  #     # @param strict_encoding [Boolean] see {Sawzall.parse_fragment}
+  #     # @param invalid [Symbol, nil] see {Sawzall.parse_fragment}
+  #     # @param max_input_bytes [Integer, nil] see {Sawzall.parse_fragment}
+  #     # @param max_depth [Integer, nil] see {Sawzall.parse_fragment}
+  #     # @param max_nodes [Integer, nil] see {Sawzall.parse_fragment}
+  #     # @return [Sawzall::StreamingParser]
+  #
+  #     # Parses the next chunk
+  #     #
+  #     # @!method <<(chunk)
+  #     # @param chunk [String]
+  #     # @raise [Sawzall::InvalidEncodingError, Sawzall::ParseLimitError]
+  #     # @raise [RuntimeError] if the parser already finished or failed
+  #     # @return [Sawzall::StreamingParser]
+  #
+  #     # Parses what's left and returns the document
+  #     #
+  #     # @!method finish
+  #     # @raise [Sawzall::InvalidEncodingError, Sawzall::ParseLimitError]
+  #     # @raise [RuntimeError] if the parser already finished or failed
+  #     # @return [Sawzall::Document]
+  #   end
+
+  # @!parse
+  #   # URL helpers shared with the link APIs, so that URLs extracted from
+  #   # documents can be compared with the same rules
+  #   module URL
+  #     # Normalizes an absolute URL so that equivalent URLs compare equal,
+  #     # e.g. to deduplicate links or sitemap entries
+  #     #
+  #     # The scheme and host are lowercased, default ports are removed, and
+  #     # so are the fragment and an empty query. Query parameters are left
+  #     # encoded as they are.
+  #     #
+  #     # @example
+  #     #   Sawzall::URL.normalize("HTTPS://Example.com:443/a?b=2&a=1#top") #=> "https://example.com/a?b=2&a=1"
+  #     #   Sawzall::URL.normalize("https://example.com/a?b=2&a=1", sort_query: true) #=> "https://example.com/a?a=1&b=2"
+  #     #
+  #     # @!method self.normalize(url, sort_query: false)
+  #     # @param url [String]
+  #     # @param sort_query [Boolean] whether to sort query parameters by
+  #     #   name, keeping repeated parameters in their original order
+  #     # @raise [ArgumentError] if `url` isn't a valid absolute URL
+  #     # @return [String]
+  #   end
+
+  # @!parse
+  #   # A text node, as returned by {Sawzall::Element#child_nodes} or created
+  #   # with {Sawzall::Document#create_text_node}
+  #   class TextNode
+  #     # Returns the node's text
+  #     #
+  #     # @!method text
+  #     # @return [String]
+  #
+  #     # Returns the node's text, like {#text}
+  #     #
+  #     # @!method content
+  #     # @return [String]
+  #
+  #     # Replaces the node's text in place, leaving the surrounding markup
+  #     # untouched. The text is escaped when serialized.
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<p>Call <b>now</b> 555-0100</p>")
+  #     #   doc.at("p").child_nodes.grep(Sawzall::TextNode).each do |node|
+  #     #     node.content = node.content.gsub(/\d{3}-\d{4}/, "<redacted>")
+  #     #   end
+  #     #   doc.to_html #=> "<p>Call <b>now</b> &lt;redacted&gt;</p>"
+  #     #
+  #     # @!method content=(text)
+  #     # @param text [String]
+  #     # @return [String]
+  #     # @raise [ArgumentError] if the node is in a raw text element like
+  #     #   `<script>` and the text would close it, see
+  #     #   {Sawzall::Element#inner_html=}
+  #
+  #     # Returns the node's text escaped like when serializing the document
+  #     #
+  #     # @example
+  #     #   Sawzall.parse_fragment("").create_text_node("Fish & chips").html #=> "Fish &amp; chips"
+  #     #
+  #     # @!method html
+  #     # @return [String]
+  #   end
+
+  # @!parse
+  #   # A comment, as returned by {Sawzall::Document#comments},
+  #   # {Sawzall::Element#child_nodes} or `each_node`
+  #   class Comment
+  #     # Returns the comment's text, without the `<!--` and `-->` delimiters
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_fragment("<p>Story</p><!-- ad:slot=top -->")
+  #     #   doc.comments.first.text #=> " ad:slot=top "
+  #     #
+  #     # @!method text
+  #     # @return [String]
+  #
+  #     # Returns the comment's text, like {#text}
+  #     #
+  #     # @!method content
+  #     # @return [String]
+  #
+  #     # Returns the comment as HTML
+  #     #
+  #     # @!method html
+  #     # @return [String]
+  #   end
+
+  # @!parse
+  #   # A document's `<!DOCTYPE>`, as yielded by
+  #   # {Sawzall::Document#each_node}. See {Sawzall::Document#doctype} to
+  #   # read it directly.
+  #   class Doctype
+  #     # Returns the doctype's name, e.g. `"html"`, as a frozen string shared
+  #     # by every call
+  #     #
+  #     # @example
+  #     #   doc = Sawzall.parse_document("<!DOCTYPE html><p>Text</p>")
+  #     #   doc.each_node.first.name #=> "html"
+  #     #
+  #     # @!method name
+  #     # @return [String]
+  #
+  #     # Returns the doctype's public identifier, which legacy doctypes have
+  #     #
+  #     # @!method public_id
+  #     # @return [String, nil]
+  #
+  #     # Returns the doctype's system identifier
+  #     #
+  #     # @!method system_id
+  #     # @return [String, nil]
+  #
+  #     # Returns the doctype as HTML, without its identifiers as when
+  #     # serializing the document
+  #     #
+  #     # @!method html
+  #     # @return [String]
+  #   end
+
+  # Stands in for a missing element when passing `null_object: true` to
+  # single-result accessors like {Sawzall::Document#at}, so that extraction
+  # code can keep chaining calls. Queries return empty results.
+  #
+  # @example
+  #   doc = Sawzall.parse_fragment("<p>Text</p>")
+  #   title = doc.at("h1", null_object: true)
+  #   title.text #=> ""
+  #   title.attr("id") #=> nil
+  #   title.select("a") #=> []
+  class NullElement
+    # @return [nil]
+    def name(**)
+      nil
+    end
+
+    # @return [nil]
+    def namespace
+      nil
+    end
+
+    # @return [String]
+    def text(**)
+      ""
+    end
+
+    # @return [String]
+    def own_text
+      ""
+    end
+
+    # @return [Array]
+    def paragraphs
+      []
+    end
+
+    # @return [Integer]
+    def text_length
+      0
+    end
+
+    # @return [Float]
+    def link_density
+      0.0
+    end
+
+    # @return [String]
+    def html(**)
+      ""
+    end
+
+    # @return [String]
+    def inner_html(**)
+      ""
+    end
+
+    # @return [nil]
+    def attr(_name)
+      nil
+    end
+
+    # @return [nil]
+    def id
+      nil
+    end
+
+    # @return [nil]
+    def attr_ns(_namespace, _local_name)
+      nil
+    end
+
+    # @return [nil]
+    def annotation
+      nil
+    end
+
+    # @return [nil]
+    def [](_name)
+      nil
+    end
+
+    # @return [String]
+    def content
+      ""
+    end
+
+    # @return [String]
+    def to_html(**)
+      ""
+    end
+
+    # @return [Array]
+    def attrs(**)
+      []
+    end
+
+    # @return [Hash]
+    def attributes(**)
+      {}
+    end
+
+    # @return [Hash]
+    def data(**)
+      {}
+    end
+
+    # @return [Array]
+    def attr_names(**)
+      []
+    end
+
+    # @return [Array]
+    def classes(**)
+      []
+    end
+
+    # @return [false]
+    def has_class?(_class_name, **)
+      false
+    end
+
+    # @return [Array]
+    def select(_css_selector, **)
+      []
+    end
+
+    # @return [self]
+    def at(_css_selector, **)
+      self
+    end
+
+    # @return [Array]
+    def css(_css_selector, **)
+      []
+    end
+
+    # @return [self]
+    def at_css(_css_selector, **)
+      self
+    end
+
+    # @return [Array]
+    def xpath(_expr)
+      []
+    end
+
+    # @return [Array]
+    def child_elements
+      []
+    end
+
+    # @return [Array]
+    def child_nodes
+      []
+    end
+
+    # @return [Array]
+    def children
+      []
+    end
+
+    # @return [String]
+    def inspect
+      "#<#{self.class.name}>"
+    end
+  end
+
+  class Element
+    # @!group 1) Querying
+
+    # Returns the element's name in lowercase
+    #
+    # The name is a frozen string shared by every call, so reading it in a
+    # loop doesn't allocate. Pass `symbolize_names: true` to get a symbol
+    # instead, like {#name_sym}.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Paragraph</p>")
+    #   doc.select("p").first.name #=> "p"
+    #   doc.select("p").first.name(symbolize_names: true) #=> :p
+    #
+    # @!method name(symbolize_names: false)
+    # @param symbolize_names [Boolean]
+    # @return [String, Symbol]
+
+    # Returns the element's name in lowercase as a symbol, which is handy for
+    # `case` statements
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Paragraph</p>")
+    #   doc.select("p").first.name_sym #=> :p
+    #
+    # @!method name_sym
+    # @return [Symbol]
+
+    # Returns the URI of the element's namespace, which is the HTML one
+    # except for inline SVG and MathML, as a frozen string shared by every
+    # call
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Chart</p><svg><rect/></svg>")
+    #   doc.at("p").namespace #=> "http://www.w3.org/1999/xhtml"
+    #   doc.at("rect").namespace #=> "http://www.w3.org/2000/svg"
+    #
+    # @!method namespace
+    # @return [String, nil] `nil` for elements without a namespace
+
+    # Returns the element's outer HTML
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment(<<~HTML)
+    #     <section>
+    #       <h1>Heading</h1>
+    #     </section>
+    #   HTML
+    #   section = doc.select("section").first
+    #   section.html #=> "<section>\n<h1>Heading</h1>\n</section>"
+    #
+    # @example Limiting the output
+    #   doc = Sawzall.parse_fragment("<p>#{"Lorem ipsum " * 1000}</p>")
+    #   doc.at("p").html(max_output_bytes: 20, truncate: true) #=> "<p>Lorem ipsum Lorem"
+    #   doc.at("p").html(max_nodes: 2).size #=> 12_007
+    #   doc.at("p").html(max_output_bytes: 20) rescue $!.message #=> "output exceeds max_output_bytes (20)"
+    #
+    # @!method html(max_output_bytes: nil, max_nodes: nil, truncate: false)
+    # @param max_output_bytes [Integer, nil] the longest output allowed, in
+    #   bytes. Serialization stops as soon as it is reached, so the full
+    #   output is never built.
+    # @param max_nodes [Integer, nil] the most nodes the element may contain,
+    #   including itself and text nodes
+    # @param truncate [Boolean] whether to cut the output at
+    #   `max_output_bytes` instead of raising. The output is cut between
+    #   characters, which may be in the middle of a tag.
+    # @return [String]
+    # @raise [Sawzall::OutputTooLargeError] if a limit is exceeded
+
+    # Returns the element's inner HTML
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment(<<~HTML)
+    #     <section>
+    #       <h1>Heading</h1>
+    #     </section>
+    #   HTML
+    #   section = doc.select("section").first
+    #   section.inner_html #=> "\n<h1>Heading</h1>\n"
+    #
+    # @!method inner_html(max_output_bytes: nil, max_nodes: nil, truncate: false)
+    # @param max_output_bytes [Integer, nil] see {#html}
+    # @param max_nodes [Integer, nil] see {#html}
+    # @param truncate [Boolean] see {#html}
+    # @return [String]
+    # @raise [Sawzall::OutputTooLargeError] if a limit is exceeded
+
+    # Replaces the element's content with the given HTML, parsed as the
+    # content of this element like the `innerHTML` setter does in browsers
+    #
+    # The content of {Sawzall.raw_text_element? raw text elements} like
+    # `<script>` is therefore text, kept as is. Since their text isn't
+    # escaped when serialized, text that would close them early (e.g.
+    # `</script>` in a script) is rejected rather than producing broken
+    # output.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<table></table><script></script>")
+    #   doc.at("table").inner_html = "<tr><td>One</td></tr>"
+    #   doc.at("script").inner_html = "if (a < b) { go('<b>') }"
+    #   doc.to_html #=> "<table><tbody><tr><td>One</td></tr></tbody></table><script>if (a < b) { go('<b>') }</script>"
+    #   doc.at("script").inner_html = "'</script>'" rescue $!.message #=> "the content of <script> can't contain \"</script\""
+    #
+    # @!method inner_html=(html)
+    # @param html [String]
+    # @return [String]
+    # @raise [ArgumentError] if the text would close a raw text element
+
+    # Returns the given attribute's value or `nil`
+    #
+    # Prefixed attributes are looked up by their name as written, like
+    # `xlink:href` in inline SVG.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<h1 id='title'>Heading</h1>")
+    #   h1 = doc.select("h1").first
+    #   h1.attr("id") #=> "title"
+    #   h1.attr("class") #=> nil
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<svg><use xlink:href='#icon'/></svg>")
+    #   doc.at("use").attr("xlink:href") #=> "#icon"
+    #
+    # @!method attr(attribute)
+    # @param attribute [String]
+    # @return [String, Nil]
+
+    # Returns the element's `id` attribute or `nil`, like `attr("id")`
+    #
+    # Not to be confused with {#node_id}, which identifies the element within
+    # its document whether it has an `id` attribute or not.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<h1 id='title'>Heading</h1><p>Text</p>")
+    #   doc.at("h1").id #=> "title"
+    #   doc.at("p").id #=> nil
+    #
+    # @!method id
+    # @return [String, nil]
+
+    # Returns the value of the attribute with the given local name in the
+    # given namespace, whatever its prefix, or `nil`
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<svg><use xlink:href='#icon'/></svg>")
+    #   use = doc.at("use")
+    #   use.attr_ns("http://www.w3.org/1999/xlink", "href") #=> "#icon"
+    #   use.attr_ns("", "href") #=> nil
+    #
+    # @!method attr_ns(namespace, local_name)
+    # @param namespace [String] the namespace URI, or `""` for attributes
+    #   without a namespace
+    # @param local_name [String]
+    # @return [String, nil]
+
+    # Returns the given attribute's value, or raises a {Sawzall::NotFound}
+    # showing the element if it doesn't have it
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<a class='next'>Next</a>")
+    #   doc.at("a").attr!("class") #=> "next"
+    #   doc.at("a").attr!("href") rescue $!.message #=> "no \"href\" attribute on <a class=\"next\">Next</a>"
+    #
+    # @!method attr!(attribute)
+    # @param attribute [String] see {#attr}
+    # @raise [Sawzall::NotFound] if the element doesn't have the attribute
+    # @return [String]
+
+    # Returns the element's attributes as an array of key-value pairs
+    #
+    # Like {#name}, the keys are frozen strings shared by every call, or
+    # symbols with `symbolize_names: true`. They include prefixes like
+    # {#attr} expects.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<h1 id='title' class='big'>Heading</h1>")
+    #   h1 = doc.select("h1").first
+    #   h1.attrs #=> [["class", "big"], ["id", "title"]]
+    #   h1.attrs(symbolize_names: true) #=> [[:class, "big"], [:id, "title"]]
     #
-    # @!method name
-    # @return [String]
+    # @!method attrs(symbolize_names: false)
+    # @param symbolize_names [Boolean]
+    # @return [Array<Array(String, String)>, Array<Array(Symbol, String)>]
 
-    # Returns the element's outer HTML
+    # Returns the element's attributes as a hash, with the same keys as
+    # {#attrs}
+    #
+    # The parser ignores repeated attributes, keeping the first one, so
+    # keys are unique and this is the same as `attrs.to_h` without building
+    # the intermediate arrays.
     #
     # @example
-    #   doc = Sawzall.parse_fragment(<<~HTML)
-    #     <section>
-    #       <h1>Heading</h1>
-    #     </section>
-    #   HTML
-    #   section = doc.select("section").first
-    #   section.html #=> "<section>\n<h1>Heading</h1>\n</section>"
+    #   doc = Sawzall.parse_fragment("<a href='/one' href='/two' rel=nofollow>One</a>")
+    #   doc.at("a").attributes #=> {"href" => "/one", "rel" => "nofollow"}
+    #   doc.at("a").attributes(symbolize_names: true) #=> {href: "/one", rel: "nofollow"}
     #
-    # @!method html
-    # @return [String]
+    # @!method attributes(symbolize_names: false)
+    # @param symbolize_names [Boolean]
+    # @return [Hash{String => String}, Hash{Symbol => String}]
 
-    # Returns the element's inner HTML
+    # Returns the element's `data-*` attributes as a hash, like the DOM's
+    # `dataset` except that names are underscored rather than camel-cased
     #
     # @example
-    #   doc = Sawzall.parse_fragment(<<~HTML)
-    #     <section>
-    #       <h1>Heading</h1>
-    #     </section>
-    #   HTML
-    #   section = doc.select("section").first
-    #   section.inner_html #=> "\n<h1>Heading</h1>\n"
+    #   doc = Sawzall.parse_fragment("<div data-user-id='42' data-role='admin' id='card'></div>")
+    #   doc.at("div").data #=> {"role" => "admin", "user_id" => "42"}
+    #   doc.at("div").data(symbolize_names: true) #=> {role: "admin", user_id: "42"}
     #
-    # @!method inner_html
-    # @return [String]
+    # @!method data(symbolize_names: false)
+    # @param symbolize_names [Boolean]
+    # @return [Hash{String => String}, Hash{Symbol => String}]
 
-    # Returns the given attribute's value or `nil`
+    # Yields each of the element's attributes as a name and value, without
+    # building the arrays {#attrs} returns. Returns an `Enumerator` without a
+    # block.
     #
     # @example
-    #   doc = Sawzall.parse_fragment("<h1 id='title'>Heading</h1>")
+    #   doc = Sawzall.parse_fragment("<h1 id='title' class='big'>Heading</h1>")
     #   h1 = doc.select("h1").first
-    #   h1.attr("id") #=> "title"
-    #   h1.attr("class") #=> nil
+    #   h1.each_attr.to_h #=> {"class" => "big", "id" => "title"}
     #
-    # @!method attr(attribute)
-    # @param attribute [String]
-    # @return [String, Nil]
+    # @!method each_attr
+    # @yieldparam name [String]
+    # @yieldparam value [String]
+    # @return [self, Enumerator]
 
-    # Returns the element's attributes as an array of key-value pairs
+    # Returns the names of the element's attributes, as frozen strings shared
+    # by every call or as symbols with `symbolize_names: true`
     #
     # @example
     #   doc = Sawzall.parse_fragment("<h1 id='title' class='big'>Heading</h1>")
-    #   h1 = doc.select("h1").first
-    #   h1.attrs #=> [["class", "big"], ["id", "title"]]
+    #   doc.select("h1").first.attr_names #=> ["class", "id"]
+    #   doc.select("h1").first.attr_names(symbolize_names: true) #=> [:class, :id]
     #
-    # @!method attrs
-    # @return [Array<Array(String, String)>]
+    # @!method attr_names(symbolize_names: false)
+    # @param symbolize_names [Boolean]
+    # @return [Array<String>, Array<Symbol>]
 
     # Returns the child elements that match the given CSS selector
     #
     # https://developer.mozilla.org/en-US/docs/Web/CSS/CSS_selectors
     #
+    # Like {Sawzall::Document#select}, elements are returned in document
+    # order and only once.
+    #
     # @example
     #   doc = Sawzall.parse_fragment(<<~HTML)
     #     <div class="container">
@@ -150,11 +2212,57 @@ module Sawzall
     #   matches = container.select("div")
     #   matches.map(&:text) #=> ["inner div 1", "inner div 2"]
     #
-    # @!method select(css_selector)
+    # @!method select(css_selector, detach: false, namespaces: nil, attr_matches: nil)
     # @param css_selector [String]
-    # @raise [ArgumentError] if the CSS selector is invalid
+    # @param detach [Boolean] see {Sawzall::Document#select}
+    # @param namespaces [Hash{String, Symbol => String}, nil] see
+    #   {Sawzall::Document#select}
+    # @param attr_matches [Hash{String, Symbol => Regexp}, nil] see
+    #   {Sawzall::Document#select}
+    # @raise [ArgumentError] if the CSS selector or a regexp is invalid
     # @return [Array<Sawzall::Element>]
 
+    # Returns the first descendant that matches the given CSS selector
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul>")
+    #   doc.at("ul").at("li").text #=> "One"
+    #
+    # @!method at(css_selector, null_object: false, namespaces: nil)
+    # @param css_selector [String]
+    # @param null_object [Boolean] see {Sawzall::Document#at}
+    # @param namespaces [Hash{String, Symbol => String}, nil] see
+    #   {Sawzall::Document#select}
+    # @raise [ArgumentError] if the CSS selector is invalid
+    # @return [Sawzall::Element, Sawzall::NullElement, nil]
+
+    # Returns the first descendant that matches the given CSS selector, or
+    # raises like {Sawzall::Document#at!}
+    #
+    # @!method at!(css_selector)
+    # @param css_selector [String]
+    # @raise [ArgumentError] if the CSS selector is invalid
+    # @raise [Sawzall::NotFound] if nothing matches
+    # @return [Sawzall::Element]
+
+    # Evaluates an XPath 1.0 expression with the element as the context
+    # node, like {Sawzall::Document#xpath}
+    #
+    # Relative paths start from the element, while absolute ones still start
+    # from the document's root.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>One</p><div><p>Two</p></div>")
+    #   div = doc.at("div")
+    #   div.xpath("p").map(&:text) #=> ["Two"]
+    #   div.xpath("//p").map(&:text) #=> ["One", "Two"]
+    #   div.xpath("count(preceding-sibling::p)") #=> 1.0
+    #
+    # @!method xpath(expr)
+    # @param expr [String]
+    # @raise [ArgumentError] if the expression is invalid
+    # @return [Array<Sawzall::Element, Sawzall::TextNode, Sawzall::Comment, String>, Float, String, Boolean]
+
     # Returns the element's child elements
     #
     # @example
@@ -174,9 +2282,79 @@ module Sawzall
     # @!method child_elements
     # @return [Array<Sawzall::Element>]
 
+    # Returns the element's children, including text nodes and comments
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Hello <b>world</b>!</p>")
+    #   doc.at("p").child_nodes.map(&:text) #=> ["Hello ", "world", "!"]
+    #
+    # @!method child_nodes
+    # @return [Array<Sawzall::Element, Sawzall::TextNode, Sawzall::Comment>]
+
+    # Yields the element and every node inside it in document order,
+    # including text nodes and comments. Returns an `Enumerator` without a
+    # block.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Hello <b>world</b><!-- note --></p>")
+    #   doc.at("p").each_node.map(&:class) #=> [Sawzall::Element, Sawzall::TextNode, Sawzall::Element, Sawzall::TextNode, Sawzall::Comment]
+    #
+    # @!method each_node
+    # @yieldparam node [Sawzall::Element, Sawzall::TextNode, Sawzall::Comment]
+    # @return [self, Enumerator]
+
+    # Yields the elements inside this one in document order, leaving out the
+    # descendants of those for which the block returns `:skip_children`.
+    # Returns an `Enumerator` without a block.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<div><nav><a>Home</a></nav><p>Text <a>link</a></p></div>")
+    #   doc.at("div").each_element.map(&:name) #=> ["nav", "a", "p", "a"]
+    #   names = []
+    #   doc.at("div").each_element { names << it.name; :skip_children if it.name == "nav" }
+    #   names #=> ["nav", "p", "a"]
+    #
+    # @!method each_element
+    # @yieldparam element [Sawzall::Element]
+    # @yieldreturn [:skip_children, Object]
+    # @return [self, Enumerator]
+
+    # Walks the element and every node inside it in document order, yielding
+    # `:open` when a node is reached and `:close` once everything inside it
+    # has been, with the same node object for both. Text nodes and comments
+    # are opened and closed right away. Returns an `Enumerator` without a
+    # block.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Hello <b>world</b></p>")
+    #   doc.at("p").traverse.map { |event, node| [event, node.text] } #=> [[:open, "Hello world"], [:open, "Hello "], [:close, "Hello "], [:open, "world"], [:open, "world"], [:close, "world"], [:close, "world"], [:close, "Hello world"]]
+    #
+    # @!method traverse
+    # @yieldparam event [:open, :close]
+    # @yieldparam node [Sawzall::Element, Sawzall::TextNode, Sawzall::Comment]
+    # @return [self, Enumerator]
+
+    # Freezes the element along with its document's tree, which then can't
+    # be modified through the document or any of its nodes either, like
+    # with {Sawzall::Document#freeze}. Frozen elements can be shared with
+    # other Ractors. Text nodes, comments and doctypes are frozen the same
+    # way.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul>")
+    #   list = Ractor.make_shareable(doc.at("ul"))
+    #   Ractor.new(list) { it.select("li").map(&:text) }.take #=> ["One", "Two"]
+    #   doc.create_element("li") rescue $!.class #=> FrozenError
+    #
+    # @!method freeze
+    # @return [self]
+
     # Returns the element's text content using a very simplified version of the
     # `innerText` algorithm.
     #
+    # Runs of whitespace are collapsed into a single space as they would be
+    # when rendered, except within preformatted elements like `<pre>`.
+    #
     # https://developer.mozilla.org/en-US/docs/Web/API/HTMLElement/innerText
     #
     # @example
@@ -189,9 +2367,72 @@ module Sawzall
     #   ul = doc.select("ul").first
     #   ul.text #=> "First item\nSecond item"
     #
-    # @!method text
+    # @!method text(max_output_bytes: nil, max_nodes: nil, truncate: false)
+    # @param max_output_bytes [Integer, nil] see {#html}
+    # @param max_nodes [Integer, nil] see {#html}
+    # @param truncate [Boolean] see {#html}
+    # @return [String]
+    # @raise [Sawzall::OutputTooLargeError] if a limit is exceeded
+
+    # Returns the text of the element's own text nodes, ignoring any text
+    # nested in child elements. Leading and trailing whitespace is removed.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<ul><li>Label <span>extra</span></li></ul>")
+    #   li = doc.select("li").first
+    #   li.own_text #=> "Label"
+    #   li.text #=> "Label extra"
+    #
+    # @!method own_text
     # @return [String]
 
+    # Splits the element's text into paragraphs at the boundaries of block
+    # elements, following the same rules as {#text} within each one
+    #
+    # Each paragraph has its `:text`, the innermost block `:element` it's in
+    # (or this element), its `:word_count` and its `:link_density`, the share
+    # of its non-whitespace characters that are inside links. This is the
+    # unit readability scoring, summarizers and chunkers usually work with.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment(<<~HTML)
+    #     <article>
+    #       <h1>Title</h1>
+    #       <p>Some <em>body</em> text</p>
+    #       <div>Read <a href="/more">more</a></div>
+    #     </article>
+    #   HTML
+    #   paragraphs = doc.select("article").first.paragraphs
+    #   paragraphs.map { it[:text] } #=> ["Title", "Some body text", "Read more"]
+    #   paragraphs.map { it[:element].name } #=> ["h1", "p", "div"]
+    #   paragraphs.last.slice(:word_count, :link_density) #=> {word_count: 2, link_density: 0.5}
+    #
+    # @!method paragraphs
+    # @return [Array<Hash{Symbol => Object}>]
+
+    # Returns the number of bytes of text in the element, not counting
+    # whitespace so that indentation doesn't make a difference. The text is
+    # measured in a single pass without building it, unlike `text.bytesize`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<nav>\n  <a href='/'>Home</a> | <a href='/blog'>Blog</a>\n</nav>")
+    #   doc.at("nav").text_length #=> 9
+    #
+    # @!method text_length
+    # @return [Integer]
+
+    # Returns the share of the element's {#text_length} that is inside
+    # links, from `0.0` to `1.0`. Navigation and other boilerplate usually
+    # has a high link density, while content has a low one.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<nav>\n  <a href='/'>Home</a> | <a href='/blog'>Blog</a>\n</nav>")
+    #   doc.at("nav").link_density #=> 0.8888888888888888
+    #   doc.at("a").link_density #=> 1.0
+    #
+    # @!method link_density
+    # @return [Float] `0.0` for elements without text
+
     # Checks whether the element has the given class
     #
     # @example
@@ -207,19 +2448,443 @@ module Sawzall
     #   Whether matching should be case sensitive. When `false`, only ASCII characters are matched case-insensitively.
     # @return [Boolean]
 
-    # Returns the element's classes
+    # Returns the element's classes as frozen strings shared by every call,
+    # or as symbols with `symbolize_names: true`
     #
     # @example
     #   doc = Sawzall.parse_fragment("<h1 class='one two'>Heading</h1>")
     #   h1 = doc.select("h1").first
     #   h1.classes #=> ["one", "two"]
+    #   h1.classes(symbolize_names: true) #=> [:one, :two]
+    #
+    # @!method classes(symbolize_names: false)
+    # @param symbolize_names [Boolean]
+    # @return [Array<String>, Array<Symbol>]
+
+    # Returns an opaque identifier for the element that remains valid for the
+    # lifetime of its document. Use {Sawzall::Document#element_for_id} to get
+    # the element back without holding on to the `Element` itself.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+    #   ids = doc.select("p").map(&:node_id)
+    #   ids.map { doc.element_for_id(it).text } #=> ["One", "Two"]
+    #
+    # @!method node_id
+    # @return [Integer]
+
+    # Returns the element's index in a preorder traversal of its document,
+    # which counts every node, so that comparing two elements of the same
+    # document tells which comes first. Unlike {#node_id}, positions change
+    # when nodes are added or removed before the element.
     #
-    # @!method classes
+    # @example
+    #   doc = Sawzall.parse_fragment("<h1>Title</h1><p>Text</p>")
+    #   doc.at("h1").document_position < doc.at("p").document_position #=> true
+    #   doc.at("h1").remove.document_position #=> nil
+    #
+    # @!method document_position
+    # @return [Integer, nil] `nil` if the element was removed from the
+    #   document or hasn't been inserted yet
+
+    # Returns where the element was in the parsed HTML, as a hash with its
+    # `:line`, counted from 1. That's the line its start tag ends on, since
+    # the tokenizer reports lines as it goes and doesn't track columns.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<h1>Title</h1>\n\n<p>Text</p>")
+    #   doc.at("p").source_position #=> {line: 3}
+    #   doc.create_element("p").source_position #=> nil
+    #
+    # @!method source_position
+    # @return [Hash{Symbol => Integer}, nil] `nil` if the element wasn't
+    #   parsed from HTML, e.g. if it was created or copied from another
+    #   document afterwards
+
+    # Returns a selector matching only this element in its document. It
+    # starts from the closest of the element and its ancestors with an id no
+    # other element has, and otherwise from the root element, adding
+    # `:nth-child` wherever siblings share a name.
+    #
+    # @example
+    #   doc = Sawzall.parse_document('<ul id="menu"><li>One</li><li>Two</li></ul>')
+    #   doc.select("li").last.css_path #=> "#menu > li:nth-child(2)"
+    #   doc.at("ul").css_path #=> "#menu"
+    #
+    # @!method css_path
+    # @return [String, nil] `nil` if the element was removed from the
+    #   document or hasn't been inserted yet
+
+    # Returns an absolute XPath selecting only this element in its document,
+    # made of the names of its ancestors from the root element, with
+    # positions wherever siblings share a name
+    #
+    # @example
+    #   doc = Sawzall.parse_document("<div></div><div><p>One</p><p>Two</p></div>")
+    #   doc.at("p").xpath_path #=> "/html/body/div[2]/p[1]"
+    #
+    # @!method xpath_path
+    # @return [String, nil] `nil` if the element was removed from the
+    #   document or hasn't been inserted yet
+
+    # Returns the value attached to the element with {#annotation=}, or `nil`
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+    #   doc.at("p").annotation = {score: 1}
+    #   doc.at("p").annotation #=> {score: 1}
+    #   doc.select("p").last.annotation #=> nil
+    #
+    # @!method annotation
+    # @return [Object, nil]
+
+    # Attaches a Ruby value to the element, e.g. a score computed in one pass
+    # of a pipeline and used in a later one. Unlike wrappers, which are
+    # created anew each time an element is returned, the value is stored with
+    # the document and kept alive as long as it is. Assigning `nil` removes
+    # it.
+    #
+    # See {Sawzall::Document#annotations} to get every annotated element.
+    #
+    # @!method annotation=(value)
+    # @param value [Object, nil]
+    # @return [Object, nil]
+
+    # Returns the unique tokens of the element's `rel` attribute, lowercased,
+    # as frozen strings shared by every call
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<a href='/' rel='NoFollow  noopener'>Home</a>")
+    #   doc.select("a").first.rel_tokens #=> ["nofollow", "noopener"]
+    #
+    # @!method rel_tokens
     # @return [Array<String>]
 
+    # Checks whether the element's `rel` attribute contains the given token.
+    # Matching is ASCII case-insensitive and only considers whole tokens.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<a href='/' rel='nofollower ugc'>Home</a>")
+    #   a = doc.select("a").first
+    #   a.rel?("UGC") #=> true
+    #   a.rel?("nofollow") #=> false
+    #
+    # @!method rel?(token)
+    # @param token [String]
+    # @return [Boolean]
+
+    # Returns the cells of a `<table>` element, or `nil` for other elements
+    #
+    # Rows are grouped under `:head`, `:body` and `:foot` following the
+    # table's `<thead>`, `<tbody>` and `<tfoot>`. When there is no `<thead>`,
+    # leading rows made only of `<th>` cells make up the head.
+    #
+    # Each row is an array of cell texts (see {#text}) with one entry per
+    # column. Cells spanning several columns or rows (with `colspan` or
+    # `rowspan`) are repeated in each of them, and columns that no cell
+    # covers are `nil`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment(<<~HTML)
+    #     <table>
+    #       <tr><th>Name</th><th>Score</th></tr>
+    #       <tr><td>Alice</td><td rowspan="2">10</td></tr>
+    #       <tr><td>Bob</td></tr>
+    #       <tfoot><tr><td colspan="2">2 players</td></tr></tfoot>
+    #     </table>
+    #   HTML
+    #   table = doc.select("table").first.to_table
+    #   table[:head] #=> [["Name", "Score"]]
+    #   table[:body] #=> [["Alice", "10"], ["Bob", "10"]]
+    #   table[:foot] #=> [["2 players", "2 players"]]
+    #
+    # @!method to_table
+    # @return [Hash{Symbol => Array<Array<String, nil>>}, nil]
+
+    # Returns the element and its descendants as nested hashes, e.g. to
+    # snapshot a tree and diff it
+    #
+    # Elements have their `:name`, `:attrs` and `:children`, and text nodes
+    # their `:text`. Comments are left out.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p class='intro'>Hello <b>world</b><!-- note --></p>")
+    #   doc.at("p").to_h #=> {name: "p", attrs: {"class" => "intro"}, children: [{text: "Hello "}, {name: "b", attrs: {}, children: [{text: "world"}]}]}
+    #
+    # @!method to_h
+    # @return [Hash{Symbol => Object}]
+
+    # Returns {#to_h} as JSON, serialized natively without building the
+    # hashes first
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Hello <b>world</b></p>")
+    #   doc.at("b").to_json #=> "{\"name\":\"b\",\"attrs\":{},\"children\":[{\"text\":\"world\"}]}"
+    #
+    # @!method to_json(*)
+    # @return [String]
+
+    # @!endgroup
+
+    # @!group 2) Modifying
+
+    # Sets the given attribute, replacing its value if it already exists.
+    # The change is reflected by the element's document (e.g. in
+    # {Document#to_html}).
+    #
+    # The name is lowercased like attribute names are when parsing. Names
+    # that couldn't be serialized (e.g. containing spaces or `=`) raise an
+    # `ArgumentError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<a href='/'>Home</a>")
+    #   doc.select("a").first.set_attr("rel", "nofollow")
+    #   doc.to_html #=> "<a href=\"/\" rel=\"nofollow\">Home</a>"
+    #
+    # @!method set_attr(name, value)
+    # @param name [String]
+    # @param value [String]
+    # @return [self]
+
+    # Removes the given attribute if the element has it
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p style='color: red' class='note'>Note</p>")
+    #   doc.select("p").first.remove_attr("style")
+    #   doc.to_html #=> "<p class=\"note\">Note</p>"
+    #
+    # @!method remove_attr(name)
+    # @param name [String]
+    # @return [self]
+
+    # Adds the given classes to the element's `class` attribute, skipping
+    # ones it already has. Like the DOM's `classList`, the attribute is
+    # rewritten without duplicates or extra whitespace.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p class='note  note'>Note</p>")
+    #   doc.at("p").add_class("important", "note")
+    #   doc.to_html #=> "<p class=\"note important\">Note</p>"
+    #
+    # @!method add_class(*names)
+    # @param names [Array<String>]
+    # @return [self]
+    # @raise [ArgumentError] if a name is empty or contains whitespace
+
+    # Removes the given classes from the element's `class` attribute, which
+    # is removed once no classes are left
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p class='note draft'>Note</p>")
+    #   doc.at("p").remove_class("draft")
+    #   doc.to_html #=> "<p class=\"note\">Note</p>"
+    #
+    # @!method remove_class(*names)
+    # @param names [Array<String>]
+    # @return [self]
+    # @raise [ArgumentError] if a name is empty or contains whitespace
+
+    # Removes the given class if the element has it and adds it otherwise
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<li class='item'>One</li>")
+    #   doc.at("li").toggle_class("active").toggle_class("item")
+    #   doc.to_html #=> "<li class=\"active\">One</li>"
+    #
+    # @!method toggle_class(name, force = nil)
+    # @param name [String]
+    # @param force [Boolean, nil] whether to only add (`true`) or only remove
+    #   (`false`) the class
+    # @return [self]
+    # @raise [ArgumentError] if the name is empty or contains whitespace
+
+    # Detaches the element and its descendants from the document, which stops
+    # returning them from {Document#select} and leaves them out of
+    # {Document#to_html}. The element can still be queried on its own.
+    #
+    # Removing the document's root element raises a `RuntimeError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment(<<~HTML)
+    #     <p>Article</p>
+    #     <div class="ad-slot">Ad</div>
+    #   HTML
+    #   doc.select(".ad-slot").each(&:remove)
+    #   doc.to_html.strip #=> "<p>Article</p>"
+    #
+    # @!method remove
+    # @return [self]
+
+    # Replaces the element with the given HTML fragment, or with a copy of the
+    # given element (which can come from any document). The element is then
+    # detached from the document like with {#remove}.
+    #
+    # Replacing the document's root element or an element that was removed
+    # raises a `RuntimeError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p><iframe src='https://example.com/video'></iframe></p>")
+    #   iframe = doc.select("iframe").first
+    #   iframe.replace_with("<a href='#{iframe.attr("src")}'>Watch the video</a>")
+    #   doc.to_html #=> "<p><a href=\"https://example.com/video\">Watch the video</a></p>"
+    #
+    # @!method replace_with(html_or_element)
+    # @param html_or_element [String, Sawzall::Element]
+    # @return [self]
+
+    # Appends a child to the element
+    #
+    # Elements and text nodes of the same document (including ones from
+    # {Sawzall::Document#create_element} and
+    # {Sawzall::Document#create_text_node}) are moved, while those of other
+    # documents are copied. Strings are parsed as HTML fragments.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<ul><li>One</li></ul><ol></ol>")
+    #   doc.at("ol").add_child(doc.at("li")).add_child("<li>Two</li>")
+    #   doc.to_html #=> "<ul></ul><ol><li>One</li><li>Two</li></ol>"
+    #
+    # @!method add_child(node)
+    # @param node [Sawzall::Element, Sawzall::TextNode, String]
+    # @return [self]
+    # @raise [ArgumentError] if the element would be added to itself or one
+    #   of its descendants
+
+    # Inserts a node right before the element, like {#add_child}
+    #
+    # Inserting next to the document's root element or an element that was
+    # removed raises a `RuntimeError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p><b>Price</b></p>")
+    #   doc.at("b").add_previous_sibling(doc.create_text_node("Total "))
+    #   doc.to_html #=> "<p>Total <b>Price</b></p>"
+    #
+    # @!method add_previous_sibling(node)
+    # @param node [Sawzall::Element, Sawzall::TextNode, String]
+    # @return [self]
+    # @raise [ArgumentError] if the element would be added to itself or one
+    #   of its descendants
+
+    # Inserts a node right after the element, like {#add_child}
+    #
+    # Inserting next to the document's root element or an element that was
+    # removed raises a `RuntimeError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p><b>Price:</b></p>")
+    #   doc.at("b").add_next_sibling(doc.create_text_node(" $5 & up"))
+    #   doc.to_html #=> "<p><b>Price:</b> $5 &amp; up</p>"
+    #
+    # @!method add_next_sibling(node)
+    # @param node [Sawzall::Element, Sawzall::TextNode, String]
+    # @return [self]
+    # @raise [ArgumentError] if the element would be added to itself or one
+    #   of its descendants
+
+    # Inserts a node so that it ends up at the given index among the
+    # element's child nodes (including text nodes), like {#add_child}. An
+    # index past the last child appends the node.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<ul><li>One</li><li>Three</li></ul>")
+    #   doc.at("ul").insert_child(1, "<li>Two</li>")
+    #   doc.to_html #=> "<ul><li>One</li><li>Two</li><li>Three</li></ul>"
+    #
+    # @!method insert_child(index, node)
+    # @param index [Integer]
+    # @param node [Sawzall::Element, Sawzall::TextNode, String]
+    # @return [self]
+    # @raise [ArgumentError] if the element would be added to itself or one
+    #   of its descendants
+
+    # Encloses the element in the given HTML, which is parsed as a fragment.
+    # The element is moved into the first element of the HTML, or into its
+    # innermost first descendant when it is nested.
+    #
+    # Wrapping the document's root element or an element that was removed
+    # raises a `RuntimeError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p><img src='/cat.png'></p>")
+    #   doc.select("img").first.wrap("<figure><a href='/cat'></a></figure>")
+    #   doc.to_html #=> "<p><figure><a href=\"/cat\"><img src=\"/cat.png\"></a></figure></p>"
+    #
+    # @!method wrap(html)
+    # @param html [String]
+    # @return [self]
+    # @raise [ArgumentError] if `html` doesn't contain any element
+
+    # Replaces the element with its children, which is then detached from the
+    # document like with {#remove}
+    #
+    # Unwrapping the document's root element raises a `RuntimeError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Some <font color='red'>red</font> text</p>")
+    #   doc.select("font").each(&:unwrap)
+    #   doc.to_html #=> "<p>Some red text</p>"
+    #
+    # @!method unwrap
+    # @return [self]
+
+    # Parses the given HTML fragment and inserts it right before the element,
+    # like the DOM's `insertAdjacentHTML("beforebegin", html)`
+    #
+    # Inserting next to the document's root element or an element that was
+    # removed raises a `RuntimeError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<p>Text</p>")
+    #   doc.select("p").first.before_html("<h1>Title</h1>")
+    #   doc.to_html #=> "<h1>Title</h1><p>Text</p>"
+    #
+    # @!method before_html(html)
+    # @param html [String]
+    # @return [self]
+
+    # Parses the given HTML fragment and inserts it before the element's first
+    # child, like the DOM's `insertAdjacentHTML("afterbegin", html)`
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<ul><li>Two</li></ul>")
+    #   doc.select("ul").first.prepend_html("<li>One</li>")
+    #   doc.to_html #=> "<ul><li>One</li><li>Two</li></ul>"
+    #
+    # @!method prepend_html(html)
+    # @param html [String]
+    # @return [self]
+
+    # Parses the given HTML fragment and inserts it after the element's last
+    # child, like the DOM's `insertAdjacentHTML("beforeend", html)`
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<ul><li>One</li></ul>")
+    #   doc.select("ul").first.append_html("<li>Two</li>")
+    #   doc.to_html #=> "<ul><li>One</li><li>Two</li></ul>"
+    #
+    # @!method append_html(html)
+    # @param html [String]
+    # @return [self]
+
+    # Parses the given HTML fragment and inserts it right after the element,
+    # like the DOM's `insertAdjacentHTML("afterend", html)`
+    #
+    # Inserting next to the document's root element or an element that was
+    # removed raises a `RuntimeError`.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<h1>Title</h1>")
+    #   doc.select("h1").first.after_html("<p>Text</p>")
+    #   doc.to_html #=> "<h1>Title</h1><p>Text</p>"
+    #
+    # @!method after_html(html)
+    # @param html [String]
+    # @return [self]
+
     # @!endgroup
 
-    # @!group 2) Debugging
+    # @!group 3) Debugging
 
     # Overrides Ruby's default `Object#inspect` so the output is a bit more useful
     def inspect
@@ -254,5 +2919,74 @@ module Sawzall
     end
 
     # @!endgroup
+
+    # @!group 4) Nokogiri compatibility
+
+    # These methods have the names and return values of their Nokogiri
+    # counterparts so that code written against Nokogiri can switch over
+    # with few changes. Node sets are plain arrays.
+
+    # Same as {#select}
+    #
+    # @!method css(css_selector, detach: false, namespaces: nil)
+    # @return [Array<Sawzall::Element>]
+
+    # Same as {#at}
+    #
+    # @!method at_css(css_selector, null_object: false, namespaces: nil)
+    # @return [Sawzall::Element, Sawzall::NullElement, nil]
+
+    # Same as {#attr}
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<a href='/home'>Home</a>")
+    #   doc.at_css("a")["href"] #=> "/home"
+    #   doc.at_css("a")["title"] #=> nil
+    #
+    # @!method [](attribute)
+    # @param attribute [String]
+    # @return [String, nil]
+
+    # Same as {#set_attr}
+    #
+    # @!method []=(name, value)
+    # @param name [String]
+    # @param value [String]
+
+    # Same as {#child_nodes}
+    #
+    # @!method children
+    # @return [Array<Sawzall::Element, Sawzall::TextNode, Sawzall::Comment>]
+
+    # Returns the text of every text node in the element, concatenated as is
+    #
+    # Unlike {#text}, whitespace isn't collapsed and block elements aren't
+    # separated by line breaks.
+    #
+    # @example
+    #   doc = Sawzall.parse_fragment("<ul>\n  <li>One</li><li>Two</li>\n</ul>")
+    #   doc.at_css("ul").content #=> "\n  OneTwo\n"
+    #   doc.at_css("ul").text #=> "One\nTwo"
+    #
+    # @!method content
+    # @return [String]
+
+    # Same as {#html}
+    #
+    # @!method to_html(max_output_bytes: nil, max_nodes: nil, truncate: false)
+    # @return [String]
+
+    # @!endgroup
   end
 end
+
+# Shorthand for {Sawzall.Document}
+#
+# @param html [String, Sawzall::Document, Sawzall::Element, #to_html]
+# @return [Sawzall::Document]
+#
+# @example
+#   Sawzall("<title>Page Title</title>").select("title").first.text #=> "Page Title"
+def Sawzall(html)
+  Sawzall.Document(html)
+end
@@ -1,7 +1,10 @@
 # frozen_string_literal: true
 
+require "objspace"
+require "pathname"
 require "pp"
 require "stringio"
+require "tempfile"
 
 RSpec.describe Sawzall do
   it "has a version number" do
@@ -36,203 +39,3090 @@ RSpec.describe Sawzall do
 
       expect(doc).to be_a(Sawzall::Document)
     end
+
+    context "with weak_elements: true" do
+      def weak_heading(html)
+        Sawzall.parse_fragment(html, weak_elements: true).select("h1").first
+      end
+
+      it "returns elements that work while the document is alive" do
+        doc = Sawzall.parse_fragment(sample_fragment, weak_elements: true)
+        heading = doc.select("h1").first
+
+        GC.start(full_mark: true, immediate_sweep: true)
+
+        expect(heading.text).to eq("Hello, world")
+        expect(heading.child_elements).to eq([])
+        expect(doc).to be_a(Sawzall::Document)
+      end
+
+      it "raises Sawzall::StaleDocumentError once the document is garbage collected" do
+        heading = weak_heading(sample_fragment)
+
+        GC.start(full_mark: true, immediate_sweep: true)
+
+        expect { heading.text }.to raise_error(Sawzall::StaleDocumentError)
+      end
+    end
+
+    it "keeps the document alive through its elements by default" do
+      heading = Sawzall.parse_fragment(sample_fragment).select("h1").first
+
+      GC.start(full_mark: true, immediate_sweep: true)
+
+      expect(heading.text).to eq("Hello, world")
+    end
+
+    context "with invalid or non UTF-8 input" do
+      it "replaces invalid bytes so every returned string is valid UTF-8" do
+        doc = Sawzall.parse_fragment("<p title='caf\xE9'>caf\xE9 \xFF</p>")
+        paragraph = doc.select("p").first
+        strings = [paragraph.name, paragraph.text, paragraph.html, paragraph.attr("title"), *paragraph.attrs.flatten]
+
+        expect(paragraph.text).to eq("caf\uFFFD \uFFFD")
+        expect(paragraph.attr("title")).to eq("caf\uFFFD")
+        expect(strings.map(&:encoding)).to all(eq(Encoding::UTF_8))
+        expect(strings).to all(be_valid_encoding)
+      end
+
+      it "converts other encodings to UTF-8" do
+        expect(Sawzall.parse_fragment("<p>café</p>".encode("ISO-8859-1")).select("p").first.text).to eq("café")
+        expect(Sawzall.parse_fragment("<p>café</p>".b).select("p").first.text).to eq("café")
+      end
+
+      it "raises an EncodingError with strict_encoding: true" do
+        expect { Sawzall.parse_fragment("<p>caf\xE9</p>", strict_encoding: true) }
+          .to raise_error(EncodingError)
+        expect(Sawzall.parse_fragment("<p>café</p>", strict_encoding: true).select("p").first.text)
+          .to eq("café")
+      end
+
+      it "replaces invalid bytes with invalid: :replace" do
+        expect(Sawzall.parse_fragment("<p>caf\xE9</p>", invalid: :replace).select("p").first.text).to eq("caf\uFFFD")
+      end
+
+      it "raises a Sawzall::InvalidEncodingError with invalid: :raise" do
+        expect { Sawzall.parse_fragment("<p>caf\xE9</p>", invalid: :raise) }
+          .to raise_error(Sawzall::InvalidEncodingError, "invalid byte sequence in UTF-8 at byte 6")
+        expect(Sawzall::InvalidEncodingError.ancestors).to include(EncodingError)
+      end
+
+      it "rejects unknown invalid: values and combining it with strict_encoding:" do
+        expect { Sawzall.parse_fragment("<p></p>", invalid: :ignore) }
+          .to raise_error(ArgumentError, "unknown invalid option :ignore, expected one of :replace, :raise")
+        expect { Sawzall.parse_fragment("<p></p>", invalid: :raise, strict_encoding: true) }
+          .to raise_error(ArgumentError, "invalid and strict_encoding can't be combined")
+      end
+    end
+  end
+
+  describe "keyword options" do
+    it "raises an ArgumentError listing the allowed options for unknown ones" do
+      expect { Sawzall.parse_fragment("<p></p>", weak: true) }
+        .to raise_error(ArgumentError, "unknown keyword :weak, expected one of :weak_elements, :strict_encoding, :invalid, :repair, :sniff_encoding, :content_type, :max_input_bytes, :max_depth, :max_nodes")
+      expect { Sawzall.parse_fragment("<p></p>").select("p", detach: true, deep: true, "all" => true) }
+        .to raise_error(ArgumentError, 'unknown keywords :deep, "all", expected one of :detach')
+      expect { Sawzall::Sanitizer.new(tags: ["p"]) }
+        .to raise_error(ArgumentError, /expected one of :elements, :attributes, :protocols/)
+    end
+  end
+
+  describe ".parse_document" do
+    it "returns a Sawzall::Document" do
+      doc = Sawzall.parse_document(sample_document)
+
+      expect(doc).to be_a(Sawzall::Document)
+      expect(doc.repairs).to eq([])
+    end
+
+    it "reads an IO in chunks" do
+      html = "<!DOCTYPE html><title>café</title>" + "<p>☕</p>" * 100_000
+      doc = Sawzall.parse_document(StringIO.new(html))
+
+      expect(doc.at("title").text).to eq("café")
+      expect(doc.select("p").length).to eq(100_000)
+      expect(doc.to_html).to eq(Sawzall.parse_document(html).to_html)
+    end
+
+    it "handles invalid bytes and limits when reading an IO" do
+      io = -> { StringIO.new("<p>caf\xE9</p>".b) }
+
+      expect(Sawzall.parse_document(io.call).at("p").text).to eq("caf\uFFFD")
+      expect { Sawzall.parse_document(io.call, invalid: :raise) }
+        .to raise_error(Sawzall::InvalidEncodingError, "invalid byte sequence in UTF-8 at byte 6")
+      expect { Sawzall.parse_document(io.call, max_input_bytes: 8) }
+        .to raise_error(Sawzall::ParseLimitError)
+      expect { Sawzall.parse_document(io.call, repair: :conservative) }
+        .to raise_error(ArgumentError, "repair isn't supported when parsing an IO")
+    end
+  end
+
+  describe ".parse_file" do
+    it "parses the file at a path" do
+      Tempfile.create(["sawzall", ".html"]) do |file|
+        file.write(sample_document)
+        file.close
+
+        expect(Sawzall.parse_file(file.path).to_html).to eq(Sawzall.parse_document(sample_document).to_html)
+        expect(Sawzall.parse_file(Pathname(file.path), max_depth: 3).at("title")).not_to be_nil
+      end
+    end
+
+    it "sniffs the encoding of the file" do
+      Tempfile.create(["sawzall", ".html"], binmode: true) do |file|
+        file.write("<meta charset=latin1><p>caf\xE9</p>".b)
+        file.close
+
+        expect(Sawzall.parse_file(file.path).at("p").text).to eq("caf\uFFFD")
+        expect(Sawzall.parse_file(file.path, sniff_encoding: true).at("p").text).to eq("café")
+      end
+    end
+
+    it "parses empty files" do
+      Tempfile.create(["sawzall", ".html"]) do |file|
+        expect(Sawzall.parse_file(file.path).to_html).to eq("<html><head></head><body></body></html>")
+      end
+    end
+
+    it "raises Errno errors for missing files" do
+      expect { Sawzall.parse_file("/does/not/exist.html") }.to raise_error(Errno::ENOENT)
+    end
+  end
+
+  describe ".each_token" do
+    it "yields tags, text, comments and doctypes" do
+      events = Sawzall.each_token("<!DOCTYPE html><P id=a>One<br/><!-- two --></p></div>").to_a
+
+      expect(events).to eq([
+        {type: :doctype, name: "html", public_id: nil, system_id: nil},
+        {type: :start_tag, name: "p", attributes: {"id" => "a"}, self_closing: false},
+        {type: :text, text: "One"},
+        {type: :start_tag, name: "br", attributes: {}, self_closing: true},
+        {type: :comment, text: " two "},
+        {type: :end_tag, name: "p"},
+        {type: :end_tag, name: "div"}
+      ])
+    end
+
+    it "yields the content of raw text elements as text" do
+      expect(Sawzall.each_token("<script>if (a <b) {}</script>").map { it[:type] })
+        .to eq([:start_tag, :text, :end_tag])
+    end
+
+    it "reads an IO in chunks" do
+      html = "<p>café</p>" * 100_000
+      count = 0
+
+      expect(Sawzall.each_token(StringIO.new(html)) { count += 1 if it[:text] == "café" }).to eq(Sawzall)
+      expect(count).to eq(100_000)
+    end
+
+    it "stops at exceptions raised by the block" do
+      count = 0
+
+      expect { Sawzall.each_token("<a><b><c>") { count += 1; raise "stop" if count == 2 } }
+        .to raise_error(RuntimeError, "stop")
+      expect(count).to eq(2)
+    end
+  end
+
+  describe ".parse_xml" do
+    let(:sitemap) do
+      <<~XML
+        <?xml version="1.0" encoding="UTF-8"?>
+        <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+          <url><loc>https://example.com/</loc><lastMod>2024-01-01</lastMod></url>
+          <url><loc>https://example.com/about?a=1&amp;b=2</loc></url>
+        </urlset>
+      XML
+    end
+
+    it "parses XML with the document API" do
+      doc = Sawzall.parse_xml(sitemap)
+
+      expect(doc.root_element.name).to eq("urlset")
+      expect(doc.select("url > loc").map(&:text)).to eq(["https://example.com/", "https://example.com/about?a=1&b=2"])
+      expect(doc.select("lastMod").map(&:text)).to eq(["2024-01-01"])
+      expect(doc.select("lastmod")).to eq([])
+    end
+
+    it "turns CDATA sections into text" do
+      doc = Sawzall.parse_xml("<feed><title><![CDATA[<b>Bold</b> & co]]></title></feed>")
+
+      expect(doc.at("title").text).to eq("<b>Bold</b> & co")
+    end
+
+    it "raises an error without a root element" do
+      expect { Sawzall.parse_xml("<!-- empty -->") }.to raise_error(ArgumentError, "XML has no root element")
+    end
+
+    it "rejects HTML-only options" do
+      expect { Sawzall.parse_xml("<a/>", repair: :conservative) }
+        .to raise_error(ArgumentError, "unknown keyword :repair, expected one of :weak_elements, :strict_encoding")
+    end
+  end
+
+  describe "encoding sniffing" do
+    it "decodes the bytes from the encoding they declare" do
+      body = "<html><head><meta charset=Shift_JIS></head><body><p>\x93\xFA\x96\x7B</p>".b
+      doc = Sawzall.parse_document(body, sniff_encoding: true)
+
+      expect(doc.at("p").text).to eq("日本")
+      expect(doc.encoding).to eq("Shift_JIS")
+    end
+
+    it "prefers the byte order mark, then the Content-Type" do
+      latin1 = "<meta charset=utf-8><p>caf\xE9</p>".b
+
+      expect(Sawzall.parse_fragment(latin1, sniff_encoding: true, content_type: "text/html; charset=ISO-8859-1").at("p").text)
+        .to eq("café")
+      expect(Sawzall.parse_fragment("\xFF\xFE<\0p\0>\0".b, sniff_encoding: true, content_type: "text/html; charset=utf-8").encoding)
+        .to eq("UTF-16LE")
+    end
+
+    it "falls back to UTF-8 or windows-1252" do
+      expect(Sawzall.parse_fragment("café".b, sniff_encoding: true).encoding).to eq("UTF-8")
+      expect(Sawzall.parse_fragment("caf\xE9".b, sniff_encoding: true).encoding).to eq("windows-1252")
+      expect(Sawzall.parse_fragment("café").encoding).to be_nil
+    end
+
+    it "raises on invalid bytes with strict_encoding" do
+      body = "<meta charset=utf-8>caf\xE9".b
+
+      expect(Sawzall.parse_fragment(body, sniff_encoding: true).root_element.text).to eq("caf\uFFFD")
+      expect { Sawzall.parse_fragment(body, sniff_encoding: true, strict_encoding: true) }.to raise_error(EncodingError)
+    end
+  end
+
+  describe "parse errors" do
+    it "reports what the parser recovered from with its line" do
+      doc = Sawzall.parse_document(<<~HTML)
+        <!DOCTYPE html>
+        <ul>
+          <li title="a" title="b">One</li>
+        </p>
+        </ul>
+      HTML
+
+      expect(doc.errors).to eq([
+        {message: "Duplicate attribute", line: 3},
+        {message: "No <p> tag to close", line: 4}
+      ])
+      expect(Sawzall.parse_fragment("<p>Valid</p>").errors).to eq([])
+      expect(Sawzall.parse_fragments(["<p>One</b>"]).first.errors.size).to eq(1)
+    end
+
+    it "isn't kept by Marshal" do
+      doc = Marshal.load(Marshal.dump(Sawzall.parse_document("<p>One")))
+
+      expect(doc.errors).to eq([])
+    end
+  end
+
+  describe "parse limits" do
+    it "raises a Sawzall::ParseLimitError when the input is too large" do
+      expect { Sawzall.parse_fragment("<p>café</p>", max_input_bytes: 11) }
+        .to raise_error(Sawzall::ParseLimitError, "input exceeds max_input_bytes (11)")
+      expect(Sawzall.parse_fragment("<p>café</p>", max_input_bytes: 12).at("p").text).to eq("café")
+    end
+
+    it "raises when the tree is nested too deeply or has too many nodes" do
+      html = "<div>" * 10_000
+
+      expect { Sawzall.parse_document(html, max_depth: 100) }
+        .to raise_error(Sawzall::ParseLimitError, "document is nested deeper than max_depth (100)")
+      expect { Sawzall.parse_document(html, max_nodes: 1000) }
+        .to raise_error(Sawzall::ParseLimitError, "document has more than max_nodes (1000) nodes")
+      expect(Sawzall.parse_document("<p>One</p>", max_depth: 3, max_nodes: 7).at("p").text).to eq("One")
+    end
+  end
+
+  describe "repair modes" do
+    let(:garbage) { "<p title='#{"x" * 70_000}'>One\0</p><!-- unterminated <p>Two</p>" }
+
+    it "parses garbage as is by default" do
+      doc = Sawzall.parse_document(garbage)
+
+      expect(doc.select("p").map(&:text)).to eq(["One"])
+      expect(doc.repairs).to eq([])
+    end
+
+    it "removes NUL bytes and ends unterminated comments in conservative mode" do
+      doc = Sawzall.parse_document(garbage, repair: :conservative)
+
+      expect(doc.select("p").map(&:text)).to eq(["One", "Two"])
+      expect(doc.at("p").attr("title").size).to eq(70_000)
+      expect(doc.repairs).to eq([{type: :null_bytes, count: 1}, {type: :unterminated_comments, count: 1}])
+    end
+
+    it "also removes overlong attributes in aggressive mode" do
+      doc = Sawzall.parse_fragment(garbage, repair: :aggressive)
+
+      expect(doc.to_html).to eq("<p>One</p><!-- unterminated --><p>Two</p>")
+      expect(doc.repairs.last).to eq({type: :overlong_attributes, count: 1})
+    end
+
+    it "rejects unknown modes" do
+      expect { Sawzall.parse_document("", repair: :all) }
+        .to raise_error(ArgumentError, "unknown repair mode :all, expected one of :none, :conservative, :aggressive")
+    end
+  end
+
+  describe ".parse_fragments" do
+    it "parses every fragment, preserving order" do
+      htmls = (1..20).map { |i| "<p>#{i}</p>" }
+
+      docs = Sawzall.parse_fragments(htmls)
+
+      expect(docs).to all(be_a(Sawzall::Document))
+      expect(docs.map { |doc| doc.select("p").first.text }).to eq((1..20).map(&:to_s))
+    end
   end
 
-  describe ".parse_document" do
-    it "returns a Sawzall::Document" do
-      doc = Sawzall.parse_document(sample_document)
+  describe ".parse_fragments with a queue" do
+    it "pushes each document to the queue as it is parsed" do
+      htmls = (1..20).map { |i| "<p>#{i}</p>" }
+      queue = Thread::Queue.new
+
+      expect(Sawzall.parse_fragments(htmls, queue: queue)).to equal(queue)
+      results = Array.new(20) { queue.pop }
+
+      expect(queue).to be_empty
+      expect(results.map(&:first).sort).to eq((0...20).to_a)
+      expect(results.map { |index, doc| [index, doc.select("p").first.text] })
+        .to all(satisfy { |index, text| text == (index + 1).to_s })
+    end
+
+    it "lets consumers run while the batch is parsed" do
+      queue = Thread::Queue.new
+      consumer = Thread.new { Array.new(3) { queue.pop.last.select("title").first.text } }
+
+      Sawzall.parse_documents(["<title>a</title>", "<title>b</title>", "<title>c</title>"], queue: queue)
+
+      expect(consumer.value.sort).to eq(["a", "b", "c"])
+    end
+
+    it "handles empty batches" do
+      queue = Thread::Queue.new
+
+      expect(Sawzall.parse_fragments([], queue: queue)).to be_empty
+    end
+  end
+
+  describe ".parse_fragments with strict_encoding" do
+    it "replaces invalid bytes by default and raises with strict_encoding: true" do
+      htmls = ["<p>ok</p>", "<p>caf\xE9</p>"]
+
+      expect(Sawzall.parse_fragments(htmls).map { it.select("p").first.text }).to eq(["ok", "caf\uFFFD"])
+      expect { Sawzall.parse_fragments(htmls, strict_encoding: true) }.to raise_error(EncodingError)
+      expect { Sawzall.parse_fragments(htmls, queue: Thread::Queue.new, strict_encoding: true) }
+        .to raise_error(EncodingError)
+    end
+  end
+
+  describe ".parse_documents" do
+    it "parses every document" do
+      docs = Sawzall.parse_documents([sample_document, sample_fragment])
+
+      expect(docs.map { |doc| doc.select("h1").first.text }).to eq(["Hello, world"] * 2)
+      expect(docs.first.select("title").first.text).to eq("Test Document")
+    end
+  end
+
+  describe ".parse_async" do
+    it "parses documents in the background" do
+      handle = Sawzall.parse_async(sample_document)
+
+      expect(handle).to be_a(Sawzall::ParseHandle)
+      doc = handle.wait
+      expect(handle).to be_done
+      expect(doc.select("title").first.text).to eq("Test Document")
+      expect(handle.wait).to equal(doc)
+    end
+
+    it "parses fragments" do
+      doc = Sawzall.parse_async("<p>Hello</p>", fragment: true).wait
+
+      expect(doc.to_html).to eq("<p>Hello</p>")
+    end
+
+    it "can be waited on from multiple threads" do
+      handle = Sawzall.parse_async(sample_fragment, fragment: true)
+
+      titles = 4.times.map { Thread.new { handle.wait.select("h1").first.text } }.map(&:value)
+      expect(titles).to eq(["Hello, world"] * 4)
+    end
+  end
+
+  describe ".link_graph" do
+    let(:home) do
+      Sawzall.parse_document(<<~HTML)
+        <a href="/about" rel="NoFollow">About <b>us</b></a>
+        <a href="#main">Skip</a>
+        <a href="mailto:hi@example.com">Email</a>
+      HTML
+    end
+    let(:about) { Sawzall.parse_document("<base href='/docs/'><a href='guide#install'>Guide</a>") }
+
+    it "returns the links between documents" do
+      edges = Sawzall.link_graph("https://example.com/#top" => home, "https://example.com/about" => about)
+
+      expect(edges).to eq([
+        {from_url: "https://example.com/", to_url: "https://example.com/about", text: "About us", rel: ["nofollow"]},
+        {from_url: "https://example.com/about", to_url: "https://example.com/docs/guide", text: "Guide", rel: []}
+      ])
+    end
+
+    it "accepts pairs of URLs and documents" do
+      edges = Sawzall.link_graph([["https://example.com/about", about], ["https://example.com/other", about]])
+
+      expect(edges.map { it[:from_url] }).to eq(["https://example.com/about", "https://example.com/other"])
+    end
+
+    it "raises an error for relative URLs" do
+      expect { Sawzall.link_graph("/about" => about) }.to raise_error(ArgumentError, 'invalid base URL "/about"')
+    end
+  end
+
+  describe "URL.normalize" do
+    it "normalizes equivalent URLs to the same string" do
+      expect(Sawzall::URL.normalize("HTTP://Example.COM:80/Path?b=2&a=1#top")).to eq("http://example.com/Path?b=2&a=1")
+      expect(Sawzall::URL.normalize("https://example.com:8443")).to eq("https://example.com:8443/")
+      expect(Sawzall::URL.normalize("https://example.com/?")).to eq("https://example.com/")
+    end
+
+    it "sorts query parameters by name with sort_query: true" do
+      expect(Sawzall::URL.normalize("https://example.com/?c&a=1&b=%20&a=0", sort_query: true))
+        .to eq("https://example.com/?a=1&a=0&b=%20&c")
+    end
+
+    it "raises an error for relative URLs" do
+      expect { Sawzall::URL.normalize("/about") }.to raise_error(ArgumentError, 'invalid URL "/about"')
+    end
+  end
+
+  describe ".sort_by_position" do
+    it "sorts elements in document order with removed elements last" do
+      doc = Sawzall.parse_fragment("<h1>One</h1><section><h2>Two</h2><p>Three</p></section><p>Four</p>")
+      removed = doc.at("section").remove
+      merged = doc.select("p") + [removed] + doc.select("h1, h2") + removed.select("p")
+
+      expect(Sawzall.sort_by_position(merged).map(&:text))
+        .to eq(["One", "Four", "Two\nThree", "Three"])
+    end
+  end
+
+  describe ".void_element? and .raw_text_element?" do
+    it "knows the parser's special elements" do
+      expect(%w[br IMG wbr p].map { Sawzall.void_element?(it) }).to eq([true, true, true, false])
+      expect(%w[script style textarea title pre].map { Sawzall.raw_text_element?(it) })
+        .to eq([true, true, true, true, false])
+    end
+  end
+
+  describe ".valid_selector?" do
+    it "checks selectors without a document" do
+      expect(Sawzall.valid_selector?("article > p.intro, h1")).to eq(true)
+      expect(Sawzall.valid_selector?("article >")).to eq(false)
+      expect(Sawzall.valid_selector?("")).to eq(false)
+    end
+  end
+
+  describe ".Fragment" do
+    it "parses HTML-ish input as a fragment" do
+      doc = Sawzall.parse_fragment("<p>One</p>")
+      html_ish = Struct.new(:to_html).new("<p>Three</p>")
+
+      expect(Sawzall.Fragment(<<~HTML).to_html).to eq("<p>Two</p>")
+        <p>Two</p>
+      HTML
+      expect(Sawzall.Fragment(doc)).to equal(doc)
+      expect(Sawzall.Fragment(doc.select("p").first).to_html).to eq("<p>One</p>")
+      expect(Sawzall.Fragment(html_ish).to_html).to eq("<p>Three</p>")
+      expect { Sawzall.Fragment(1) }.to raise_error(TypeError)
+    end
+  end
+
+  describe ".Document" do
+    it "parses HTML-ish input as a document" do
+      doc = Sawzall.parse_document("<title>One</title>")
+
+      expect(Sawzall.Document("<title>Two</title>").select("head title").first.text).to eq("Two")
+      expect(Sawzall.Document(doc)).to equal(doc)
+      expect(Sawzall("<p>Three</p>").select("body p").first.text).to eq("Three")
+    end
+  end
+
+  describe ".join" do
+    it "builds a new fragment from copies of the parts" do
+      header = Sawzall.parse_fragment("<h1>Title</h1>")
+      body = Sawzall.parse_document("<title>Ignored</title><p>Body</p>")
+      link = Sawzall.parse_fragment("<p><a href='/'>Home</a></p>").select("a").first
+
+      joined = Sawzall.join([header, body, link, "<script>"], "\n")
+
+      expect(joined.to_html).to eq(%(<h1>Title</h1>\n<p>Body</p>\n<a href="/">Home</a>\n&lt;script&gt;))
+      expect(joined.select("h1, p, a").map(&:name)).to eq(["h1", "p", "a"])
+      expect(header.to_html).to eq("<h1>Title</h1>")
+      expect(link.html).to eq('<a href="/">Home</a>')
+      expect(Sawzall.join([]).to_html).to eq("")
+      expect { Sawzall.join([1]) }.to raise_error(TypeError)
+    end
+  end
+
+  describe ".configure" do
+    after do
+      Sawzall.configure do |config|
+        config.threads = nil
+        config.weak_elements = false
+        config.strict_encoding = false
+      end
+    end
+
+    it "sets the number of threads used by batch APIs" do
+      [4, 1, nil].each do |threads|
+        Sawzall.configure { |config| config.threads = threads }
+
+        expect(Sawzall.configuration.threads).to eq(threads)
+        expect(Sawzall.parse_fragments(["<p>a</p>", "<p>b</p>"]).map { |doc| doc.select("p").first.text })
+          .to eq(["a", "b"])
+      end
+    end
+
+    it "rejects invalid thread counts" do
+      expect { Sawzall.configure { |config| config.threads = -1 } }.to raise_error(RangeError)
+    end
+
+    it "sets the options used when they aren't passed" do
+      expect(Sawzall.defaults).to eq(weak_elements: false, strict_encoding: false)
+
+      Sawzall.configure { |config| config.strict_encoding = true }
+
+      expect(Sawzall.defaults).to eq(weak_elements: false, strict_encoding: true)
+      expect { Sawzall.parse_fragment("<p>caf\xE9</p>") }.to raise_error(EncodingError)
+      expect { Sawzall.parse_documents(["<p>caf\xE9</p>"]) }.to raise_error(EncodingError)
+      expect { Sawzall::Pool.new.parse("<p>caf\xE9</p>") }.to raise_error(EncodingError)
+      expect(Sawzall.parse_fragment("<p>caf\xE9</p>", strict_encoding: false).select("p").first.text)
+        .to eq("caf\uFFFD")
+    end
+
+    it "applies the weak_elements default to background and batch parses" do
+      Sawzall.configure { |config| config.weak_elements = true }
+
+      headings = [
+        -> { Sawzall.parse_async(sample_fragment, fragment: true).wait },
+        -> { Sawzall.parse_fragments([sample_fragment]).first },
+        -> { Sawzall.parse_fragments([sample_fragment], queue: Thread::Queue.new).pop.last }
+      ].map { |parse| parse.call.select("h1").first }
+
+      GC.start(full_mark: true, immediate_sweep: true)
+
+      headings.each do |heading|
+        expect { heading.text }.to raise_error(Sawzall::StaleDocumentError)
+      end
+    end
+  end
+
+  describe Sawzall::Document do
+    describe "#select" do
+      it "returns elements that match the CSS selector" do
+        doc = Sawzall.parse_document(sample_document)
+
+        selection = doc.select("p")
+        expect(selection.size).to eq(1)
+        expect(selection[0]).to be_a(Sawzall::Element)
+        expect(selection[0].name).to eq("p")
+        expect(selection[0].inner_html).to eq("This is an HTML document")
+      end
+
+      it "works with multiple matching elements" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <ul>
+            <li>One</li>
+            <li>Two</li>
+          </ul>
+        HTML
+
+        selection = doc.select("ul li")
+        expect(selection.map(&:name)).to eq(["li", "li"])
+        expect(selection.map(&:inner_html)).to eq(["One", "Two"])
+      end
+
+      it "searches large documents from several threads at once" do
+        doc = Sawzall.parse_fragment("<ul>#{"<li><a href='#'>Link</a></li>" * 10_000}</ul>")
+
+        counts = Array.new(4) { Thread.new { doc.select("li:nth-child(odd) > a").size } }.map(&:value)
+        expect(counts).to eq([5_000] * 4)
+        expect(doc.select("li", detach: true).first.html).to eq('<li><a href="#">Link</a></li>')
+      end
+
+      it "returns nothing if there are no matching elements" do
+        doc = Sawzall.parse_document(sample_document)
+
+        selection = doc.select("table")
+        expect(selection).to be_empty
+      end
+
+      it "raises an error if the selector is invalid" do
+        doc = Sawzall.parse_fragment("")
+
+        expect { doc.select("div[]") }
+          .to raise_error(ArgumentError, /failed to parse selector "div\[\]"/)
+      end
+
+      it "copies matches into standalone documents with detach: true" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <table><tr class="row"><td>One</td></tr><tr class="row"><td>Two</td></tr></table>
+        HTML
+
+        rows = doc.select(".row", detach: true)
+        expect(rows.map(&:html))
+          .to eq(['<tr class="row"><td>One</td></tr>', '<tr class="row"><td>Two</td></tr>'])
+        expect(rows.map { |row| row.select("td").first.text }).to eq(["One", "Two"])
+        expect(rows.first.node_id).to eq(rows.last.node_id)
+      end
+
+      it "resolves namespace prefixes with namespaces:" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <a href="/home">Home</a>
+          <svg><a xlink:href="#logo"><title>Logo</title></a></svg>
+          <math><mi>x</mi></math>
+        HTML
+        svg = "http://www.w3.org/2000/svg"
+
+        expect(doc.select("svg|a", namespaces: {svg:}).map { it.attr("xlink:href") }).to eq(["#logo"])
+        expect(doc.select("svg|a > svg|title", namespaces: {"svg" => svg}).map(&:text)).to eq(["Logo"])
+        expect(doc.select("[xlink|href]", namespaces: {xlink: "http://www.w3.org/1999/xlink"}).size).to eq(1)
+        expect(doc.select("*", namespaces: {xmlns: svg}).map(&:name)).to eq(["svg", "a", "title"])
+        expect(doc.at("svg", namespaces: {svg:}).select("a", namespaces: {xmlns: svg}).size).to eq(1)
+        expect(doc.at("math|mi", namespaces: {math: "http://www.w3.org/1998/Math/MathML"}).text).to eq("x")
+        expect { doc.select("svg|a", namespaces: {}) }
+          .to raise_error(ArgumentError, /failed to parse selector "svg\|a"/)
+      end
+
+      it "filters by attribute values with attr_matches:" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <a href="/2024/05/launch" rel="next">Launch</a>
+          <a href="/about">About</a>
+          <a>Anchor</a>
+          <p><a href="/2023/11/recap">Recap</a></p>
+        HTML
+
+        expect(doc.select("a", attr_matches: {href: %r{\d{4}/\d{2}}}).map(&:text)).to eq(["Launch", "Recap"])
+        expect(doc.select("a", attr_matches: {"href" => /^\/2024/, :rel => /NEXT/i}).map(&:text)).to eq(["Launch"])
+        expect(doc.at("p").select("a", attr_matches: {href: /2024/})).to eq([])
+        expect { doc.select("a", attr_matches: {href: "2024"}) }.to raise_error(TypeError)
+        expect { doc.select("a", attr_matches: {href: /(?<=\/)2024/}) }
+          .to raise_error(ArgumentError, /unsupported regexp/)
+      end
+
+      it "matches text with :contains" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <ul><li>Apples</li><li class="pear">Pears <b>(on sale)</b></li></ul>
+        HTML
+
+        expect(doc.select('li:contains("(ON SALE)")').map(&:text)).to eq(["Pears (on sale)"])
+        expect(doc.select("ul :contains(sale)").map(&:name)).to eq(["li", "b"])
+        expect(doc.select("li:contains(apple), li.pear").size).to eq(2)
+        expect(doc.at("ul").at("li:contains('pears')").attr("class")).to eq("pear")
+        expect(Sawzall.valid_selector?("li:contains(sale)")).to eq(true)
+        expect { doc.select("li:contains(sale) > b") }
+          .to raise_error(ArgumentError, /:contains\(\) is only supported on the last compound selector/)
+      end
+    end
+
+    describe "#root_element" do
+      it "returns the root element" do
+        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+        element = doc.root_element
+
+        expect(element).to be_a(Sawzall::Element)
+        expect(element.name).to eq("html")
+        expect(element.html).to eq("<html><h1>Heading</h1></html>")
+      end
+    end
+
+    describe "#at" do
+      it "returns the first matching element" do
+        doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul>")
+
+        expect(doc.at("li").text).to eq("One")
+        expect(doc.at("ul").at("li:last-child").text).to eq("Two")
+        expect(doc.at("h1")).to be_nil
+        expect(doc.at("ul").at("h1")).to be_nil
+        expect { doc.at("<") }.to raise_error(ArgumentError)
+      end
+
+      it "returns a null element with null_object: true" do
+        doc = Sawzall.parse_fragment("<p>Text</p>")
+        missing = doc.at("header", null_object: true)
+
+        expect(missing).to be_a(Sawzall::NullElement)
+        expect(missing.at("h1").select("a")).to eq([])
+        expect(missing.text).to eq("")
+        expect(missing.attr("id")).to be_nil
+        expect(missing.has_class?("title", case_sensitive: false)).to be(false)
+        expect(doc.at("p", null_object: true).text).to eq("Text")
+        expect(doc.at("p").at("b", null_object: true)).to be_a(Sawzall::NullElement)
+      end
+
+      it "returns attributes shaped like an element's from the null element" do
+        doc = Sawzall.parse_fragment("<p>Text</p>")
+        missing = doc.at("header", null_object: true)
+
+        expect(missing.attrs).to eq([])
+        expect(missing.attrs.class).to eq(doc.at("p").attrs.class)
+        expect(missing.attrs(symbolize_names: true)).to eq(doc.at("p").attrs(symbolize_names: true))
+        expect(missing.attributes).to eq(doc.at("p").attributes)
+      end
+    end
+
+    describe "#at!" do
+      it "returns the first matching element" do
+        doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul>")
+
+        expect(doc.at!("li").text).to eq("One")
+        expect(doc.at!("ul").at!("li + li").text).to eq("Two")
+      end
+
+      it "raises a NotFound pointing at the nearest match" do
+        doc = Sawzall.parse_fragment("<article><p class='byline'>By <b>Jane</b></p></article>")
+
+        expect { doc.at!("article .byline a") }.to raise_error(Sawzall::NotFound) { |error|
+          expect(error.message).to eq(
+            'no element matches "article .byline a", but "article .byline" matches <p class="byline">By <b>Jane</b></p>'
+          )
+          expect(error.selector).to eq("article .byline a")
+          expect(error.nearest_match.attr("class")).to eq("byline")
+          expect(error.snippet).to eq('<p class="byline">By <b>Jane</b></p>')
+        }
+
+        expect { doc.at!("article").at!("h1") }.to raise_error(Sawzall::NotFound) { |error|
+          expect(error.message).to eq('no element matches "h1" in <article><p class="byline">By <b>Jane</b></p></article>')
+          expect(error.nearest_match).to be_nil
+        }
+      end
+
+      it "truncates long snippets" do
+        doc = Sawzall.parse_fragment("<p>#{"a" * 500}</p>")
+
+        expect { doc.at!("h1") }.to raise_error(Sawzall::NotFound) { |error|
+          expect(error.snippet.length).to eq(201)
+          expect(error.snippet).to end_with("…")
+        }
+      end
+    end
+
+    describe "#xpath" do
+      let(:doc) do
+        Sawzall.parse_document(<<~HTML)
+          <h1 id="title">Products</h1>
+          <ul>
+            <li data-price="10">Hammer <b>sale</b></li>
+            <li data-price="25">Saw</li>
+          </ul>
+          <!-- footer -->
+        HTML
+      end
+
+      it "returns the selected nodes" do
+        expect(doc.xpath("//li").map(&:text)).to eq(["Hammer sale", "Saw"])
+        expect(doc.xpath("//li[@data-price > 20]").first).to be_a(Sawzall::Element)
+        expect(doc.xpath("//li[1]/text()").map(&:class)).to eq([Sawzall::TextNode])
+        expect(doc.xpath("//comment()").map(&:text)).to eq([" footer "])
+        expect(doc.xpath("//li/@data-price")).to eq(["10", "25"])
+        expect(doc.xpath("//b/ancestor::ul/preceding-sibling::h1/@id")).to eq(["title"])
+        expect(doc.xpath("//table")).to eq([])
+      end
+
+      it "returns the value of other expressions" do
+        expect(doc.xpath("count(//li)")).to eq(2.0)
+        expect(doc.xpath("normalize-space(//li[1])")).to eq("Hammer sale")
+        expect(doc.xpath("boolean(//b)")).to be(true)
+      end
+
+      it "raises an error for invalid expressions" do
+        expect { doc.xpath("//li[") }
+          .to raise_error(ArgumentError, /failed to evaluate XPath "\/\/li\["\nexpected a step/)
+        expect { doc.xpath("1 | //li") }.to raise_error(ArgumentError, /expected a node-set/)
+      end
+    end
+
+    describe "#annotations" do
+      it "returns the annotated elements in document order" do
+        doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li><li>Three</li></ul>")
+        one, two, three = doc.select("li")
+
+        three.annotation = :last
+        one.annotation = {score: 1}
+        two.annotation = :removed
+        two.remove
+
+        expect(doc.annotations.map { |li, value| [li.text, value] })
+          .to eq([["One", {score: 1}], ["Three", :last]])
+      end
+
+      it "returns an empty hash without annotations" do
+        expect(Sawzall.parse_fragment("<p>One</p>").annotations).to eq({})
+      end
+    end
+
+    describe "#dup and #clone" do
+      it "copies the tree so that changes don't affect the original" do
+        original = Sawzall.parse_fragment("<ul><li>One</li></ul>")
+        copy = original.dup
+
+        copy.at("ul").append_html("<li>Two</li>")
+        copy.at("li").set_attr("class", "first")
+
+        expect(original.to_html).to eq("<ul><li>One</li></ul>")
+        expect(copy.to_html).to eq('<ul><li class="first">One</li><li>Two</li></ul>')
+        expect(original.clone.to_html).to eq(original.to_html)
+      end
+
+      it "keeps node ids, annotations and repairs" do
+        original = Sawzall.parse_fragment("<p>One</p><!-- <p>Two</p>", repair: :conservative)
+        original.at("p").annotation = {score: 1}
+        copy = original.clone
+
+        copy.at("p").annotation = {score: 2}
+
+        expect(copy.element_for_id(original.at("p").node_id).text).to eq("One")
+        expect(original.at("p").annotation).to eq(score: 1)
+        expect(copy.repairs).to eq(original.repairs)
+        expect(original.clone(freeze: true)).to be_frozen
+      end
+    end
+
+    describe "#freeze" do
+      it "keeps the document and its nodes from modifying the tree" do
+        doc = Sawzall.parse_fragment("<p>One</p>").freeze
+        error = [FrozenError, "can't modify frozen Sawzall::Document"]
+
+        expect(doc).to be_frozen
+        expect { doc.at("p").set_attr("class", "a") }.to raise_error(*error)
+        expect { doc.at("p").inner_html = "Two" }.to raise_error(*error)
+        expect { doc.at("p").annotation = 1 }.to raise_error(*error)
+        expect { doc.at("p").child_nodes.first.content = "Two" }.to raise_error(*error)
+        expect { doc.create_element("p") }.to raise_error(*error)
+        expect { doc.strip!(:comments) }.to raise_error(*error)
+        expect(doc.at("p").text).to eq("One")
+        expect(doc.dup.at("p").set_attr("class", "a").html).to eq('<p class="a">One</p>')
+        expect { doc.clone(freeze: true).at("p").remove }.to raise_error(*error)
+      end
+
+      it "lets documents be shared with other Ractors" do
+        doc = Ractor.make_shareable(Sawzall.parse_fragment("<p>One</p><p>Two</p>"))
+        ractors = [0, 1].map do |index|
+          Ractor.new(doc, index) { |shared, i| shared.select("p")[i].text }
+        end
+
+        expect(Ractor.shareable?(doc)).to be(true)
+        expect(ractors.map(&:take)).to eq(["One", "Two"])
+      end
+
+      it "lets elements and other nodes be shared with other Ractors" do
+        doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul><!-- note -->")
+        list = Ractor.make_shareable(doc.at("ul"))
+        comment = Ractor.make_shareable(doc.comments.first)
+        ractor = Ractor.new(list, comment) { |shared, note| [shared.select("li").map(&:text), note.text] }
+
+        expect(Ractor.shareable?(list)).to be(true)
+        expect(ractor.take).to eq([["One", "Two"], " note "])
+        expect { doc.create_element("li") }.to raise_error(FrozenError)
+        expect { list.set_attr("class", "a") }.to raise_error(FrozenError)
+      end
+
+      it "only lets the main Ractor configure Sawzall" do
+        ractor = Ractor.new { Sawzall.defaults }
+
+        expect { ractor.take }.to raise_error(Ractor::RemoteError) { |error|
+          expect(error.cause).to be_a(Ractor::UnsafeError)
+        }
+      end
+    end
+
+    describe "#to_html" do
+      it "re-indents block elements when pretty" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <!DOCTYPE html><title>Title</title>
+          <div><p>One <b>two</b>
+          </p><pre>  three
+            four</pre><textarea> five </textarea></div>
+        HTML
+
+        expect(doc.to_html(pretty: true, indent: 1)).to eq(<<~HTML.chomp)
+          <!DOCTYPE html>
+          <html>
+           <head>
+            <title>Title</title>
+           </head>
+           <body>
+            <div>
+             <p>One <b>two</b></p>
+             <pre>  three
+            four</pre>
+             <textarea> five </textarea>
+            </div>
+           </body>
+          </html>
+        HTML
+      end
+
+      it "minifies" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <div id="main">
+            <!-- Content -->
+            <p title='Hello there'>Hello,
+              <a href="/world">world</a> </p>
+            <pre> as  is </pre>
+          </div>
+        HTML
+
+        expect(doc.to_html(minify: true)).to eq(
+          %(<div id=main><p title="Hello there">Hello, <a href=/world>world</a></p><pre> as  is </pre></div>)
+        )
+        expect { doc.to_html(pretty: true, minify: true) }.to raise_error(ArgumentError)
+      end
+
+      it "writes XHTML" do
+        doc = Sawzall.parse_document(%(<title>A &amp; B</title><p>One<br>two <img src="a.png" alt=""><svg viewBox="0 0 1 1"></svg>))
+
+        expect(doc.to_html(xhtml: true)).to eq(
+          %(<html xmlns="http://www.w3.org/1999/xhtml"><head><title>A &amp; B</title></head><body>) +
+            %(<p>One<br />two <img alt="" src="a.png" /><svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 1 1"></svg></p></body></html>)
+        )
+        expect(doc.to_html(xhtml: true, pretty: true)).to include("    <p>One<br />two")
+      end
+
+      it "is unchanged when not pretty" do
+        doc = Sawzall.parse_fragment("<div>\n  <p>One</p>\n</div>")
+
+        expect(doc.to_html(pretty: false)).to eq(doc.to_html)
+        expect(doc.to_html).to eq("<div>\n  <p>One</p>\n</div>")
+      end
+    end
+
+    describe "Marshal support" do
+      it "round-trips fragments and documents" do
+        fragment = Sawzall.parse_fragment("<p>One</p> <!-- two -->")
+        document = Sawzall.parse_document("<!DOCTYPE html><title>Title</title><p>One</p>")
+
+        fragment_copy = Marshal.load(Marshal.dump(fragment))
+        document_copy = Marshal.load(Marshal.dump(document))
+
+        expect(fragment_copy).to be_a(Sawzall::Document)
+        expect(fragment_copy.to_html).to eq(fragment.to_html)
+        expect(document_copy.to_html).to eq(document.to_html)
+        expect(document_copy.doctype).to eq(document.doctype)
+        expect(document_copy.at("title").text).to eq("Title")
+      end
+
+      it "keeps changes made before dumping" do
+        doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+        doc.at("p").remove
+
+        expect(Marshal.load(Marshal.dump(doc)).select("p").map(&:text)).to eq(["Two"])
+      end
+
+      it "refuses XML documents and invalid data" do
+        xml = Sawzall.parse_xml("<rss><channel/></rss>")
+
+        expect { Marshal.dump(xml) }.to raise_error(TypeError, "XML documents can't be dumped")
+        expect { Sawzall::Document._load("html\n<p>") }
+          .to raise_error(ArgumentError, "invalid dumped document")
+      end
+    end
+
+    describe "#select ordering" do
+      it "returns elements in document order, once, whatever the selector order" do
+        doc = Sawzall.parse_fragment("<h2>A</h2><div><h1>B</h1><h2>C</h2></div><h1>D</h1>")
+
+        expect(doc.select("h1, h2, div > *").map(&:text)).to eq(["A", "B", "C", "D"])
+        expect(doc.at("div").select("h2, h1").map(&:text)).to eq(["B", "C"])
+        expect(doc.select("h1 , h2", namespaces: {}).map(&:text)).to eq(["A", "B", "C", "D"])
+      end
+    end
+
+    describe "#find_by_text" do
+      it "returns the innermost elements whose text matches" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <dl>
+            <dt>
+              ISBN
+            </dt>
+            <dd>978-0-00-000000-0</dd>
+            <dt><b>Pages</b></dt>
+            <dd>320</dd>
+          </dl>
+        HTML
+
+        expect(doc.find_by_text("ISBN").map(&:name)).to eq(["dt"])
+        expect(doc.find_by_text(" Pages ").map(&:name)).to eq(["b"])
+        expect(doc.find_by_text("Pages", selector: "dt").map(&:name)).to eq(["dt"])
+        expect(doc.find_by_text(/^\d+$/).map(&:text)).to eq(["320"])
+        expect(doc.find_by_text(/isbn/i, selector: "dl, dt").map(&:name)).to eq(["dl", "dt"])
+        expect(doc.find_by_text("ISBN 978")).to eq([])
+        expect { doc.find_by_text(/(?=a)/) }.to raise_error(ArgumentError, /unsupported regexp/)
+      end
+    end
+
+    describe "#build_index!" do
+      it "answers simple selectors the same way as without an index" do
+        html = '<p class="a b">One <a class="b">two</a></p><svg><a class="B"></a></svg>'
+        doc = Sawzall.parse_fragment(html)
+        indexed = Sawzall.parse_fragment(html)
+
+        expect(indexed.build_index!).to be(indexed)
+        ["a", "p", ".a", ".b", ".B", "html", "div", "p > a"].each do |selector|
+          expect(indexed.select(selector).map(&:html)).to eq(doc.select(selector).map(&:html))
+          expect(indexed.at(selector)&.html).to eq(doc.at(selector)&.html)
+        end
+      end
+
+      it "reflects changes to the document" do
+        doc = Sawzall.parse_fragment('<p class="a">One</p><p>Two</p>').build_index!
+
+        doc.at("p").remove
+        doc.at("p").set_attr("class", "a")
+        expect(doc.select(".a").map(&:text)).to eq(["Two"])
+        expect(doc.at("p").text).to eq("Two")
+      end
+    end
+
+    describe "#element_for_id" do
+      it "returns the element with the given node id" do
+        doc = Sawzall.parse_document(sample_document)
+        node_ids = doc.select("h1, p").map(&:node_id)
+
+        expect(node_ids.map { |id| doc.element_for_id(id).name }).to eq(["h1", "p"])
+      end
+
+      it "returns nil for unknown ids and non-element nodes" do
+        doc = Sawzall.parse_fragment("<p>text</p>")
+        p_id = doc.select("p").first.node_id
+
+        expect(doc.element_for_id(p_id + 1)).to be_nil
+        expect(doc.element_for_id(1_000_000)).to be_nil
+        expect(doc.element_for_id(1_000_000, null_object: true)).to be_a(Sawzall::NullElement)
+      end
+    end
+
+    describe "#element_by_id" do
+      it "returns the first element with the given id" do
+        doc = Sawzall.parse_fragment('<p id="a">One</p><p id="a">Two</p><p id="">Three</p>')
+
+        expect(doc.element_by_id("a").text).to eq("One")
+        expect(doc.element_by_id("")).to be_nil
+        expect(doc.element_by_id("b")).to be_nil
+        expect(doc.element_by_id("b", null_object: true)).to be_a(Sawzall::NullElement)
+      end
+
+      it "reflects changes to the document" do
+        doc = Sawzall.parse_fragment('<p id="a">One</p><p id="a">Two</p>')
+
+        doc.element_by_id("a").remove
+        expect(doc.element_by_id("a").text).to eq("Two")
+
+        doc.at("p").set_attr("id", "b")
+        expect(doc.element_by_id("a")).to be_nil
+        expect(doc.element_by_id("b").text).to eq("Two")
+
+        doc.at("p").remove_attr("id")
+        expect(doc.element_by_id("b")).to be_nil
+      end
+    end
+
+    describe "#content_security_policies" do
+      it "parses policies declared in meta tags" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <meta http-equiv="content-security-policy" content="default-src 'self'; SCRIPT-SRC 'none'; script-src *">
+          <meta http-equiv="Content-Security-Policy" content="upgrade-insecure-requests">
+        HTML
+
+        expect(doc.content_security_policies).to eq([
+          {"default-src" => ["'self'"], "script-src" => ["'none'"]},
+          {"upgrade-insecure-requests" => []}
+        ])
+      end
+
+      it "returns an empty array when there are no policies" do
+        doc = Sawzall.parse_document(sample_document)
+
+        expect(doc.content_security_policies).to eq([])
+      end
+    end
+
+    describe "#robots_directives" do
+      it "parses robots meta tags and link rel flags" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <meta name="robots" content="noindex, max-image-preview:large">
+          <meta name="googlebot" content="none">
+          <a href="/ad" rel="sponsored">Ad</a>
+          <a href="/about">About</a>
+        HTML
+
+        directives = doc.robots_directives
+        expect(directives[:meta].keys).to eq(["robots", "googlebot"])
+        expect(directives[:meta]["robots"]).to include(
+          noindex: true,
+          nofollow: false,
+          max_image_preview: "large"
+        )
+        expect(directives[:meta]["googlebot"]).to include(noindex: true, nofollow: true)
+        expect(directives[:links]).to eq([
+          {href: "/ad", nofollow: false, ugc: false, sponsored: true}
+        ])
+      end
+
+      it "returns empty collections when there are no directives" do
+        doc = Sawzall.parse_document(sample_document)
+
+        expect(doc.robots_directives).to eq(meta: {}, links: [])
+      end
+    end
+
+    describe "#indexability" do
+      it "returns a single verdict from robots metas and the canonical link" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <link rel="canonical" href="https://Example.com/post#top">
+        HTML
+
+        expect(doc.indexability("https://example.com/post")).to eq(
+          indexable: true,
+          verdict: :indexable,
+          follow: true,
+          canonical_url: "https://example.com/post",
+          reasons: ["canonical link points to the page itself"]
+        )
+        expect(doc.indexability("https://example.com/post?page=2")).to include(
+          indexable: false,
+          verdict: :canonicalized
+        )
+      end
+
+      it "lets noindex win over the canonical link" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <meta name="robots" content="noindex">
+          <link rel="canonical" href="/other">
+        HTML
+
+        expect(doc.indexability("https://example.com/post")).to include(
+          verdict: :noindex,
+          follow: true,
+          canonical_url: "https://example.com/other",
+          reasons: ['<meta name="robots"> has noindex']
+        )
+      end
+
+      it "ignores conflicting canonical links" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <link rel="canonical" href="/one">
+          <link rel="canonical" href="/two">
+        HTML
+
+        expect(doc.indexability("https://example.com/post")).to include(
+          verdict: :indexable,
+          canonical_url: nil,
+          reasons: ["2 conflicting canonical links are ignored"]
+        )
+      end
+
+      it "raises an error for relative URLs" do
+        doc = Sawzall.parse_document("")
+
+        expect { doc.indexability("/post") }.to raise_error(ArgumentError, 'invalid base URL "/post"')
+      end
+    end
+
+    describe "#images" do
+      it "returns images with their parsed srcset" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <img src="a.png" alt="A" width="100px" srcset="a-2x.png 2x, a-400.png 400w, bad.png 1x 2x">
+          <img>
+        HTML
+
+        expect(doc.images).to eq([
+          {
+            src: "a.png",
+            alt: "A",
+            width: 100,
+            height: nil,
+            srcset: [
+              {url: "a-2x.png", width: nil, height: nil, density: 2.0},
+              {url: "a-400.png", width: 400, height: nil, density: nil}
+            ],
+            sizes: nil,
+            sources: []
+          },
+          {src: nil, alt: nil, width: nil, height: nil, srcset: [], sizes: nil, sources: []}
+        ])
+      end
+
+      it "includes the sources of pictures" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <picture>
+            <source srcset="wide.webp 1200w" sizes="100vw" media="(min-width: 800px)" type="image/webp">
+            <img src="narrow.jpg">
+          </picture>
+        HTML
+
+        expect(doc.images.first[:sources]).to eq([
+          {
+            srcset: [{url: "wide.webp", width: 1200, height: nil, density: nil}],
+            sizes: "100vw",
+            media: "(min-width: 800px)",
+            type: "image/webp"
+          }
+        ])
+      end
+    end
+
+    describe "#forms" do
+      it "returns each form with its fields" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <form id="signup" action="/signup" method="post" enctype="multipart/form-data">
+            <input type="hidden" name="token" value="abc">
+            <input type="email" name="email">
+            <input type="checkbox" name="terms" checked>
+            <textarea name="bio">Hello</textarea>
+            <button type="submit" disabled>Sign up</button>
+          </form>
+          <input type="radio" name="plan" value="pro" form="signup">
+        HTML
+
+        expect(doc.forms).to eq([
+          {
+            action: "/signup",
+            method: "post",
+            enctype: "multipart/form-data",
+            fields: [
+              {type: "hidden", name: "token", value: "abc", checked: false, disabled: false, options: []},
+              {type: "email", name: "email", value: "", checked: false, disabled: false, options: []},
+              {type: "checkbox", name: "terms", value: "on", checked: true, disabled: false, options: []},
+              {type: "textarea", name: "bio", value: "Hello", checked: false, disabled: false, options: []},
+              {type: "submit", name: nil, value: "", checked: false, disabled: true, options: []},
+              {type: "radio", name: "plan", value: "pro", checked: false, disabled: false, options: []}
+            ]
+          }
+        ])
+      end
+
+      it "returns the options of selects" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <form>
+            <select name="size">
+              <option disabled>Pick a size</option>
+              <option value="s">Small</option>
+              <optgroup label="Large" disabled><option>XL</option></optgroup>
+            </select>
+            <select name="toppings" multiple>
+              <option selected>Cheese</option>
+              <option>Ham</option>
+            </select>
+          </form>
+        HTML
+
+        size, toppings = doc.forms.first[:fields]
+
+        expect(size).to include(type: "select-one", value: nil)
+        expect(size[:options]).to eq([
+          {value: "Pick a size", text: "Pick a size", selected: false, disabled: true},
+          {value: "s", text: "Small", selected: true, disabled: false},
+          {value: "XL", text: "XL", selected: false, disabled: true}
+        ])
+        expect(toppings[:type]).to eq("select-multiple")
+        expect(toppings[:options].map { it[:selected] }).to eq([true, false])
+      end
+
+      it "returns an empty array without forms" do
+        expect(Sawzall.parse_fragment("<input name='q'>").forms).to eq([])
+      end
+    end
+
+    describe "#outline" do
+      it "returns the headings as a tree" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <h1 id="title">Title</h1>
+          <h2>Intro</h2>
+          <h3><a id="details"></a>Details</h3>
+          <h2>Usage</h2>
+          <h1>Appendix</h1>
+        HTML
+
+        expect(doc.outline).to eq([
+          {
+            level: 1,
+            text: "Title",
+            id: "title",
+            children: [
+              {
+                level: 2,
+                text: "Intro",
+                id: nil,
+                children: [{level: 3, text: "Details", id: "details", children: []}]
+              },
+              {level: 2, text: "Usage", id: nil, children: []}
+            ]
+          },
+          {level: 1, text: "Appendix", id: nil, children: []}
+        ])
+      end
+
+      it "returns an empty array without headings" do
+        expect(Sawzall.parse_fragment("<p>Paragraph</p>").outline).to eq([])
+      end
+    end
+
+    describe "#chunks" do
+      let(:doc) do
+        Sawzall.parse_document(<<~HTML)
+          <h1>Install</h1>
+          <p>Run the installer.</p>
+          <h1>Usage</h1>
+          <p>Call the command with a file.</p>
+        HTML
+      end
+
+      it "splits the content into chunks within sections" do
+        expect(doc.chunks(max_tokens: 50)).to eq([
+          {
+            text: "Install\n\nRun the installer.",
+            tokens: 4,
+            paths: ["html > body > h1:nth-of-type(1)", "html > body > p:nth-of-type(1)"]
+          },
+          {
+            text: "Usage\n\nCall the command with a file.",
+            tokens: 7,
+            paths: ["html > body > h1:nth-of-type(2)", "html > body > p:nth-of-type(2)"]
+          }
+        ])
+      end
+
+      it "returns paths that select the source elements" do
+        paths = doc.chunks(max_tokens: 50).flat_map { it[:paths] }
+
+        expect(paths.map { doc.select(it).map(&:text) }).to eq([
+          ["Install"], ["Run the installer."], ["Usage"], ["Call the command with a file."]
+        ])
+      end
+
+      it "splits paragraphs that don't fit, with overlap" do
+        expect(doc.chunks(max_tokens: 3, overlap: 1).map { it[:text] }).to eq([
+          "Install", "Run the installer.", "Usage", "Call the command", "command with a", "a file."
+        ])
+      end
+
+      it "fills chunks across headings by paragraphs" do
+        expect(doc.chunks(max_tokens: 5, by: :paragraphs).map { it[:text] }).to eq([
+          "Install\n\nRun the installer.\n\nUsage", "Call the command with a", "file."
+        ])
+      end
+
+      it "validates its options" do
+        expect { doc.chunks }.to raise_error(ArgumentError, "missing keyword: :max_tokens")
+        expect { doc.chunks(max_tokens: 0) }.to raise_error(ArgumentError, "max_tokens must be positive")
+        expect { doc.chunks(max_tokens: 2, overlap: 2) }.to raise_error(
+          ArgumentError, "overlap (2) must be less than max_tokens (2)"
+        )
+        expect { doc.chunks(max_tokens: 2, by: :words) }.to raise_error(
+          ArgumentError, "unknown chunk boundary :words, expected one of :headings, :paragraphs"
+        )
+      end
+    end
+
+    describe "#probable_type" do
+      it "recognizes articles" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <meta property="og:type" content="article">
+          <article>
+            <h1>Headline</h1>
+            <time datetime="2024-05-01">May 1st</time>
+            #{"<p>#{"Lorem ipsum dolor sit amet. " * 10}</p>" * 3}
+          </article>
+        HTML
+
+        result = doc.probable_type
+        expect(result[:type]).to eq(:article)
+        expect(result[:scores]).to eq(error: 0, login: 0, product: 0, article: 8, listing: 0)
+        expect(result[:evidence].map { it[:reason] }).to eq([
+          "og:type is article",
+          "has an <article> element",
+          "has 3 paragraphs of 150+ characters",
+          "has a publication date"
+        ])
+      end
+
+      it "recognizes listings, ignoring navigation menus" do
+        menu = "<li><a href='/section'>Section</a></li>" * 6
+        items = "<div class='result'><a href='/item'>Item</a></div>" * 6
+        doc = Sawzall.parse_document(<<~HTML)
+          <nav><ul>#{menu}</ul></nav>
+          <main>#{items}</main>
+          <a rel="next" href="?page=2">Next</a>
+        HTML
+
+        expect(doc.probable_type[:type]).to eq(:listing)
+        expect(doc.probable_type[:scores][:listing]).to eq(4)
+        expect(Sawzall.parse_document("<nav><ul>#{menu}</ul></nav>").probable_type[:type]).to eq(:unknown)
+      end
+
+      it "recognizes products, login forms and error pages" do
+        product = Sawzall.parse_document(<<~HTML)
+          <div itemscope itemtype="https://schema.org/Product">
+            <span itemprop="price">$10</span><button>Add to cart</button>
+          </div>
+        HTML
+        login = Sawzall.parse_document("<form><input name='user'><input type='password'></form>")
+        error = Sawzall.parse_document("<title>Page not found</title><form><input type='password'></form>")
+
+        expect(product.probable_type[:type]).to eq(:product)
+        expect(login.probable_type[:type]).to eq(:login)
+        expect(error.probable_type[:type]).to eq(:error)
+      end
+
+      it "returns :unknown without enough evidence" do
+        result = Sawzall.parse_fragment("<p>Hello</p>").probable_type
+
+        expect(result).to eq(
+          type: :unknown,
+          evidence: [],
+          scores: {error: 0, login: 0, product: 0, article: 0, listing: 0}
+        )
+      end
+    end
+
+    describe "#strip!" do
+      let(:html) do
+        <<~HTML
+          <link rel="stylesheet" href="site.css"><style>p {}</style>
+          <p>Hello<!-- comment --></p>
+          <script>alert(1)</script><noscript>No JS</noscript>
+          <form><input name="q"></form>
+        HTML
+      end
+
+      it "removes the given categories and returns the document" do
+        doc = Sawzall.parse_fragment(html)
+
+        expect(doc.strip!(:scripts, :comments)).to equal(doc)
+        expect(doc.select("script, noscript")).to be_empty
+        expect(doc.select("p").first.html).to eq("<p>Hello</p>")
+        expect(doc.select("style, link, form").map(&:name)).to eq(["link", "style", "form"])
+      end
+
+      it "removes every category when none are given" do
+        doc = Sawzall.parse_fragment(html).strip!
+
+        expect(doc.root_element.text).to eq("Hello")
+        expect(doc.select("link, style, script, noscript, form")).to be_empty
+      end
+
+      it "raises an error for unknown categories" do
+        doc = Sawzall.parse_fragment(html)
+
+        expect { doc.strip!(:images) }
+          .to raise_error(ArgumentError, /unknown category :images/)
+      end
+    end
+
+    describe "#remove!" do
+      it "removes every matching element at once" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <p>Article <img src="/ad.png" class="ad-slot"></p>
+          <div class="ad-slot"><span class="ad-slot">Ad</span></div>
+        HTML
+
+        removed = doc.remove!(".ad-slot")
+
+        expect(removed.map(&:name)).to eq(["img", "div", "span"])
+        expect(doc.select(".ad-slot, img")).to be_empty
+        expect(doc.to_html.strip).to eq("<p>Article </p>")
+        expect(removed[1].select("span").map(&:text)).to eq(["Ad"])
+        expect(doc.remove!(".ad-slot")).to eq([])
+      end
+
+      it "leaves the root element and frozen documents alone" do
+        doc = Sawzall.parse_fragment("<p>Text</p>")
+
+        expect(doc.remove!("html")).to eq([])
+        expect(doc.to_html).to eq("<p>Text</p>")
+        expect { doc.freeze.remove!("p") }.to raise_error(FrozenError)
+      end
+    end
+
+    describe "#inline_css!" do
+      it "moves style rules into style attributes and returns the document" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <style>
+            p { color: red; margin: 0 }
+            .intro { color: blue !important }
+          </style>
+          <p class="intro" style="color: green">Hello</p>
+          <p style="margin: 1em">World</p>
+        HTML
+
+        expect(doc.inline_css!).to equal(doc)
+        expect(doc.select("style")).to be_empty
+        expect(doc.select("p").map { |p| p.attr("style") })
+          .to eq(["color: blue; margin: 0", "color: red; margin: 1em"])
+      end
+
+      it "keeps rules that can't be inlined" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <style>a:hover { color: red } @media print { a { color: black } } a { color: blue }</style>
+          <a href="/">Home</a>
+        HTML
+
+        doc.inline_css!
+
+        expect(doc.select("a").first.attr("style")).to eq("color: blue")
+        expect(doc.select("style").first.text)
+          .to eq("a:hover { color: red }\n@media print { a { color: black } }\n")
+      end
+    end
+
+    describe "#absolutize_urls!" do
+      it "resolves relative URLs and returns the document" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <a href="post">Post</a>
+          <a href="https://other.example/">Other</a>
+          <img src="/a.png" srcset="a-2x.png 2x, /a-3x.png 3x">
+        HTML
+
+        expect(doc.absolutize_urls!("https://example.com/blog/")).to equal(doc)
+        expect(doc.select("a").map { |a| a.attr("href") })
+          .to eq(["https://example.com/blog/post", "https://other.example/"])
+        expect(doc.select("img").first.attr("src")).to eq("https://example.com/a.png")
+        expect(doc.select("img").first.attr("srcset"))
+          .to eq("https://example.com/blog/a-2x.png 2x, https://example.com/a-3x.png 3x")
+      end
+
+      it "uses the document's base element" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <head><base href="/docs/"></head>
+          <body><a href="intro">Intro</a></body>
+        HTML
+
+        doc.absolutize_urls!("https://example.com/")
+
+        expect(doc.select("a").first.attr("href")).to eq("https://example.com/docs/intro")
+      end
+
+      it "raises an error if the base URL isn't absolute" do
+        doc = Sawzall.parse_fragment("<a href='post'>Post</a>")
+
+        expect { doc.absolutize_urls!("/blog/") }
+          .to raise_error(ArgumentError, 'invalid base URL "/blog/"')
+      end
+    end
+
+    describe "#base_uri" do
+      it "resolves the base element against the document URL" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <head><base target="_blank"><base href="../assets/"></head>
+        HTML
+
+        expect(doc.base_uri("https://example.com/blog/post")).to eq("https://example.com/assets/")
+        expect(doc.base_uri).to be_nil
+      end
+
+      it "returns absolute base elements without a document URL" do
+        doc = Sawzall.parse_document("<base href='https://cdn.example.com/'>")
+
+        expect(doc.base_uri).to eq("https://cdn.example.com/")
+      end
+
+      it "falls back to the document URL" do
+        doc = Sawzall.parse_document(sample_document)
+
+        expect(doc.base_uri("https://example.com/")).to eq("https://example.com/")
+      end
+
+      it "raises an error if the document URL isn't absolute" do
+        doc = Sawzall.parse_document(sample_document)
+
+        expect { doc.base_uri("blog") }.to raise_error(ArgumentError, 'invalid base URL "blog"')
+      end
+    end
+
+    describe "#doctype" do
+      it "returns the HTML5 doctype without identifiers" do
+        doc = Sawzall.parse_document("<!doctype HTML><title>Title</title>")
+
+        expect(doc.doctype).to eq(name: "html", public_id: nil, system_id: nil)
+      end
+
+      it "returns the identifiers of legacy doctypes" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN"
+            "http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd">
+          <title>Title</title>
+        HTML
+
+        expect(doc.doctype).to eq(
+          name: "html",
+          public_id: "-//W3C//DTD XHTML 1.0 Transitional//EN",
+          system_id: "http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd"
+        )
+      end
+
+      it "returns nil without a doctype" do
+        expect(Sawzall.parse_document("<p>Paragraph</p>").doctype).to be_nil
+        expect(Sawzall.parse_fragment("<!DOCTYPE html><p>Paragraph</p>").doctype).to be_nil
+      end
+    end
+
+    describe "#quirks_mode" do
+      it "returns the mode picked from the doctype" do
+        legacy = '<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01 Transitional//EN"%s><p>Hi</p>'
+
+        expect(Sawzall.parse_document("<!DOCTYPE html><p>Hi</p>").quirks_mode).to eq(:no_quirks)
+        expect(Sawzall.parse_document(legacy % ' "http://www.w3.org/TR/html4/loose.dtd"').quirks_mode).to eq(:limited_quirks)
+        expect(Sawzall.parse_document(legacy % "").quirks_mode).to eq(:quirks)
+        expect(Sawzall.parse_document("<p>Hi</p>")).to be_quirks_mode
+        expect(Sawzall.parse_fragment("<p>Hi</p>")).not_to be_quirks_mode
+      end
+    end
+
+    describe "#comments" do
+      it "returns every comment in document order" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <!-- before -->
+          <html><body><p>Text<!-- {"id": 1} --></p></body></html>
+          <!-- after -->
+        HTML
+
+        expect(doc.comments.map(&:text)).to eq([" before ", ' {"id": 1} ', " after "])
+        expect(doc.comments[1].html).to eq('<!-- {"id": 1} -->')
+      end
+
+      it "skips removed comments" do
+        doc = Sawzall.parse_fragment("<div><!-- ad --></div><p><!-- kept --></p>")
+        doc.at("div").remove
+
+        expect(doc.comments.map(&:content)).to eq([" kept "])
+      end
+    end
+
+    describe "#each_element" do
+      it "yields every element in document order" do
+        doc = Sawzall.parse_document("<title>Title</title><p>Text <b>bold</b></p>")
+
+        expect(doc.each_element.map(&:name)).to eq(["html", "head", "title", "body", "p", "b"])
+        expect(doc.each_element { }).to be(doc)
+      end
+
+      it "leaves out the element fragments are wrapped in" do
+        doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+
+        expect(doc.each_element.map(&:text)).to eq(["One", "Two"])
+      end
+
+      it "skips the descendants of elements for which the block returns :skip_children" do
+        doc = Sawzall.parse_fragment("<header><nav><a>Home</a></nav></header><main><a>Post</a></main>")
+        names = []
+
+        doc.each_element do |element|
+          names << element.name
+          element.name == "nav" ? :skip_children : nil
+        end
+
+        expect(names).to eq(["header", "nav", "main", "a"])
+      end
+    end
+
+    describe "#each_node" do
+      it "yields every node in document order" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <!DOCTYPE html PUBLIC "-//W3C//DTD HTML 4.01//EN" "http://www.w3.org/TR/html4/strict.dtd">
+          <!-- before -->
+          <title>Title</title>
+        HTML
+        nodes = doc.each_node.to_a
+
+        expect(nodes.map(&:class)).to eq([
+          Sawzall::Doctype, Sawzall::Comment, Sawzall::Element, Sawzall::Element,
+          Sawzall::Element, Sawzall::TextNode, Sawzall::TextNode, Sawzall::Element
+        ])
+        expect(nodes.first.name).to eq("html").and be_frozen
+        expect(nodes.first.public_id).to eq("-//W3C//DTD HTML 4.01//EN")
+        expect(nodes.first.system_id).to eq("http://www.w3.org/TR/html4/strict.dtd")
+        expect(nodes.first.html).to eq("<!DOCTYPE html>")
+        expect(nodes.grep(Sawzall::Element).map(&:name)).to eq(["html", "head", "title", "body"])
+      end
+
+      it "allows modifying the document from the block" do
+        doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+
+        result = doc.each_node { it.remove if it.is_a?(Sawzall::Element) && it.text == "One" }
+
+        expect(result).to be(doc)
+        expect(doc.to_html).to eq("<p>Two</p>")
+      end
+    end
+
+    describe "#tag_counts" do
+      it "counts the elements of each name in document order" do
+        doc = Sawzall.parse_document("<title>Title</title><p>One</p><p>Two <b>!</b></p>")
+
+        expect(doc.tag_counts).to eq({"html" => 1, "head" => 1, "title" => 1, "body" => 1, "p" => 2, "b" => 1})
+      end
+
+      it "reflects changes to the document" do
+        doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul>")
+
+        expect(doc.tag_counts).to eq({"ul" => 1, "li" => 2})
+
+        doc.at("li").remove
+        doc.at("ul").add_child("<li>Three</li><li>Four</li>")
+        expect(doc.tag_counts).to eq({"ul" => 1, "li" => 3})
+      end
+    end
+
+    describe "#stats" do
+      it "profiles the document" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <!DOCTYPE html>
+          <title>Title</title>
+          <!-- comment -->
+          <p>One <b>two</b></p>
+        HTML
+
+        expect(doc.stats).to eq(
+          nodes: {element: 6, text: 6, comment: 1, doctype: 1, processing_instruction: 0},
+          tags: doc.tag_counts,
+          max_depth: 5,
+          text_length: doc.root_element.text_length
+        )
+      end
+
+      it "reflects changes to the document" do
+        doc = Sawzall.parse_fragment("<p>One</p>")
+
+        doc.at("p").add_child("<b>Two</b>")
+
+        expect(doc.stats).to include(tags: {"p" => 1, "b" => 1}, max_depth: 4, text_length: 6)
+      end
+    end
+
+    describe "ObjectSpace.memsize_of" do
+      it "includes the size of the tree" do
+        small = Sawzall.parse_fragment("<p>One</p>")
+        large = Sawzall.parse_fragment("<p>#{"x" * 100_000}</p>" * 10)
+
+        expect(ObjectSpace.memsize_of(large)).to be > 1_000_000
+        expect(ObjectSpace.memsize_of(small)).to be < 10_000
+      end
+
+      it "is updated when the document changes" do
+        doc = Sawzall.parse_fragment("<p>One</p>")
+        before = ObjectSpace.memsize_of(doc)
+
+        doc.at("p").add_child("<b>#{"x" * 10_000}</b>")
+
+        expect(ObjectSpace.memsize_of(doc)).to be > before + 10_000
+      end
+
+      it "only counts an element's own node" do
+        doc = Sawzall.parse_fragment("<div><p title='#{"x" * 10_000}'>#{"y" * 100_000}</p></div>")
+
+        expect(ObjectSpace.memsize_of(doc.at("p"))).to be_between(10_000, 100_000)
+        expect(ObjectSpace.memsize_of(doc.at("div"))).to be < 10_000
+      end
+    end
+
+    describe "memory usage reported to the GC" do
+      it "includes the size of the tree" do
+        html = "<p>#{"x" * 1_000_000}</p>"
+        GC.disable
+        before = GC.stat(:malloc_increase_bytes)
+
+        Sawzall.parse_fragment(html)
+
+        expect(GC.stat(:malloc_increase_bytes) - before).to be > 1_000_000
+      ensure
+        GC.enable
+      end
+    end
+
+    describe "#create_element" do
+      it "creates a detached element that can be added to the document" do
+        doc = Sawzall.parse_fragment("<nav></nav>")
+        link = doc.create_element("A", "HREF" => "/", :class => "home")
+
+        expect(link.name).to eq("a")
+        expect(link.attrs.to_h).to eq({"href" => "/", "class" => "home"})
+        expect(doc.to_html).to eq("<nav></nav>")
+
+        doc.at("nav").add_child(link.add_child("Home"))
+        expect(doc.to_html).to eq('<nav><a class="home" href="/">Home</a></nav>')
+        expect(doc.at("nav a").text).to eq("Home")
+      end
+
+      it "rejects invalid names" do
+        doc = Sawzall.parse_fragment("")
+
+        expect { doc.create_element("1p") }.to raise_error(ArgumentError, 'invalid element name "1p"')
+        expect { doc.create_element("p", "a b" => "c") }.to raise_error(ArgumentError, 'invalid attribute name "a b"')
+      end
+    end
+
+    describe "#create_text_node" do
+      it "creates a detached text node that is escaped when serialized" do
+        doc = Sawzall.parse_fragment("<p></p><script></script>")
+        text = doc.create_text_node("1 < 2 & 3")
+
+        expect(text.text).to eq("1 < 2 & 3")
+        expect(text.html).to eq("1 &lt; 2 &amp; 3")
+        expect(doc.to_html).to eq("<p></p><script></script>")
+
+        doc.at("p").add_child(text)
+        expect(doc.to_html).to eq("<p>1 &lt; 2 &amp; 3</p><script></script>")
+        expect(doc.at("p").text).to eq("1 < 2 & 3")
+
+        doc.at("script").add_child(text)
+        expect(text.html).to eq("1 < 2 & 3")
+        expect(doc.to_html).to eq("<p></p><script>1 < 2 & 3</script>")
+      end
+
+      it "copies text nodes of other documents" do
+        doc = Sawzall.parse_fragment("<p></p>")
+        text = Sawzall.parse_fragment("").create_text_node("Hello")
+
+        doc.at("p").add_child(text).add_child(text)
+        expect(doc.to_html).to eq("<p>HelloHello</p>")
+      end
+    end
+
+    describe "#+" do
+      it "returns a new fragment followed by the other part" do
+        doc = Sawzall.parse_fragment("<p>One</p>")
+
+        expect((doc + Sawzall.parse_fragment("<p>Two</p>")).to_html).to eq("<p>One</p><p>Two</p>")
+        expect((doc + "<b>").to_html).to eq("<p>One</p>&lt;b&gt;")
+        expect(doc.to_html).to eq("<p>One</p>")
+      end
+    end
+
+    describe "#equivalent_to?" do
+      it "ignores formatting, attribute order and comments by default" do
+        doc = Sawzall.parse_fragment('<ul class="nav" id="menu"><li>Home  page</li></ul>')
+        formatted = Sawzall.parse_fragment(<<~HTML)
+          <ul id="menu" class="nav">
+            <!-- Links -->
+            <li>
+              Home page
+            </li>
+          </ul>
+        HTML
+
+        expect(doc.equivalent_to?(formatted)).to be(true)
+        expect(doc.equivalent_to?(doc)).to be(true)
+        expect(doc.equivalent_to?(formatted, ignore_whitespace: false)).to be(false)
+        expect(doc.equivalent_to?(formatted, ignore_comments: false)).to be(false)
+      end
+
+      it "compares names, attributes and text" do
+        doc = Sawzall.parse_fragment('<p class="a">Hello</p>')
+
+        expect(doc.equivalent_to?(Sawzall.parse_fragment('<div class="a">Hello</div>'))).to be(false)
+        expect(doc.equivalent_to?(Sawzall.parse_fragment('<p class="b">Hello</p>'))).to be(false)
+        expect(doc.equivalent_to?(Sawzall.parse_fragment('<p class="a" hidden>Hello</p>'))).to be(false)
+        expect(doc.equivalent_to?(Sawzall.parse_fragment('<p class="a">Hello!</p>'))).to be(false)
+      end
+
+      it "rejects unknown options" do
+        doc = Sawzall.parse_fragment("<p>Hello</p>")
+
+        expect { doc.equivalent_to?(doc, ignore_case: true) }.to raise_error(ArgumentError)
+      end
+
+      it "always ignores the order of attributes" do
+        doc = Sawzall.parse_fragment('<p class="a" id="b">Hello</p>')
+        other = Sawzall.parse_fragment('<p id="b" class="a">Hello</p>')
+
+        expect(doc.equivalent_to?(other, ignore_attr_order: true)).to be(true)
+        expect { doc.equivalent_to?(other, ignore_attr_order: false) }
+          .to raise_error(ArgumentError, /ignore_attr_order: false isn't supported/)
+      end
+    end
+
+    describe "#to_html" do
+      it "serializes fragments without a wrapping element" do
+        doc = Sawzall.parse_fragment("<p class=a>Hello</p>")
+
+        expect(doc.to_html).to eq('<p class="a">Hello</p>')
+      end
+
+      it "serializes whole documents" do
+        doc = Sawzall.parse_document("<!DOCTYPE html><p>Hello</p>")
+
+        expect(doc.to_html).to eq("<!DOCTYPE html><html><head></head><body><p>Hello</p></body></html>")
+      end
+    end
+  end
+
+  describe Sawzall::Element do
+    describe "#name" do
+      it "returns the element's name" do
+        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+
+        expect(doc.select("h1").first.name).to eq("h1")
+      end
+
+      it "returns the same frozen string every time" do
+        doc = Sawzall.parse_fragment("<h1>One</h1><h1>Two</h1>")
+        one, two = doc.select("h1")
+
+        expect(one.name).to be_frozen
+        expect(one.name).to equal(two.name)
+      end
+
+      it "returns a symbol with symbolize_names: true" do
+        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+
+        expect(doc.at("h1").name(symbolize_names: true)).to eq(:h1)
+        expect(doc.at("h2", null_object: true).name(symbolize_names: true)).to be_nil
+      end
+    end
+
+    describe "#namespace" do
+      it "returns the element's namespace URI" do
+        doc = Sawzall.parse_fragment("<p>Text</p><svg><rect/></svg><math><mi>x</mi></math>")
+
+        expect(doc.at("p").namespace).to eq("http://www.w3.org/1999/xhtml")
+        expect(doc.at("rect").namespace).to eq("http://www.w3.org/2000/svg")
+        expect(doc.at("mi").namespace).to eq("http://www.w3.org/1998/Math/MathML")
+      end
+
+      it "returns the same frozen string every time" do
+        doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+        one, two = doc.select("p")
+
+        expect(one.namespace).to be_frozen
+        expect(one.namespace).to equal(two.namespace)
+      end
+    end
+
+    describe "#name_sym" do
+      it "returns the element's name as a symbol" do
+        doc = Sawzall.parse_fragment("<h1>Heading</h1><my-element></my-element>")
+
+        expect(doc.select("h1").first.name_sym).to eq(:h1)
+        expect(doc.select("my-element").first.name_sym).to eq(:"my-element")
+      end
+    end
+
+    describe "#html" do
+      it "returns the element's outer HTML" do
+        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+
+        expect(doc.select("h1").first.html).to eq("<h1>Heading</h1>")
+      end
+
+      it "raises or truncates when exceeding the given limits" do
+        doc = Sawzall.parse_fragment("<div><p>Café</p><p>#{"x" * 1000}</p></div>")
+        div = doc.at("div")
+
+        expect(div.html(max_output_bytes: 1_030, max_nodes: 5)).to eq(div.html)
+        expect { div.html(max_output_bytes: 1_000) }
+          .to raise_error(Sawzall::OutputTooLargeError, "output exceeds max_output_bytes (1000)")
+        expect { div.inner_html(max_nodes: 4) }
+          .to raise_error(Sawzall::OutputTooLargeError, "element has more than max_nodes (4) nodes")
+        expect(div.inner_html(max_output_bytes: 7, truncate: true)).to eq("<p>Caf")
+        expect(div.text(max_output_bytes: 3, truncate: true)).to eq("Caf")
+        expect { div.text(max_output_bytes: 3) }.to raise_error(Sawzall::OutputTooLargeError)
+      end
+    end
+
+    describe "#inner_html" do
+      it "returns the element's inner HTML" do
+        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+
+        expect(doc.select("h1").first.inner_html).to eq("Heading")
+      end
+
+      it "returns an empty string if there is no inner HTML" do
+        doc = Sawzall.parse_fragment("<img src='https://example.com/image.png'/>")
+
+        expect(doc.select("img").first.inner_html).to eq("")
+      end
+    end
+
+    describe "#attr" do
+      it "returns the attribute value" do
+        doc = Sawzall.parse_fragment("<h1 id='main-heading'>Heading</h1>")
+
+        expect(doc.select("h1").first.attr("id")).to eq("main-heading")
+      end
+
+      it "returns nil when the attribute does not exist" do
+        doc = Sawzall.parse_fragment("<h1 id='main-heading'>Heading</h1>")
+
+        expect(doc.select("h1").first.attr("class")).to be_nil
+      end
+
+      it "finds prefixed attributes by their full name" do
+        use = Sawzall.parse_fragment("<svg><use xlink:href='#icon' href='#other'/></svg>").at("use")
+
+        expect(use.attr("xlink:href")).to eq("#icon")
+        expect(use.attr("href")).to eq("#other")
+        expect(use.attr!("xlink:href")).to eq("#icon")
+      end
+    end
+
+    describe "#id" do
+      it "returns the id attribute" do
+        doc = Sawzall.parse_fragment("<h1 id='main-heading'>Heading</h1><p>Text</p>")
+
+        expect(doc.at("h1").id).to eq("main-heading")
+        expect(doc.at("p").id).to be_nil
+        expect(doc.at("table", null_object: true).id).to be_nil
+      end
+
+      it "follows changes to the attribute" do
+        h1 = Sawzall.parse_fragment("<h1 id='one'>Heading</h1>").at("h1")
+
+        h1.set_attr("id", "two")
+        expect(h1.id).to eq("two")
+
+        h1.remove_attr("id")
+        expect(h1.id).to be_nil
+      end
+    end
+
+    describe "#attr_ns" do
+      it "returns the attribute in the given namespace" do
+        use = Sawzall.parse_fragment("<svg><use xlink:href='#icon' href='#other'/></svg>").at("use")
+
+        expect(use.attr_ns("http://www.w3.org/1999/xlink", "href")).to eq("#icon")
+        expect(use.attr_ns("", "href")).to eq("#other")
+        expect(use.attr_ns("http://www.w3.org/2000/svg", "href")).to be_nil
+      end
+    end
+
+    describe "#attr!" do
+      it "returns the attribute or raises a NotFound" do
+        link = Sawzall.parse_fragment("<a class='next'>Next</a>").at("a")
+
+        expect(link.attr!("class")).to eq("next")
+        expect { link.attr!("href") }.to raise_error(Sawzall::NotFound) { |error|
+          expect(error.message).to eq('no "href" attribute on <a class="next">Next</a>')
+          expect(error.attribute).to eq("href")
+          expect(error.selector).to be_nil
+        }
+      end
+    end
+
+    describe "#attrs" do
+      it "returns an array of key-value pairs" do
+        doc = Sawzall.parse_fragment("<h1 id='heading' class='big' data-foo='bar'>Heading</h1>")
+
+        expect(doc.select("h1").first.attrs).to eq([
+          ["class", "big"],
+          ["data-foo", "bar"],
+          ["id", "heading"]
+        ])
+      end
+
+      it "returns frozen keys" do
+        doc = Sawzall.parse_fragment("<h1 id='heading'>Heading</h1>")
+        key, value = doc.select("h1").first.attrs.first
+
+        expect(key).to be_frozen
+        expect(value).not_to be_frozen
+      end
+
+      it "returns symbol keys with symbolize_names: true" do
+        doc = Sawzall.parse_fragment("<h1 id='heading' data-foo='bar'>Heading</h1>")
+
+        expect(doc.at("h1").attrs(symbolize_names: true)).to eq([[:"data-foo", "bar"], [:id, "heading"]])
+      end
+
+      it "returns an empty array if there are no attributes" do
+        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+
+        expect(doc.select("h1").first.attrs).to eq([])
+      end
+
+      it "includes attribute prefixes" do
+        use = Sawzall.parse_fragment("<svg><use xlink:href='#icon'/></svg>").at("use")
+
+        expect(use.attrs).to eq([["xlink:href", "#icon"]])
+        expect(use.attr_names).to eq(["xlink:href"])
+        expect(use.each_attr.to_a).to eq([["xlink:href", "#icon"]])
+      end
+    end
+
+    describe "#each_attr" do
+      it "yields each attribute's name and value" do
+        heading = Sawzall.parse_fragment("<h1 id='heading' class='big'>Heading</h1>").select("h1").first
+        yielded = []
+
+        expect(heading.each_attr { |name, value| yielded << [name, value] }).to equal(heading)
+        expect(yielded).to eq([["class", "big"], ["id", "heading"]])
+      end
+
+      it "returns an enumerator without a block" do
+        heading = Sawzall.parse_fragment("<h1 id='heading' class='big'>Heading</h1>").select("h1").first
+
+        expect(heading.each_attr.to_a).to eq(heading.attrs)
+      end
+
+      it "lets the block use the element" do
+        heading = Sawzall.parse_fragment("<h1 id='heading' class='big'>Heading</h1>").select("h1").first
+
+        expect(heading.each_attr.map { |name, _| heading.attr(name) }).to eq(["big", "heading"])
+      end
+    end
+
+    describe "#attributes" do
+      it "returns a hash, keeping the first of repeated attributes" do
+        doc = Sawzall.parse_fragment("<a href='/one' data-id=1 href='/two'>One</a><b>Two</b>")
+
+        expect(doc.at("a").attributes).to eq("href" => "/one", "data-id" => "1")
+        expect(doc.at("a").attributes.keys).to all(be_frozen)
+        expect(doc.at("a").attributes(symbolize_names: true)).to eq(href: "/one", "data-id": "1")
+        expect(doc.at("b").attributes).to eq({})
+        expect(doc.at("i", null_object: true).attributes).to eq({})
+      end
+    end
+
+    describe "#data" do
+      it "returns the data attributes with underscored names" do
+        doc = Sawzall.parse_fragment("<div data-user-id='42' data-Role='admin' data-='' title='Card'></div>")
+
+        expect(doc.at("div").data).to eq("user_id" => "42", "role" => "admin")
+        expect(doc.at("div").data(symbolize_names: true)).to eq(user_id: "42", role: "admin")
+        expect(doc.at("p", null_object: true).data).to eq({})
+      end
+    end
+
+    describe "#attr_names" do
+      it "returns the attribute names" do
+        doc = Sawzall.parse_fragment("<h1 id='heading' class='big'>Heading</h1><h2>Subheading</h2>")
+
+        expect(doc.select("h1").first.attr_names).to eq(["class", "id"])
+        expect(doc.select("h2").first.attr_names).to eq([])
+        expect(doc.select("h1").first.attr_names(symbolize_names: true)).to eq([:class, :id])
+      end
+    end
+
+    describe "#set_attr" do
+      it "adds or replaces the attribute in the document" do
+        doc = Sawzall.parse_fragment("<a href='/old' class='link'>Link</a>")
+        link = doc.select("a").first
+
+        expect(link.set_attr("href", "/new")).to equal(link)
+        link.set_attr("Target", "_blank")
+
+        expect(link.attr("href")).to eq("/new")
+        expect(link.attr("target")).to eq("_blank")
+        expect(doc.to_html).to eq('<a class="link" href="/new" target="_blank">Link</a>')
+        expect(doc.select("a[target=_blank]").size).to eq(1)
+      end
+
+      it "updates classes and ids used for matching" do
+        doc = Sawzall.parse_fragment("<p class='old'>Text</p>")
+        paragraph = doc.select("p").first
+
+        paragraph.set_attr("class", "new")
+
+        expect(paragraph.classes).to eq(["new"])
+        expect(paragraph).not_to have_class("old")
+        expect(doc.select("p.new").size).to eq(1)
+      end
+
+      it "raises for invalid attribute names" do
+        paragraph = Sawzall.parse_fragment("<p>Text</p>").select("p").first
+
+        expect { paragraph.set_attr("on click", "x") }.to raise_error(ArgumentError, 'invalid attribute name "on click"')
+        expect { paragraph.set_attr("", "x") }.to raise_error(ArgumentError)
+      end
+    end
+
+    describe "#remove_attr" do
+      it "removes the attribute from the document" do
+        doc = Sawzall.parse_fragment("<p id='intro' style='color: red'>Text</p>")
+        paragraph = doc.select("p").first
+
+        expect(paragraph.remove_attr("style")).to equal(paragraph)
+        paragraph.remove_attr("missing")
+
+        expect(paragraph.attr("style")).to be_nil
+        expect(doc.to_html).to eq('<p id="intro">Text</p>')
+      end
+    end
+
+    describe "class list helpers" do
+      it "adds, removes and toggles classes without duplicates" do
+        doc = Sawzall.parse_fragment("<p class=' a  b a'>Text</p>")
+        paragraph = doc.at("p")
+
+        expect(paragraph.add_class("c", "b")).to equal(paragraph)
+        expect(paragraph.classes).to eq(["a", "b", "c"])
+
+        expect(paragraph.remove_class("a", "missing")).to equal(paragraph)
+        expect(doc.to_html).to eq('<p class="b c">Text</p>')
+        expect(paragraph.has_class?("a")).to be(false)
+
+        expect(paragraph.toggle_class("b").toggle_class("d")).to equal(paragraph)
+        paragraph.toggle_class("c", true).toggle_class("e", false)
+        expect(doc.to_html).to eq('<p class="c d">Text</p>')
+
+        paragraph.remove_class("c", "d")
+        expect(doc.to_html).to eq("<p>Text</p>")
+        expect(doc.select(".c")).to eq([])
+      end
+
+      it "rejects names that would be split into several classes" do
+        paragraph = Sawzall.parse_fragment("<p>Text</p>").at("p")
+
+        expect { paragraph.add_class("a b") }.to raise_error(ArgumentError, 'invalid class name "a b"')
+        expect { paragraph.toggle_class("") }.to raise_error(ArgumentError, 'invalid class name ""')
+      end
+    end
+
+    describe "#remove" do
+      it "detaches the element from the document" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <p>Article <img src="/ad.png" class="ad-slot"></p>
+          <div class="ad-slot"><img src="/banner.png"></div>
+        HTML
+
+        ads = doc.select(".ad-slot")
+        expect(ads.map(&:remove)).to eq(ads)
+
+        expect(doc.select(".ad-slot, img")).to be_empty
+        expect(doc.images).to be_empty
+        expect(doc.to_html.strip).to eq("<p>Article </p>")
+
+        expect(ads[1].select("img").map { it.attr("src") }).to eq(["/banner.png"])
+        expect(ads[1].remove).to equal(ads[1])
+      end
+
+      it "refuses to remove the root element" do
+        doc = Sawzall.parse_fragment("<p>Text</p>")
+
+        expect { doc.root_element.remove }.to raise_error(RuntimeError, "can't remove the root element")
+      end
+
+      it "leaves removed elements out of document-wide queries" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <head>
+            <base href="https://ads.example.com/">
+            <meta http-equiv="Content-Security-Policy" content="img-src 'self'">
+            <meta name="robots" content="noindex">
+            <style>p { color: red }</style>
+          </head>
+          <body>
+            <div class="ad-slot">
+              <h1>Sponsored</h1>
+              <form action="/click"><input name="q"></form>
+              <img src="/ad.png">
+              <a href="/click">Click</a>
+            </div>
+            <p><a href="/home">Home</a></p>
+          </body>
+        HTML
+        removed = doc.select("base, meta, style, .ad-slot")
+        removed.each(&:remove)
+
+        expect(doc.content_security_policies).to eq([])
+        expect(doc.robots_directives).to eq(meta: {}, links: [])
+        expect(doc.images).to be_empty
+        expect(doc.forms).to eq([])
+        expect(doc.outline).to eq([])
+
+        doc.inline_css!.absolutize_urls!("https://example.com/")
+
+        expect(doc.select("p").first.attr("style")).to be_nil
+        expect(doc.select("a").map { it.attr("href") }).to eq(["https://example.com/home"])
+        expect(removed.last.select("a").first.attr("href")).to eq("/click")
+      end
+    end
+
+    describe "#replace_with" do
+      it "replaces the element with the given HTML" do
+        doc = Sawzall.parse_fragment("<p>Watch <iframe src='/video'></iframe> now</p>")
+        iframe = doc.select("iframe").first
+
+        expect(iframe.replace_with("<a href='/video'>the video</a> or <b>not</b>")).to equal(iframe)
+
+        expect(doc.to_html).to eq('<p>Watch <a href="/video">the video</a> or <b>not</b> now</p>')
+        expect(doc.select("iframe")).to be_empty
+        expect(doc.select("a").first.attr("href")).to eq("/video")
+      end
+
+      it "replaces the element with a copy of another element" do
+        doc = Sawzall.parse_fragment("<p><span>Old</span></p><em id='new'>New</em>")
+        other = Sawzall.parse_fragment("<b>Other</b>").select("b").first
+
+        doc.select("span").first.replace_with(doc.select("#new").first)
+        expect(doc.to_html).to eq('<p><em id="new">New</em></p><em id="new">New</em>')
+
+        doc.select("p em").first.replace_with(other)
+        expect(doc.to_html).to eq('<p><b>Other</b></p><em id="new">New</em>')
+        expect(other.html).to eq("<b>Other</b>")
+      end
+
+      it "refuses to replace the root element or a removed element" do
+        doc = Sawzall.parse_fragment("<p>Text</p>")
+        paragraph = doc.select("p").first.remove
+
+        expect { doc.root_element.replace_with("<p></p>") }
+          .to raise_error(RuntimeError, "can't replace the root element")
+        expect { paragraph.replace_with("<p></p>") }
+          .to raise_error(RuntimeError, "can't replace a removed element")
+      end
+    end
+
+    describe "#add_child" do
+      it "moves elements of the same document and copies others" do
+        doc = Sawzall.parse_fragment("<ul><li>One</li></ul><ol></ol>")
+        other = Sawzall.parse_fragment("<li>Three</li>").at("li")
+        item = doc.at("li")
+
+        ordered = doc.at("ol")
+        expect(ordered.add_child(item)).to equal(ordered)
+        ordered.add_child("<li>Two</li>").add_child(other)
+
+        expect(doc.to_html).to eq("<ul></ul><ol><li>One</li><li>Two</li><li>Three</li></ol>")
+        expect(item.html).to eq("<li>One</li>")
+        expect(other.html).to eq("<li>Three</li>")
+      end
+
+      it "refuses to create cycles" do
+        doc = Sawzall.parse_fragment("<div><p>Text</p></div>")
+        div = doc.at("div")
+
+        expect { doc.at("p").add_child(div) }
+          .to raise_error(ArgumentError, "can't add an element to itself or its descendants")
+        expect { div.add_child(div) }
+          .to raise_error(ArgumentError, "can't add an element to itself or its descendants")
+        expect { doc.create_element("div").add_child(doc.root_element) }
+          .to raise_error(RuntimeError, "can't move the root element")
+      end
+    end
+
+    describe "#add_previous_sibling" do
+      it "inserts the node before the element" do
+        doc = Sawzall.parse_fragment("<p><b>Two</b></p><i>One</i>")
+        bold = doc.at("b")
+
+        expect(bold.add_previous_sibling(doc.at("i"))).to equal(bold)
+        bold.add_previous_sibling(doc.create_text_node(" & "))
+
+        expect(doc.to_html).to eq("<p><i>One</i> &amp; <b>Two</b></p>")
+      end
+
+      it "can't insert next to the root element or a removed element" do
+        doc = Sawzall.parse_fragment("<p>Text</p>")
+        paragraph = doc.at("p").remove
+
+        expect { doc.root_element.add_previous_sibling("<p></p>") }
+          .to raise_error(RuntimeError, "can't insert next to the root element")
+        expect { paragraph.add_previous_sibling("<p></p>") }
+          .to raise_error(RuntimeError, "can't insert next to a removed element")
+      end
+    end
+
+    describe "#add_next_sibling" do
+      it "inserts the node after the element" do
+        doc = Sawzall.parse_fragment("<p><b>One</b>Three</p>")
+        bold = doc.at("b")
+
+        expect(bold.add_next_sibling("<i>Two</i>")).to equal(bold)
+        bold.add_next_sibling(doc.create_text_node(" "))
+
+        expect(doc.to_html).to eq("<p><b>One</b> <i>Two</i>Three</p>")
+      end
+
+      it "refuses to create cycles" do
+        doc = Sawzall.parse_fragment("<div><p>Text</p></div><span></span>")
+        paragraph = doc.at("p")
+
+        expect { paragraph.add_next_sibling(doc.at("div")) }
+          .to raise_error(ArgumentError, "can't add an element to itself or its descendants")
+        expect { doc.at("span").add_next_sibling(doc.at("span")) }
+          .to raise_error(ArgumentError, "can't add an element to itself or its descendants")
+      end
+    end
+
+    describe "#insert_child" do
+      it "inserts the node at the given index among child nodes" do
+        doc = Sawzall.parse_fragment("<ul><li>One</li>Text<li>Four</li></ul>")
+        list = doc.at("ul")
+
+        expect(list.insert_child(2, "<li>Three</li>")).to equal(list)
+        list.insert_child(1, doc.create_text_node("Two"))
+        list.insert_child(10, "<li>Five</li>")
+
+        expect(doc.to_html).to eq("<ul><li>One</li>TwoText<li>Three</li><li>Four</li><li>Five</li></ul>")
+      end
+
+      it "moves existing children to the given index" do
+        doc = Sawzall.parse_fragment("<ol><li>A</li><li>B</li><li>C</li></ol>")
+        list = doc.at("ol")
+
+        list.insert_child(2, doc.at("li"))
+        expect(list.select("li").map(&:text)).to eq(%w[B C A])
+
+        list.insert_child(0, doc.at("li"))
+        expect(list.select("li").map(&:text)).to eq(%w[B C A])
+      end
+    end
+
+    describe "#wrap" do
+      it "encloses the element in the given HTML" do
+        doc = Sawzall.parse_fragment("<p>Before <img src='/cat.png'> after</p>")
+        image = doc.select("img").first
+
+        expect(image.wrap("<figure><a href='/cat'></a><figcaption>Cat</figcaption></figure>")).to equal(image)
+
+        expect(doc.to_html).to eq(
+          '<p>Before <figure><a href="/cat"><img src="/cat.png"></a><figcaption>Cat</figcaption></figure> after</p>'
+        )
+        expect(doc.select("a > img").map { it.attr("src") }).to eq(["/cat.png"])
+      end
+
+      it "requires an element to wrap with" do
+        doc = Sawzall.parse_fragment("<p><img></p>")
+
+        expect { doc.select("img").first.wrap("text") }
+          .to raise_error(ArgumentError, "the HTML to wrap with must contain an element")
+        expect { doc.root_element.wrap("<div></div>") }
+          .to raise_error(RuntimeError, "can't wrap the root element")
+      end
+    end
+
+    describe "#unwrap" do
+      it "replaces the element with its children" do
+        doc = Sawzall.parse_fragment("<p>Some <font color='red'>red <b>bold</b></font> text</p>")
+        font = doc.select("font").first
+
+        expect(font.unwrap).to equal(font)
+
+        expect(doc.to_html).to eq("<p>Some red <b>bold</b> text</p>")
+        expect(font.html).to eq('<font color="red"></font>')
+        expect { doc.root_element.unwrap }.to raise_error(RuntimeError, "can't unwrap the root element")
+      end
+    end
+
+    describe "#before_html, #prepend_html, #append_html and #after_html" do
+      it "inserts the parsed HTML relative to the element" do
+        doc = Sawzall.parse_fragment("<div><p>Text</p></div>")
+        paragraph = doc.select("p").first
+
+        expect(paragraph.before_html("<h1>Title</h1>")).to equal(paragraph)
+        expect(paragraph.prepend_html("<b>Bold</b> ")).to equal(paragraph)
+        expect(paragraph.append_html(" <i>italic</i>")).to equal(paragraph)
+        expect(paragraph.after_html("<hr><footer>End</footer>")).to equal(paragraph)
+
+        expect(doc.to_html).to eq(
+          "<div><h1>Title</h1><p><b>Bold</b> Text <i>italic</i></p><hr><footer>End</footer></div>"
+        )
+        expect(doc.select("div > *").map(&:name)).to eq(["h1", "p", "hr", "footer"])
+      end
+
+      it "refuses to insert next to the root element or a removed element" do
+        doc = Sawzall.parse_fragment("<p>Text</p>")
+        paragraph = doc.select("p").first.remove
+
+        expect { doc.root_element.after_html("<p></p>") }
+          .to raise_error(RuntimeError, "can't insert next to the root element")
+        expect { paragraph.before_html("<p></p>") }
+          .to raise_error(RuntimeError, "can't insert next to a removed element")
+        expect(paragraph.append_html("<b>!</b>").html).to eq("<p>Text<b>!</b></p>")
+      end
+    end
+
+    describe "#select" do
+      it "returns elements that match the CSS selector" do
+        doc = Sawzall.parse_document(sample_document)
+
+        selection = doc.root_element.select("p")
+        expect(selection.size).to eq(1)
+        expect(selection[0]).to be_a(Sawzall::Element)
+        expect(selection[0].name).to eq("p")
+        expect(selection[0].inner_html).to eq("This is an HTML document")
+      end
+    end
+
+    describe "#xpath" do
+      it "evaluates relative paths from the element" do
+        doc = Sawzall.parse_fragment("<p>One</p><div><p>Two</p></div>")
+        div = doc.at("div")
+
+        expect(div.xpath("p").map(&:text)).to eq(["Two"])
+        expect(div.xpath(".//p | ../p").map(&:text)).to eq(["One", "Two"])
+        expect(div.xpath("//p").map(&:text)).to eq(["One", "Two"])
+        expect(div.xpath("name()")).to eq("div")
+      end
+    end
+
+    describe "#child_elements" do
+      it "returns an array of child elements" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <ul>
+            <li id="child1">One</li>
+            <li id="child2">
+              <ul id="grand-child1">
+                <li>Two</li>
+                <li>Three</li>
+              </ul>
+            </li>
+          </ul>
+        HTML
+
+        ul = doc.select("ul").first
+        children = ul.child_elements
+        expect(children).to all(be_a(Sawzall::Element))
+        expect(children.map { |c| c.attr("id") }).to eq(["child1", "child2"])
+        expect(children[1].child_elements.map { |c| c.attr("id") }).to eq(["grand-child1"])
+      end
+
+      it "returns an empty array if there are no child elements" do
+        doc = Sawzall.parse_fragment("<img src='https://example.com/image.png'/>")
+
+        expect(doc.select("img").first.child_elements).to be_empty
+      end
+    end
+
+    describe "#child_nodes" do
+      it "returns child elements, text nodes and comments" do
+        doc = Sawzall.parse_fragment("<p>Call <b>now</b><!-- note --> 555-0100</p>")
+        nodes = doc.at("p").child_nodes
+
+        expect(nodes.map(&:class)).to eq([Sawzall::TextNode, Sawzall::Element, Sawzall::Comment, Sawzall::TextNode])
+        expect(nodes.map(&:text)).to eq(["Call ", "now", " note ", " 555-0100"])
+      end
+
+      it "allows rewriting text in place" do
+        doc = Sawzall.parse_fragment("<p>Call <b>now</b> 555-0100</p>")
+        text = doc.at("p").child_nodes.last
+
+        text.content = text.content.sub("555-0100", "<555-0199>")
+
+        expect(text.content).to eq(" <555-0199>")
+        expect(doc.to_html).to eq("<p>Call <b>now</b> &lt;555-0199&gt;</p>")
+        expect(doc.at("p").text).to eq("Call now <555-0199>")
+      end
+
+      it "rejects text that would close a raw text element" do
+        doc = Sawzall.parse_fragment("<style>a {}</style>")
+        text = doc.at("style").child_nodes.first
+
+        expect { text.content = "a {} </style><script>" }
+          .to raise_error(ArgumentError, "the content of <style> can't contain \"</style\"")
+        expect(text.content).to eq("a {}")
+      end
+    end
+
+    describe "#each_node" do
+      it "yields the element and its descendants in document order" do
+        doc = Sawzall.parse_fragment("<div><p>Call <b>now</b><!-- note --></p></div>")
+        p = doc.at("p")
+
+        expect(p.each_node.map(&:class)).to eq([
+          Sawzall::Element, Sawzall::TextNode, Sawzall::Element, Sawzall::TextNode, Sawzall::Comment
+        ])
+        expect(p.each_node.map(&:html)).to eq([
+          "<p>Call <b>now</b><!-- note --></p>", "Call ", "<b>now</b>", "now", "<!-- note -->"
+        ])
+        expect(p.each_node { }).to be(p)
+      end
+    end
+
+    describe "#each_element" do
+      it "yields the elements inside the element in document order" do
+        doc = Sawzall.parse_fragment("<div><nav><a>Home</a></nav><p>Text <a>link</a><!-- note --></p></div>")
+        div = doc.at("div")
+
+        expect(div.each_element.map(&:name)).to eq(["nav", "a", "p", "a"])
+        expect(div.each_element { }).to be(div)
+      end
+
+      it "skips the descendants of elements for which the block returns :skip_children" do
+        doc = Sawzall.parse_fragment("<div><nav><a>Home</a><ul><li>Menu</li></ul></nav><p><a>link</a></p></div>")
+        names = []
+
+        doc.at("div").each_element do |element|
+          names << element.name
+          :skip_children if element.name == "nav"
+        end
+
+        expect(names).to eq(["nav", "p", "a"])
+      end
+    end
+
+    describe "#traverse" do
+      it "yields open and close events for the element and its descendants" do
+        doc = Sawzall.parse_fragment("<div><p>Call <b>now</b><!-- note --></p></div>")
+        p = doc.at("p")
+
+        expect(p.traverse.map { |event, node| [event, node.html] }).to eq([
+          [:open, "<p>Call <b>now</b><!-- note --></p>"],
+          [:open, "Call "],
+          [:close, "Call "],
+          [:open, "<b>now</b>"],
+          [:open, "now"],
+          [:close, "now"],
+          [:close, "<b>now</b>"],
+          [:open, "<!-- note -->"],
+          [:close, "<!-- note -->"],
+          [:close, "<p>Call <b>now</b><!-- note --></p>"]
+        ])
+        expect(p.traverse { }).to be(p)
+      end
+
+      it "yields the same node for both events" do
+        doc = Sawzall.parse_fragment("<p>Call <b>now</b></p>")
+        opened = []
+
+        doc.at("p").traverse do |event, node|
+          if event == :open
+            opened.push(node)
+          else
+            expect(opened.pop).to be(node)
+          end
+        end
+
+        expect(opened).to be_empty
+      end
+    end
+
+    describe "#text" do
+      it "returns the element's text content" do
+        doc = Sawzall.parse_fragment(sample_fragment)
+
+        expect(doc.root_element.text).to eq("Hello, world\n\nThis is an HTML fragment")
+      end
+
+      it "collapses whitespace outside of preformatted elements" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <p>
+            A paragraph that was
+            wrapped   by an editor
+          </p>
+          <pre>  indented
+            code</pre>
+        HTML
+
+        expect(doc.root_element.text).to eq(
+          "A paragraph that was wrapped by an editor\n\n  indented\n  code"
+        )
+      end
+    end
+
+    describe "#own_text" do
+      it "returns only the element's direct text" do
+        doc = Sawzall.parse_fragment("<li> Label <span>extra</span> more </li>")
+
+        expect(doc.select("li").first.own_text).to eq("Label  more")
+      end
+
+      it "returns an empty string if there are no text nodes" do
+        doc = Sawzall.parse_fragment("<div><p>nested</p></div>")
+
+        expect(doc.select("div").first.own_text).to eq("")
+      end
+    end
+
+    describe "#paragraphs" do
+      it "splits the text at block boundaries" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <section>
+            Intro
+            <p>First <b>paragraph</b><br>continued</p>
+            <ul><li><a href="/one">One</a></li><li>Two <a href="/two">links</a></li></ul>
+          </section>
+        HTML
+        paragraphs = doc.select("section").first.paragraphs
+
+        expect(paragraphs.map { it[:text] }).to eq(["Intro", "First paragraph\ncontinued", "One", "Two links"])
+        expect(paragraphs.map { it[:element].name }).to eq(["section", "p", "li", "li"])
+        expect(paragraphs.map { it[:word_count] }).to eq([1, 3, 1, 2])
+        expect(paragraphs.map { it[:link_density] }).to eq([0.0, 0.0, 1.0, 0.625])
+      end
+
+      it "returns an empty array without text" do
+        doc = Sawzall.parse_fragment("<div><p> </p><img src='a.png'></div>")
+
+        expect(doc.select("div").first.paragraphs).to eq([])
+        expect(doc.at("span", null_object: true).paragraphs).to eq([])
+      end
+    end
+
+    describe "#text_length and #link_density" do
+      let(:doc) do
+        Sawzall.parse_fragment(<<~HTML)
+          <article>
+            <p>Über <a href="/one">one</a> and <a href="/two"><b>two</b></a>.</p>
+            <ul class="share"><li><a href="/x">Share</a></li></ul>
+          </article>
+        HTML
+      end
+
+      it "measures the non-whitespace text in bytes" do
+        expect(doc.at("p").text_length).to eq(15)
+        expect(doc.at("article").text_length).to eq(20)
+        expect(doc.at("p").text_length).to eq(doc.at("p").text.delete(" ").bytesize)
+      end
+
+      it "returns the share of the text inside links" do
+        expect(doc.at("p").link_density).to eq(6 / 15.0)
+        expect(doc.at(".share").link_density).to eq(1.0)
+        expect(doc.at("article").link_density).to eq(11 / 20.0)
+        expect(Sawzall.parse_fragment("<p> </p>").at("p").link_density).to eq(0.0)
+      end
+    end
+
+    describe "#has_class?" do
+      it "returns true if the element has the given class" do
+        doc = Sawzall.parse_fragment("<h1 class='one two Élément'>Heading</h1>")
+        h1 = doc.select("h1").first
+
+        expect(h1).to have_class("one")
+        expect(h1).to have_class("OnE", case_sensitive: false)
+        expect(h1).not_to have_class("three")
+
+        # Case-insensitive matching is ASCII-only
+        expect(h1).to have_class("Élément")
+        expect(h1).not_to have_class("élément", case_sensitive: false)
+      end
+    end
+
+    describe "#classes" do
+      it "returns the element's classes" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <h1 class="one two">Heading</h1>
+          <h2>Subheading</h1>
+        HTML
 
-      expect(doc).to be_a(Sawzall::Document)
+        expect(doc.select("h1").first.classes).to eq(["one", "two"])
+        expect(doc.select("h2").first.classes).to eq([])
+      end
+
+      it "returns frozen strings" do
+        doc = Sawzall.parse_fragment("<h1 class='one'>Heading</h1>")
+
+        expect(doc.select("h1").first.classes).to all(be_frozen)
+      end
+
+      it "returns symbols with symbolize_names: true" do
+        doc = Sawzall.parse_fragment("<h1 class='one two-three'>Heading</h1>")
+
+        expect(doc.at("h1").classes(symbolize_names: true)).to eq([:one, :"two-three"])
+        expect { doc.at("h1").classes(symbolize: true) }
+          .to raise_error(ArgumentError, "unknown keyword :symbolize, expected one of :symbolize_names")
+      end
     end
-  end
 
-  describe Sawzall::Document do
-    describe "#select" do
-      it "returns elements that match the CSS selector" do
-        doc = Sawzall.parse_document(sample_document)
+    describe "#node_id" do
+      it "is stable across lookups" do
+        doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul>")
 
-        selection = doc.select("p")
-        expect(selection.size).to eq(1)
-        expect(selection[0]).to be_a(Sawzall::Element)
-        expect(selection[0].name).to eq("p")
-        expect(selection[0].inner_html).to eq("This is an HTML document")
+        first = doc.select("li").map(&:node_id)
+        second = doc.select("li").map(&:node_id)
+
+        expect(first).to eq(second)
+        expect(first.uniq.size).to eq(2)
       end
+    end
 
-      it "works with multiple matching elements" do
-        doc = Sawzall.parse_fragment(<<~HTML)
-          <ul>
-            <li>One</li>
-            <li>Two</li>
-          </ul>
-        HTML
+    describe "#annotation" do
+      it "is shared by every wrapper of the element" do
+        doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
 
-        selection = doc.select("ul li")
-        expect(selection.map(&:name)).to eq(["li", "li"])
-        expect(selection.map(&:inner_html)).to eq(["One", "Two"])
+        doc.at("p").annotation = {score: 1}
+
+        expect(doc.select("p").map(&:annotation)).to eq([{score: 1}, nil])
+        expect(doc.xpath("//p[1]").first.annotation).to eq({score: 1})
       end
 
-      it "returns nothing if there are no matching elements" do
-        doc = Sawzall.parse_document(sample_document)
+      it "removes the annotation when assigned nil" do
+        doc = Sawzall.parse_fragment("<p>One</p>")
 
-        selection = doc.select("table")
-        expect(selection).to be_empty
+        doc.at("p").annotation = "value"
+        doc.at("p").annotation = nil
+
+        expect(doc.at("p").annotation).to be_nil
+        expect(doc.annotations).to eq({})
       end
 
-      it "raises an error if the selector is invalid" do
-        doc = Sawzall.parse_fragment("")
+      it "keeps the values alive through garbage collections" do
+        doc = Sawzall.parse_fragment("<p>One</p>")
+        doc.at("p").annotation = Array.new(3) { "value #{it}" }
 
-        expect { doc.select("div[]") }
-          .to raise_error(ArgumentError, /failed to parse selector "div\[\]"/)
+        GC.start(full_mark: true, immediate_sweep: true)
+
+        expect(doc.at("p").annotation).to eq(["value 0", "value 1", "value 2"])
+      end
+
+      it "keeps the values alive through elements that outlive their document wrapper" do
+        heading = Sawzall.parse_fragment("<h1>Title</h1>").at("h1")
+        heading.annotation = Object.new.to_s
+
+        GC.start(full_mark: true, immediate_sweep: true)
+
+        expect(heading.annotation).to match(/\A#<Object:/)
+      end
+
+      it "raises Sawzall::StaleDocumentError once a weak element's document is garbage collected" do
+        heading = Sawzall.parse_fragment("<h1>Title</h1>", weak_elements: true).at("h1")
+
+        GC.start(full_mark: true, immediate_sweep: true)
+
+        expect { heading.annotation = 1 }.to raise_error(Sawzall::StaleDocumentError)
       end
     end
 
-    describe "#root_element" do
-      it "returns the root element" do
-        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
-        element = doc.root_element
+    describe "#inner_html=" do
+      it "replaces the content with HTML parsed in the element's context" do
+        doc = Sawzall.parse_fragment("<ul><li>Old</li></ul><table></table>")
 
-        expect(element).to be_a(Sawzall::Element)
-        expect(element.name).to eq("html")
-        expect(element.html).to eq("<html><h1>Heading</h1></html>")
+        doc.at("ul").inner_html = "<li>One</li><li>Two</li>"
+        doc.at("table").inner_html = "<tr><td>Cell</td></tr>"
+
+        expect(doc.select("li").map(&:text)).to eq(["One", "Two"])
+        expect(doc.at("table td").text).to eq("Cell")
+      end
+
+      it "keeps the content of raw text elements as text" do
+        doc = Sawzall.parse_fragment("<script></script><style></style><textarea></textarea>")
+
+        doc.at("script").inner_html = "if (a < b && c) { d('<b>') }"
+        doc.at("style").inner_html = "a > b { color: red }"
+        doc.at("textarea").inner_html = "<b>bold</b> &amp; more"
+
+        expect(doc.at("script").child_elements).to eq([])
+        expect(doc.to_html).to eq(
+          "<script>if (a < b && c) { d('<b>') }</script>" \
+          "<style>a > b { color: red }</style>" \
+          "<textarea>&lt;b&gt;bold&lt;/b&gt; &amp; more</textarea>"
+        )
+        expect(doc.at("textarea").content).to eq("<b>bold</b> & more")
+      end
+
+      it "rejects text that would close a raw text element" do
+        doc = Sawzall.parse_fragment("<script>ok()</script><svg><script></script></svg>")
+
+        expect { doc.at("script").inner_html = "a = '</SCRIPT>'" }
+          .to raise_error(ArgumentError, "the content of <script> can't contain \"</script\"")
+        expect(doc.at("script").inner_html).to eq("ok()")
+
+        expect { doc.select("script").last.inner_html = "<![CDATA[ a = '</script>' ]]>" }
+          .not_to raise_error
       end
     end
-  end
 
-  describe Sawzall::Element do
-    describe "#name" do
-      it "returns the element's name" do
-        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+    describe "#to_h" do
+      it "returns the subtree as nested hashes" do
+        doc = Sawzall.parse_fragment(<<~HTML.chomp)
+          <ul id="list"><li>One</li><!-- skip --><li><svg><use xlink:href="#a"/></svg></li></ul>
+        HTML
 
-        expect(doc.select("h1").first.name).to eq("h1")
+        expect(doc.at("ul").to_h).to eq(
+          name: "ul",
+          attrs: {"id" => "list"},
+          children: [
+            {name: "li", attrs: {}, children: [{text: "One"}]},
+            {name: "li", attrs: {}, children: [
+              {name: "svg", attrs: {}, children: [
+                {name: "use", attrs: {"xlink:href" => "#a"}, children: []}
+              ]}
+            ]}
+          ]
+        )
+      end
+
+      it "handles deeply nested documents" do
+        doc = Sawzall.parse_fragment("<span>" * 10_000)
+
+        expect(doc.at("span").to_h[:children].first[:name]).to eq("span")
       end
     end
 
-    describe "#html" do
-      it "returns the element's outer HTML" do
-        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+    describe "#to_json" do
+      it "matches #to_h" do
+        require "json"
+        doc = Sawzall.parse_fragment(%(<p title="a &quot;quote&quot;">Tab\tand \u00e9<br></p>))
+        p = doc.at("p")
 
-        expect(doc.select("h1").first.html).to eq("<h1>Heading</h1>")
+        expect(JSON.parse(p.to_json, symbolize_names: true)).to eq(
+          name: "p",
+          attrs: {title: 'a "quote"'},
+          children: [{text: "Tab\tand \u00e9"}, {name: "br", attrs: {}, children: []}]
+        )
+        expect(JSON.generate([p])).to eq("[#{p.to_json}]")
+        expect(JSON.parse(doc.to_json)).to eq(JSON.parse(doc.root_element.to_json))
+        expect(doc.to_h).to eq(doc.root_element.to_h)
       end
     end
 
-    describe "#inner_html" do
-      it "returns the element's inner HTML" do
-        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+    describe "#document_position" do
+      it "orders elements like a preorder traversal" do
+        doc = Sawzall.parse_document("<ul><li>One<b>!</b></li><li>Two</li></ul>")
+        positions = doc.select("ul, li, b").to_h { [it.text, it.document_position] }
 
-        expect(doc.select("h1").first.inner_html).to eq("Heading")
+        expect(positions.keys).to eq(["One!\nTwo", "One!", "!", "Two"])
+        expect(positions.values).to eq(positions.values.sort)
       end
 
-      it "returns an empty string if there is no inner HTML" do
-        doc = Sawzall.parse_fragment("<img src='https://example.com/image.png'/>")
+      it "is updated when the document changes" do
+        doc = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+        one, two = doc.select("p")
+        before = two.document_position
 
-        expect(doc.select("img").first.inner_html).to eq("")
+        one.remove
+
+        expect(one.document_position).to be_nil
+        expect(two.document_position).to be < before
+        expect(doc.create_element("p").document_position).to be_nil
       end
     end
 
-    describe "#attr" do
-      it "returns the attribute value" do
-        doc = Sawzall.parse_fragment("<h1 id='main-heading'>Heading</h1>")
+    describe "#source_position" do
+      it "returns the line the element's start tag ends on" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <!DOCTYPE html>
+          <title>Title</title>
+          <p
+            class="a">One</p>
+          <p>Two<br></p>
+        HTML
 
-        expect(doc.select("h1").first.attr("id")).to eq("main-heading")
+        expect(doc.select("p, br").map(&:source_position)).to eq([{line: 4}, {line: 5}, {line: 5}])
+        expect(doc.at("title").source_position).to eq({line: 2})
       end
 
-      it "returns nil when the attribute does not exist" do
-        doc = Sawzall.parse_fragment("<h1 id='main-heading'>Heading</h1>")
+      it "is nil for elements that weren't parsed" do
+        doc = Sawzall.parse_fragment("<p>One</p>")
+        doc.root_element.add_child(doc.create_element("p"))
+        doc.root_element.append_html("<p>Three</p>")
 
-        expect(doc.select("h1").first.attr("class")).to be_nil
+        expect(doc.select("p").map(&:source_position)).to eq([{line: 1}, nil, nil])
       end
     end
 
-    describe "#attrs" do
-      it "returns an array of key-value pairs" do
-        doc = Sawzall.parse_fragment("<h1 id='heading' class='big' data-foo='bar'>Heading</h1>")
+    describe "#css_path" do
+      it "returns a selector matching only the element" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <main id="content">
+            <p>One</p>
+            <p class="a b">Two <b>2</b></p>
+          </main>
+          <footer><p id="a">Three</p><p id="a">Four</p></footer>
+        HTML
 
-        expect(doc.select("h1").first.attrs).to eq([
-          ["class", "big"],
-          ["data-foo", "bar"],
-          ["id", "heading"]
+        doc.select("main, p, b").each do |element|
+          expect(doc.select(element.css_path).map(&:node_id)).to eq([element.node_id])
+        end
+        expect(doc.at("b").css_path).to eq("#content > p:nth-child(2) > b")
+        expect(doc.select("footer p").map(&:css_path)).to eq([
+          "html > body > footer > p:nth-child(1)",
+          "html > body > footer > p:nth-child(2)"
         ])
       end
 
-      it "returns an empty array if there are no attributes" do
-        doc = Sawzall.parse_fragment("<h1>Heading</h1>")
+      it "is nil for elements outside the document" do
+        doc = Sawzall.parse_fragment("<p>One</p>")
 
-        expect(doc.select("h1").first.attrs).to eq([])
+        expect(doc.at("p").remove.css_path).to be_nil
+        expect(doc.create_element("p").css_path).to be_nil
       end
     end
 
-    describe "#select" do
-      it "returns elements that match the CSS selector" do
-        doc = Sawzall.parse_document(sample_document)
+    describe "#xpath_path" do
+      it "returns an XPath selecting only the element" do
+        doc = Sawzall.parse_document(<<~HTML)
+          <ul><li>One</li><li>Two <b>2</b></li></ul>
+          <svg><circle/><circle/></svg>
+        HTML
 
-        selection = doc.root_element.select("p")
-        expect(selection.size).to eq(1)
-        expect(selection[0]).to be_a(Sawzall::Element)
-        expect(selection[0].name).to eq("p")
-        expect(selection[0].inner_html).to eq("This is an HTML document")
+        doc.select("ul, li, b, circle").each do |element|
+          expect(doc.xpath(element.xpath_path).map(&:node_id)).to eq([element.node_id])
+        end
+        expect(doc.at("b").xpath_path).to eq("/html/body/ul/li[2]/b")
+        expect(doc.at("circle").xpath_path).to eq("/html/body/svg/circle[1]")
+      end
+
+      it "is nil for elements outside the document" do
+        doc = Sawzall.parse_fragment("<p>One</p>")
+
+        expect(doc.at("p").xpath_path).to eq("/html/p")
+        expect(doc.at("p").remove.xpath_path).to be_nil
       end
     end
 
-    describe "#child_elements" do
-      it "returns an array of child elements" do
+    describe "#rel_tokens" do
+      it "returns the unique lowercased rel tokens" do
         doc = Sawzall.parse_fragment(<<~HTML)
-          <ul>
-            <li id="child1">One</li>
-            <li id="child2">
-              <ul id="grand-child1">
-                <li>Two</li>
-                <li>Three</li>
-              </ul>
-            </li>
-          </ul>
+          <a href="/one" rel=" Nofollow	ugc nofollow ">One</a>
+          <a href="/two">Two</a>
         HTML
 
-        ul = doc.select("ul").first
-        children = ul.child_elements
-        expect(children).to all(be_a(Sawzall::Element))
-        expect(children.map { |c| c.attr("id") }).to eq(["child1", "child2"])
-        expect(children[1].child_elements.map { |c| c.attr("id") }).to eq(["grand-child1"])
+        expect(doc.select("a").map(&:rel_tokens)).to eq([["nofollow", "ugc"], []])
       end
 
-      it "returns an empty array if there are no child elements" do
-        doc = Sawzall.parse_fragment("<img src='https://example.com/image.png'/>")
+      it "returns frozen strings shared by every call" do
+        doc = Sawzall.parse_fragment('<a rel="nofollow">One</a><a rel="NOFOLLOW">Two</a>')
+        one, two = doc.select("a").map { it.rel_tokens.first }
 
-        expect(doc.select("img").first.child_elements).to be_empty
+        expect(one).to be_frozen
+        expect(one).to equal(two)
       end
     end
 
-    describe "#text" do
-      it "returns the element's text content" do
-        doc = Sawzall.parse_fragment(sample_fragment)
+    describe "#rel?" do
+      it "matches whole tokens case-insensitively" do
+        doc = Sawzall.parse_fragment("<form rel='NoOpener nofollower'></form>")
+        form = doc.select("form").first
 
-        expect(doc.root_element.text).to eq("Hello, world\n\nThis is an HTML fragment")
+        expect(form).to be_rel("noopener")
+        expect(form).not_to be_rel("nofollow")
       end
     end
 
-    describe "#has_class?" do
-      it "returns true if the element has the given class" do
-        doc = Sawzall.parse_fragment("<h1 class='one two Élément'>Heading</h1>")
-        h1 = doc.select("h1").first
-
-        expect(h1).to have_class("one")
-        expect(h1).to have_class("OnE", case_sensitive: false)
-        expect(h1).not_to have_class("three")
+    describe "#to_table" do
+      it "returns the table's rows by group" do
+        doc = Sawzall.parse_fragment(<<~HTML)
+          <table>
+            <thead><tr><th>Name</th><th>Age</th></tr></thead>
+            <tbody><tr><td>Alice</td><td>30</td></tr><tr><td>Bob</td></tr></tbody>
+            <tfoot><tr><td>Total</td><td>2</td></tr></tfoot>
+          </table>
+        HTML
 
-        # Case-insensitive matching is ASCII-only
-        expect(h1).to have_class("Élément")
-        expect(h1).not_to have_class("élément", case_sensitive: false)
+        expect(doc.select("table").first.to_table).to eq(
+          head: [["Name", "Age"]],
+          body: [["Alice", "30"], ["Bob", nil]],
+          foot: [["Total", "2"]]
+        )
       end
-    end
 
-    describe "#classes" do
-      it "returns the element's classes" do
+      it "expands cells spanning rows and columns" do
         doc = Sawzall.parse_fragment(<<~HTML)
-          <h1 class="one two">Heading</h1>
-          <h2>Subheading</h1>
+          <table>
+            <tr><th rowspan="2">Region</th><th colspan="2">Sales</th></tr>
+            <tr><th>Q1</th><th>Q2</th></tr>
+            <tr><td>North</td><td>1</td><td>2</td></tr>
+          </table>
         HTML
 
-        expect(doc.select("h1").first.classes).to eq(["one", "two"])
-        expect(doc.select("h2").first.classes).to eq([])
+        expect(doc.select("table").first.to_table).to eq(
+          head: [["Region", "Sales", "Sales"], ["Region", "Q1", "Q2"]],
+          body: [["North", "1", "2"]],
+          foot: []
+        )
+      end
+
+      it "returns nil for other elements" do
+        doc = Sawzall.parse_fragment("<p>Paragraph</p>")
+
+        expect(doc.select("p").first.to_table).to be_nil
       end
     end
 
@@ -276,4 +3166,214 @@ RSpec.describe Sawzall do
       end
     end
   end
+
+  describe "Nokogiri compatibility" do
+    let(:doc) do
+      Sawzall.parse_fragment(<<~HTML)
+        <ul class="links">
+          <li><a href="/one">One</a></li>
+          <li><a href="/two">Two</a> <!-- new --></li>
+        </ul>
+      HTML
+    end
+
+    it "queries with css and at_css" do
+      expect(doc.css("a").map(&:text)).to eq(["One", "Two"])
+      expect(doc.at_css("li").css("a").map(&:text)).to eq(["One"])
+      expect(doc.at_css("table")).to be_nil
+      expect(doc.at_css("table", null_object: true).at_css("a").css("b")).to eq([])
+    end
+
+    it "reads and writes attributes with []" do
+      link = doc.at_css("a")
+      link["rel"] = "nofollow"
+
+      expect(link["href"]).to eq("/one")
+      expect(link["title"]).to be_nil
+      expect(doc.at_css("a")["rel"]).to eq("nofollow")
+      expect(doc.at_css("table", null_object: true)["href"]).to be_nil
+    end
+
+    it "returns child nodes with children" do
+      expect(doc.at_css("li:last-child").children.map(&:class))
+        .to eq([Sawzall::Element, Sawzall::TextNode, Sawzall::Comment])
+      expect(doc.children.map(&:class)).to eq([Sawzall::Element, Sawzall::TextNode])
+      expect(Sawzall.parse_document("<!DOCTYPE html><p>One</p>").children.map(&:class))
+        .to eq([Sawzall::Doctype, Sawzall::Element])
+    end
+
+    it "returns the raw text with content" do
+      expect(doc.at_css("li:last-child").content).to eq("Two ")
+      expect(doc.content).to eq("\n  One\n  Two \n\n")
+    end
+
+    it "serializes elements with to_html" do
+      expect(doc.at_css("li").to_html).to eq('<li><a href="/one">One</a></li>')
+      expect(doc.at_css("table", null_object: true).to_html).to eq("")
+    end
+  end
+
+  describe ".diff" do
+    it "returns the changes between two documents" do
+      old = Sawzall.parse_document(<<~HTML)
+        <ul>
+          <li data-id="1">One</li>
+          <li data-id="2">Two</li>
+        </ul>
+        <!-- v1 -->
+      HTML
+      new = Sawzall.parse_document(<<~HTML)
+        <ul><li data-id="0">Zero</li><li data-id="1">One</li><li data-id="2" class="sold">2</li></ul>
+        <!-- v2 -->
+      HTML
+
+      changes = Sawzall.diff(old, new)
+
+      expect(changes.map { it[:type] }).to eq([:insert, :attribute, :text, :text])
+      expect(changes[0][:node].html).to eq('<li data-id="0">Zero</li>')
+      expect(changes[1]).to include(name: "class", from: nil, to: "sold")
+      expect(changes[1][:old_node].attr("data-id")).to eq("2")
+      expect(changes[2]).to include(from: "Two", to: "2")
+      expect(changes[3]).to include(from: " v1 ", to: " v2 ")
+      expect(changes[3][:node]).to be_a(Sawzall::Comment)
+    end
+
+    it "returns deleted nodes from the old document" do
+      old = Sawzall.parse_fragment("<p>One</p><p>Two</p>")
+      new = Sawzall.parse_fragment("<p>Two</p>")
+
+      changes = Sawzall.diff(old, new)
+
+      expect(changes.map { [it[:type], it[:node].text] }).to eq([[:delete, "One"]])
+      expect(changes[0][:node].document_position).to eq(old.at("p").document_position)
+    end
+
+    it "returns no changes for equivalent documents" do
+      doc = Sawzall.parse_fragment("<p class='a' id='b'>One</p>")
+
+      expect(Sawzall.diff(doc, doc)).to eq([])
+      expect(Sawzall.diff(doc, Sawzall.parse_fragment("\n<p id=b class=a>One</p>\n"))).to eq([])
+    end
+  end
+
+  describe Sawzall::Selector do
+    describe ".parse" do
+      it "returns selectors that can be used to select" do
+        selector = Sawzall::Selector.parse("li + li")
+        doc = Sawzall.parse_fragment("<ul><li>One</li><li>Two</li></ul>")
+
+        expect(selector.to_s).to eq("li + li")
+        expect(doc.select(selector).map(&:text)).to eq(["Two"])
+      end
+
+      it "raises for invalid selectors" do
+        expect { Sawzall::Selector.parse("li +") }
+          .to raise_error(ArgumentError, /failed to parse selector "li \+"/)
+      end
+    end
+  end
+
+  describe Sawzall::Pool do
+    describe "#parse" do
+      it "parses documents" do
+        pool = Sawzall::Pool.new
+
+        doc = pool.parse("<title>Page</title><p>Body</p>")
+
+        expect(doc.select("head title").first.text).to eq("Page")
+        expect(doc.to_html).to eq("<html><head><title>Page</title></head><body><p>Body</p></body></html>")
+      end
+
+      it "parses fragments" do
+        pool = Sawzall::Pool.new
+
+        docs = ["<p>One</p>", "<p>Two <b>2</b></p>", "<p>Three</p>"].map { pool.parse(it, fragment: true) }
+
+        expect(docs.map(&:to_html)).to eq(["<p>One</p>", "<p>Two <b>2</b></p>", "<p>Three</p>"])
+      end
+    end
+  end
+
+  describe Sawzall::StreamingParser do
+    it "parses a document fed in chunks" do
+      html = "<!DOCTYPE html><title>café</title>" + "<p>☕</p>" * 10_000
+      parser = Sawzall::StreamingParser.new
+
+      html.b.each_char.each_slice(1000) { parser << it.join }
+      doc = parser.finish
+
+      expect(doc.at("title").text).to eq("café")
+      expect(doc.to_html).to eq(Sawzall.parse_document(html).to_html)
+    end
+
+    it "parses fragments" do
+      parser = Sawzall::StreamingParser.new(fragment: true)
+
+      expect((parser << "<p>One <b" << ">two</b>").finish.to_html).to eq("<p>One <b>two</b></p>")
+    end
+
+    it "handles invalid bytes and limits" do
+      expect((Sawzall::StreamingParser.new << "<p>caf\xC3" << "\xA9\xE9</p>").finish.at("p").text)
+        .to eq("café\uFFFD")
+      expect { Sawzall::StreamingParser.new(invalid: :raise) << "<p>caf" << "\xE9</p>" }
+        .to raise_error(Sawzall::InvalidEncodingError, "invalid byte sequence in UTF-8 at byte 6")
+      expect { Sawzall::StreamingParser.new(max_input_bytes: 8) << "<p>caf" << "é</p>" }
+        .to raise_error(Sawzall::ParseLimitError)
+      expect { (Sawzall::StreamingParser.new(max_depth: 3) << "<div><div><div>").finish }
+        .to raise_error(Sawzall::ParseLimitError)
+    end
+
+    it "can't be used once finished or failed" do
+      parser = Sawzall::StreamingParser.new
+      parser.finish
+
+      expect { parser << "<p>" }.to raise_error(RuntimeError, "parser already finished or failed")
+      expect { parser.finish }.to raise_error(RuntimeError, "parser already finished or failed")
+
+      parser = Sawzall::StreamingParser.new(max_input_bytes: 2)
+
+      expect { parser << "<p>" }.to raise_error(Sawzall::ParseLimitError)
+      expect { parser.finish }.to raise_error(RuntimeError, "parser already finished or failed")
+    end
+  end
+
+  describe Sawzall::Sanitizer do
+    describe "#sanitize" do
+      it "keeps allowed elements and attributes" do
+        sanitizer = Sawzall::Sanitizer.new(
+          elements: ["p", "a"],
+          attributes: {a: ["href"], all: ["title"]}
+        )
+
+        html = '<p title="Intro" class="lead">Read <a href="/more" target="_blank">more</a></p>'
+
+        expect(sanitizer.sanitize(html)).to eq('<p title="Intro">Read <a href="/more">more</a></p>')
+      end
+
+      it "unwraps disallowed elements and removes script-like elements" do
+        sanitizer = Sawzall::Sanitizer.new(elements: ["p"])
+
+        expect(sanitizer.sanitize("<p><b>Hi</b><script>alert(1)</script><!-- x --></p>"))
+          .to eq("<p>Hi</p>")
+      end
+
+      it "removes URLs with disallowed protocols" do
+        sanitizer = Sawzall::Sanitizer.new(elements: ["a"], attributes: {"a" => ["href"]})
+
+        expect(sanitizer.sanitize("<a href='javascript:alert(1)'>one</a><a href='mailto:me@example.com'>two</a>"))
+          .to eq("<a>one</a><a href=\"mailto:me@example.com\">two</a>")
+
+        sanitizer = Sawzall::Sanitizer.new(elements: ["a"], attributes: {"a" => ["href"]}, protocols: ["https"])
+
+        expect(sanitizer.sanitize("<a href='mailto:me@example.com'>two</a>")).to eq("<a>two</a>")
+      end
+
+      it "escapes text" do
+        sanitizer = Sawzall::Sanitizer.new
+
+        expect(sanitizer.sanitize("<textarea><img src=x onerror=alert(1)></textarea>"))
+          .to eq("&lt;img src=x onerror=alert(1)&gt;")
+      end
+    end
+  end
 end